@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE forming_candles (
+    market_id uuid NOT NULL,
+    tf TEXT NOT NULL,
+    datetime timestamptz NOT NULL,
+    is_closed BOOLEAN NOT NULL,
+    data TEXT NOT NULL,
+    update_dt timestamptz NOT NULL,
+    PRIMARY KEY (market_id, tf)
+);
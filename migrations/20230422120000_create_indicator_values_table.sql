@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE indicator_values (
+    market_id uuid NOT NULL,
+    tf TEXT NOT NULL,
+    datetime timestamptz NOT NULL,
+    data JSONB NOT NULL,
+    insert_dt timestamptz NOT NULL,
+    PRIMARY KEY (market_id, tf, datetime)
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE correlation_matrices (
+    source TEXT NOT NULL,
+    tf TEXT NOT NULL,
+    datetime timestamptz NOT NULL,
+    data JSONB NOT NULL,
+    insert_dt timestamptz NOT NULL,
+    PRIMARY KEY (source, tf, datetime)
+);
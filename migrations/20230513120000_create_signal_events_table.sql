@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE signal_events (
+    signal_id uuid NOT NULL PRIMARY KEY,
+    market_id uuid NOT NULL,
+    name TEXT NOT NULL,
+    tf TEXT NOT NULL,
+    datetime timestamptz NOT NULL,
+    message TEXT NOT NULL,
+    insert_dt timestamptz NOT NULL
+);
+CREATE INDEX signal_events_market_id_datetime ON signal_events (market_id, datetime);
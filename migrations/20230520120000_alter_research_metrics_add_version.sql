@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE IF EXISTS research_metrics
+ADD COLUMN version INT NOT NULL DEFAULT 0,
+ADD COLUMN param_hash TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE signal_rules (
+    name TEXT NOT NULL PRIMARY KEY,
+    rule TEXT NOT NULL,
+    markets TEXT[] NOT NULL DEFAULT '{}',
+    tfs TEXT[] NOT NULL DEFAULT '{}',
+    cooldown BIGINT NOT NULL DEFAULT 0,
+    insert_dt timestamptz NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE IF EXISTS forming_candles ALTER COLUMN data TYPE JSONB USING data::JSONB;
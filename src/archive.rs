@@ -1,6 +1,5 @@
 // TODO: Make candles for months and zip / compress trades
 use crate::{
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
     markets::{MarketArchiveDetail, MarketCandleDetail, MarketDetail, MarketStatus},
     utilities::{DateRange, TimeFrame},
@@ -31,12 +30,12 @@ impl ElDorado {
 
     async fn archive_market(&self, market: &MarketDetail) -> Result<(), ElDoradoError> {
         // Check if there is a market archive detail
-        match MarketArchiveDetail::select(&self.pools[&Database::ElDorado], market).await {
+        match self.storage.select_mad(market).await {
             // Yes - proceed from the market archive detail information
             Ok(mad) => Ok(self.archive_months(market, &None, &mad).await?),
             Err(sqlx::Error::RowNotFound) => {
                 // Check if there is a market candle detail
-                match MarketCandleDetail::select(&self.pools[&Database::ElDorado], market).await {
+                match self.storage.select_mcd(market).await {
                     Ok(mcd) => {
                         let mad = self
                             .create_mad_and_archive_first_month(market, &mcd)
@@ -72,7 +71,7 @@ impl ElDorado {
         let mut mad = mad.to_owned();
         let mcd = match mcd {
             Some(m) => m.clone(),
-            None => MarketCandleDetail::select(&self.pools[&Database::ElDorado], market).await?,
+            None => self.storage.select_mcd(market).await?,
         };
         // Determine months to archive and put in date range
         match DateRange::new_monthly(&mad.next_month, &ElDorado::trunc_month_dt(&mcd.last_candle)) {
@@ -105,11 +104,7 @@ impl ElDorado {
         Ok(mad
             .as_ref()
             .unwrap()
-            .update(
-                &self.pools[&Database::ElDorado],
-                &next_month,
-                candles.last().unwrap(),
-            )
+            .update(self.storage.as_ref(), &next_month, candles.last().unwrap())
             .await?)
     }
 
@@ -124,7 +119,7 @@ impl ElDorado {
         if next_month <= current_month {
             let mad = self.archive_first_month(market, mcd, &next_month);
             // Insert and return mad
-            mad.insert(&self.pools[&Database::ElDorado]).await?;
+            self.storage.insert_mad(&mad).await?;
             Ok(Some(mad))
         } else {
             Ok(None)
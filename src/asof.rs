@@ -0,0 +1,149 @@
+use crate::{
+    eldorado::{ElDorado, ElDoradoError},
+    markets::MarketDetail,
+    metrics::{ResearchMetric, METRIC_VERSION},
+    sessions::Session,
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+// A metric is stamped with the open of its candle but is only known once the candle closes. The
+// candles that have closed as of a datetime are the ones opened before the start of the interval
+// containing it, so the metrics known as of the datetime are the ones stamped before the cutoff.
+pub fn as_of_cutoff(session: &Session, tf: &TimeFrame, as_of: &DateTime<Utc>) -> DateTime<Utc> {
+    session.trunc(tf, as_of)
+}
+
+// Latest metric stamped before the cutoff from metrics sorted by datetime
+pub fn latest_before<'a>(
+    metrics: &'a [ResearchMetric],
+    cutoff: &DateTime<Utc>,
+) -> Option<&'a ResearchMetric> {
+    let i = metrics.partition_point(|m| m.datetime < *cutoff);
+    i.checked_sub(1).map(|i| &metrics[i])
+}
+
+impl ElDorado {
+    // Metrics of each market and time frame that were known as of the datetime, ie the metric of
+    // the last closed candle. The research metrics table only holds the last few days so the
+    // metric archive is read for markets it has nothing for. Markets with neither are left out.
+    pub async fn select_metrics_as_of(
+        &self,
+        markets: &[MarketDetail],
+        tfs: &[TimeFrame],
+        as_of: &DateTime<Utc>,
+    ) -> Result<HashMap<Uuid, HashMap<TimeFrame, ResearchMetric>>, ElDoradoError> {
+        let mut map: HashMap<Uuid, HashMap<TimeFrame, ResearchMetric>> = HashMap::new();
+        for tf in tfs.iter() {
+            // Markets on the same session share the cutoff
+            let mut cutoffs: BTreeMap<DateTime<Utc>, Vec<&MarketDetail>> = BTreeMap::new();
+            for market in markets.iter() {
+                cutoffs
+                    .entry(as_of_cutoff(&self.sessions.session(market), tf, as_of))
+                    .or_default()
+                    .push(market);
+            }
+            for (cutoff, markets) in cutoffs.iter() {
+                let ids: Vec<_> = markets.iter().map(|m| m.market_id).collect();
+                for metric in self
+                    .storage
+                    .select_metrics_lt_dt(&ids, tf, cutoff, as_of)
+                    .await?
+                    .into_iter()
+                {
+                    map.entry(metric.market_id)
+                        .or_default()
+                        .insert(metric.tf, metric);
+                }
+                for market in markets.iter() {
+                    if map
+                        .get(&market.market_id)
+                        .map(|hm| hm.contains_key(tf))
+                        .unwrap_or(false)
+                    {
+                        continue;
+                    }
+                    if let Some(metric) = self.read_archived_metric_before(market, tf, cutoff) {
+                        map.entry(market.market_id).or_default().insert(*tf, metric);
+                    }
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    // Latest archived metric stamped before the cutoff. It is in the month file of the cutoff
+    // unless no candle of the time frame opened in that month before the cutoff, in which case it
+    // is the last one of the month before.
+    pub fn read_archived_metric_before(
+        &self,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        cutoff: &DateTime<Utc>,
+    ) -> Option<ResearchMetric> {
+        let mut month = Self::trunc_month_dt(&(*cutoff - Duration::seconds(1)));
+        for _ in 0..2 {
+            let metrics = self.read_metrics_from_file_for_month(market, &month, tf, METRIC_VERSION);
+            if let Some(metric) = latest_before(&metrics, cutoff) {
+                return Some(metric.clone());
+            }
+            month = Self::trunc_month_dt(&(month - Duration::days(1)));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asof::{as_of_cutoff, latest_before},
+        configuration::SessionConfig,
+        metrics::ResearchMetric,
+        sessions::Session,
+        test_helpers::{candles, market},
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    pub fn as_of_only_sees_closed_candles() {
+        let market = market("BTC-USD", TimeFrame::T15);
+        // Hourly metrics from 00:00 through 05:00
+        let start = Utc.with_ymd_and_hms(2023, 3, 10, 0, 0, 0).unwrap();
+        let closes: Vec<_> = (0..6).map(|i| Decimal::from(100 + i)).collect();
+        let candles = candles(start, &TimeFrame::H01, &closes);
+        let metrics: Vec<_> = (1..=candles.len())
+            .map(|n| ResearchMetric::new(&market, TimeFrame::H01, &candles[..n]))
+            .collect();
+        let utc = Session::utc();
+        // At 03:00 the 02:00 candle has just closed, at 03:59 the 03:00 candle is still open
+        let at_close = start + Duration::hours(3);
+        let cutoff = as_of_cutoff(&utc, &TimeFrame::H01, &at_close);
+        assert_eq!(
+            latest_before(&metrics, &cutoff).unwrap().datetime,
+            start + Duration::hours(2)
+        );
+        let before_close = at_close + Duration::minutes(59);
+        let cutoff = as_of_cutoff(&utc, &TimeFrame::H01, &before_close);
+        assert_eq!(
+            latest_before(&metrics, &cutoff).unwrap().datetime,
+            start + Duration::hours(2)
+        );
+        assert!(latest_before(&metrics, &start).is_none());
+        // Session days close at the session start, not at midnight
+        let settlement = Session::try_from(&SessionConfig {
+            timezone: "UTC".to_string(),
+            start: "08:00".to_string(),
+        })
+        .unwrap();
+        let as_of = Utc.with_ymd_and_hms(2023, 3, 10, 7, 0, 0).unwrap();
+        assert_eq!(
+            as_of_cutoff(&settlement, &TimeFrame::D01, &as_of),
+            Utc.with_ymd_and_hms(2023, 3, 9, 8, 0, 0).unwrap()
+        );
+    }
+}
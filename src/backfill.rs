@@ -0,0 +1,543 @@
+use crate::{
+    candles::ProductionCandle,
+    eldorado::{ElDorado, ElDoradoError},
+    indicators::{Indicator, IndicatorValues},
+    markets::MarketDetail,
+    metrics::{ResearchMetric, METRIC_VERSION},
+    mita::Heartbeat,
+    repair::diff_fields,
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, Utc};
+use csv::Writer;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+// Metrics of a month by time frame, ordered by datetime
+pub fn group_metrics_by_month(
+    metrics: Vec<ResearchMetric>,
+) -> BTreeMap<(DateTime<Utc>, TimeFrame), Vec<ResearchMetric>> {
+    let mut grouped: BTreeMap<(DateTime<Utc>, TimeFrame), Vec<ResearchMetric>> = BTreeMap::new();
+    for metric in metrics.into_iter() {
+        grouped
+            .entry((ElDorado::trunc_month_dt(&metric.datetime), metric.tf))
+            .or_default()
+            .push(metric);
+    }
+    for v in grouped.values_mut() {
+        v.sort_by_key(|m| m.datetime);
+    }
+    grouped
+}
+
+// Indicator values as of the candle of each metric, calculated from the heartbeat candles of the
+// metric time frame. The base time frame candles are the lower time frame candles for the
+// indicators that use them.
+pub fn indicators_for_metrics<F>(
+    market: &MarketDetail,
+    candles: &HashMap<TimeFrame, Vec<ProductionCandle>>,
+    metrics: &[ResearchMetric],
+    indicators_for: F,
+) -> Vec<IndicatorValues>
+where
+    F: Fn(&TimeFrame) -> Vec<Box<dyn Indicator>>,
+{
+    let base = candles.get(&market.tf).map(|c| c.as_slice()).unwrap_or(&[]);
+    let mut values = Vec::new();
+    for (tf, candles) in candles.iter() {
+        let dts: HashSet<_> = metrics
+            .iter()
+            .filter(|m| m.tf == *tf)
+            .map(|m| m.datetime)
+            .collect();
+        let first = match dts.iter().min() {
+            Some(dt) => *dt,
+            None => continue,
+        };
+        let indicators = indicators_for(tf);
+        if indicators.is_empty() {
+            continue;
+        }
+        let start = candles.partition_point(|c| c.datetime < first);
+        values.extend(
+            IndicatorValues::history(market, *tf, candles, base, &indicators, start)
+                .into_iter()
+                .filter(|v| dts.contains(&v.datetime)),
+        );
+    }
+    values
+}
+
+// Comparison of the metrics of a time frame recomputed under the current metric version against
+// the metrics of another version
+#[derive(Debug)]
+pub struct MetricReport {
+    pub tf: TimeFrame,
+    pub against: i32,
+    pub compared: usize,
+    // Recomputed metrics with at least one value that differs
+    pub changed: usize,
+    // Recomputed metrics with no metric to compare against
+    pub missing: usize,
+    // Number of recomputed metrics each field differs on
+    pub fields: BTreeMap<String, usize>,
+}
+
+impl MetricReport {
+    pub fn new(tf: &TimeFrame, against: i32) -> Self {
+        Self {
+            tf: *tf,
+            against,
+            compared: 0,
+            changed: 0,
+            missing: 0,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    // Compare the recomputed metrics to the metrics for the same datetime field by field. The
+    // version and param hash differ by definition so they are not compared.
+    pub fn add(&mut self, stored: &[ResearchMetric], recomputed: &[ResearchMetric]) {
+        let stored: HashMap<_, _> = stored.iter().map(|m| (m.datetime, m)).collect();
+        for metric in recomputed.iter() {
+            match stored.get(&metric.datetime) {
+                Some(s) => {
+                    self.compared += 1;
+                    let diffs: Vec<_> = diff_fields(&metric.datetime, *s, metric)
+                        .into_iter()
+                        .filter(|d| d.field != "version" && d.field != "param_hash")
+                        .collect();
+                    if !diffs.is_empty() {
+                        self.changed += 1;
+                    }
+                    for diff in diffs.into_iter() {
+                        *self.fields.entry(diff.field).or_default() += 1;
+                    }
+                }
+                None => self.missing += 1,
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{}: {} metrics compared against v{}, {} differ and {} missing.",
+            self.tf, self.compared, self.against, self.changed, self.missing
+        );
+        for (field, n) in self.fields.iter() {
+            println!("  {}\t{}", field, n);
+        }
+    }
+}
+
+impl ElDorado {
+    // Calculate the research metrics of the market for the archived months from through to
+    // (inclusive), defaulting to every month in the market archive detail, and write them to
+    // monthly metric files next to the candle archive. The archived candles are replayed through
+    // the mita heartbeat one base candle at a time after a warm up of the sync days before the
+    // first month, so the metrics are the ones a mita started at the first month would have made.
+    // The files are written under the current metric version. Returns the months replayed.
+    pub async fn backfill_metrics(
+        &self,
+        market: &MarketDetail,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<DateTime<Utc>>, ElDoradoError> {
+        let mad = self.storage.select_mad(market).await?;
+        let first_month = Self::trunc_month_dt(&mad.first_candle_dt);
+        let start = from
+            .map(|dt| Self::trunc_month_dt(&dt))
+            .unwrap_or(first_month)
+            .max(first_month);
+        let end = to
+            .map(|dt| Self::next_month_dt(&dt))
+            .unwrap_or(mad.next_month)
+            .min(mad.next_month);
+        if start >= end {
+            println!(
+                "No archived months for {} from {} to {}.",
+                market.market_name, start, end
+            );
+            return Ok(Vec::new());
+        }
+        println!(
+            "Backfilling {} metrics from {} to {}.",
+            market.market_name, start, end
+        );
+        // Warm up the heartbeat as the mita sync would
+        let warm_start = market
+            .tf
+            .trunc(&(start - Duration::days(self.sync_days)).max(mad.first_candle_dt));
+        let mut warmup = self.read_archive_production_candles(market, &warm_start, &start);
+        let mut month = start;
+        let mut candles = self.read_archive_production_candles(
+            market,
+            &month,
+            &end.min(Self::next_month_dt(&month)),
+        );
+        if warmup.is_empty() {
+            // The first archived month has no candles before it, start from its first candle
+            if candles.is_empty() {
+                println!("No archived candles for {}.", market.market_name);
+                return Ok(Vec::new());
+            }
+            warmup.push(candles.remove(0));
+        }
+        let mut heartbeats = HashMap::from([(market.market_name.clone(), Heartbeat::new())]);
+        heartbeats
+            .get_mut(&market.market_name)
+            .unwrap()
+            .candles
+            .insert(market.tf, warmup);
+        self.complete_heartbeat(market, &mut heartbeats).await;
+        let metrics = self.calc_metrics_all_tfs(market, &mut heartbeats);
+        let mut hb = heartbeats.remove(&market.market_name).unwrap();
+        hb.metrics = Some(metrics);
+        // Metrics written to the last month file of each time frame. Candles that open in one month
+        // and close in the next, ie weeks, get their metric in the next month so the file of the
+        // month they open in is rewritten with it. Indicator values are written the same way.
+        let mut written: HashMap<TimeFrame, (DateTime<Utc>, Vec<ResearchMetric>)> = HashMap::new();
+        let mut written_values: HashMap<TimeFrame, (DateTime<Utc>, Vec<IndicatorValues>)> =
+            HashMap::new();
+        // Replay each month and write its metrics. The heartbeat is trimmed to the sync days each
+        // day as the live one is so the metrics are calculated over the same window.
+        let mut months = Vec::new();
+        while month < end {
+            let mut metrics = Vec::new();
+            let mut values = Vec::new();
+            for candle in candles.into_iter() {
+                let interval_end = candle.datetime + market.tf.as_dur();
+                let new = self.advance_heartbeat(market, &mut hb, vec![candle], &interval_end);
+                values.extend(indicators_for_metrics(market, &hb.candles, &new, |tf| {
+                    self.indicators_for(market, tf)
+                }));
+                metrics.extend(new);
+            }
+            let mut grouped: BTreeMap<(DateTime<Utc>, TimeFrame), Vec<IndicatorValues>> =
+                BTreeMap::new();
+            for v in values.into_iter() {
+                grouped
+                    .entry((Self::trunc_month_dt(&v.datetime), v.tf))
+                    .or_default()
+                    .push(v);
+            }
+            for ((dt, tf), mut values) in grouped.into_iter() {
+                let file = written_values.entry(tf).or_insert_with(|| (dt, Vec::new()));
+                if file.0 != dt {
+                    *file = (dt, Vec::new());
+                }
+                file.1.append(&mut values);
+                self.write_indicators_to_file_for_month(market, &dt, &tf, &file.1);
+            }
+            for ((dt, tf), mut metrics) in group_metrics_by_month(metrics).into_iter() {
+                let file = written.entry(tf).or_insert_with(|| (dt, Vec::new()));
+                if file.0 != dt {
+                    *file = (dt, Vec::new());
+                }
+                file.1.append(&mut metrics);
+                self.write_metrics_to_file_for_month(market, &dt, &tf, &file.1);
+            }
+            months.push(month);
+            month = Self::next_month_dt(&month);
+            candles = self.read_archive_production_candles(
+                market,
+                &month,
+                &end.min(Self::next_month_dt(&month)),
+            );
+        }
+        Ok(months)
+    }
+
+    // Recalculate the metrics of the market under the current metric version next to the archived
+    // metrics of the version to compare against and print how they differ for each time frame.
+    // The live engine is not touched so the new version can be reviewed before switching to it.
+    pub async fn recompute_metrics(
+        &self,
+        market: &MarketDetail,
+        against: i32,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<(), ElDoradoError> {
+        if against == METRIC_VERSION {
+            println!(
+                "Metrics are already calculated under version {}.",
+                METRIC_VERSION
+            );
+            return Ok(());
+        }
+        let months = self.backfill_metrics(market, from, to).await?;
+        println!(
+            "Comparing {} metrics v{} against v{}.",
+            market.market_name, METRIC_VERSION, against
+        );
+        for tf in TimeFrame::tfs().iter() {
+            let mut report = MetricReport::new(tf, against);
+            for month in months.iter() {
+                report.add(
+                    &self.read_metrics_from_file_for_month(market, month, tf, against),
+                    &self.read_metrics_from_file_for_month(market, month, tf, METRIC_VERSION),
+                );
+            }
+            report.print();
+        }
+        Ok(())
+    }
+
+    // Archived S15 candles of the market from start to end (exclusive) resampled to the market
+    // time frame
+    fn read_archive_production_candles(
+        &self,
+        market: &MarketDetail,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Vec<ProductionCandle> {
+        let mut candles = Vec::new();
+        let mut month = Self::trunc_month_dt(start);
+        while month < *end {
+            candles.extend(
+                self.read_research_candles_from_file_for_month(market, &month, &TimeFrame::S15)
+                    .into_iter()
+                    .filter(|c| c.datetime >= *start && c.datetime < *end),
+            );
+            month = Self::next_month_dt(&month);
+        }
+        if candles.is_empty() {
+            return Vec::new();
+        }
+        self.resample_and_convert_research_candles_by_hashmap_v2(&candles, &market.tf)
+    }
+
+    pub fn write_metrics_to_file_for_month(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        metrics: &[ResearchMetric],
+    ) {
+        let pb = self.prep_metric_archive_path(market, dt, tf);
+        println!(
+            "Writing {} {} {} metrics for {}.",
+            metrics.len(),
+            market.market_name,
+            tf,
+            dt.format("%Y-%m")
+        );
+        let mut wtr = Writer::from_path(pb).expect("Failed to open file.");
+        for metric in metrics.iter() {
+            wtr.serialize(metric).expect("Failed to serialize metric.");
+        }
+        wtr.flush().expect("Failed to flush wtr.");
+    }
+
+    // Indicator values of the month next to the metric file, one column per indicator key, ie
+    // BTCUSD_h01_202303_indicators.csv
+    pub fn write_indicators_to_file_for_month(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        values: &[IndicatorValues],
+    ) {
+        let metric_pb = self.prep_metric_archive_path(market, dt, tf);
+        let pb = metric_pb.with_file_name(format!(
+            "{}_indicators.csv",
+            metric_pb.file_stem().unwrap().to_string_lossy()
+        ));
+        println!(
+            "Writing {} {} {} indicator values for {}.",
+            values.len(),
+            market.market_name,
+            tf,
+            dt.format("%Y-%m")
+        );
+        let keys: Vec<_> = values
+            .iter()
+            .flat_map(|v| v.values.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let mut wtr = Writer::from_path(pb).expect("Failed to open file.");
+        let mut header = vec!["datetime"];
+        header.extend(keys.iter().map(|k| k.as_str()));
+        wtr.write_record(&header).expect("Failed to write header.");
+        for v in values.iter() {
+            let mut record = vec![v.datetime.to_rfc3339()];
+            record.extend(keys.iter().map(|k| match v.values.get(*k) {
+                Some(Some(d)) => d.to_string(),
+                _ => String::new(),
+            }));
+            wtr.write_record(&record)
+                .expect("Failed to write indicator values.");
+        }
+        wtr.flush().expect("Failed to flush wtr.");
+    }
+
+    // Metric archive path mirroring the candle archive with a directory for each metric version so
+    // versions sit side by side, ie {archive}/metrics/v1/gdax/BTCUSD/2023/BTCUSD_h01_202303.csv
+    // Version 0 is the archive written before the versioning, directly under {archive}/metrics.
+    pub fn metric_archive_path(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        version: i32,
+    ) -> PathBuf {
+        let f = format!(
+            "{}_{}_{}{}.csv",
+            market.as_strip(),
+            tf.as_str(),
+            dt.format("%Y"),
+            dt.format("%m"),
+        );
+        let mut pb = Path::new(&self.storage_path).join("metrics");
+        if version > 0 {
+            pb = pb.join(format!("v{}", version));
+        }
+        pb.join(market.exchange_name.as_str())
+            .join(market.as_strip())
+            .join(dt.format("%Y").to_string())
+            .join(f)
+    }
+
+    pub fn prep_metric_archive_path(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+    ) -> PathBuf {
+        let pb = self.metric_archive_path(market, dt, tf, METRIC_VERSION);
+        std::fs::create_dir_all(pb.parent().unwrap()).expect("Failed to create directories.");
+        pb
+    }
+
+    // Archived metrics of the month under the version, empty if the month has not been backfilled
+    pub fn read_metrics_from_file_for_month(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        version: i32,
+    ) -> Vec<ResearchMetric> {
+        let pb = self.metric_archive_path(market, dt, tf, version);
+        if pb.exists() {
+            ResearchMetric::from_file(&pb)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backfill::{group_metrics_by_month, indicators_for_metrics, MetricReport},
+        indicators::IndicatorSpec,
+        markets::MarketDetail,
+        metrics::{ResearchMetric, METRIC_VERSION},
+        test_helpers::{candles, market},
+        utilities::TimeFrame,
+    };
+    use chrono::{DateTime, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use std::collections::HashMap;
+
+    // Daily metrics for n days of rising closes from the start
+    fn daily_metrics(market: &MarketDetail, start: DateTime<Utc>, n: i64) -> Vec<ResearchMetric> {
+        let closes: Vec<_> = (0..n).map(|i| Decimal::from(100 + i)).collect();
+        let candles = candles(start, &TimeFrame::D01, &closes);
+        (1..=candles.len())
+            .map(|n| ResearchMetric::new(market, TimeFrame::D01, &candles[..n]))
+            .collect()
+    }
+
+    #[test]
+    pub fn metrics_group_into_their_month_files() {
+        let market = market("BTC-USD", TimeFrame::T15);
+        // Daily candles across the end of March
+        let start = Utc.with_ymd_and_hms(2023, 3, 28, 0, 0, 0).unwrap();
+        let mut metrics = daily_metrics(&market, start, 6);
+        metrics.reverse();
+        let grouped = group_metrics_by_month(metrics);
+        let march = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let april = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            grouped.keys().cloned().collect::<Vec<_>>(),
+            vec![(march, TimeFrame::D01), (april, TimeFrame::D01)]
+        );
+        let april_metrics = &grouped[&(april, TimeFrame::D01)];
+        assert_eq!(april_metrics.len(), 2);
+        assert!(april_metrics[0].datetime < april_metrics[1].datetime);
+    }
+
+    #[test]
+    pub fn metric_report_compares_values_not_lineage() {
+        let market = market("BTC-USD", TimeFrame::T15);
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let recomputed = daily_metrics(&market, start, 5);
+        assert!(recomputed
+            .iter()
+            .all(|m| m.version == METRIC_VERSION
+                && m.param_hash == TimeFrame::D01.metric_param_hash()));
+        assert_ne!(
+            TimeFrame::D01.metric_param_hash(),
+            TimeFrame::H01.metric_param_hash()
+        );
+        // Stored under an older version with one close changed and the last day not stored
+        let mut stored: Vec<_> = recomputed[..4]
+            .iter()
+            .cloned()
+            .map(|mut m| {
+                m.version = 0;
+                m.param_hash = String::new();
+                m
+            })
+            .collect();
+        stored[2].close += Decimal::ONE;
+        let mut report = MetricReport::new(&TimeFrame::D01, 0);
+        report.add(&stored, &recomputed);
+        assert_eq!(report.compared, 4);
+        assert_eq!(report.changed, 1);
+        assert_eq!(report.missing, 1);
+        assert_eq!(report.fields.keys().collect::<Vec<_>>(), vec!["close"]);
+        // Metric files from before the versioning read as version 0
+        let fp = std::path::Path::new("tests").join("AVAXUSD_d01_202205.csv");
+        assert!(ResearchMetric::from_file(&fp)
+            .iter()
+            .all(|m| m.version == 0 && m.param_hash.is_empty()));
+    }
+
+    #[test]
+    pub fn indicator_values_are_calculated_for_each_metric() {
+        let market = market("BTC-USD", TimeFrame::D01);
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let closes: Vec<_> = (0..5).map(|i| Decimal::from(100 + i)).collect();
+        let candles = candles(start, &TimeFrame::D01, &closes);
+        // The last two candles closed in the replayed interval
+        let metrics: Vec<_> = (4..=5)
+            .map(|n| ResearchMetric::new(&market, TimeFrame::D01, &candles[..n]))
+            .collect();
+        let spec = IndicatorSpec {
+            name: "rsi".to_string(),
+            params: HashMap::from([("lbp".to_string(), "3".to_string())]),
+            markets: Vec::new(),
+            tfs: Vec::new(),
+        };
+        let hb_candles = HashMap::from([(TimeFrame::D01, candles.clone())]);
+        let values = indicators_for_metrics(&market, &hb_candles, &metrics, |tf| {
+            vec![spec.build(tf).unwrap()]
+        });
+        assert_eq!(
+            values.iter().map(|v| v.datetime).collect::<Vec<_>>(),
+            vec![candles[3].datetime, candles[4].datetime]
+        );
+        // Only gains over the lookback
+        assert_eq!(values[1].values["rsi_3"], Some(Decimal::from(100)));
+        // No metrics for the time frame calculates nothing
+        assert!(indicators_for_metrics(&market, &hb_candles, &[], |tf| {
+            vec![spec.build(tf).unwrap()]
+        })
+        .is_empty());
+    }
+}
@@ -0,0 +1,517 @@
+use crate::{
+    candles::ResearchCandle,
+    configuration::Schema,
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::{gdax::Trade as GdaxTrade, ExchangeName},
+    markets::MarketDetail,
+    mita::Heartbeat,
+    trades::Trade,
+    utilities::DateRange,
+};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{collections::HashMap, convert::TryFrom};
+
+// Information driven bars sample the trade stream by activity instead of time. Tick, volume and
+// dollar bars close once the trades, base volume or quote value in the bar reach the threshold.
+// Imbalance bars close once the signed trade imbalance in the bar exceeds the expected imbalance,
+// where the expected trades per bar and imbalance per trade are ewmas of the previous bars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BarType {
+    Tick,
+    Volume,
+    Dollar,
+    TickImbalance,
+    VolumeImbalance,
+}
+
+impl BarType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BarType::Tick => "tick",
+            BarType::Volume => "volume",
+            BarType::Dollar => "dollar",
+            BarType::TickImbalance => "tib",
+            BarType::VolumeImbalance => "vib",
+        }
+    }
+}
+
+impl TryFrom<String> for BarType {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "tick" => Ok(Self::Tick),
+            "volume" => Ok(Self::Volume),
+            "dollar" => Ok(Self::Dollar),
+            "tib" | "tickimbalance" => Ok(Self::TickImbalance),
+            "vib" | "volumeimbalance" => Ok(Self::VolumeImbalance),
+            other => Err(format!("{} is not a supported bar type.", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct BarSpec {
+    pub bar_type: BarType,
+    // Trades, base volume or quote value per bar. For imbalance bars it is the expected number of
+    // trades for the first bar.
+    pub threshold: Decimal,
+    // Span of the ewma for the expected trades and imbalance of imbalance bars
+    #[serde(default = "BarSpec::default_span")]
+    pub span: i64,
+}
+
+impl BarSpec {
+    pub fn new(bar_type: BarType, threshold: Decimal) -> Self {
+        Self {
+            bar_type,
+            threshold,
+            span: Self::default_span(),
+        }
+    }
+
+    fn default_span() -> i64 {
+        20
+    }
+
+    // ie dollar_1000000 or vib_100
+    pub fn as_str(&self) -> String {
+        format!(
+            "{}_{}",
+            self.bar_type.as_str(),
+            self.threshold.normalize().to_string().replace('.', "p")
+        )
+    }
+
+    pub fn table(&self, schema: &Schema, market: &MarketDetail) -> String {
+        format!(
+            "{}.bars_{}_{}_{}",
+            schema.candles(&market.exchange_name),
+            market.exchange_name.as_str(),
+            market.as_strip(),
+            self.as_str()
+        )
+    }
+
+    // Bars use the same columns as the research candles with the datetime of the first trade
+    pub async fn create_table(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                datetime timestamptz NOT NULL,
+                open NUMERIC NOT NULL,
+                high NUMERIC NOT NULL,
+                low NUMERIC NOT NULL,
+                close NUMERIC NOT NULL,
+                volume NUMERIC NOT NULL,
+                volume_buy NUMERIC NOT NULL,
+                volume_sell NUMERIC NOT NULL,
+                volume_liq NUMERIC NOT NULL,
+                volume_liq_buy NUMERIC NOT NULL,
+                volume_liq_sell NUMERIC NOT NULL,
+                value NUMERIC NOT NULL,
+                value_buy NUMERIC NOT NULL,
+                value_sell NUMERIC NOT NULL,
+                value_liq NUMERIC NOT NULL,
+                value_liq_buy NUMERIC NOT NULL,
+                value_liq_sell NUMERIC NOT NULL,
+                trade_count BIGINT NOT NULL,
+                trade_count_buy BIGINT NOT NULL,
+                trade_count_sell BIGINT NOT NULL,
+                liq_count BIGINT NOT NULL,
+                liq_count_buy BIGINT NOT NULL,
+                liq_count_sell BIGINT NOT NULL,
+                last_trade_ts timestamptz NOT NULL,
+                last_trade_id TEXT NOT NULL,
+                first_trade_ts timestamptz NOT NULL,
+                first_trade_id TEXT NOT NULL,
+                vwap NUMERIC NOT NULL DEFAULT 0,
+                vwap_buy NUMERIC NOT NULL DEFAULT 0,
+                vwap_sell NUMERIC NOT NULL DEFAULT 0,
+                twap NUMERIC NOT NULL DEFAULT 0,
+                vap TEXT,
+                PRIMARY KEY (datetime, first_trade_id)
+            )
+            "#,
+            self.table(schema, market)
+        );
+        sqlx::query(&sql).execute(pool).await?;
+        Ok(())
+    }
+
+    pub async fn insert(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        bar: &ResearchCandle,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            INSERT INTO {}
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32)
+            ON CONFLICT (datetime, first_trade_id) DO NOTHING
+            "#,
+            self.table(schema, market)
+        );
+        sqlx::query(&sql)
+            .bind(bar.datetime)
+            .bind(bar.open)
+            .bind(bar.high)
+            .bind(bar.low)
+            .bind(bar.close)
+            .bind(bar.volume)
+            .bind(bar.volume_buy)
+            .bind(bar.volume_sell)
+            .bind(bar.volume_liq)
+            .bind(bar.volume_liq_buy)
+            .bind(bar.volume_liq_sell)
+            .bind(bar.value)
+            .bind(bar.value_buy)
+            .bind(bar.value_sell)
+            .bind(bar.value_liq)
+            .bind(bar.value_liq_buy)
+            .bind(bar.value_liq_sell)
+            .bind(bar.trade_count)
+            .bind(bar.trade_count_buy)
+            .bind(bar.trade_count_sell)
+            .bind(bar.liq_count)
+            .bind(bar.liq_count_buy)
+            .bind(bar.liq_count_sell)
+            .bind(bar.last_trade_ts)
+            .bind(&bar.last_trade_id)
+            .bind(bar.first_trade_ts)
+            .bind(&bar.first_trade_id)
+            .bind(bar.vwap)
+            .bind(bar.vwap_buy)
+            .bind(bar.vwap_sell)
+            .bind(bar.twap)
+            .bind(&bar.vap)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn select_gte_dt(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<Vec<ResearchCandle>, sqlx::Error> {
+        let sql = format!(
+            r#"
+            SELECT datetime, open, high, low, close, volume, volume_buy, volume_sell, volume_liq,
+                volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
+                value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
+                liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap
+            FROM {}
+            WHERE datetime >= $1
+            ORDER BY datetime ASC
+            "#,
+            self.table(schema, market)
+        );
+        let rows = sqlx::query_as::<_, ResearchCandle>(&sql)
+            .bind(dt)
+            .fetch_all(pool)
+            .await?;
+        Ok(rows)
+    }
+}
+
+// Builds bars one trade at a time so the same builder works on the live stream and on the archive
+// files. The bar in progress is kept until the trade that completes it is pushed.
+#[derive(Debug, Clone)]
+pub struct BarBuilder {
+    pub spec: BarSpec,
+    bar: Option<ResearchCandle>,
+    imbalance: Decimal,
+    expected_trades: Decimal,
+    expected_imbalance: Option<Decimal>,
+    // Running sums for the twap of the bar in progress
+    twap_weighted: Decimal,
+    price_sum: Decimal,
+}
+
+impl BarBuilder {
+    pub fn new(spec: &BarSpec) -> Self {
+        Self {
+            spec: spec.clone(),
+            bar: None,
+            imbalance: dec!(0),
+            expected_trades: spec.threshold.max(dec!(1)),
+            expected_imbalance: None,
+            twap_weighted: dec!(0),
+            price_sum: dec!(0),
+        }
+    }
+
+    // Add the trade to the bar in progress. Returns the bar if the trade completes it.
+    pub fn push<T: Trade>(&mut self, trade: &T) -> Option<ResearchCandle> {
+        match self.bar.take() {
+            Some(mut bar) => {
+                self.add_trade(&mut bar, trade);
+                self.bar = Some(bar);
+            }
+            None => {
+                self.twap_weighted = dec!(0);
+                self.price_sum = trade.price();
+                self.bar = Some(ResearchCandle::from_trades_v2(
+                    trade.time(),
+                    std::slice::from_ref(trade),
+                    None,
+                ))
+            }
+        };
+        self.imbalance += self.signed_imbalance(trade);
+        if self.is_complete() {
+            let bar = self.bar.take().unwrap();
+            self.update_expected(&bar);
+            self.imbalance = dec!(0);
+            Some(bar)
+        } else {
+            None
+        }
+    }
+
+    pub fn push_all<T: Trade>(&mut self, trades: &[T]) -> Vec<ResearchCandle> {
+        trades.iter().filter_map(|t| self.push(t)).collect()
+    }
+
+    // Bar that has started but not yet reached its threshold
+    pub fn pending(&self) -> Option<&ResearchCandle> {
+        self.bar.as_ref()
+    }
+
+    fn signed_imbalance<T: Trade>(&self, trade: &T) -> Decimal {
+        let sign = if trade.side() == "buy" {
+            dec!(1)
+        } else {
+            dec!(-1)
+        };
+        match self.spec.bar_type {
+            BarType::VolumeImbalance => sign * trade.size(),
+            _ => sign,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let bar = match &self.bar {
+            Some(b) => b,
+            None => return false,
+        };
+        match self.spec.bar_type {
+            BarType::Tick => Decimal::from(bar.trade_count) >= self.spec.threshold,
+            BarType::Volume => bar.volume >= self.spec.threshold,
+            BarType::Dollar => bar.value >= self.spec.threshold,
+            BarType::TickImbalance | BarType::VolumeImbalance => match self.expected_imbalance {
+                Some(e) => self.imbalance.abs() >= self.expected_trades * e.abs(),
+                // No bars yet to estimate the imbalance from, close the first bar on trades
+                None => Decimal::from(bar.trade_count) >= self.expected_trades,
+            },
+        }
+    }
+
+    fn update_expected(&mut self, bar: &ResearchCandle) {
+        let trades = Decimal::from(bar.trade_count);
+        let imbalance = self.imbalance / trades;
+        let alpha = dec!(2) / Decimal::from(self.spec.span + 1);
+        self.expected_imbalance = Some(match self.expected_imbalance {
+            Some(e) => alpha * imbalance + (dec!(1) - alpha) * e,
+            None => imbalance,
+        });
+        self.expected_trades =
+            (alpha * trades + (dec!(1) - alpha) * self.expected_trades).max(dec!(1));
+    }
+
+    fn add_trade<T: Trade>(&mut self, bar: &mut ResearchCandle, t: &T) {
+        // Previous close is weighted by the time until this trade
+        self.twap_weighted +=
+            bar.close * Decimal::from((t.time() - bar.last_trade_ts).num_milliseconds());
+        self.price_sum += t.price();
+        let value = t.size() * t.price();
+        let buy = t.side() == "buy";
+        bar.high = bar.high.max(t.price());
+        bar.low = bar.low.min(t.price());
+        bar.close = t.price();
+        bar.volume += t.size();
+        bar.value += value;
+        bar.trade_count += 1;
+        if buy {
+            bar.volume_buy += t.size();
+            bar.value_buy += value;
+            bar.trade_count_buy += 1;
+        } else {
+            bar.volume_sell += t.size();
+            bar.value_sell += value;
+            bar.trade_count_sell += 1;
+        }
+        if t.liquidation() {
+            bar.volume_liq += t.size();
+            bar.value_liq += value;
+            bar.liq_count += 1;
+            if buy {
+                bar.volume_liq_buy += t.size();
+                bar.value_liq_buy += value;
+                bar.liq_count_buy += 1;
+            } else {
+                bar.volume_liq_sell += t.size();
+                bar.value_liq_sell += value;
+                bar.liq_count_sell += 1;
+            }
+        }
+        bar.last_trade_ts = t.time();
+        bar.last_trade_id = t.trade_id().to_string();
+        bar.set_vwaps();
+        let span = (bar.last_trade_ts - bar.first_trade_ts).num_milliseconds();
+        bar.twap = if span == 0 {
+            self.price_sum / Decimal::from(bar.trade_count)
+        } else {
+            self.twap_weighted / Decimal::from(span)
+        };
+    }
+}
+
+impl ElDorado {
+    // Make the bars for each day in the archive from start to end. The builders carry the bar in
+    // progress across days so bars are continuous across the daily files.
+    pub async fn make_bars_from_archive(
+        &self,
+        market: &MarketDetail,
+        specs: &[BarSpec],
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<(), ElDoradoError> {
+        if market.exchange_name != ExchangeName::Gdax {
+            return Err(ElDoradoError::UnsupportedExchange(
+                market.exchange_name,
+                "bars",
+            ));
+        }
+        let pool = self.pools.exchange(&market.exchange_name);
+        let mut builders: Vec<BarBuilder> = specs.iter().map(BarBuilder::new).collect();
+        for spec in specs.iter() {
+            spec.create_table(pool, &self.schema, market).await?;
+        }
+        let mut dt = *start;
+        while dt < *end {
+            let pb = self.prep_trade_archive_path_final(market, &dt);
+            if !pb.exists() {
+                println!("No archive file for {} on {}.", market.market_name, dt);
+                dt += Duration::days(1);
+                continue;
+            }
+            let trades = self.read_gdax_trades_from_file_into_vec(&pb);
+            for builder in builders.iter_mut() {
+                let bars = builder.push_all(&trades);
+                println!(
+                    "{} {} bars for {} on {}.",
+                    bars.len(),
+                    builder.spec.as_str(),
+                    market.market_name,
+                    dt
+                );
+                for bar in bars.iter() {
+                    builder.spec.insert(pool, &self.schema, market, bar).await?;
+                }
+            }
+            dt += Duration::days(1);
+        }
+        Ok(())
+    }
+
+    // Feed the trades for the interval to the market bar builders in the heartbeat and insert any
+    // completed bars. The bar in progress is not persisted so a restart starts a new bar.
+    pub async fn process_interval_bars(
+        &self,
+        market: &MarketDetail,
+        heartbeats: &mut HashMap<String, Heartbeat>,
+        dr: &DateRange,
+    ) -> Result<(), ElDoradoError> {
+        if self.bars.is_empty() {
+            return Ok(());
+        }
+        let trades: Vec<GdaxTrade> = match market.exchange_name {
+            ExchangeName::Gdax => self
+                .select_gdax_trades_for_interval(market, dr)
+                .await?
+                .unwrap_or_default(),
+            _ => return Ok(()),
+        };
+        let pool = self.pools.exchange(&market.exchange_name);
+        let hb = heartbeats.get_mut(&market.market_name).unwrap();
+        if hb.bars.is_empty() {
+            for spec in self.bars.iter() {
+                spec.create_table(pool, &self.schema, market).await?;
+            }
+            hb.bars = self.bars.iter().map(BarBuilder::new).collect();
+        }
+        for builder in hb.bars.iter_mut() {
+            for bar in builder.push_all(&trades).iter() {
+                builder.spec.insert(pool, &self.schema, market, bar).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bars::{BarBuilder, BarSpec, BarType},
+        test_helpers::{start, trades},
+    };
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn tick_volume_and_dollar_bars_close_on_threshold() {
+        let trades = trades(start(), 100);
+        let total_volume: Decimal = trades.iter().map(|t| t.size).sum();
+        let tick = BarBuilder::new(&BarSpec::new(BarType::Tick, dec!(10))).push_all(&trades);
+        assert_eq!(tick.len(), 10);
+        assert!(tick.iter().all(|b| b.trade_count == 10));
+        assert_eq!(tick[1].first_trade_id, "10");
+        assert_eq!(tick[1].last_trade_id, "19");
+        let mut builder = BarBuilder::new(&BarSpec::new(BarType::Volume, dec!(10)));
+        let volume = builder.push_all(&trades);
+        assert!(volume.iter().all(|b| b.volume >= dec!(10)));
+        // Every trade is in either a completed bar or the pending bar
+        let pending = builder.pending().map_or(dec!(0), |b| b.volume);
+        assert_eq!(
+            volume.iter().map(|b| b.volume).sum::<Decimal>() + pending,
+            total_volume
+        );
+        let dollar = BarBuilder::new(&BarSpec::new(BarType::Dollar, dec!(1000))).push_all(&trades);
+        assert!(dollar.iter().all(|b| b.value >= dec!(1000)));
+        assert!(dollar.iter().all(|b| b.value_buy + b.value_sell == b.value
+            && b.trade_count_buy + b.trade_count_sell == b.trade_count));
+    }
+
+    #[test]
+    pub fn imbalance_bars_adapt_expected_imbalance() {
+        let trades = trades(start(), 100);
+        let mut builder = BarBuilder::new(&BarSpec::new(BarType::TickImbalance, dec!(8)));
+        let bars = builder.push_all(&trades);
+        // The first bar closes on the initial expected trades, the rest on the imbalance
+        assert_eq!(bars[0].trade_count, 8);
+        assert!(bars.len() > 1);
+        assert!(builder.expected_imbalance.unwrap() > dec!(0));
+        let vib =
+            BarBuilder::new(&BarSpec::new(BarType::VolumeImbalance, dec!(8))).push_all(&trades);
+        assert!(!vib.is_empty());
+    }
+}
@@ -1,22 +1,26 @@
 use crate::{
-    configuration::Database,
+    configuration::Schema,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::{
         ftx::Trade as FtxTrade, gdax::Candle as GdaxCandle, gdax::Trade as GdaxTrade, ExchangeName,
     },
     markets::{MarketArchiveDetail, MarketCandleDetail, MarketDetail},
+    sessions::Session,
     trades::{PrIdTi, Trade},
     utilities::{DateRange, TimeFrame},
 };
 use chrono::{DateTime, Duration, DurationRound, Utc};
-use csv::Writer;
+use csv::{Reader, Writer};
 use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 pub trait Candle {
     fn datetime(&self) -> DateTime<Utc>;
@@ -86,6 +90,21 @@ pub struct ProductionCandle {
     pub last_trade_id: String,
     pub first_trade_ts: DateTime<Utc>,
     pub first_trade_id: String,
+    // Defaults allow reading archives written before the price fields were added
+    #[serde(default)]
+    pub vwap: Decimal,
+    #[serde(default)]
+    pub vwap_buy: Decimal,
+    #[serde(default)]
+    pub vwap_sell: Decimal,
+    #[serde(default)]
+    pub twap: Decimal,
+    // Volume at price histogram for the candle, see VolumeAtPrice
+    #[serde(default)]
+    pub vap: Option<String>,
+    // Number of times the candle was amended by trades that arrived after it was closed
+    #[serde(default)]
+    pub revision: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, sqlx::FromRow)]
@@ -117,9 +136,438 @@ pub struct ResearchCandle {
     pub last_trade_id: String,
     pub first_trade_ts: DateTime<Utc>,
     pub first_trade_id: String,
+    // Defaults allow reading archives written before the price fields were added
+    #[serde(default)]
+    pub vwap: Decimal,
+    #[serde(default)]
+    pub vwap_buy: Decimal,
+    #[serde(default)]
+    pub vwap_sell: Decimal,
+    #[serde(default)]
+    pub twap: Decimal,
+    // Volume at price histogram for the candle, see VolumeAtPrice
+    #[serde(default)]
+    pub vap: Option<String>,
+}
+
+// Volume at price histogram of a candle. Trade sizes are summed into buckets of the market
+// quote step and stored as `price:volume` pairs separated by `;` ie 100.5:2.1;101:0.4
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VolumeAtPrice(BTreeMap<Decimal, Decimal>);
+
+impl VolumeAtPrice {
+    pub fn from_trades<I: IntoIterator<Item = (Decimal, Decimal)>>(
+        trades: I,
+        step: &Decimal,
+    ) -> Self {
+        let mut vap = Self::default();
+        for (price, size) in trades {
+            vap.add(price, size, step);
+        }
+        vap
+    }
+
+    pub fn add(&mut self, price: Decimal, size: Decimal, step: &Decimal) {
+        let bucket = if step.is_zero() {
+            price
+        } else {
+            (price / step).floor() * step
+        };
+        *self.0.entry(bucket.normalize()).or_insert(dec!(0)) += size;
+    }
+
+    // Sum the histograms of the candles being resampled. None if none of the candles have one.
+    pub fn merge<'a, I: IntoIterator<Item = Option<&'a str>>>(vaps: I) -> Option<String> {
+        let mut merged: Option<Self> = None;
+        for vap in vaps.into_iter().flatten() {
+            let levels = vap
+                .parse::<Self>()
+                .expect("Failed to parse volume at price.");
+            let m = merged.get_or_insert_with(Self::default);
+            for (price, size) in levels.0 {
+                *m.0.entry(price).or_insert(dec!(0)) += size;
+            }
+        }
+        merged.map(|m| m.to_string())
+    }
+
+    pub fn levels(&self) -> &BTreeMap<Decimal, Decimal> {
+        &self.0
+    }
+}
+
+impl fmt::Display for VolumeAtPrice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels: Vec<String> = self
+            .0
+            .iter()
+            .map(|(p, v)| format!("{}:{}", p, v.normalize()))
+            .collect();
+        write!(f, "{}", levels.join(";"))
+    }
+}
+
+impl FromStr for VolumeAtPrice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut levels = BTreeMap::new();
+        for level in s.split(';').filter(|l| !l.is_empty()) {
+            let (p, v) = level
+                .split_once(':')
+                .ok_or(format!("{} is not a volume at price level.", level))?;
+            let p = Decimal::from_str(p).map_err(|e| e.to_string())?;
+            let v = Decimal::from_str(v).map_err(|e| e.to_string())?;
+            levels.insert(p, v);
+        }
+        Ok(Self(levels))
+    }
+}
+
+// Value over volume, the fallback price if there is no volume
+pub fn volume_weighted_price(value: Decimal, volume: Decimal, fallback: Decimal) -> Decimal {
+    if volume.is_zero() {
+        fallback
+    } else {
+        value / volume
+    }
+}
+
+// Each trade price is weighted by the time until the next trade. If all trades are at the same
+// time it is the mean of the trade prices.
+pub fn time_weighted_price<I: IntoIterator<Item = (DateTime<Utc>, Decimal)>>(trades: I) -> Decimal {
+    let mut weighted = dec!(0);
+    let mut sum = dec!(0);
+    let mut n = 0;
+    let mut first: Option<DateTime<Utc>> = None;
+    let mut prev: Option<(DateTime<Utc>, Decimal)> = None;
+    for (ts, price) in trades {
+        if let Some((pts, pprice)) = prev {
+            weighted += pprice * Decimal::from((ts - pts).num_milliseconds());
+        }
+        first.get_or_insert(ts);
+        sum += price;
+        n += 1;
+        prev = Some((ts, price));
+    }
+    let span = match (first, prev) {
+        (Some(f), Some((l, _))) => (l - f).num_milliseconds(),
+        _ => return dec!(0),
+    };
+    if span == 0 {
+        sum / Decimal::from(n)
+    } else {
+        weighted / Decimal::from(span)
+    }
+}
+
+// Candle built one trade at a time. Accumulators merge associatively so partial bars of adjacent
+// trades, ie the trades of each day of a month, combine into the same candle as a single pass
+// over all the trades. Open and close are the first and last trades by time and id so either
+// accumulator can be merged into the other. Trades pushed to one accumulator should be in order
+// as the twap weights each price by the time to the next trade.
+#[derive(Debug, Clone)]
+pub struct CandleAccumulator {
+    pub first: Option<PrIdTi>,
+    pub last: Option<PrIdTi>,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub volume: Decimal,
+    pub volume_buy: Decimal,
+    pub volume_sell: Decimal,
+    pub volume_liq: Decimal,
+    pub volume_liq_buy: Decimal,
+    pub volume_liq_sell: Decimal,
+    pub value: Decimal,
+    pub value_buy: Decimal,
+    pub value_sell: Decimal,
+    pub value_liq: Decimal,
+    pub value_liq_buy: Decimal,
+    pub value_liq_sell: Decimal,
+    pub trade_count: i64,
+    pub trade_count_buy: i64,
+    pub trade_count_sell: i64,
+    pub liq_count: i64,
+    pub liq_count_buy: i64,
+    pub liq_count_sell: i64,
+    // Sum of each price weighted by the milliseconds to the next trade and the sum of prices
+    twap_weighted: Decimal,
+    price_sum: Decimal,
+    quote_step: Option<Decimal>,
+    vap: VolumeAtPrice,
+}
+
+impl CandleAccumulator {
+    pub fn new(quote_step: Option<Decimal>) -> Self {
+        Self {
+            first: None,
+            last: None,
+            high: Decimal::MIN,
+            low: Decimal::MAX,
+            volume: dec!(0),
+            volume_buy: dec!(0),
+            volume_sell: dec!(0),
+            volume_liq: dec!(0),
+            volume_liq_buy: dec!(0),
+            volume_liq_sell: dec!(0),
+            value: dec!(0),
+            value_buy: dec!(0),
+            value_sell: dec!(0),
+            value_liq: dec!(0),
+            value_liq_buy: dec!(0),
+            value_liq_sell: dec!(0),
+            trade_count: 0,
+            trade_count_buy: 0,
+            trade_count_sell: 0,
+            liq_count: 0,
+            liq_count_buy: 0,
+            liq_count_sell: 0,
+            twap_weighted: dec!(0),
+            price_sum: dec!(0),
+            quote_step,
+            vap: VolumeAtPrice::default(),
+        }
+    }
+
+    pub fn from_trades<'a, T: Trade + 'a, I: IntoIterator<Item = &'a T>>(
+        trades: I,
+        quote_step: Option<Decimal>,
+    ) -> Self {
+        let mut acc = Self::new(quote_step);
+        for trade in trades {
+            acc.push(trade);
+        }
+        acc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first.is_none()
+    }
+
+    pub fn push<T: Trade>(&mut self, trade: &T) {
+        let pit = trade.as_pridti();
+        let size = trade.size();
+        let value = size * pit.price;
+        if let Some(last) = self.last {
+            if Self::key(&pit) > Self::key(&last) {
+                self.twap_weighted +=
+                    last.price * Decimal::from((pit.dt - last.dt).num_milliseconds());
+            }
+        }
+        self.extend(&pit, &pit);
+        self.high = self.high.max(pit.price);
+        self.low = self.low.min(pit.price);
+        self.price_sum += pit.price;
+        self.volume += size;
+        self.value += value;
+        self.trade_count += 1;
+        let liq = trade.liquidation();
+        if trade.side() == "buy" {
+            self.volume_buy += size;
+            self.value_buy += value;
+            self.trade_count_buy += 1;
+            if liq {
+                self.volume_liq_buy += size;
+                self.value_liq_buy += value;
+                self.liq_count_buy += 1;
+            }
+        } else {
+            self.volume_sell += size;
+            self.value_sell += value;
+            self.trade_count_sell += 1;
+            if liq {
+                self.volume_liq_sell += size;
+                self.value_liq_sell += value;
+                self.liq_count_sell += 1;
+            }
+        }
+        if liq {
+            self.volume_liq += size;
+            self.value_liq += value;
+            self.liq_count += 1;
+        }
+        if let Some(step) = self.quote_step {
+            self.vap.add(pit.price, size, &step);
+        }
+    }
+
+    // Merge the trades of another accumulator. The twap is joined with the price of the earlier
+    // accumulator's last trade weighted by the gap to the later accumulator's first trade.
+    pub fn merge(&mut self, other: &Self) {
+        let (earlier, later) = match (self.first, other.first) {
+            (_, None) => return,
+            (None, Some(_)) => {
+                *self = other.clone();
+                return;
+            }
+            (Some(a), Some(b)) if Self::key(&a) <= Self::key(&b) => (&*self, other),
+            _ => (other, &*self),
+        };
+        let (el, lf) = (earlier.last.unwrap(), later.first.unwrap());
+        let gap = if Self::key(&lf) > Self::key(&el) {
+            el.price * Decimal::from((lf.dt - el.dt).num_milliseconds())
+        } else {
+            dec!(0)
+        };
+        self.twap_weighted += other.twap_weighted + gap;
+        self.extend(&other.first.unwrap(), &other.last.unwrap());
+        self.high = self.high.max(other.high);
+        self.low = self.low.min(other.low);
+        self.price_sum += other.price_sum;
+        self.volume += other.volume;
+        self.volume_buy += other.volume_buy;
+        self.volume_sell += other.volume_sell;
+        self.volume_liq += other.volume_liq;
+        self.volume_liq_buy += other.volume_liq_buy;
+        self.volume_liq_sell += other.volume_liq_sell;
+        self.value += other.value;
+        self.value_buy += other.value_buy;
+        self.value_sell += other.value_sell;
+        self.value_liq += other.value_liq;
+        self.value_liq_buy += other.value_liq_buy;
+        self.value_liq_sell += other.value_liq_sell;
+        self.trade_count += other.trade_count;
+        self.trade_count_buy += other.trade_count_buy;
+        self.trade_count_sell += other.trade_count_sell;
+        self.liq_count += other.liq_count;
+        self.liq_count_buy += other.liq_count_buy;
+        self.liq_count_sell += other.liq_count_sell;
+        for (price, size) in other.vap.0.iter() {
+            *self.vap.0.entry(*price).or_insert(dec!(0)) += size;
+        }
+    }
+
+    // Time weighted price, the mean price if all the trades are at the same time
+    pub fn twap(&self) -> Decimal {
+        match (self.first, self.last) {
+            (Some(f), Some(l)) if l.dt > f.dt => {
+                self.twap_weighted / Decimal::from((l.dt - f.dt).num_milliseconds())
+            }
+            (Some(_), Some(_)) => self.price_sum / Decimal::from(self.trade_count),
+            _ => dec!(0),
+        }
+    }
+
+    // Finalize to a candle for the interval datetime. None if there were no trades.
+    pub fn production_candle(&self, datetime: DateTime<Utc>) -> Option<ProductionCandle> {
+        let (first, last) = (self.first?, self.last?);
+        let mut candle = ProductionCandle {
+            datetime,
+            open: first.price,
+            high: self.high,
+            low: self.low,
+            close: last.price,
+            volume: self.volume,
+            volume_buy: self.volume_buy,
+            volume_sell: self.volume_sell,
+            volume_liq: self.volume_liq,
+            volume_liq_buy: self.volume_liq_buy,
+            volume_liq_sell: self.volume_liq_sell,
+            value: self.value,
+            value_buy: self.value_buy,
+            value_sell: self.value_sell,
+            value_liq: self.value_liq,
+            value_liq_buy: self.value_liq_buy,
+            value_liq_sell: self.value_liq_sell,
+            trade_count: self.trade_count,
+            trade_count_buy: self.trade_count_buy,
+            trade_count_sell: self.trade_count_sell,
+            liq_count: self.liq_count,
+            liq_count_buy: self.liq_count_buy,
+            liq_count_sell: self.liq_count_sell,
+            last_trade_ts: last.dt,
+            last_trade_id: last.id.to_string(),
+            first_trade_ts: first.dt,
+            first_trade_id: first.id.to_string(),
+            vwap: dec!(0),
+            vwap_buy: dec!(0),
+            vwap_sell: dec!(0),
+            twap: self.twap(),
+            vap: self.quote_step.map(|_| self.vap.to_string()),
+            revision: 0,
+        };
+        candle.set_vwaps();
+        Some(candle)
+    }
+
+    pub fn research_candle(&self, datetime: DateTime<Utc>) -> Option<ResearchCandle> {
+        self.production_candle(datetime)
+            .map(|c| c.as_research_candle())
+    }
+
+    fn key(pit: &PrIdTi) -> (DateTime<Utc>, i64) {
+        (pit.dt, pit.id)
+    }
+
+    // Widen the first and last trades to include the given first and last
+    fn extend(&mut self, first: &PrIdTi, last: &PrIdTi) {
+        match self.first {
+            Some(f) if Self::key(&f) <= Self::key(first) => (),
+            _ => self.first = Some(*first),
+        }
+        match self.last {
+            Some(l) if Self::key(&l) >= Self::key(last) => (),
+            _ => self.last = Some(*last),
+        }
+    }
+}
+
+// Interval accumulators of a market built from the streamed trades. Intervals that start after
+// the first streamed trade have seen every trade so can be used in place of the trade table.
+#[derive(Debug)]
+pub struct LiveCandles {
+    pub since: DateTime<Utc>,
+    pub intervals: BTreeMap<DateTime<Utc>, CandleAccumulator>,
+    // Latest streamed trade time, the event time of the market
+    pub watermark: DateTime<Utc>,
+    // End of the intervals that have been closed and taken as candles
+    pub closed: DateTime<Utc>,
+    // Closed intervals that received trades after they were closed
+    pub late: BTreeSet<DateTime<Utc>>,
+}
+
+impl LiveCandles {
+    pub fn new(since: DateTime<Utc>) -> Self {
+        Self {
+            since,
+            intervals: BTreeMap::new(),
+            watermark: since,
+            closed: DateTime::<Utc>::MIN_UTC,
+            late: BTreeSet::new(),
+        }
+    }
+
+    // Add the trade to its interval accumulator. Trades for an interval that has already been
+    // closed mark it late so the candle can be amended.
+    pub fn push<T: Trade>(&mut self, trade: &T, tf: &TimeFrame, quote_step: Option<Decimal>) {
+        self.watermark = self.watermark.max(trade.time());
+        let interval = tf.trunc(&trade.time());
+        if interval < self.closed {
+            self.late.insert(interval);
+        } else {
+            self.intervals
+                .entry(interval)
+                .or_insert_with(|| CandleAccumulator::new(quote_step))
+                .push(trade);
+        }
+    }
+
+    // Add trades inserted outside of the stream, ie by gap repair or the sync fill
+    pub fn push_trades<T: Trade>(&mut self, trades: &[T], tf: &TimeFrame, step: Option<Decimal>) {
+        for trade in trades.iter() {
+            self.push(trade, tf, step);
+        }
+    }
 }
 
 impl ProductionCandle {
+    // Volume weighted prices from the value and volume sums
+    pub fn set_vwaps(&mut self) {
+        self.vwap = volume_weighted_price(self.value, self.volume, self.close);
+        self.vwap_buy = volume_weighted_price(self.value_buy, self.volume_buy, self.vwap);
+        self.vwap_sell = volume_weighted_price(self.value_sell, self.volume_sell, self.vwap);
+    }
+
     pub fn open_as_pridti(&self) -> PrIdTi {
         PrIdTi {
             id: self.first_trade_id.parse::<i64>().unwrap(),
@@ -128,6 +576,43 @@ impl ProductionCandle {
         }
     }
 
+    pub fn as_research_candle(&self) -> ResearchCandle {
+        ResearchCandle {
+            datetime: self.datetime,
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            close: self.close,
+            volume: self.volume,
+            volume_buy: self.volume_buy,
+            volume_sell: self.volume_sell,
+            volume_liq: self.volume_liq,
+            volume_liq_buy: self.volume_liq_buy,
+            volume_liq_sell: self.volume_liq_sell,
+            value: self.value,
+            value_buy: self.value_buy,
+            value_sell: self.value_sell,
+            value_liq: self.value_liq,
+            value_liq_buy: self.value_liq_buy,
+            value_liq_sell: self.value_liq_sell,
+            trade_count: self.trade_count,
+            trade_count_buy: self.trade_count_buy,
+            trade_count_sell: self.trade_count_sell,
+            liq_count: self.liq_count,
+            liq_count_buy: self.liq_count_buy,
+            liq_count_sell: self.liq_count_sell,
+            last_trade_ts: self.last_trade_ts,
+            last_trade_id: self.last_trade_id.clone(),
+            first_trade_ts: self.first_trade_ts,
+            first_trade_id: self.first_trade_id.clone(),
+            vwap: self.vwap,
+            vwap_buy: self.vwap_buy,
+            vwap_sell: self.vwap_sell,
+            twap: self.twap,
+            vap: self.vap.clone(),
+        }
+    }
+
     pub fn close_as_pridti(&self) -> PrIdTi {
         PrIdTi {
             id: self.last_trade_id.parse::<i64>().unwrap(),
@@ -137,224 +622,16 @@ impl ProductionCandle {
     }
 
     // Takes a Vec of Trade and aggregates into a Candle with the Datetime = the
-    // datetime passed as argument. Candle built from trades in the order they are in
-    // the Vec, sort before calling this function otherwise Open / Close / Datetime may
-    // be incorrect.
-    pub fn from_trades<T: Trade>(datetime: DateTime<Utc>, trades: &[&T]) -> Self {
-        let candle_tuple = trades.iter().fold(
-            (
-                trades.first().expect("No first trade for candle.").price(), // open
-                Decimal::MIN,                                                // high
-                Decimal::MAX,                                                // low
-                dec!(0),                                                     // close
-                dec!(0),                                                     // volume
-                dec!(0),                                                     // volume buy
-                dec!(0),                                                     // volume sell
-                dec!(0),                                                     // volume liq
-                dec!(0),                                                     // volume liq buy
-                dec!(0),                                                     // volume liq sell
-                dec!(0),                                                     // value
-                dec!(0),                                                     // value buy
-                dec!(0),                                                     // value sell
-                dec!(0),                                                     // value liq
-                dec!(0),                                                     // value liq buy
-                dec!(0),                                                     // value liq sell
-                0,                                                           // count
-                0,                                                           // count buy
-                0,                                                           // count sell
-                0,                                                           // liq count,
-                0,                                                           // liq count buy,
-                0,                                                           // liq count sell,
-                datetime,                                                    // last_trade_ts
-                "".to_string(),                                              // last_trade_id
-                trades.first().expect("No first trade.").time(),             // first_trade_ts
-                trades
-                    .first()
-                    .expect("No first trade.")
-                    .trade_id()
-                    .to_string(), // first_trade_id
-            ),
-            |(
-                o,
-                h,
-                l,
-                _c,
-                v,
-                vb,
-                vs,
-                vl,
-                vlb,
-                vls,
-                u,
-                ub,
-                us,
-                al,
-                alb,
-                als,
-                n,
-                nb,
-                ns,
-                ln,
-                lnb,
-                lns,
-                _ts,
-                _id,
-                fts,
-                fid,
-            ),
-             t| {
-                // Put side and liq if statements here
-                let value = t.size() * t.price();
-                let (
-                    volume_buy,
-                    volume_sell,
-                    volume_liq,
-                    volume_liq_buy,
-                    volume_liq_sell,
-                    value_buy,
-                    value_sell,
-                    value_liq,
-                    value_liq_buy,
-                    value_liq_sell,
-                    n_buy,
-                    n_sell,
-                    n_liq,
-                    n_liq_buy,
-                    n_liq_sell,
-                ) = if t.side() == "buy" {
-                    if t.liquidation() {
-                        (
-                            t.size(),
-                            dec!(0),
-                            t.size(),
-                            t.size(),
-                            dec!(0),
-                            value,
-                            dec!(0),
-                            value,
-                            value,
-                            dec!(0),
-                            1,
-                            0,
-                            1,
-                            1,
-                            0,
-                        )
-                    } else {
-                        (
-                            t.size(),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            value,
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            1,
-                            0,
-                            0,
-                            0,
-                            0,
-                        )
-                    }
-                } else if t.liquidation() {
-                    (
-                        dec!(0),
-                        t.size(),
-                        t.size(),
-                        dec!(0),
-                        t.size(),
-                        dec!(0),
-                        value,
-                        value,
-                        dec!(0),
-                        value,
-                        0,
-                        1,
-                        1,
-                        0,
-                        1,
-                    )
-                } else {
-                    (
-                        dec!(0),
-                        t.size(),
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        value,
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        0,
-                        1,
-                        0,
-                        0,
-                        0,
-                    )
-                };
-                (
-                    o,                // open
-                    h.max(t.price()), // high
-                    l.min(t.price()), // low
-                    t.price(),        // close
-                    v + t.size(),     // volume
-                    vb + volume_buy,
-                    vs + volume_sell,
-                    vl + volume_liq,
-                    vlb + volume_liq_buy,
-                    vls + volume_liq_sell,
-                    u + value,
-                    ub + value_buy,
-                    us + value_sell,
-                    al + value_liq,
-                    alb + value_liq_buy,
-                    als + value_liq_sell,
-                    n + 1,
-                    nb + n_buy,
-                    ns + n_sell,
-                    ln + n_liq,
-                    lnb + n_liq_buy,
-                    lns + n_liq_sell,
-                    t.time(),
-                    t.trade_id().to_string(),
-                    fts,
-                    fid,
-                )
-            },
-        );
-        Self {
-            datetime,
-            open: candle_tuple.0,
-            high: candle_tuple.1,
-            low: candle_tuple.2,
-            close: candle_tuple.3,
-            volume: candle_tuple.4,
-            volume_buy: candle_tuple.5,
-            volume_sell: candle_tuple.6,
-            volume_liq: candle_tuple.7,
-            volume_liq_buy: candle_tuple.8,
-            volume_liq_sell: candle_tuple.9,
-            value: candle_tuple.10,
-            value_buy: candle_tuple.11,
-            value_sell: candle_tuple.12,
-            value_liq: candle_tuple.13,
-            value_liq_buy: candle_tuple.14,
-            value_liq_sell: candle_tuple.15,
-            trade_count: candle_tuple.16,
-            trade_count_buy: candle_tuple.17,
-            trade_count_sell: candle_tuple.18,
-            liq_count: candle_tuple.19,
-            liq_count_buy: candle_tuple.20,
-            liq_count_sell: candle_tuple.21,
-            last_trade_ts: candle_tuple.22,
-            last_trade_id: candle_tuple.23,
-            first_trade_ts: candle_tuple.24,
-            first_trade_id: candle_tuple.25,
-        }
+    // datetime passed as argument. Open and close are the first and last trades by time and id.
+    // Sort before calling this function as the twap weights each trade by the time to the next.
+    pub fn from_trades<T: Trade>(
+        datetime: DateTime<Utc>,
+        trades: &[&T],
+        quote_step: Option<Decimal>,
+    ) -> Self {
+        CandleAccumulator::from_trades(trades.iter().copied(), quote_step)
+            .production_candle(datetime)
+            .expect("No first trade for candle.")
     }
 
     // This function will build a placeholder trade with 0 volume and
@@ -388,9 +665,35 @@ impl ProductionCandle {
             last_trade_id: pit.id.to_string(),
             first_trade_ts: pit.dt,
             first_trade_id: pit.id.to_string(),
+            vwap: pit.price,
+            vwap_buy: pit.price,
+            vwap_sell: pit.price,
+            twap: pit.price,
+            vap: None,
+            revision: 0,
         }
     }
 
+    // Candles for the date range from the live interval accumulators. Intervals without trades
+    // are made from the last trade.
+    pub fn from_live_for_dr(
+        intervals: &BTreeMap<DateTime<Utc>, CandleAccumulator>,
+        last_trade: &PrIdTi,
+        dr: &[DateTime<Utc>],
+    ) -> Vec<Self> {
+        let mut last = *last_trade;
+        dr.iter()
+            .map(|d| {
+                let candle = intervals
+                    .get(d)
+                    .and_then(|acc| acc.production_candle(*d))
+                    .unwrap_or_else(|| Self::from_last(*d, &last));
+                last = candle.close_as_pridti();
+                candle
+            })
+            .collect()
+    }
+
     // Takes a Vec of Candles and resamples into a Candle with the Datetime = the
     // datetime passed as argument. Candle built from candes in the order they are in
     // the Vec, sort before calling this function otherwise Open / Close may
@@ -488,7 +791,7 @@ impl ProductionCandle {
                 )
             },
         );
-        Self {
+        let mut resampled = Self {
             datetime: *dt,
             open: candle.0,
             high: candle.1,
@@ -516,7 +819,16 @@ impl ProductionCandle {
             last_trade_id: candle.23,
             first_trade_ts: candle.24,
             first_trade_id: candle.25,
-        }
+            vwap: dec!(0),
+            vwap_buy: dec!(0),
+            vwap_sell: dec!(0),
+            // Candles in the interval cover equal time so the twap is the mean of their twaps
+            twap: candles.iter().map(|c| c.twap).sum::<Decimal>() / Decimal::from(candles.len()),
+            vap: VolumeAtPrice::merge(candles.iter().map(|c| c.vap.as_deref())),
+            revision: 0,
+        };
+        resampled.set_vwaps();
+        resampled
     }
 
     pub fn from_trades_for_dr<T: Trade>(
@@ -524,6 +836,7 @@ impl ProductionCandle {
         mut last_trade: Option<PrIdTi>,
         tf: &TimeFrame,
         dr: &[DateTime<Utc>],
+        quote_step: Option<Decimal>,
     ) -> Vec<Self> {
         // Iterate through dr, filter trades and make candles for each interval
         // TODO - Add validation that dr start interval has trades if the last trade is None to
@@ -531,11 +844,11 @@ impl ProductionCandle {
         let candles = dr.iter().fold(Vec::new(), |mut v, d| {
             let filtered_trades: Vec<_> = trades
                 .iter()
-                .filter(|t| t.time().duration_trunc(tf.as_dur()).unwrap() == *d)
+                .filter(|t| tf.trunc(&t.time()) == *d)
                 .collect();
             let new_candle = match filtered_trades.is_empty() {
                 true => Self::from_last(*d, &last_trade.unwrap()),
-                false => Self::from_trades(*d, &filtered_trades),
+                false => Self::from_trades(*d, &filtered_trades, quote_step),
             };
             last_trade = Some(new_candle.close_as_pridti());
             v.push(new_candle);
@@ -544,7 +857,12 @@ impl ProductionCandle {
         candles
     }
 
-    pub fn resample(candles: &[Self], tf: &TimeFrame, dr: &[DateTime<Utc>]) -> Vec<Self> {
+    pub fn resample(
+        candles: &[Self],
+        tf: &TimeFrame,
+        dr: &[DateTime<Utc>],
+        session: &Session,
+    ) -> Vec<Self> {
         // Check first that there are candles to resample
         if candles.is_empty() {
             // Return original empty vec
@@ -555,7 +873,7 @@ impl ProductionCandle {
             dr.iter().fold(Vec::new(), |mut v, d| {
                 let interval_candles: Vec<_> = candles
                     .iter()
-                    .filter(|c| c.datetime.duration_trunc(tf.as_dur()).unwrap() == *d)
+                    .filter(|c| session.trunc(tf, &c.datetime) == *d)
                     .cloned()
                     .collect();
                 let resampled_candle = Self::new_from_candles(d, &interval_candles);
@@ -567,13 +885,14 @@ impl ProductionCandle {
 
     pub async fn create_table(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
     ) -> Result<(), sqlx::Error> {
         // Cannot use query! macro as table does not exist
         let sql = format!(
             r#"
-            CREATE TABLE IF NOT EXISTS candles.production_{}_{}_{} (
+            CREATE TABLE IF NOT EXISTS {s}.production_{}_{}_{} (
                 datetime timestamptz NOT NULL,
                 PRIMARY KEY (datetime),
                 open NUMERIC NOT NULL,
@@ -601,12 +920,19 @@ impl ProductionCandle {
                 last_trade_ts timestamptz NOT NULL,
                 last_trade_id TEXT NOT NULL,
                 first_trade_ts timestamptz NOT NULL,
-                first_trade_id TExT NOT NULL
+                first_trade_id TExT NOT NULL,
+                vwap NUMERIC NOT NULL DEFAULT 0,
+                vwap_buy NUMERIC NOT NULL DEFAULT 0,
+                vwap_sell NUMERIC NOT NULL DEFAULT 0,
+                twap NUMERIC NOT NULL DEFAULT 0,
+                vap TEXT,
+                revision INTEGER NOT NULL DEFAULT 0
             )
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         sqlx::query(&sql).execute(pool).await?;
         Ok(())
@@ -615,21 +941,58 @@ impl ProductionCandle {
     pub async fn insert(
         &self,
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
     ) -> Result<(), sqlx::Error> {
         // Cannot use query! macro as table may not exist at compile time
         let sql = format!(
             r#"
-            INSERT INTO candles.production_{}_{}_{}
+            INSERT INTO {s}.production_{}_{}_{}
             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
-                $19, $20, $21, $22, $23, $24, $25, $26, $27)
+                $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33)
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
-        sqlx::query(&sql)
+        self.execute_with_binds(pool, &sql).await
+    }
+
+    // Insert the candle or replace the existing candle for the datetime, ie with an amended one
+    pub async fn upsert(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            INSERT INTO {s}.production_{}_{}_{}
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33)
+            ON CONFLICT (datetime) DO UPDATE
+            SET (open, high, low, close, volume, volume_buy, volume_sell, volume_liq,
+                volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
+                value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
+                liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap, revision)
+                = ($2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33)
+            "#,
+            market.exchange_name.as_str(),
+            market.as_strip(),
+            tf.as_str(),
+            s = schema.candles(&market.exchange_name),
+        );
+        self.execute_with_binds(pool, &sql).await
+    }
+
+    // Bind the candle fields in table column order to the insert statement and execute it
+    async fn execute_with_binds(&self, pool: &PgPool, sql: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(sql)
             .bind(self.datetime)
             .bind(self.open)
             .bind(self.high)
@@ -657,6 +1020,12 @@ impl ProductionCandle {
             .bind(&self.last_trade_id)
             .bind(self.first_trade_ts)
             .bind(&self.first_trade_id)
+            .bind(self.vwap)
+            .bind(self.vwap_buy)
+            .bind(self.vwap_sell)
+            .bind(self.twap)
+            .bind(&self.vap)
+            .bind(self.revision)
             .execute(pool)
             .await?;
         Ok(())
@@ -664,6 +1033,7 @@ impl ProductionCandle {
 
     pub async fn select_gte_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: &DateTime<Utc>,
     ) -> Result<Vec<Self>, sqlx::Error> {
@@ -673,14 +1043,15 @@ impl ProductionCandle {
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.production_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap, revision
+            FROM {s}.production_{}_{}_{}
             WHERE datetime >= $1
             ORDER BY datetime ASC
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             market.tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let rows = sqlx::query_as::<_, ProductionCandle>(&sql)
             .bind(dt)
@@ -691,6 +1062,7 @@ impl ProductionCandle {
 
     pub async fn select_eq_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: &DateTime<Utc>,
     ) -> Result<Self, sqlx::Error> {
@@ -700,14 +1072,15 @@ impl ProductionCandle {
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.production_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap, revision
+            FROM {s}.production_{}_{}_{}
             WHERE datetime = $1
             ORDER BY datetime ASC
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             market.tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let row = sqlx::query_as::<_, ProductionCandle>(&sql)
             .bind(dt)
@@ -716,20 +1089,25 @@ impl ProductionCandle {
         Ok(row)
     }
 
-    pub async fn select_first(pool: &PgPool, market: &MarketDetail) -> Result<Self, sqlx::Error> {
+    pub async fn select_first(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<Self, sqlx::Error> {
         let sql = format!(
             r#"
             SELECT datetime, open, high, low, close, volume, volume_buy, volume_sell, volume_liq,
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.production_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap, revision
+            FROM {s}.production_{}_{}_{}
             ORDER BY datetime ASC
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             market.tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let row = sqlx::query_as::<_, ProductionCandle>(&sql)
             .fetch_one(pool)
@@ -738,6 +1116,7 @@ impl ProductionCandle {
     }
     pub async fn select_last_non_zero(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
     ) -> Result<Self, sqlx::Error> {
         let sql = format!(
@@ -746,14 +1125,15 @@ impl ProductionCandle {
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.production_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap, revision
+            FROM {s}.production_{}_{}_{}
             WHERE volume > 0
             ORDER BY datetime DESC
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             market.tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let row = sqlx::query_as::<_, ProductionCandle>(&sql)
             .fetch_one(pool)
@@ -761,45 +1141,82 @@ impl ProductionCandle {
         Ok(row)
     }
 
-    // Delete an trades less than a give date for a give market
+    // Delete an trades less than a give date for a give market. Returns the number of candles
+    // deleted.
     pub async fn delete_lt_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
         dt: &DateTime<Utc>,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<u64, sqlx::Error> {
         let sql = format!(
             r#"
-            DELETE FROM candles.production_{}_{}_{}
+            DELETE FROM {s}.production_{}_{}_{}
             WHERE datetime < $1
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
-        sqlx::query(&sql).bind(dt).execute(pool).await?;
-        Ok(())
+        let result = sqlx::query(&sql).bind(dt).execute(pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn table_exists(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+    ) -> Result<bool, sqlx::Error> {
+        let table = format!(
+            "{}.production_{}_{}_{}",
+            schema.candles(&market.exchange_name),
+            market.exchange_name.as_str(),
+            market.as_strip(),
+            tf.as_str(),
+        );
+        let row: (bool,) = sqlx::query_as("SELECT to_regclass($1) IS NOT NULL")
+            .bind(table)
+            .fetch_one(pool)
+            .await?;
+        Ok(row.0)
     }
 
     pub async fn delete_gt_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: &DateTime<Utc>,
     ) -> Result<(), sqlx::Error> {
         let sql = format!(
             r#"
-            DELETE FROM candles.production_{}_{}_{}
+            DELETE FROM {s}.production_{}_{}_{}
             WHERE datetime > $1
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             market.tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         sqlx::query(&sql).bind(dt).execute(pool).await?;
         Ok(())
     }
 }
 
+impl Candle for ProductionCandle {
+    fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+    fn close(&self) -> Decimal {
+        self.close
+    }
+    fn volume(&self) -> Decimal {
+        self.volume
+    }
+}
+
 impl Candle for ResearchCandle {
     fn datetime(&self) -> DateTime<Utc> {
         self.datetime
@@ -813,6 +1230,13 @@ impl Candle for ResearchCandle {
 }
 
 impl ResearchCandle {
+    // Volume weighted prices from the value and volume sums
+    pub fn set_vwaps(&mut self) {
+        self.vwap = volume_weighted_price(self.value, self.volume, self.close);
+        self.vwap_buy = volume_weighted_price(self.value_buy, self.volume_buy, self.vwap);
+        self.vwap_sell = volume_weighted_price(self.value_sell, self.volume_sell, self.vwap);
+    }
+
     pub fn close_as_pridti(&self) -> PrIdTi {
         PrIdTi {
             id: self.last_trade_id.parse::<i64>().unwrap(),
@@ -821,222 +1245,15 @@ impl ResearchCandle {
         }
     }
 
-    // Reduces the number of if statements in each iteration
-    pub fn from_trades_v2<T: Trade>(datetime: DateTime<Utc>, trades: &[T]) -> Self {
-        let candle_tuple = trades.iter().fold(
-            (
-                trades.first().expect("No first trade for candle.").price(), // open
-                Decimal::MIN,                                                // high
-                Decimal::MAX,                                                // low
-                dec!(0),                                                     // close
-                dec!(0),                                                     // volume
-                dec!(0),                                                     // volume buy
-                dec!(0),                                                     // volume sell
-                dec!(0),                                                     // volume liq
-                dec!(0),                                                     // volume liq buy
-                dec!(0),                                                     // volume liq sell
-                dec!(0),                                                     // value
-                dec!(0),                                                     // value buy
-                dec!(0),                                                     // value sell
-                dec!(0),                                                     // value liq
-                dec!(0),                                                     // value liq buy
-                dec!(0),                                                     // value liq sell
-                0,                                                           // count
-                0,                                                           // count buy
-                0,                                                           // count sell
-                0,                                                           // liq count,
-                0,                                                           // liq count buy,
-                0,                                                           // liq count sell,
-                datetime,                                                    // last_trade_ts
-                "".to_string(),                                              // last_trade_id
-                trades.first().expect("No first trade.").time(),             // first_trade_ts
-                trades
-                    .first()
-                    .expect("No first trade.")
-                    .trade_id()
-                    .to_string(), // first_trade_id
-            ),
-            |(
-                o,
-                h,
-                l,
-                _c,
-                v,
-                vb,
-                vs,
-                vl,
-                vlb,
-                vls,
-                u,
-                ub,
-                us,
-                al,
-                alb,
-                als,
-                n,
-                nb,
-                ns,
-                ln,
-                lnb,
-                lns,
-                _ts,
-                _id,
-                fts,
-                fid,
-            ),
-             t| {
-                // Put side and liq if statements here
-                let value = t.size() * t.price();
-                let (
-                    volume_buy,
-                    volume_sell,
-                    volume_liq,
-                    volume_liq_buy,
-                    volume_liq_sell,
-                    value_buy,
-                    value_sell,
-                    value_liq,
-                    value_liq_buy,
-                    value_liq_sell,
-                    n_buy,
-                    n_sell,
-                    n_liq,
-                    n_liq_buy,
-                    n_liq_sell,
-                ) = if t.side() == "buy" {
-                    if t.liquidation() {
-                        (
-                            t.size(),
-                            dec!(0),
-                            t.size(),
-                            t.size(),
-                            dec!(0),
-                            value,
-                            dec!(0),
-                            value,
-                            value,
-                            dec!(0),
-                            1,
-                            0,
-                            1,
-                            1,
-                            0,
-                        )
-                    } else {
-                        (
-                            t.size(),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            value,
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            dec!(0),
-                            1,
-                            0,
-                            0,
-                            0,
-                            0,
-                        )
-                    }
-                } else if t.liquidation() {
-                    (
-                        dec!(0),
-                        t.size(),
-                        t.size(),
-                        dec!(0),
-                        t.size(),
-                        dec!(0),
-                        value,
-                        value,
-                        dec!(0),
-                        value,
-                        0,
-                        1,
-                        1,
-                        0,
-                        1,
-                    )
-                } else {
-                    (
-                        dec!(0),
-                        t.size(),
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        value,
-                        dec!(0),
-                        dec!(0),
-                        dec!(0),
-                        0,
-                        1,
-                        0,
-                        0,
-                        0,
-                    )
-                };
-                (
-                    o,                // open
-                    h.max(t.price()), // high
-                    l.min(t.price()), // low
-                    t.price(),        // close
-                    v + t.size(),     // volume
-                    vb + volume_buy,
-                    vs + volume_sell,
-                    vl + volume_liq,
-                    vlb + volume_liq_buy,
-                    vls + volume_liq_sell,
-                    u + value,
-                    ub + value_buy,
-                    us + value_sell,
-                    al + value_liq,
-                    alb + value_liq_buy,
-                    als + value_liq_sell,
-                    n + 1,
-                    nb + n_buy,
-                    ns + n_sell,
-                    ln + n_liq,
-                    lnb + n_liq_buy,
-                    lns + n_liq_sell,
-                    t.time(),
-                    t.trade_id().to_string(),
-                    fts,
-                    fid,
-                )
-            },
-        );
-        Self {
-            datetime,
-            open: candle_tuple.0,
-            high: candle_tuple.1,
-            low: candle_tuple.2,
-            close: candle_tuple.3,
-            volume: candle_tuple.4,
-            volume_buy: candle_tuple.5,
-            volume_sell: candle_tuple.6,
-            volume_liq: candle_tuple.7,
-            volume_liq_buy: candle_tuple.8,
-            volume_liq_sell: candle_tuple.9,
-            value: candle_tuple.10,
-            value_buy: candle_tuple.11,
-            value_sell: candle_tuple.12,
-            value_liq: candle_tuple.13,
-            value_liq_buy: candle_tuple.14,
-            value_liq_sell: candle_tuple.15,
-            trade_count: candle_tuple.16,
-            trade_count_buy: candle_tuple.17,
-            trade_count_sell: candle_tuple.18,
-            liq_count: candle_tuple.19,
-            liq_count_buy: candle_tuple.20,
-            liq_count_sell: candle_tuple.21,
-            last_trade_ts: candle_tuple.22,
-            last_trade_id: candle_tuple.23,
-            first_trade_ts: candle_tuple.24,
-            first_trade_id: candle_tuple.25,
-        }
+    // Takes a Vec of Trade and aggregates into a Candle, see ProductionCandle::from_trades
+    pub fn from_trades_v2<T: Trade>(
+        datetime: DateTime<Utc>,
+        trades: &[T],
+        quote_step: Option<Decimal>,
+    ) -> Self {
+        CandleAccumulator::from_trades(trades, quote_step)
+            .research_candle(datetime)
+            .expect("No first trade for candle.")
     }
 
     // This function will build a placeholder trade with 0 volume and
@@ -1070,6 +1287,11 @@ impl ResearchCandle {
             last_trade_id: pit.id.to_string(),
             first_trade_ts: pit.dt,
             first_trade_id: pit.id.to_string(),
+            vwap: pit.price,
+            vwap_buy: pit.price,
+            vwap_sell: pit.price,
+            twap: pit.price,
+            vap: None,
         }
     }
 
@@ -1170,7 +1392,7 @@ impl ResearchCandle {
                 )
             },
         );
-        Self {
+        let mut resampled = Self {
             datetime: *dt,
             open: candle.0,
             high: candle.1,
@@ -1198,7 +1420,15 @@ impl ResearchCandle {
             last_trade_id: candle.23,
             first_trade_ts: candle.24,
             first_trade_id: candle.25,
-        }
+            vwap: dec!(0),
+            vwap_buy: dec!(0),
+            vwap_sell: dec!(0),
+            // Candles in the interval cover equal time so the twap is the mean of their twaps
+            twap: candles.iter().map(|c| c.twap).sum::<Decimal>() / Decimal::from(candles.len()),
+            vap: VolumeAtPrice::merge(candles.iter().map(|c| c.vap.as_deref())),
+        };
+        resampled.set_vwaps();
+        resampled
     }
 
     // This function will take a vec of ResearchCandle and convert each candle to a ProductionCandle
@@ -1232,19 +1462,42 @@ impl ResearchCandle {
             last_trade_id: self.last_trade_id.clone(),
             first_trade_ts: self.first_trade_ts,
             first_trade_id: self.first_trade_id.clone(),
+            vwap: self.vwap,
+            vwap_buy: self.vwap_buy,
+            vwap_sell: self.vwap_sell,
+            twap: self.twap,
+            vap: self.vap.clone(),
+            revision: 0,
         }
     }
 
+    pub fn from_accumulators_for_dr(
+        accumulators: &HashMap<DateTime<Utc>, CandleAccumulator>,
+        mut last_trade: Option<PrIdTi>,
+        dr: &[DateTime<Utc>],
+    ) -> Vec<Self> {
+        dr.iter().fold(Vec::new(), |mut v, d| {
+            let new_candle = match accumulators.get(d).and_then(|a| a.research_candle(*d)) {
+                Some(candle) => candle,
+                None => ResearchCandle::from_last(*d, &last_trade.unwrap()),
+            };
+            last_trade = Some(new_candle.close_as_pridti());
+            v.push(new_candle);
+            v
+        })
+    }
+
     pub fn from_trades_hm_for_dr<T: Trade>(
         trades: &HashMap<DateTime<Utc>, Vec<T>>,
         mut last_trade: Option<PrIdTi>,
         dr: &[DateTime<Utc>],
+        quote_step: Option<Decimal>,
     ) -> Vec<Self> {
         let candles = dr.iter().fold(Vec::new(), |mut v, d| {
             let new_candle = if !trades.contains_key(d) {
                 ResearchCandle::from_last(*d, &last_trade.unwrap())
             } else {
-                ResearchCandle::from_trades_v2(*d, trades[d].as_slice())
+                ResearchCandle::from_trades_v2(*d, trades[d].as_slice(), quote_step)
             };
             last_trade = Some(new_candle.close_as_pridti());
             v.push(new_candle);
@@ -1253,7 +1506,12 @@ impl ResearchCandle {
         candles
     }
 
-    pub fn resample(candles: &[Self], tf: &TimeFrame, dr: &[DateTime<Utc>]) -> Vec<Self> {
+    pub fn resample(
+        candles: &[Self],
+        tf: &TimeFrame,
+        dr: &[DateTime<Utc>],
+        session: &Session,
+    ) -> Vec<Self> {
         // Check first that there are candles to resample
         if candles.is_empty() {
             // Return original empty vec
@@ -1264,7 +1522,7 @@ impl ResearchCandle {
             dr.iter().fold(Vec::new(), |mut v, d| {
                 let interval_candles: Vec<_> = candles
                     .iter()
-                    .filter(|c| c.datetime().duration_trunc(tf.as_dur()).unwrap() == *d)
+                    .filter(|c| session.trunc(tf, &c.datetime()) == *d)
                     .cloned()
                     .collect();
                 let resampled_candle = Self::new_from_candles(d, &interval_candles);
@@ -1274,16 +1532,78 @@ impl ResearchCandle {
         }
     }
 
+    // Resample by mapping the candles to their interval first instead of filtering the candles for
+    // each interval. The candles must have no gaps.
+    pub fn resample_by_hashmap(candles: &[Self], tf: &TimeFrame, session: &Session) -> Vec<Self> {
+        // First map research candles into hash map, then resample each hashmap key
+        let mut candle_map: HashMap<DateTime<Utc>, Vec<ResearchCandle>> = HashMap::new();
+        for candle in candles.iter() {
+            candle_map
+                .entry(session.trunc(tf, &candle.datetime))
+                .and_modify(|v| v.push(candle.clone()))
+                .or_insert_with(|| vec![candle.clone()]);
+        }
+        // Second create the date range for the resampled candles. If candles passed are S15
+        // research candles then the last candle is 23:59:45. The last dt for the dr should be
+        // for a T15 tf should be 23:45:00 so the end time for the DR:new is 00:00:00
+        let last = session.trunc(tf, &candles.last().unwrap().datetime);
+        let dr = session.date_range(
+            tf,
+            &session.trunc(tf, &candles.first().unwrap().datetime),
+            &session.next(tf, &last),
+        );
+        // For each date in the daterange - aggregate the candles
+        dr.iter().fold(Vec::new(), |mut v, d| {
+            v.push(ResearchCandle::new_from_candles(d, &candle_map[d]));
+            v
+        })
+    }
+
+    // Resample the S15 candles of the month to each archive time frame, keeping only the candles
+    // that open in the month. Session days do not line up with the month end so the last H12 and
+    // D01 candles of the month are completed with the S15 candles of the next month when they
+    // are archived and the first ones, opened in the previous month, are left to its files.
+    pub fn resample_month(
+        month: &DateTime<Utc>,
+        candles: &[Self],
+        next: &[Self],
+        session: &Session,
+    ) -> Vec<(TimeFrame, Vec<Self>)> {
+        let next_month = ElDorado::next_month_dt(month);
+        let end = session.next(
+            &TimeFrame::D01,
+            &session.trunc(&TimeFrame::D01, &(next_month - Duration::seconds(1))),
+        );
+        let mut extended = candles.to_vec();
+        extended.extend(next.iter().filter(|c| c.datetime < end).cloned());
+        let mut candles_map = HashMap::new();
+        candles_map.insert(TimeFrame::S15, extended);
+        let mut months = Vec::new();
+        for tf in TimeFrame::atfs().iter().skip(1) {
+            let resampled =
+                Self::resample_by_hashmap(&candles_map[&session.resample_from(tf)], tf, session);
+            let in_month: Vec<_> = resampled
+                .iter()
+                .filter(|c| c.datetime >= *month && c.datetime < next_month)
+                .cloned()
+                .collect();
+            months.push((*tf, in_month));
+            candles_map.insert(*tf, resampled);
+        }
+        months
+    }
+
     // This function will create the research candle table for the given market
     pub async fn create_table(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
     ) -> Result<(), sqlx::Error> {
         // Cannot use query! macro for query validation as the table does not exist
         let sql = format!(
             r#"
-            CREATE TABLE IF NOT EXISTS candles.research_{}_{}_{} (
+            CREATE TABLE IF NOT EXISTS {s}.research_{}_{}_{} (
                 datetime timestamptz NOT NULL,
                 PRIMARY KEY (datetime),
                 open NUMERIC NOT NULL,
@@ -1311,12 +1631,18 @@ impl ResearchCandle {
                 last_trade_ts timestamptz NOT NULL,
                 last_trade_id TEXT NOT NULL,
                 first_trade_ts timestamptz NOT NULL,
-                first_trade_id TExT NOT NULL
+                first_trade_id TExT NOT NULL,
+                vwap NUMERIC NOT NULL DEFAULT 0,
+                vwap_buy NUMERIC NOT NULL DEFAULT 0,
+                vwap_sell NUMERIC NOT NULL DEFAULT 0,
+                twap NUMERIC NOT NULL DEFAULT 0,
+                vap TEXT
             )
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         sqlx::query(&sql).execute(pool).await?;
         Ok(())
@@ -1325,6 +1651,7 @@ impl ResearchCandle {
     // This function will select the last (latest datetime) research candle from the given table
     pub async fn select_last(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
     ) -> Result<Self, sqlx::Error> {
@@ -1336,13 +1663,14 @@ impl ResearchCandle {
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.research_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap
+            FROM {s}.research_{}_{}_{}
             ORDER BY datetime DESC LIMIT 1
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
-            tf.as_str()
+            tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let row = sqlx::query_as::<_, ResearchCandle>(&sql)
             .fetch_one(pool)
@@ -1353,6 +1681,7 @@ impl ResearchCandle {
     // This function will select all research candles for a given date range start and end
     pub async fn select_dr(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
         start: &DateTime<Utc>,
@@ -1365,15 +1694,16 @@ impl ResearchCandle {
                 volume_liq_buy, volume_liq_sell, value, value_buy, value_sell, value_liq,
                 value_liq_buy, value_liq_sell, trade_count, trade_count_buy, trade_count_sell,
                 liq_count, liq_count_buy, liq_count_sell, last_trade_ts, last_trade_id,
-                first_trade_ts, first_trade_id
-            FROM candles.research_{}_{}_{}
+                first_trade_ts, first_trade_id, vwap, vwap_buy, vwap_sell, twap, vap
+            FROM {s}.research_{}_{}_{}
             WHERE datetime >= $1
             AND datetime < $2
             ORDER BY datetime ASC
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
-            tf.as_str()
+            tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         let rows = sqlx::query_as::<_, ResearchCandle>(&sql)
             .bind(start)
@@ -1387,19 +1717,21 @@ impl ResearchCandle {
     pub async fn insert(
         &self,
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
     ) -> Result<(), sqlx::Error> {
         // Cannot user query! macro as table may not exist as compile time.
         let sql = format!(
             r#"
-            INSERT INTO candles.research_{}_{}_{}
+            INSERT INTO {s}.research_{}_{}_{}
             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
-                $19, $20, $21, $22, $23, $24, $25, $26, $27)
+                $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32)
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         sqlx::query(&sql)
             .bind(self.datetime)
@@ -1429,6 +1761,11 @@ impl ResearchCandle {
             .bind(&self.last_trade_id)
             .bind(self.first_trade_ts)
             .bind(&self.first_trade_id)
+            .bind(self.vwap)
+            .bind(self.vwap_buy)
+            .bind(self.vwap_sell)
+            .bind(self.twap)
+            .bind(&self.vap)
             .execute(pool)
             .await?;
         Ok(())
@@ -1437,33 +1774,135 @@ impl ResearchCandle {
     // Delete an trades less than a give date for a give market
     pub async fn delete_lt_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         tf: &TimeFrame,
         dt: &DateTime<Utc>,
     ) -> Result<(), sqlx::Error> {
         let sql = format!(
             r#"
-            DELETE FROM candles.research_{}_{}_{}
+            DELETE FROM {s}.research_{}_{}_{}
             WHERE datetime < $1
             "#,
             market.exchange_name.as_str(),
             market.as_strip(),
             tf.as_str(),
+            s = schema.candles(&market.exchange_name),
         );
         sqlx::query(&sql).bind(dt).execute(pool).await?;
         Ok(())
     }
+
+    pub async fn delete_dr(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            DELETE FROM {s}.research_{}_{}_{}
+            WHERE datetime >= $1
+            AND datetime < $2
+            "#,
+            market.exchange_name.as_str(),
+            market.as_strip(),
+            tf.as_str(),
+            s = schema.candles(&market.exchange_name),
+        );
+        sqlx::query(&sql)
+            .bind(start)
+            .bind(end)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
 }
 
 impl ElDorado {
-    pub async fn create_candles_schema(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
-        let sql = r#"
-            CREATE SCHEMA IF NOT EXISTS candles
+    // Quote step to bucket the volume at price by if enabled for the instance
+    pub fn vap_step(&self, market: &MarketDetail) -> Option<Decimal> {
+        if self.volume_at_price {
+            market.quote_step
+        } else {
+            None
+        }
+    }
+
+    pub async fn create_candles_schema(&self, exchange: &ExchangeName) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            CREATE SCHEMA IF NOT EXISTS {}
+            "#,
+            self.schema.candles(exchange)
+        );
+        sqlx::query(&sql)
+            .execute(self.pools.exchange(exchange))
+            .await?;
+        self.upgrade_candle_tables(exchange).await
+    }
+
+    // Bring candle tables created before the price and revision columns up to the current
+    // columns. Run once with the schema creation as the tables are created per market.
+    pub async fn upgrade_candle_tables(&self, exchange: &ExchangeName) -> Result<(), sqlx::Error> {
+        let pool = self.pools.exchange(exchange);
+        let schema = self.schema.candles(exchange);
+        let price_sql = r#"
+            ADD COLUMN IF NOT EXISTS vwap NUMERIC NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS vwap_buy NUMERIC NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS vwap_sell NUMERIC NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS twap NUMERIC NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS vap TEXT
             "#;
-        sqlx::query(sql).execute(pool).await?;
+        let revision_sql = r#"
+            ADD COLUMN IF NOT EXISTS revision INTEGER NOT NULL DEFAULT 0
+            "#;
+        let upgrades = [
+            ("production", "vwap", price_sql),
+            ("research", "vwap", price_sql),
+            ("bars", "vwap", price_sql),
+            ("production", "revision", revision_sql),
+        ];
+        for (prefix, column, alter) in upgrades.iter() {
+            for table in Self::select_tables_without_column(pool, &schema, prefix, column).await? {
+                println!("Adding {} columns to {}.{}", column, schema, table);
+                let sql = format!("ALTER TABLE {}.{} {}", schema, table, alter);
+                sqlx::query(&sql).execute(pool).await?;
+            }
+        }
         Ok(())
     }
 
+    // Tables in the schema with the name prefix ie production_ that do not have the column
+    async fn select_tables_without_column(
+        pool: &PgPool,
+        schema: &str,
+        prefix: &str,
+        column: &str,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let sql = r#"
+            SELECT t.table_name::TEXT
+            FROM information_schema.tables t
+            WHERE t.table_schema = $1
+            AND t.table_name LIKE $2
+            AND NOT EXISTS (
+                SELECT 1 FROM information_schema.columns c
+                WHERE c.table_schema = t.table_schema
+                AND c.table_name = t.table_name
+                AND c.column_name = $3
+            )
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(schema)
+            .bind(format!("{}\\_%", prefix))
+            .bind(column)
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.into_iter().map(|(t,)| t).collect())
+    }
+
     pub async fn candle_table_exists(
         &self,
         market: &MarketDetail,
@@ -1479,12 +1918,8 @@ impl ElDorado {
             market.as_strip(),
             tf.as_str(),
         );
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for candles.", name),
-        };
-        ElDorado::table_exists(&self.pools[&db], "candles", &table).await
+        let pool = self.pools.exchange(&market.exchange_name);
+        ElDorado::table_exists(pool, &self.schema.candles(&market.exchange_name), &table).await
     }
 
     pub fn convert_research_candles(&self, candles: &[ResearchCandle]) -> Vec<ProductionCandle> {
@@ -1507,7 +1942,8 @@ impl ElDorado {
         candles: &[ResearchCandle],
         tf: &TimeFrame,
     ) -> Vec<ProductionCandle> {
-        let resampled_candles = self.resample_research_candles_by_hashmap(candles, tf);
+        let resampled_candles =
+            self.resample_research_candles_by_hashmap(candles, tf, &Session::utc());
         // Finally convert to production
         self.convert_research_candles(&resampled_candles)
     }
@@ -1522,7 +1958,7 @@ impl ElDorado {
         let mut candle_map: HashMap<DateTime<Utc>, Vec<ProductionCandle>> = HashMap::new();
         for candle in candles.iter() {
             candle_map
-                .entry(candle.datetime.duration_trunc(tf.as_dur()).unwrap())
+                .entry(tf.trunc(&candle.datetime))
                 .and_modify(|v| v.push(candle.as_production_candle()))
                 .or_insert_with(|| vec![candle.as_production_candle()]);
         }
@@ -1532,19 +1968,8 @@ impl ElDorado {
         // dr can be unwrapped as there will be control on calling this function to make sure there
         // is at least one date in dr to resample. TODO: Convert to Result<Vec, Err>
         let dr = DateRange::new(
-            &candles
-                .first()
-                .unwrap()
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .unwrap(),
-            &(candles
-                .last()
-                .unwrap()
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .unwrap()
-                + tf.as_dur()),
+            &tf.trunc(&candles.first().unwrap().datetime),
+            &tf.next(&tf.trunc(&candles.last().unwrap().datetime)),
             tf,
         )
         .unwrap();
@@ -1559,42 +1984,9 @@ impl ElDorado {
         &self,
         candles: &[ResearchCandle],
         tf: &TimeFrame,
+        session: &Session,
     ) -> Vec<ResearchCandle> {
-        // First map research candles into hash map, then resample each hashmap key
-        let mut candle_map: HashMap<DateTime<Utc>, Vec<ResearchCandle>> = HashMap::new();
-        for candle in candles.iter() {
-            candle_map
-                .entry(candle.datetime.duration_trunc(tf.as_dur()).unwrap())
-                .and_modify(|v| v.push(candle.clone()))
-                .or_insert_with(|| vec![candle.clone()]);
-        }
-        // Second create the date range for the resampled candles. If candles passed are S15
-        // research candles then the last candle is 23:59:45. The last dt for the dr should be
-        // for a T15 tf should be 23:45:00 so the end time for the DR:new is 00:00:00
-        // dr can be unwrapped as there will be control on calling this function to make sure there
-        // is at least one date in dr to resample. TODO: Convert to Result<Vec, Err>
-        let dr = DateRange::new(
-            &candles
-                .first()
-                .unwrap()
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .unwrap(),
-            &(candles
-                .last()
-                .unwrap()
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .unwrap()
-                + tf.as_dur()),
-            tf,
-        )
-        .unwrap();
-        // For each date in the daterange - aggregate the candles
-        dr.dts.iter().fold(Vec::new(), |mut v, d| {
-            v.push(ResearchCandle::new_from_candles(d, &candle_map[d]));
-            v
-        })
+        ResearchCandle::resample_by_hashmap(candles, tf, session)
     }
 
     // Get date range and resample
@@ -1609,20 +2001,10 @@ impl ElDorado {
             Vec::new()
         } else {
             // Create date range for resample period
-            let first = candles
-                .first()
-                .unwrap()
-                .datetime()
-                .duration_trunc(tf.as_dur())
-                .unwrap();
-            let last = candles
-                .last()
-                .unwrap()
-                .datetime()
-                .duration_trunc(tf.as_dur())
-                .unwrap();
-            let dr = self.create_date_range(&first, &(last + tf.as_dur()), tf);
-            ResearchCandle::resample(candles, tf, &dr)
+            let first = tf.trunc(&candles.first().unwrap().datetime());
+            let last = tf.trunc(&candles.last().unwrap().datetime());
+            let dr = self.create_date_range(&first, &tf.next(&last), tf);
+            ResearchCandle::resample(candles, tf, &dr, &Session::utc())
         }
     }
 
@@ -1630,23 +2012,14 @@ impl ElDorado {
         &self,
         candles: &[ProductionCandle],
         tf: &TimeFrame,
+        session: &Session,
     ) -> Vec<ProductionCandle> {
         if !candles.is_empty() {
             // Create date range for reample period
-            let first = candles
-                .first()
-                .expect("Expected candles.")
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .expect("Expected truncation");
-            let last = candles
-                .last()
-                .expect("Expected candles.")
-                .datetime
-                .duration_trunc(tf.as_dur())
-                .expect("Expected truncation.");
-            let dr = self.create_date_range(&first, &(last + tf.as_dur()), tf);
-            ProductionCandle::resample(candles, tf, &dr)
+            let first = session.trunc(tf, &candles.first().expect("Expected candles.").datetime);
+            let last = session.trunc(tf, &candles.last().expect("Expected candles.").datetime);
+            let dr = session.date_range(tf, &first, &session.next(tf, &last));
+            ProductionCandle::resample(candles, tf, &dr, session)
         } else {
             // No candles to resample, return empty vec
             Vec::new()
@@ -1660,11 +2033,51 @@ impl ElDorado {
         cdr: &DateRange,
         tdr: &DateRange,
     ) -> Vec<ResearchCandle> {
-        // For each day in the dr, read trades from file and create candles
-        let trades = self.read_gdax_trades_for_dr(market, &TimeFrame::S15, &tdr.dts);
+        // For each day in the dr, read trades from file into accumulators in parallel
+        let accumulators = self.accumulate_gdax_trades_for_dr(market, &TimeFrame::S15, &tdr.dts);
         // Make candles for the candle dr
         let last_trade = mad.as_ref().map(|m| m.last_as_pridti());
-        ResearchCandle::from_trades_hm_for_dr(&trades, last_trade, &cdr.dts)
+        ResearchCandle::from_accumulators_for_dr(&accumulators, last_trade, &cdr.dts)
+    }
+
+    // Add a streamed trade to the market's live interval accumulator
+    pub fn push_live_trade<T: Trade>(&self, market: &MarketDetail, trade: &T) {
+        let mut live = self.live.lock().unwrap();
+        live.entry(market.market_id)
+            .or_insert_with(|| LiveCandles::new(trade.time()))
+            .push(trade, &market.tf, self.vap_step(market));
+    }
+
+    // Add trades inserted outside of the stream, ie by gap repair, to the live accumulators so
+    // open intervals include them and closed intervals are amended. Markets without a stream make
+    // their candles from the trade table.
+    pub fn push_repaired_trades<T: Trade>(&self, market: &MarketDetail, trades: &[T]) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(lc) = live.get_mut(&market.market_id) {
+            lc.push_trades(trades, &market.tf, self.vap_step(market));
+        }
+    }
+
+    // Take the live accumulators for the date range and finalize them to candles. Intervals
+    // without trades are made from the last trade. None if the stream started after the first
+    // interval began, in which case the trades must be selected from the trade table.
+    pub fn take_live_candles(
+        &self,
+        market: &MarketDetail,
+        dr: &DateRange,
+        last_trade: &PrIdTi,
+    ) -> Option<Vec<ProductionCandle>> {
+        let mut live = self.live.lock().unwrap();
+        let lc = live.get_mut(&market.market_id)?;
+        let later = lc.intervals.split_off(&(dr.last + market.tf.as_dur()));
+        let intervals = std::mem::replace(&mut lc.intervals, later);
+        lc.closed = lc.closed.max(dr.last + market.tf.as_dur());
+        if dr.first <= lc.since {
+            return None;
+        }
+        Some(ProductionCandle::from_live_for_dr(
+            &intervals, last_trade, &dr.dts,
+        ))
     }
 
     pub async fn make_research_candles_for_dt_from_file(
@@ -1696,7 +2109,10 @@ impl ElDorado {
                                 &TimeFrame::S15,
                             );
                             Ok(Some(ResearchCandle::from_trades_hm_for_dr(
-                                &trades_hm, last_trade, &dr,
+                                &trades_hm,
+                                last_trade,
+                                &dr,
+                                self.vap_step(market),
                             )))
                         }
                         None => Ok(None),
@@ -1720,7 +2136,10 @@ impl ElDorado {
                         dr.last().unwrap()
                     );
                     Ok(Some(ResearchCandle::from_trades_hm_for_dr(
-                        &trades_hm, last_trade, &dr,
+                        &trades_hm,
+                        last_trade,
+                        &dr,
+                        self.vap_step(market),
                     )))
                 }
             }
@@ -1737,7 +2156,13 @@ impl ElDorado {
         // Select trades for the given date
         match market.exchange_name {
             ExchangeName::Ftx | ExchangeName::FtxUs => {
-                let trades = FtxTrade::select_all(&self.pools[&Database::Ftx], market, dt).await?;
+                let trades = FtxTrade::select_all(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    dt,
+                )
+                .await?;
                 if trades.is_empty() {
                     // TODO: Handle case where there are no trades for the day (Kraken in Jan 2018)
                     Ok(None)
@@ -1755,13 +2180,22 @@ impl ElDorado {
                         dr.last().unwrap()
                     );
                     Ok(Some(ProductionCandle::from_trades_for_dr(
-                        &trades, last_trade, &market.tf, &dr,
+                        &trades,
+                        last_trade,
+                        &market.tf,
+                        &dr,
+                        self.vap_step(market),
                     )))
                 }
             }
             ExchangeName::Gdax => {
-                let trades =
-                    GdaxTrade::select_all(&self.pools[&Database::Gdax], market, dt).await?;
+                let trades = GdaxTrade::select_all(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    dt,
+                )
+                .await?;
                 if trades.is_empty() {
                     // TODO: Handle case where there are no trades for the day (Kraken in Jan 2018)
                     Ok(None)
@@ -1779,7 +2213,11 @@ impl ElDorado {
                         dr.last().unwrap()
                     );
                     Ok(Some(ProductionCandle::from_trades_for_dr(
-                        &trades, last_trade, &market.tf, &dr,
+                        &trades,
+                        last_trade,
+                        &market.tf,
+                        &dr,
+                        self.vap_step(market),
                     )))
                 }
             }
@@ -1829,6 +2267,7 @@ impl ElDorado {
                 *last_trade,
                 &market.tf,
                 &dr,
+                self.vap_step(market),
             ))
         } else {
             println!("No candles to make. DR len 0");
@@ -1845,6 +2284,10 @@ impl ElDorado {
         match market.exchange_name {
             ExchangeName::Ftx | ExchangeName::FtxUs => Ok(None),
             ExchangeName::Gdax => {
+                // Use the streamed trades if they cover the interval
+                if let Some(candles) = self.take_live_candles(market, dr, last_trade) {
+                    return Ok(Some(candles));
+                }
                 // Get the trades for the interval
                 Ok(self.select_gdax_trades_for_interval(market, dr).await?.map(
                     |trades|  // Make candles with the trades
@@ -1853,9 +2296,14 @@ impl ElDorado {
                             Some(*last_trade),
                             &market.tf,
                             &dr.dts,
+                            self.vap_step(market),
                         ),
                 ))
             }
+            ExchangeName::Index => Ok(Some(
+                self.make_virtual_candles(market, dr, Some(*last_trade))
+                    .await?,
+            )),
             name => panic!("{:?} not supported for candles.", name),
         }
     }
@@ -1881,7 +2329,7 @@ impl ElDorado {
             Some(_) => *dt,
             // There have been no previous trades or candles loaded for sync. If there are
             // trades on the date given, the first trade is when the candles should start.
-            None => trade.time().duration_trunc(tf.as_dur()).unwrap(),
+            None => tf.trunc(&trade.time()),
         };
         self.create_date_range(&dr_start, &(*dt + Duration::days(1)), tf)
     }
@@ -1911,26 +2359,24 @@ impl ElDorado {
         market: &MarketDetail,
         candles: &[ProductionCandle],
     ) -> Result<(), ElDoradoError> {
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for candles.", name),
-        };
+        let pool = self.pools.exchange(&market.exchange_name);
         for candle in candles.iter() {
-            candle.insert(&self.pools[&db], market, &market.tf).await?;
+            candle
+                .insert(pool, &self.schema, market, &market.tf)
+                .await?;
         }
         Ok(())
     }
 
     pub async fn insert_research_candles(&self, market: &MarketDetail, candles: &[ResearchCandle]) {
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for candles.", name),
-        };
+        let pool = self.pools.exchange(&market.exchange_name);
+        // Make sure the table has any columns added since it was created
+        ResearchCandle::create_table(pool, &self.schema, market, &TimeFrame::S15)
+            .await
+            .expect("Failed to create table.");
         for candle in candles.iter() {
             candle
-                .insert(&self.pools[&db], market, &TimeFrame::S15)
+                .insert(pool, &self.schema, market, &TimeFrame::S15)
                 .await
                 .expect("Failed to insert candle.");
         }
@@ -1976,12 +2422,8 @@ impl ElDorado {
             .await?
         {
             // Get first production candle
-            let db = match market.exchange_name {
-                ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-                ExchangeName::Gdax => Database::Gdax,
-                name => panic!("{:?} not supported for candles.", name),
-            };
-            match ProductionCandle::select_first(&self.pools[&db], market).await {
+            let pool = self.pools.exchange(&market.exchange_name);
+            match ProductionCandle::select_first(pool, &self.schema, market).await {
                 Ok(c) => {
                     // Validate candle is start of day and has volume
                     if c.datetime == c.datetime.duration_trunc(Duration::days(1)).unwrap() {
@@ -1989,7 +2431,8 @@ impl ElDorado {
                     } else {
                         Ok(Some(
                             ProductionCandle::select_eq_dt(
-                                &self.pools[&db],
+                                pool,
+                                &self.schema,
                                 market,
                                 &(c.datetime.duration_trunc(Duration::days(1)).unwrap()
                                     + Duration::days(1)),
@@ -2018,25 +2461,79 @@ impl ElDorado {
         // Create file and write candles
         println!("Writing S15 candles for the month.");
         self.write_research_candles_to_file(&pb, candles);
-        // Create map to store resampled candles for larger timeframes
-        let mut candles_map = HashMap::new();
-        candles_map.insert(TimeFrame::S15, candles.to_owned());
-        // Resample and write for other time frames
-        for tf in TimeFrame::atfs().iter().skip(1) {
-            let resampled_candles =
-                self.resample_research_candles_by_hashmap(&candles_map[&tf.resample_from()], tf);
-            let pb = self.prep_candle_archive_path(market, dt, tf);
+        // Resample and write for other time frames. H12 and longer use the market session and the
+        // session candles straddling the month ends need the S15 candles of the next month.
+        let session = self.sessions.session(market);
+        let prev_month = Self::trunc_month_dt(&(*dt - Duration::days(1)));
+        let (prev, next) = if session.anchors(&TimeFrame::D01) {
+            (
+                self.read_research_candles_from_file_for_month(
+                    market,
+                    &prev_month,
+                    &TimeFrame::S15,
+                ),
+                self.read_research_candles_from_file_for_month(
+                    market,
+                    &Self::next_month_dt(dt),
+                    &TimeFrame::S15,
+                ),
+            )
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        self.write_resampled_candles_to_file_for_month(market, dt, candles, &next, &session);
+        // Complete the last session candles of the previous month with the candles of this month
+        if !prev.is_empty() {
+            println!("Completing the session candles of the previous month.");
+            self.write_resampled_candles_to_file_for_month(
+                market,
+                &prev_month,
+                &prev,
+                candles,
+                &session,
+            );
+        }
+    }
+
+    fn write_resampled_candles_to_file_for_month(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        candles: &[ResearchCandle],
+        next: &[ResearchCandle],
+        session: &Session,
+    ) {
+        for (tf, resampled_candles) in ResearchCandle::resample_month(dt, candles, next, session) {
+            let pb = self.prep_candle_archive_path(market, dt, &tf);
             println!(
                 "Writing {} {} candles for the month.",
                 resampled_candles.len(),
                 tf.as_str()
             );
             self.write_research_candles_to_file(&pb, &resampled_candles);
-            candles_map.insert(*tf, resampled_candles);
         }
     }
 
-    fn write_research_candles_to_file(&self, pb: &PathBuf, candles: &[ResearchCandle]) {
+    // Read the research candle archive for the market, month and time frame. Empty if the month
+    // has not been archived.
+    pub fn read_research_candles_from_file_for_month(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+    ) -> Vec<ResearchCandle> {
+        let pb = self.prep_candle_archive_path(market, dt, tf);
+        let mut candles = Vec::new();
+        if let Ok(file) = File::open(pb) {
+            for result in Reader::from_reader(file).deserialize() {
+                let candle: ResearchCandle = result.expect("Failed to deserialize record.");
+                candles.push(candle);
+            }
+        }
+        candles
+    }
+
+    pub fn write_research_candles_to_file(&self, pb: &PathBuf, candles: &[ResearchCandle]) {
         let mut wtr = Writer::from_path(pb).expect("Failed to open file.");
         for candle in candles.iter() {
             wtr.serialize(candle).expect("Failed to serialize candle.");
@@ -2044,7 +2541,7 @@ impl ElDorado {
         wtr.flush().expect("Failed to flush wtr.");
     }
 
-    fn prep_candle_archive_path(
+    pub fn prep_candle_archive_path(
         &self,
         market: &MarketDetail,
         dt: &DateTime<Utc>,
@@ -2804,3 +3301,187 @@ impl ElDorado {
 //         // Assert
 //     }
 // }
+
+#[cfg(test)]
+mod price_tests {
+    use crate::{
+        candles::{CandleAccumulator, ResearchCandle, VolumeAtPrice},
+        configuration::SessionConfig,
+        exchanges::gdax::Trade as GdaxTrade,
+        sessions::Session,
+        utilities::TimeFrame,
+    };
+    use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+    use std::convert::TryFrom;
+
+    fn trade(id: i64, side: &str, size: &str, price: &str, secs: i64) -> GdaxTrade {
+        let start = Utc.from_utc_datetime(
+            &NaiveDate::from_ymd_opt(2023, 3, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        );
+        GdaxTrade {
+            trade_id: id,
+            side: side.to_string(),
+            size: size.parse().unwrap(),
+            price: price.parse().unwrap(),
+            time: start + Duration::seconds(secs),
+        }
+    }
+
+    #[test]
+    pub fn merged_accumulators_match_single_pass() {
+        let trades = vec![
+            trade(1, "buy", "1", "100", 0),
+            trade(2, "sell", "3", "102", 10),
+            trade(3, "buy", "2", "101", 15),
+            trade(4, "sell", "1", "99", 40),
+            trade(5, "buy", "4", "103", 45),
+        ];
+        let step = Some(dec!(1));
+        let dt = trades[0].time;
+        let whole = CandleAccumulator::from_trades(&trades, step)
+            .production_candle(dt)
+            .unwrap();
+        // Adjacent partials merge in either direction
+        let mut earlier = CandleAccumulator::from_trades(&trades[..1], step);
+        earlier.merge(&CandleAccumulator::from_trades(&trades[1..3], step));
+        let mut merged = CandleAccumulator::from_trades(&trades[3..], step);
+        merged.merge(&earlier);
+        merged.merge(&CandleAccumulator::new(step));
+        let merged = merged.production_candle(dt).unwrap();
+        assert_eq!(merged.open, dec!(100));
+        assert_eq!(merged.close, dec!(103));
+        assert_eq!(merged.high, dec!(103));
+        assert_eq!(merged.low, dec!(99));
+        assert_eq!(merged.first_trade_id, "1");
+        assert_eq!(merged.last_trade_id, "5");
+        assert_eq!(merged.volume, whole.volume);
+        assert_eq!(merged.value_sell, whole.value_sell);
+        assert_eq!(merged.trade_count_buy, 3);
+        assert_eq!(merged.vwap, whole.vwap);
+        assert_eq!(merged.twap, whole.twap);
+        assert_eq!(merged.vap, whole.vap);
+        assert!(CandleAccumulator::new(None).production_candle(dt).is_none());
+    }
+
+    #[test]
+    pub fn candle_has_vwap_twap_and_volume_at_price() {
+        let trades = vec![
+            trade(1, "buy", "1", "100", 0),
+            trade(2, "sell", "3", "102", 10),
+            trade(3, "buy", "1", "101.4", 15),
+        ];
+        let dt = trades[0].time;
+        let candle = ResearchCandle::from_trades_v2(dt, &trades, Some(dec!(0.5)));
+        assert_eq!(candle.vwap, dec!(507.4) / dec!(5));
+        assert_eq!(candle.vwap_buy, dec!(100.7));
+        assert_eq!(candle.vwap_sell, dec!(102));
+        // 100 for 10s and 102 for 5s
+        assert_eq!(candle.twap, dec!(1510) / dec!(15));
+        assert_eq!(candle.vap.as_deref(), Some("100:1;101:1;102:3"));
+        let vap: VolumeAtPrice = candle.vap.as_deref().unwrap().parse().unwrap();
+        assert_eq!(vap.levels()[&dec!(102)], dec!(3));
+        // Without a quote step there is no histogram
+        assert!(ResearchCandle::from_trades_v2(dt, &trades, None)
+            .vap
+            .is_none());
+    }
+
+    #[test]
+    pub fn resampled_candle_recalculates_vwap_and_merges_volume_at_price() {
+        let first = vec![trade(1, "buy", "1", "100", 0)];
+        let second = vec![trade(2, "sell", "3", "104", 20)];
+        let step = Some(dec!(1));
+        let c1 = ResearchCandle::from_trades_v2(first[0].time, &first, step);
+        let c2 =
+            ResearchCandle::from_trades_v2(first[0].time + TimeFrame::S15.as_dur(), &second, step);
+        let resampled = ResearchCandle::resample(
+            &[c1, c2],
+            &TimeFrame::T01,
+            &[first[0].time],
+            &Session::utc(),
+        );
+        assert_eq!(resampled[0].vwap, dec!(103));
+        assert_eq!(resampled[0].twap, dec!(102));
+        assert_eq!(resampled[0].vap.as_deref(), Some("100:1;104:3"));
+    }
+
+    // One S15 candle with a single trade of size 1 for each 15 seconds from start to end
+    fn s15_candles(start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<ResearchCandle> {
+        let mut candles = Vec::new();
+        let mut dt = start;
+        while dt < end {
+            let trade = GdaxTrade {
+                trade_id: candles.len() as i64,
+                side: "buy".to_string(),
+                size: dec!(1),
+                price: dec!(100),
+                time: dt,
+            };
+            candles.push(ResearchCandle::from_trades_v2(dt, &[trade], None));
+            dt += TimeFrame::S15.as_dur();
+        }
+        candles
+    }
+
+    #[test]
+    pub fn session_candles_are_archived_in_the_month_they_open() {
+        let ny = Session::try_from(&SessionConfig {
+            timezone: "America/New_York".to_string(),
+            start: "00:00".to_string(),
+        })
+        .unwrap();
+        // New York days start at 04:00 UTC in EDT so the last day of March closes in April
+        let march = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let april = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+        let candles = s15_candles(Utc.with_ymd_and_hms(2023, 3, 30, 4, 0, 0).unwrap(), april);
+        let next = s15_candles(april, april + Duration::hours(6));
+        let months = ResearchCandle::resample_month(&march, &candles, &next, &ny);
+        let days = &months
+            .iter()
+            .find(|(tf, _)| *tf == TimeFrame::D01)
+            .unwrap()
+            .1;
+        assert_eq!(days.len(), 2);
+        assert_eq!(
+            days[1].datetime,
+            Utc.with_ymd_and_hms(2023, 3, 31, 4, 0, 0).unwrap()
+        );
+        // The full day including the hours in April
+        assert_eq!(days[1].volume, dec!(5760));
+        let halves = &months
+            .iter()
+            .find(|(tf, _)| *tf == TimeFrame::H12)
+            .unwrap()
+            .1;
+        assert_eq!(halves.last().unwrap().volume, dec!(2880));
+        // Without the next month the day is partial until April is archived
+        let partial = ResearchCandle::resample_month(&march, &candles, &[], &ny);
+        let days = &partial
+            .iter()
+            .find(|(tf, _)| *tf == TimeFrame::D01)
+            .unwrap()
+            .1;
+        assert_eq!(days[1].volume, dec!(4800));
+        // April leaves the day that opened in March to the March files
+        let months = ResearchCandle::resample_month(&april, &next, &[], &ny);
+        let days = &months
+            .iter()
+            .find(|(tf, _)| *tf == TimeFrame::D01)
+            .unwrap()
+            .1;
+        assert_eq!(
+            days[0].datetime,
+            Utc.with_ymd_and_hms(2023, 4, 1, 4, 0, 0).unwrap()
+        );
+        let hours = &months
+            .iter()
+            .find(|(tf, _)| *tf == TimeFrame::H01)
+            .unwrap()
+            .1;
+        assert_eq!(hours.len(), 6);
+    }
+}
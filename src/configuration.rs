@@ -1,12 +1,52 @@
+use crate::{
+    bars::BarSpec, conqui::ScreenSpec, correlations::CorrelationSettings, exchanges::ExchangeName,
+    indexes::IndexSpec, indicators::IndicatorSpec, signals::SignalSpec, synthetics::SyntheticSpec,
+    utilities::TimeFrame,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
-use std::convert::{TryFrom, TryInto};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
 
 #[derive(serde::Deserialize, Debug)]
 pub struct Settings {
-    pub ed_db: DatabaseSettings,
-    pub ftx_db: DatabaseSettings,
-    pub gdax_db: DatabaseSettings,
+    // Named databases. The El Dorado system database is named `eldorado`.
+    pub databases: HashMap<String, DatabaseSettings>,
+    // Exchange to database name. Exchanges without a route use the database with their name.
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
     pub application: ApplicationSettings,
+    #[serde(default)]
+    pub retention: RetentionSettings,
+    // Information driven bars to generate in Mita for each market
+    #[serde(default)]
+    pub bars: Vec<BarSpec>,
+    // Custom metric time frames ie 2h30m. Must be a multiple of 15 minutes.
+    #[serde(default)]
+    pub timeframes: Vec<String>,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    // Composite indexes of the same asset across exchanges, run with the `index` command
+    #[serde(default)]
+    pub indexes: Vec<IndexSpec>,
+    // Synthetic cross pairs made by the mita running both legs, ie ETH-USD / BTC-USD
+    #[serde(default)]
+    pub synthetics: Vec<SyntheticSpec>,
+    #[serde(default)]
+    pub watermarks: WatermarkSettings,
+    // Indicators calculated with the metrics and stored in indicator_values
+    #[serde(default)]
+    pub indicators: Vec<IndicatorSpec>,
+    // Cross market correlations and betas of the active markets refreshed by the conqui
+    #[serde(default)]
+    pub correlations: CorrelationSettings,
+    // Cross sectional screens ranking the active markets, run by the conqui
+    #[serde(default)]
+    pub screens: Vec<ScreenSpec>,
+    // Rules over the metrics evaluated by the mita that store signal events and send alerts
+    #[serde(default)]
+    pub signals: Vec<SignalSpec>,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -26,6 +66,86 @@ pub struct ApplicationSettings {
     pub droplet: String,
     pub instance_type: String,
     pub archive_path: String,
+    // Path to a SQLite file to use in place of the Postgres databases. ie sqlite://eldorado.db
+    pub sqlite_path: Option<String>,
+    // Prefix for the trades and candles schemas so all exchanges can share a single database.
+    // `{exchange}` is replaced by the exchange name. ie "{exchange}_" for gdax_trades.
+    pub schema_prefix: Option<String>,
+    // Add the volume at price histogram to candles, bucketed by the market quote step
+    pub volume_at_price: Option<bool>,
+    // Milliseconds between updates of the forming candles and provisional metrics in Mita. 0
+    // updates on every trade. Not published if not set.
+    pub forming_cadence: Option<i64>,
+}
+
+// Retention is resolved from the most specific policy to the least: market, exchange, then the
+// default. Exchange keys are lowercase, market keys are matched ignoring case.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct RetentionSettings {
+    #[serde(default)]
+    pub default: RetentionPolicy,
+    #[serde(default)]
+    pub exchanges: HashMap<String, ExchangeRetention>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct ExchangeRetention {
+    #[serde(default)]
+    pub default: RetentionPolicy,
+    #[serde(default)]
+    pub markets: HashMap<String, RetentionPolicy>,
+}
+
+// Number of days to keep for each data class. None leaves the data class to the default handling.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Trade day partitions to keep after they are archived. None drops them once archived.
+    pub trades: Option<i64>,
+    // Production candles to keep per time frame
+    #[serde(default)]
+    pub candles: HashMap<TimeFrame, i64>,
+    // Research S15 candles. None keeps the sync days needed to rebuild the heartbeat.
+    pub research: Option<i64>,
+    // Research metrics
+    pub metrics: Option<i64>,
+    // WS trades left in the default trade partition
+    pub journals: Option<i64>,
+}
+
+// Day boundary for the H12, D01 and W01 candles. The market session wins over the default and
+// without either the day starts at 00:00 UTC. Market keys are matched ignoring case.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct SessionSettings {
+    pub default: Option<SessionConfig>,
+    #[serde(default)]
+    pub markets: HashMap<String, SessionConfig>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    // IANA time zone ie America/New_York or UTC
+    pub timezone: String,
+    // Local time of day the session starts, on a 15 minute boundary ie 00:00 or 08:00
+    #[serde(default = "SessionConfig::midnight")]
+    pub start: String,
+}
+
+impl SessionConfig {
+    fn midnight() -> String {
+        "00:00".to_string()
+    }
+}
+
+// Intervals close when the event time watermark passes their end. The event time is the latest
+// streamed trade time but no more than idle behind the wall clock so quiet markets still close.
+// The watermark trails the event time by the allowed lateness. Both are in milliseconds and the
+// defaults close intervals on the wall clock.
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default)]
+pub struct WatermarkSettings {
+    #[serde(default)]
+    pub allowed_lateness: i64,
+    #[serde(default)]
+    pub idle: i64,
 }
 
 impl Settings {
@@ -78,6 +198,37 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     settings.try_into()
 }
 
+// Schema names for the exchange trade and candle tables, built from the configured prefix. The
+// default empty prefix gives the plain trades and candles schemas of the per exchange databases.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    prefix: String,
+}
+
+impl Schema {
+    pub fn new(prefix: Option<&str>) -> Self {
+        Self {
+            prefix: prefix.unwrap_or_default().to_string(),
+        }
+    }
+
+    pub fn trades(&self, exchange: &ExchangeName) -> String {
+        self.name(exchange, "trades")
+    }
+
+    pub fn candles(&self, exchange: &ExchangeName) -> String {
+        self.name(exchange, "candles")
+    }
+
+    fn name(&self, exchange: &ExchangeName, schema: &str) -> String {
+        format!(
+            "{}{}",
+            self.prefix.replace("{exchange}", exchange.as_str()),
+            schema
+        )
+    }
+}
+
 impl DatabaseSettings {
     pub fn with_db(&self) -> PgConnectOptions {
         let ssl_mode = if self.require_ssl {
@@ -125,34 +276,3 @@ impl TryFrom<String> for Environment {
         }
     }
 }
-
-// Define the databases that will be used
-#[derive(Eq, PartialEq, Hash, Debug)]
-pub enum Database {
-    ElDorado,
-    Ftx,
-    Gdax,
-}
-
-impl Database {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            Database::ElDorado => "eldorado",
-            Database::Ftx => "ftx",
-            Database::Gdax => "gdax",
-        }
-    }
-}
-
-impl TryFrom<String> for Database {
-    type Error = String;
-
-    fn try_from(s: String) -> Result<Self, Self::Error> {
-        match s.to_lowercase().as_str() {
-            "eldorado" => Ok(Self::ElDorado),
-            "ftx" => Ok(Self::Ftx),
-            "gdax" => Ok(Self::Gdax),
-            other => Err(format!("{} is not a supported database.", other)),
-        }
-    }
-}
@@ -0,0 +1,416 @@
+use crate::{
+    eldorado::{ElDorado, ElDoradoError},
+    markets::MarketDetail,
+    metrics::{ResearchMetric, DONS},
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Metrics with a z score in the research metrics, ranked as return_z_l or volume_z_s
+pub const Z_METRICS: [&str; 23] = [
+    "return",
+    "tr",
+    "upper_wick",
+    "body",
+    "lower_wick",
+    "volume",
+    "volume_net",
+    "volume_pct",
+    "volume_liq",
+    "volume_liq_net",
+    "volume_liq_pct",
+    "value",
+    "value_net",
+    "value_pct",
+    "value_liq",
+    "value_liq_net",
+    "value_liq_pct",
+    "trade_count",
+    "trade_count_net",
+    "trade_count_pct",
+    "liq_count",
+    "liq_count_net",
+    "liq_count_pct",
+];
+
+// Time after an interval closes to wait for the metrics of markets that have not caught up
+pub const SCREEN_GRACE_SECS: i64 = 60;
+
+// Research metric value a screen ranks the markets on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Factor {
+    // Long or short lookback z score of a metric, ie return_z_l
+    Z { metric: String, long: bool },
+    // Distance of the close above the Donchian high (or below the low) in short ATRs, ie
+    // don_high_32. Positive once the close breaks out of the range.
+    Breakout { range: i32, high: bool },
+    // Distance of the close from the middle of the Donchian range in short ATRs, ie momentum_32
+    Momentum { range: i32 },
+}
+
+impl Factor {
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let range = |s: &str| s.parse::<i32>().ok().filter(|r| DONS.contains(r));
+        if let Some(metric) = name.strip_suffix("_z_l") {
+            return Z_METRICS.contains(&metric).then(|| Factor::Z {
+                metric: metric.to_string(),
+                long: true,
+            });
+        }
+        if let Some(metric) = name.strip_suffix("_z_s") {
+            return Z_METRICS.contains(&metric).then(|| Factor::Z {
+                metric: metric.to_string(),
+                long: false,
+            });
+        }
+        if let Some(r) = name.strip_prefix("don_high_") {
+            return range(r).map(|range| Factor::Breakout { range, high: true });
+        }
+        if let Some(r) = name.strip_prefix("don_low_") {
+            return range(r).map(|range| Factor::Breakout { range, high: false });
+        }
+        if let Some(r) = name.strip_prefix("momentum_") {
+            return range(r).map(|range| Factor::Momentum { range });
+        }
+        None
+    }
+
+    // Value of the factor for the metric, None if the metric does not have the Donchian range yet
+    // or there is no ATR to normalize by
+    pub fn value(&self, metric: &ResearchMetric) -> Option<Decimal> {
+        match self {
+            Factor::Z { metric: m, long } => Some(match long {
+                true => metric.z_l(m),
+                false => metric.z_s(m),
+            }),
+            Factor::Breakout { range, high } => {
+                let distance = match high {
+                    true => metric.close - metric.don_h(*range)?,
+                    false => metric.don_l(*range)? - metric.close,
+                };
+                distance.checked_div(metric.atr_s)
+            }
+            Factor::Momentum { range } => {
+                let mid = (metric.don_h(*range)? + metric.don_l(*range)?) / Decimal::TWO;
+                (metric.close - mid).checked_div(metric.atr_s)
+            }
+        }
+        .map(|v| v.round_dp(4))
+    }
+}
+
+// Screen configured for the conqui to rank the active markets on at each interval close, ie
+//   - name: "breakouts"
+//     factor: "don_high_32"
+//     top: 10
+// Markets are ranked from the highest value unless ascending. Empty tfs screen every time frame.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ScreenSpec {
+    pub name: String,
+    pub factor: String,
+    #[serde(default)]
+    pub ascending: bool,
+    #[serde(default = "ScreenSpec::default_top")]
+    pub top: usize,
+    #[serde(default)]
+    pub tfs: Vec<TimeFrame>,
+}
+
+impl ScreenSpec {
+    fn default_top() -> usize {
+        10
+    }
+
+    pub fn applies(&self, tf: &TimeFrame) -> bool {
+        self.tfs.is_empty() || self.tfs.contains(tf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenRank {
+    pub rank: usize,
+    pub market_id: Uuid,
+    pub market_name: String,
+    pub value: Decimal,
+}
+
+// Markets ranked on a factor for a time frame interval. Markets without a value for the factor
+// are left out of the ranks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Screen {
+    pub name: String,
+    pub factor: String,
+    pub tf: TimeFrame,
+    pub datetime: DateTime<Utc>,
+    pub ranks: Vec<ScreenRank>,
+}
+
+impl Screen {
+    pub fn new(
+        spec: &ScreenSpec,
+        tf: TimeFrame,
+        datetime: DateTime<Utc>,
+        metrics: &[(&MarketDetail, &ResearchMetric)],
+    ) -> Option<Self> {
+        let factor = Factor::parse(&spec.factor)?;
+        let mut values: Vec<_> = metrics
+            .iter()
+            .filter_map(|(market, metric)| factor.value(metric).map(|v| (*market, v)))
+            .collect();
+        // Ties rank by market name so the screen is stable between runs
+        values.sort_by(|(ma, a), (mb, b)| {
+            match spec.ascending {
+                true => a.cmp(b),
+                false => b.cmp(a),
+            }
+            .then_with(|| ma.market_name.cmp(&mb.market_name))
+        });
+        let ranks = values
+            .iter()
+            .enumerate()
+            .map(|(i, (market, value))| ScreenRank {
+                rank: i + 1,
+                market_id: market.market_id,
+                market_name: market.market_name.clone(),
+                value: *value,
+            })
+            .collect();
+        Some(Self {
+            name: spec.name.clone(),
+            factor: spec.factor.clone(),
+            tf,
+            datetime,
+            ranks,
+        })
+    }
+
+    pub fn top(&self, n: usize) -> &[ScreenRank] {
+        &self.ranks[..n.min(self.ranks.len())]
+    }
+
+    pub async fn upsert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO screens (name, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4::JSONB, $5)
+            ON CONFLICT (name, tf, datetime) DO UPDATE
+            SET (data, insert_dt) = (EXCLUDED.data, EXCLUDED.insert_dt)
+            "#;
+        let data = serde_json::to_string(self).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        sqlx::query(sql)
+            .bind(&self.name)
+            .bind(self.tf.as_str())
+            .bind(self.datetime)
+            .bind(data)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // Latest screen of each name for the time frame
+    pub async fn select_latest(pool: &PgPool, tf: &TimeFrame) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT DISTINCT ON (name) data::TEXT FROM screens
+            WHERE tf = $1
+            ORDER BY name, datetime DESC
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(tf.as_str())
+            .fetch_all(pool)
+            .await?;
+        rows.iter()
+            .map(|r| serde_json::from_str(&r.0).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+            .collect()
+    }
+}
+
+impl ElDorado {
+    // Run Conqui instance.
+    // Poll the latest metrics of the active markets, screen each time frame interval once the
+    // markets have closed it and refresh the correlations between the markets
+    pub async fn conqui(&mut self) -> Result<(), ElDoradoError> {
+        // Set restart value to false, error handling must explicitly set back to true
+        self.instance.restart = false;
+        let mut screened: HashMap<TimeFrame, DateTime<Utc>> = HashMap::new();
+        // Last interval with correlations by time frame and the closes for their returns
+        let mut correlated: HashMap<TimeFrame, DateTime<Utc>> = HashMap::new();
+        let mut history = self.load_close_history().await?;
+        let market_ids: Vec<_> = self.markets.iter().map(|m| m.market_id).collect();
+        loop {
+            let metrics = self.storage.select_latest_metrics(&market_ids).await?;
+            let metrics = ResearchMetric::map_by_id_tf_distinct(&metrics);
+            self.screen_markets(&metrics, &mut screened).await?;
+            // Refresh the cross market correlations once the markets close an interval
+            self.update_correlations(&metrics, &mut history, &mut correlated)
+                .await?;
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        }
+    }
+
+    // Rank the markets on each configured screen for the time frames with a new interval. An
+    // interval is screened once every market has its metric or the grace period has passed.
+    pub async fn screen_markets(
+        &self,
+        metrics: &HashMap<Uuid, HashMap<TimeFrame, ResearchMetric>>,
+        screened: &mut HashMap<TimeFrame, DateTime<Utc>>,
+    ) -> Result<(), ElDoradoError> {
+        for tf in TimeFrame::tfs().iter() {
+            let specs: Vec<_> = self.screens.iter().filter(|s| s.applies(tf)).collect();
+            if specs.is_empty() {
+                continue;
+            }
+            let latest: Vec<_> = self
+                .markets
+                .iter()
+                .filter_map(|m| metrics.get(&m.market_id)?.get(tf).map(|metric| (m, metric)))
+                .collect();
+            let datetime = match latest.iter().map(|(_, metric)| metric.datetime).max() {
+                Some(dt) => dt,
+                None => continue,
+            };
+            if screened.get(tf) >= Some(&datetime) {
+                continue;
+            }
+            let closed: Vec<_> = latest
+                .into_iter()
+                .filter(|(_, metric)| metric.datetime == datetime)
+                .collect();
+            let grace = tf.next(&datetime) + Duration::seconds(SCREEN_GRACE_SECS);
+            if closed.len() < self.markets.len() && Utc::now() < grace {
+                continue;
+            }
+            println!(
+                "{} - Screening {} of {} markets for {} {}.",
+                Utc::now(),
+                closed.len(),
+                self.markets.len(),
+                tf,
+                datetime
+            );
+            for spec in specs.iter() {
+                if let Some(screen) = Screen::new(spec, *tf, datetime, &closed) {
+                    self.storage.upsert_screen(&screen).await?;
+                }
+            }
+            screened.insert(*tf, datetime);
+        }
+        Ok(())
+    }
+
+    // Top markets of the latest screen with the name for the time frame
+    pub async fn top_markets(
+        &self,
+        name: &str,
+        tf: &TimeFrame,
+        n: usize,
+    ) -> Result<Vec<ScreenRank>, ElDoradoError> {
+        let screens = self.storage.select_screens(tf).await?;
+        Ok(screens
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.top(n).to_vec())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        conqui::{Factor, Screen, ScreenSpec},
+        markets::MarketDetail,
+        metrics::ResearchMetric,
+        test_helpers::{candle, market},
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use rust_decimal_macros::dec;
+
+    // Candles that drift by the step with a jump on the last candle
+    fn metric(market: &MarketDetail, step: Decimal, jump: Decimal) -> ResearchMetric {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let candles: Vec<_> = (0..40)
+            .map(|i| {
+                let dt = start + Duration::minutes(15 * i as i64);
+                let mut close = dec!(100) + step * Decimal::from(i) + Decimal::from(i % 3);
+                if i == 39 {
+                    close += jump;
+                }
+                let mut c = candle(dt, i as i64, close);
+                c.high = close + Decimal::ONE;
+                c.low = close - Decimal::ONE;
+                c
+            })
+            .collect();
+        ResearchMetric::new(market, TimeFrame::T15, &candles)
+    }
+
+    #[test]
+    pub fn factors_parse_from_metric_names() {
+        assert_eq!(
+            Factor::parse("return_z_l"),
+            Some(Factor::Z {
+                metric: "return".to_string(),
+                long: true
+            })
+        );
+        assert_eq!(
+            Factor::parse("DON_LOW_32"),
+            Some(Factor::Breakout {
+                range: 32,
+                high: false
+            })
+        );
+        assert_eq!(
+            Factor::parse("momentum_16"),
+            Some(Factor::Momentum { range: 16 })
+        );
+        assert_eq!(Factor::parse("momentum_10"), None);
+        assert_eq!(Factor::parse("close_z_l"), None);
+    }
+
+    #[test]
+    pub fn screen_ranks_markets_on_the_factor() {
+        let markets = [
+            market("BTC-USD", TimeFrame::T15),
+            market("ETH-USD", TimeFrame::T15),
+            market("SOL-USD", TimeFrame::T15),
+        ];
+        let metrics = [
+            metric(&markets[0], dec!(0.1), dec!(2)),
+            metric(&markets[1], dec!(0.1), dec!(10)),
+            metric(&markets[2], dec!(-0.1), dec!(-5)),
+        ];
+        let pairs: Vec<_> = markets.iter().zip(metrics.iter()).collect();
+        let spec = ScreenSpec {
+            name: "breakouts".to_string(),
+            factor: "don_high_8".to_string(),
+            ascending: false,
+            top: 2,
+            tfs: Vec::new(),
+        };
+        let screen = Screen::new(&spec, TimeFrame::T15, metrics[0].datetime, &pairs).unwrap();
+        let names: Vec<_> = screen
+            .ranks
+            .iter()
+            .map(|r| r.market_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["ETH-USD", "BTC-USD", "SOL-USD"]);
+        assert_eq!(screen.top(spec.top).len(), 2);
+        // ETH broke out of its 8 candle high, SOL fell below it
+        assert!(screen.ranks[0].value > Decimal::ZERO);
+        assert!(screen.ranks[2].value < Decimal::ZERO);
+        let spec = ScreenSpec {
+            ascending: true,
+            ..spec
+        };
+        let screen = Screen::new(&spec, TimeFrame::T15, metrics[0].datetime, &pairs).unwrap();
+        assert_eq!(screen.ranks[0].market_name, "SOL-USD");
+    }
+}
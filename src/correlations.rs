@@ -0,0 +1,484 @@
+use crate::{
+    conqui::SCREEN_GRACE_SECS,
+    eldorado::{ElDorado, ElDoradoError},
+    indicators::Lookback,
+    metrics::{ResearchMetric, METRIC_VERSION},
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+// Fewest overlapping returns for a correlation or beta between two markets
+pub const MIN_RETURNS: usize = 3;
+
+// Log returns of the closes through the end datetime over the lookback, keyed by the candle
+// datetime so the markets can be lined up. Closes without a price have no return.
+pub fn log_returns(
+    closes: &BTreeMap<DateTime<Utc>, Decimal>,
+    end: &DateTime<Utc>,
+    lbp: usize,
+) -> BTreeMap<DateTime<Utc>, Decimal> {
+    let closes: Vec<_> = closes.range(..=*end).collect();
+    let first = closes.len().saturating_sub(lbp + 1);
+    closes[first..]
+        .windows(2)
+        .filter(|w| *w[0].1 > Decimal::ZERO && *w[1].1 > Decimal::ZERO)
+        .filter_map(|w| (w[1].1 / w[0].1).checked_ln().map(|r| (*w[1].0, r)))
+        .collect()
+}
+
+// Closes of the metrics of each market and time frame, enough for the return lookback. Kept by
+// the conqui instance as the metrics of all the active markets come in.
+#[derive(Debug, Default)]
+pub struct CloseHistory {
+    closes: HashMap<Uuid, HashMap<TimeFrame, BTreeMap<DateTime<Utc>, Decimal>>>,
+}
+
+impl CloseHistory {
+    // Add the close of the metric keeping the last n closes of the market and time frame
+    pub fn add(&mut self, metric: &ResearchMetric, n: usize) {
+        let closes = self
+            .closes
+            .entry(metric.market_id)
+            .or_default()
+            .entry(metric.tf)
+            .or_default();
+        closes.insert(metric.datetime, metric.close);
+        while closes.len() > n {
+            let first = *closes.keys().next().unwrap();
+            closes.remove(&first);
+        }
+    }
+
+    pub fn closes(
+        &self,
+        market_id: &Uuid,
+        tf: &TimeFrame,
+    ) -> Option<&BTreeMap<DateTime<Utc>, Decimal>> {
+        self.closes.get(market_id)?.get(tf)
+    }
+}
+
+// Returns of both markets for the datetimes they share
+fn aligned(
+    a: &BTreeMap<DateTime<Utc>, Decimal>,
+    b: &BTreeMap<DateTime<Utc>, Decimal>,
+) -> (Vec<Decimal>, Vec<Decimal>) {
+    a.iter()
+        .filter_map(|(dt, r)| b.get(dt).map(|s| (*r, *s)))
+        .unzip()
+}
+
+// Covariance of x and y along with the variances of x and y
+fn moments(x: &[Decimal], y: &[Decimal]) -> (Decimal, Decimal, Decimal) {
+    let n = Decimal::from(x.len());
+    let mx = x.iter().sum::<Decimal>() / n;
+    let my = y.iter().sum::<Decimal>() / n;
+    x.iter().zip(y.iter()).fold(
+        (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO),
+        |(cov, vx, vy), (a, b)| {
+            (
+                cov + (a - mx) * (b - my),
+                vx + (a - mx).powi(2),
+                vy + (b - my).powi(2),
+            )
+        },
+    )
+}
+
+// Pearson correlation, None without enough returns or if either market did not move
+pub fn correlation(x: &[Decimal], y: &[Decimal]) -> Option<Decimal> {
+    if x.len() < MIN_RETURNS || x.len() != y.len() {
+        return None;
+    }
+    let (cov, vx, vy) = moments(x, y);
+    let sd = (vx * vy).sqrt()?;
+    cov.checked_div(sd)
+        .map(|c| c.max(-Decimal::ONE).min(Decimal::ONE).round_dp(4))
+}
+
+// Beta of the returns x to the benchmark returns b
+pub fn beta(x: &[Decimal], b: &[Decimal]) -> Option<Decimal> {
+    if x.len() < MIN_RETURNS || x.len() != b.len() {
+        return None;
+    }
+    let (cov, _, vb) = moments(x, b);
+    cov.checked_div(vb).map(|beta| beta.round_dp(4))
+}
+
+// Group the markets by average linkage on the correlations, merging the most correlated groups
+// until no two groups have an average correlation of at least the threshold. Missing correlations
+// count as uncorrelated.
+pub fn cluster(
+    markets: &[String],
+    correlations: &[Vec<Option<Decimal>>],
+    threshold: Decimal,
+) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<usize>> = (0..markets.len()).map(|i| vec![i]).collect();
+    loop {
+        let mut best: Option<(Decimal, usize, usize)> = None;
+        for i in 0..groups.len() {
+            for j in i + 1..groups.len() {
+                let sum = groups[i]
+                    .iter()
+                    .flat_map(|a| groups[j].iter().map(move |b| (*a, *b)))
+                    .fold(Decimal::ZERO, |s, (a, b)| {
+                        s + correlations[a][b].unwrap_or(Decimal::ZERO)
+                    });
+                let avg = sum / Decimal::from(groups[i].len() * groups[j].len());
+                if avg >= threshold && best.iter().all(|(b, _, _)| avg > *b) {
+                    best = Some((avg, i, j));
+                }
+            }
+        }
+        match best {
+            Some((_, i, j)) => {
+                let merged = groups.remove(j);
+                groups[i].extend(merged);
+            }
+            None => break,
+        }
+    }
+    let mut clusters: Vec<Vec<String>> = groups
+        .iter()
+        .map(|g| {
+            let mut names: Vec<_> = g.iter().map(|i| markets[*i].clone()).collect();
+            names.sort();
+            names
+        })
+        .collect();
+    // Largest groups first so the concentrated exposure is at the top
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    clusters
+}
+
+// Return correlations between the active markets and their betas to the benchmark for a time
+// frame as of the last interval closed by the markets. Refreshed by the conqui instance on
+// interval close and stored as a JSONB document for the trading engine to limit concentration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorrelationMatrix {
+    // Droplet of the conqui instance that calculated the matrix
+    pub source: String,
+    pub tf: TimeFrame,
+    pub datetime: DateTime<Utc>,
+    pub lbp: usize,
+    pub benchmark: String,
+    pub markets: Vec<String>,
+    // Correlation of each pair of markets in market order, None without enough shared returns
+    pub correlations: Vec<Vec<Option<Decimal>>>,
+    pub betas: BTreeMap<String, Option<Decimal>>,
+    pub clusters: Vec<Vec<String>>,
+}
+
+impl CorrelationMatrix {
+    // Matrix from the returns of each market. Betas are None if the benchmark is not one of the
+    // markets.
+    pub fn new(
+        source: &str,
+        tf: TimeFrame,
+        datetime: DateTime<Utc>,
+        lbp: usize,
+        settings: &CorrelationSettings,
+        returns: &BTreeMap<String, BTreeMap<DateTime<Utc>, Decimal>>,
+    ) -> Self {
+        let markets: Vec<String> = returns.keys().cloned().collect();
+        let correlations: Vec<Vec<Option<Decimal>>> = markets
+            .iter()
+            .map(|a| {
+                markets
+                    .iter()
+                    .map(|b| match a == b {
+                        true => Some(Decimal::ONE),
+                        false => {
+                            let (x, y) = aligned(&returns[a], &returns[b]);
+                            correlation(&x, &y)
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let betas = markets
+            .iter()
+            .map(|m| {
+                let value = returns.get(&settings.benchmark).and_then(|b| {
+                    let (x, y) = aligned(&returns[m], b);
+                    beta(&x, &y)
+                });
+                (m.clone(), value)
+            })
+            .collect();
+        let clusters = cluster(&markets, &correlations, settings.cluster_threshold);
+        Self {
+            source: source.to_string(),
+            tf,
+            datetime,
+            lbp,
+            benchmark: settings.benchmark.clone(),
+            markets,
+            correlations,
+            betas,
+            clusters,
+        }
+    }
+
+    pub async fn upsert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO correlation_matrices (source, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4::JSONB, $5)
+            ON CONFLICT (source, tf, datetime) DO UPDATE
+            SET (data, insert_dt) = (EXCLUDED.data, EXCLUDED.insert_dt)
+            "#;
+        let data = serde_json::to_string(self).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        sqlx::query(sql)
+            .bind(&self.source)
+            .bind(self.tf.as_str())
+            .bind(self.datetime)
+            .bind(data)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    // Latest matrix of each source for the time frame
+    pub async fn select_latest(pool: &PgPool, tf: &TimeFrame) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT DISTINCT ON (source) data::TEXT FROM correlation_matrices
+            WHERE tf = $1
+            ORDER BY source, datetime DESC
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(tf.as_str())
+            .fetch_all(pool)
+            .await?;
+        rows.iter()
+            .map(|r| serde_json::from_str(&r.0).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+            .collect()
+    }
+}
+
+// Benchmark for the betas, ie BTC-USD, and the return lookback as a number of candles or l or s
+// for the time frame lbp_l or lbp_s. Markets are grouped when their average correlation is at
+// least the cluster threshold. Correlations are only calculated with a benchmark configured.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CorrelationSettings {
+    #[serde(default)]
+    pub benchmark: String,
+    #[serde(default = "CorrelationSettings::default_lbp")]
+    pub lbp: String,
+    #[serde(default = "CorrelationSettings::default_threshold")]
+    pub cluster_threshold: Decimal,
+}
+
+impl CorrelationSettings {
+    fn default_lbp() -> String {
+        "l".to_string()
+    }
+
+    fn default_threshold() -> Decimal {
+        Decimal::new(7, 1)
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.benchmark.is_empty()
+    }
+}
+
+impl Default for CorrelationSettings {
+    fn default() -> Self {
+        Self {
+            benchmark: String::new(),
+            lbp: Self::default_lbp(),
+            cluster_threshold: Self::default_threshold(),
+        }
+    }
+}
+
+impl ElDorado {
+    // Closes of the active markets for the return lookbacks from the stored metrics, filling in
+    // the lookbacks longer than the metric retention from the metric archive
+    pub async fn load_close_history(&self) -> Result<CloseHistory, ElDoradoError> {
+        let mut history = CloseHistory::default();
+        if !self.correlations.enabled() {
+            return Ok(history);
+        }
+        let market_ids: Vec<_> = self.markets.iter().map(|m| m.market_id).collect();
+        let now = Utc::now();
+        for tf in TimeFrame::tfs().iter() {
+            let lbp = match Lookback::new(&self.correlations.lbp, tf) {
+                Some(lbp) => lbp.n,
+                None => continue,
+            };
+            let start = tf.trunc(&now) - Duration::seconds(tf.as_secs() * (lbp as i64 + 1));
+            for market in self.markets.iter() {
+                let mut month = Self::trunc_month_dt(&start);
+                while month <= now {
+                    for metric in self
+                        .read_metrics_from_file_for_month(market, &month, tf, METRIC_VERSION)
+                        .iter()
+                    {
+                        history.add(metric, lbp + 1);
+                    }
+                    month = Self::next_month_dt(&month);
+                }
+            }
+        }
+        for metric in self.storage.select_metrics(&market_ids).await?.iter() {
+            if let Some(lbp) = Lookback::new(&self.correlations.lbp, &metric.tf) {
+                history.add(metric, lbp.n + 1);
+            }
+        }
+        Ok(history)
+    }
+
+    // Calculate the correlation matrix for each time frame with a new interval closed by all of
+    // the active markets since the last matrix, or by the markets that closed it once the grace
+    // period has passed
+    pub async fn update_correlations(
+        &self,
+        metrics: &HashMap<Uuid, HashMap<TimeFrame, ResearchMetric>>,
+        history: &mut CloseHistory,
+        last: &mut HashMap<TimeFrame, DateTime<Utc>>,
+    ) -> Result<(), ElDoradoError> {
+        if !self.correlations.enabled() {
+            return Ok(());
+        }
+        for tf in TimeFrame::tfs().iter() {
+            let lbp = match Lookback::new(&self.correlations.lbp, tf) {
+                Some(lbp) => lbp.n,
+                None => continue,
+            };
+            let latest: Vec<_> = self
+                .markets
+                .iter()
+                .filter_map(|m| metrics.get(&m.market_id)?.get(tf))
+                .collect();
+            for metric in latest.iter() {
+                history.add(metric, lbp + 1);
+            }
+            let datetime = match latest.iter().map(|metric| metric.datetime).max() {
+                Some(dt) => dt,
+                None => continue,
+            };
+            if last.get(tf) >= Some(&datetime) {
+                continue;
+            }
+            let closed = latest.iter().filter(|m| m.datetime == datetime).count();
+            let grace = tf.next(&datetime) + Duration::seconds(SCREEN_GRACE_SECS);
+            if closed < self.markets.len() && Utc::now() < grace {
+                continue;
+            }
+            let returns: BTreeMap<_, _> = self
+                .markets
+                .iter()
+                .filter_map(|m| {
+                    let closes = history.closes(&m.market_id, tf)?;
+                    Some((m.market_name.clone(), log_returns(closes, &datetime, lbp)))
+                })
+                .filter(|(_, r)| !r.is_empty())
+                .collect();
+            if returns.len() >= 2 {
+                let matrix = CorrelationMatrix::new(
+                    &self.instance.droplet,
+                    *tf,
+                    datetime,
+                    lbp,
+                    &self.correlations,
+                    &returns,
+                );
+                self.storage.upsert_correlations(&matrix).await?;
+            }
+            last.insert(*tf, datetime);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        correlations::{beta, cluster, correlation, log_returns, CloseHistory},
+        metrics::ResearchMetric,
+        test_helpers::{candle, market},
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+
+    #[test]
+    pub fn correlations_betas_and_clusters_follow_the_returns() {
+        let x = [dec!(0.01), dec!(-0.02), dec!(0.03), dec!(-0.01)];
+        let double: Vec<_> = x.iter().map(|r| r * dec!(2)).collect();
+        let inverse: Vec<_> = x.iter().map(|r| -r).collect();
+        assert_eq!(correlation(&double, &x), Some(Decimal::ONE));
+        assert_eq!(correlation(&inverse, &x), Some(-Decimal::ONE));
+        assert_eq!(beta(&double, &x), Some(dec!(2)));
+        assert_eq!(beta(&inverse, &x), Some(-Decimal::ONE));
+        // Flat benchmark and too few returns have no value
+        assert_eq!(beta(&x, &[Decimal::ZERO; 4]), None);
+        assert_eq!(correlation(&x[..2], &double[..2]), None);
+        // BTC and ETH move together, SOL does not
+        let markets = vec![
+            "BTC-USD".to_string(),
+            "ETH-USD".to_string(),
+            "SOL-USD".to_string(),
+        ];
+        let correlations = vec![
+            vec![Some(dec!(1)), Some(dec!(0.9)), Some(dec!(0.2))],
+            vec![Some(dec!(0.9)), Some(dec!(1)), None],
+            vec![Some(dec!(0.2)), None, Some(dec!(1))],
+        ];
+        assert_eq!(
+            cluster(&markets, &correlations, dec!(0.7)),
+            vec![
+                vec!["BTC-USD".to_string(), "ETH-USD".to_string()],
+                vec!["SOL-USD".to_string()]
+            ]
+        );
+        assert_eq!(cluster(&markets, &correlations, dec!(0.95)).len(), 3);
+    }
+
+    #[test]
+    pub fn log_returns_cover_the_lookback_to_the_end() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let closes: BTreeMap<_, _> = [dec!(100), dec!(110), dec!(121), dec!(0), dec!(100)]
+            .iter()
+            .enumerate()
+            .map(|(i, close)| (start + Duration::minutes(15 * i as i64), *close))
+            .collect();
+        let returns = log_returns(&closes, &(start + Duration::minutes(30)), 5);
+        assert_eq!(returns.len(), 2);
+        assert_eq!(returns[&(start + Duration::minutes(15))], dec!(1.1).ln());
+        // The close without a price has no return into or out of it
+        let returns = log_returns(&closes, &(start + Duration::minutes(60)), 2);
+        assert!(returns.is_empty());
+    }
+
+    #[test]
+    pub fn close_history_keeps_the_lookback() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let mut history = CloseHistory::default();
+        let market = market("BTC-USD", TimeFrame::T15);
+        let candles = [candle(start, 1, dec!(100))];
+        let mut metric = ResearchMetric::new(&market, TimeFrame::H01, &candles);
+        for i in 0..5 {
+            metric.datetime = start + Duration::hours(i);
+            metric.close = Decimal::from(100 + i);
+            history.add(&metric, 3);
+        }
+        // The same interval published again replaces the close
+        metric.close = dec!(200);
+        history.add(&metric, 3);
+        let closes = history.closes(&metric.market_id, &TimeFrame::H01).unwrap();
+        assert_eq!(closes.len(), 3);
+        assert_eq!(*closes.keys().next().unwrap(), start + Duration::hours(2));
+        assert_eq!(closes[&(start + Duration::hours(4))], dec!(200));
+        assert!(history.closes(&metric.market_id, &TimeFrame::D01).is_none());
+    }
+}
@@ -1,23 +1,40 @@
 use crate::{
-    configuration::{Database, Settings},
+    bars::BarSpec,
+    candles::LiveCandles,
+    configuration::{RetentionSettings, Schema, SessionSettings, Settings, WatermarkSettings},
+    conqui::{Factor, ScreenSpec},
+    correlations::CorrelationSettings,
     exchanges::{
         client::RestClient,
         error::{RestError, WsError},
         ExchangeName,
     },
+    indexes::IndexSpec,
+    indicators::{IndicatorSpec, Lookback},
     instances::{Instance, InstanceType},
     markets::{MarketDetail, MarketStatus},
-    utilities::Twilio,
+    signals::SignalSpec,
+    storage::{PgStorage, SqliteStorage, Storage},
+    synthetics::SyntheticSpec,
+    utilities::{TimeFrame, Twilio},
 };
 use chrono::{DateTime, Duration, Utc};
 use sqlx::PgPool;
-use std::{collections::HashMap, convert::TryInto, io::ErrorKind};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+};
 use thiserror::Error;
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct ElDorado {
-    pub pools: HashMap<Database, PgPool>,
+    pub pools: Pools,
+    pub storage: Box<dyn Storage>,
+    // Schema names of the exchange trade and candle tables
+    pub schema: Schema,
     pub clients: HashMap<ExchangeName, RestClient>,
     pub twilio: Twilio,
     pub markets: Vec<MarketDetail>,
@@ -27,6 +44,98 @@ pub struct ElDorado {
     pub storage_path: String,
     pub start_dt: DateTime<Utc>,
     pub sync_days: i64,
+    pub retention: RetentionSettings,
+    pub bars: Vec<BarSpec>,
+    pub volume_at_price: bool,
+    pub sessions: SessionSettings,
+    pub indexes: Vec<IndexSpec>,
+    pub synthetics: Vec<SyntheticSpec>,
+    // Virtual markets for the synthetics run by this mita
+    pub synthetic_markets: Vec<MarketDetail>,
+    // Candle accumulators for the streamed trades by market
+    pub live: Arc<Mutex<HashMap<Uuid, LiveCandles>>>,
+    // Time between forming candle updates, None to not publish them
+    pub forming_cadence: Option<Duration>,
+    pub watermarks: WatermarkSettings,
+    pub indicators: Vec<IndicatorSpec>,
+    pub correlations: CorrelationSettings,
+    pub screens: Vec<ScreenSpec>,
+    pub signals: Vec<SignalSpec>,
+    // Signal rules from the signal_rules table, read when the mita starts
+    pub signal_rules: Vec<SignalSpec>,
+}
+
+// Commands that only need the system tables and the file archive and so run in SQLite mode
+pub const SQLITE_COMMANDS: [&str; 3] =
+    ["backfill-metrics", "recompute-metrics", "resample-candles"];
+
+// Pools for each named database in the configuration and the routing of exchanges to them. Pools
+// are created lazily so a database is only connected to once an instance uses it.
+#[derive(Debug, Default)]
+pub struct Pools {
+    pools: HashMap<String, PgPool>,
+    routes: HashMap<ExchangeName, String>,
+}
+
+impl Pools {
+    pub const SYSTEM: &'static str = "eldorado";
+
+    pub fn new(settings: &Settings) -> Self {
+        let pools = settings
+            .databases
+            .iter()
+            .map(|(name, db)| (name.clone(), PgPool::connect_lazy_with(db.with_db())))
+            .collect();
+        let routes = settings
+            .routes
+            .iter()
+            .map(|(exchange, db)| {
+                let exchange: ExchangeName = exchange
+                    .clone()
+                    .try_into()
+                    .expect("Failed to parse exchange route.");
+                (exchange, db.clone())
+            })
+            .collect();
+        Self { pools, routes }
+    }
+
+    // No Postgres databases are configured in SQLite mode
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PgPool> {
+        self.pools.get(name)
+    }
+
+    pub fn system(&self) -> &PgPool {
+        self.get(Self::SYSTEM)
+            .expect("El Dorado database not configured.")
+    }
+
+    // Name of the database the exchange is routed to, defaults to the exchange name
+    pub fn route(&self, exchange: &ExchangeName) -> &str {
+        match self.routes.get(exchange) {
+            Some(db) => db,
+            None => exchange.as_str(),
+        }
+    }
+
+    pub fn get_exchange(&self, exchange: &ExchangeName) -> Option<&PgPool> {
+        self.get(self.route(exchange))
+    }
+
+    pub fn exchange(&self, exchange: &ExchangeName) -> &PgPool {
+        match self.get_exchange(exchange) {
+            Some(pool) => pool,
+            None => panic!(
+                "{:?} database {} not configured.",
+                exchange,
+                self.route(exchange)
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -39,6 +148,8 @@ pub enum ElDoradoError {
     WsError(#[from] WsError),
     #[error(transparent)]
     RestError(#[from] RestError),
+    #[error("{0:?} not supported for {1}.")]
+    UnsupportedExchange(ExchangeName, &'static str),
 }
 
 impl ElDorado {
@@ -46,16 +157,36 @@ impl ElDorado {
     pub async fn new() -> Option<Self> {
         // Load configuration settings
         let settings = Settings::from_configuration().expect("Failed to read configuration.");
-        // Create PgPools to each database in settings
-        let pools = ElDorado::create_pgpools(&settings).await;
+        // Add any custom time frames to the metric time frames
+        for spec in settings.timeframes.iter() {
+            TimeFrame::add_custom(spec).expect("Failed to parse custom time frame.");
+        }
+        // Create PgPools to each database in settings and the storage for the system tables. In
+        // SQLite mode there are no Postgres databases and the system tables are in a SQLite file.
+        let (pools, storage): (Pools, Box<dyn Storage>) = match &settings.application.sqlite_path {
+            Some(path) => (
+                Pools::default(),
+                Box::new(
+                    SqliteStorage::new(path)
+                        .await
+                        .expect("Failed to open SQLite database."),
+                ),
+            ),
+            None => {
+                let pools = Pools::new(&settings);
+                let storage = PgStorage::new(pools.system().clone());
+                (pools, Box::new(storage))
+            }
+        };
         // Create clients map
         let clients = RestClient::initialize_client_map();
         // Create Twilio client
         let twilio = Twilio::new();
         // Initialize Instance
-        let mut instance = Instance::initialize(&pools[&Database::ElDorado], &settings).await;
+        let mut instance = Instance::initialize(pools.get(Pools::SYSTEM), &settings).await;
         // Load markets and insert into market maps
-        let market_details = MarketDetail::select_all(&pools[&Database::ElDorado])
+        let market_details = storage
+            .select_markets()
             .await
             .expect("Failed to select markets.");
         let (market_names, market_ids) = ElDorado::map_markets(&market_details);
@@ -70,13 +201,14 @@ impl ElDorado {
                     .collect()
             }
             InstanceType::Mita => {
-                let markets = MarketDetail::select_by_exchange_mita(
-                    &pools[&Database::ElDorado],
-                    &instance.exchange_name.unwrap(),
-                    &instance.droplet,
-                )
-                .await
-                .expect("Failed to select market details.");
+                let markets: Vec<MarketDetail> = market_details
+                    .iter()
+                    .filter(|m| {
+                        m.exchange_name == instance.exchange_name.unwrap()
+                            && m.mita.as_ref() == Some(&instance.droplet)
+                    })
+                    .cloned()
+                    .collect();
                 // Validate mita markets are in the correct status and have a candle timeframe
                 for market in markets.iter() {
                     if market.status != MarketStatus::Active {
@@ -93,12 +225,51 @@ impl ElDorado {
                 markets
             }
         };
+        // Validate the configured indicators are registered and have their parameters
+        for spec in settings.indicators.iter() {
+            for tf in TimeFrame::tfs().iter() {
+                if let Err(e) = spec.build(tf) {
+                    println!("Invalid indicator config: {}", e);
+                    return None;
+                }
+            }
+        }
+        if settings.correlations.enabled()
+            && TimeFrame::tfs()
+                .iter()
+                .any(|tf| Lookback::new(&settings.correlations.lbp, tf).is_none())
+        {
+            println!(
+                "Invalid correlations lbp: {}. Must be a positive number, l or s.",
+                settings.correlations.lbp
+            );
+            return None;
+        }
+        // Validate the configured screens rank on a research metric factor
+        for spec in settings.screens.iter() {
+            if Factor::parse(&spec.factor).is_none() {
+                println!(
+                    "Invalid screen config: {} is not a factor for {}.",
+                    spec.factor, spec.name
+                );
+                return None;
+            }
+        }
+        // Validate the configured signal rules parse
+        for spec in settings.signals.iter() {
+            if let Err(e) = spec.parse() {
+                println!("Invalid signal config for {}: {}", spec.name, e);
+                return None;
+            }
+        }
         // Update instance market number field
         instance.num_markets = markets.len() as i32;
         // Get storage path from config
         let storage_path = settings.application.archive_path.clone();
         Some(Self {
             pools,
+            storage,
+            schema: Schema::new(settings.application.schema_prefix.as_deref()),
             clients,
             twilio,
             markets,
@@ -108,12 +279,54 @@ impl ElDorado {
             storage_path,
             start_dt: Utc::now(),
             sync_days: 100,
+            retention: settings.retention.clone(),
+            bars: settings.bars.clone(),
+            sessions: settings.sessions.clone(),
+            indexes: settings.indexes.clone(),
+            synthetics: settings.synthetics.clone(),
+            synthetic_markets: Vec::new(),
+            live: Arc::new(Mutex::new(HashMap::new())),
+            watermarks: settings.watermarks,
+            indicators: settings.indicators.clone(),
+            correlations: settings.correlations.clone(),
+            screens: settings.screens.clone(),
+            signals: settings.signals.clone(),
+            signal_rules: Vec::new(),
+            volume_at_price: settings.application.volume_at_price.unwrap_or(false),
+            forming_cadence: settings
+                .application
+                .forming_cadence
+                .map(Duration::milliseconds),
         })
     }
 
+    // Initialize a new instance for the command. In SQLite mode only the system tables are
+    // in storage, the trades and candles are in the exchange Postgres databases, so only the
+    // commands that work from the file archive and the conqui instance can run.
+    pub async fn for_command(command: &str) -> Option<Self> {
+        let eld = Self::new().await?;
+        if eld.pools.is_empty() {
+            let research = match command {
+                "run" => eld.instance.instance_type == InstanceType::Conqui,
+                _ => SQLITE_COMMANDS.contains(&command),
+            };
+            if !research {
+                println!(
+                    "{} needs the exchange databases and can not run in SQLite mode. \
+                    SQLite mode runs {} and conqui instances.",
+                    command,
+                    SQLITE_COMMANDS.join(", ")
+                );
+                return None;
+            }
+        }
+        Some(eld)
+    }
+
     // Run the default function based on InstanceType and continue restarting until explict exit.
     // IG - manage the events and state
     // Mita - manage the trades / candles and metrics for the give exchange and markets
+    // Conqui - rank the active markets on the configured screens at each interval close
     pub async fn run(&mut self) {
         // let mut restart = self.instance.restart;
         while self.instance.restart {
@@ -121,7 +334,7 @@ impl ElDorado {
             let result = match self.instance.instance_type {
                 InstanceType::Ig => self.inquisidor().await,
                 InstanceType::Mita => self.mita().await,
-                InstanceType::Conqui => return,
+                InstanceType::Conqui => self.conqui().await,
             };
             if let Err(e) = result {
                 self.process_restart(self.handle_error_for_restart(e)).await;
@@ -162,94 +375,6 @@ impl ElDorado {
     }
 
     // Create hashmap of database pool connections based on instance type.
-    pub async fn create_pgpools(settings: &Settings) -> HashMap<Database, PgPool> {
-        let instance_type = settings
-            .application
-            .instance_type
-            .clone()
-            .try_into()
-            .expect("Failed to parse instance type.");
-        let pools = match instance_type {
-            InstanceType::Mita => Self::create_pgpools_mita(settings).await,
-            InstanceType::Ig | InstanceType::Conqui => Self::create_pgpools_ig(settings).await,
-        };
-        pools
-    }
-
-    // Create hashmap of the system db and the trade db for the Mita. Prevents excessive db
-    // connections to exchange trade dbs that are not utilized when compared to IG pools
-    pub async fn create_pgpools_mita(settings: &Settings) -> HashMap<Database, PgPool> {
-        let mut pools = HashMap::new();
-        let exchange: ExchangeName = settings
-            .application
-            .exchange
-            .clone()
-            .try_into()
-            .expect("Failed to parse exchange.");
-        // Insert system pool
-        pools.insert(
-            Database::ElDorado,
-            PgPool::connect_with(settings.ed_db.with_db())
-                .await
-                .expect("Failed to connect to El Dorado database."),
-        );
-        // Insert trade pool
-        match exchange {
-            ExchangeName::Ftx | ExchangeName::FtxUs => {
-                pools.insert(
-                    Database::Ftx,
-                    PgPool::connect_with(settings.ftx_db.with_db())
-                        .await
-                        .expect("Failed to connect to El Dorado database."),
-                );
-            }
-            ExchangeName::Gdax => {
-                pools.insert(
-                    Database::Gdax,
-                    PgPool::connect_with(settings.gdax_db.with_db())
-                        .await
-                        .expect("Failed to connect to El Dorado database."),
-                );
-            }
-            ExchangeName::Kraken => {
-                pools.insert(
-                    Database::Gdax,
-                    PgPool::connect_with(settings.gdax_db.with_db())
-                        .await
-                        .expect("Failed to connect to El Dorado database."),
-                );
-            }
-            name => panic!("{:?} not supported for El-Dorado instance.", name),
-        };
-        pools
-    }
-
-    // Create hashmap of all database connections, the main system db and each exchange trade db.
-    // To be used by IG instances which manage the entire platform
-    pub async fn create_pgpools_ig(settings: &Settings) -> HashMap<Database, PgPool> {
-        let mut pools = HashMap::new();
-        // Insert system pool
-        pools.insert(
-            Database::ElDorado,
-            PgPool::connect_with(settings.ed_db.with_db())
-                .await
-                .expect("Failed to connect to El Dorado database."),
-        );
-        pools.insert(
-            Database::Ftx,
-            PgPool::connect_with(settings.ftx_db.with_db())
-                .await
-                .expect("Failed to connect to El Dorado database."),
-        );
-        pools.insert(
-            Database::Gdax,
-            PgPool::connect_with(settings.gdax_db.with_db())
-                .await
-                .expect("Failed to connect to El Dorado database."),
-        );
-        pools
-    }
-
     pub fn handle_error_for_restart(&self, e: ElDoradoError) -> bool {
         match e {
             ElDoradoError::Sqlx(e) => self.handle_sqlx_error_for_restart(e),
@@ -1,4 +1,4 @@
-use crate::{configuration::Database, eldorado::ElDorado};
+use crate::eldorado::ElDorado;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
@@ -38,6 +38,8 @@ pub enum ExchangeName {
     Mango,
     Dydx,
     Bybit,
+    // Virtual exchange for the composite indexes
+    Index,
 }
 
 impl ExchangeName {
@@ -52,6 +54,7 @@ impl ExchangeName {
             ExchangeName::Mango => "mango",
             ExchangeName::Dydx => "dydx",
             ExchangeName::Bybit => "bybit",
+            ExchangeName::Index => "index",
         }
     }
 }
@@ -70,6 +73,7 @@ impl TryFrom<String> for ExchangeName {
             "mango" => Ok(Self::Gdax),
             "dydx" => Ok(Self::Kraken),
             "bybit" => Ok(Self::Bybit),
+            "index" => Ok(Self::Index),
             other => Err(format!("{} is not a supported exchange.", other)),
         }
     }
@@ -159,7 +163,7 @@ impl ElDorado {
         };
         // Match against available exchanges for instance
         let active_exchanges =
-            Exchange::select_by_status(&self.pools[&Database::ElDorado], ExchangeStatus::Active)
+            Exchange::select_by_status(self.pools.system(), ExchangeStatus::Active)
                 .await
                 .expect("Failed to select exchanges from db.");
         active_exchanges.into_iter().find(|e| e.name == exchange)
@@ -214,7 +218,7 @@ mod tests {
         println!("Configuration: {:?}", configuration);
 
         // Create db connection
-        let pool = PgPool::connect_with(configuration.ftx_db.with_db())
+        let pool = PgPool::connect_with(configuration.databases["ftx"].with_db())
             .await
             .expect("Failed to connect to Postgres.");
 
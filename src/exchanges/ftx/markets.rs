@@ -1,6 +1,7 @@
-use crate::exchanges::{client::RestClient, error::RestError};
+use crate::configuration::Schema;
+use crate::exchanges::{client::RestClient, error::RestError, ExchangeName};
 use crate::markets::MarketDetail;
-use crate::trades::PrIdTi;
+use crate::trades::{PrIdTi, TradePartition};
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, DurationRound, Utc};
 use rust_decimal::prelude::*;
@@ -117,58 +118,57 @@ impl crate::trades::Trade for Trade {
         }
     }
 
-    async fn create_table(
+    async fn create_parent_table(
         pool: &PgPool,
-        market: &MarketDetail,
-        dt: DateTime<Utc>,
+        schema: &Schema,
+        exchange: &ExchangeName,
     ) -> Result<(), sqlx::Error> {
         let table_sql = format!(
             r#"
-            CREATE TABLE IF NOT EXISTS trades.{}_{}_{} (
+            CREATE TABLE IF NOT EXISTS {s}.{e} (
                 market_id uuid NOT NULL,
                 trade_id BIGINT NOT NULL,
-                PRIMARY KEY (trade_id),
                 price NUMERIC NOT NULL,
                 size NUMERIC NOT NULL,
                 side TEXT NOT NULL,
                 liquidation BOOLEAN NOT NULL,
-                time timestamptz NOT NULL
-            )
+                time timestamptz NOT NULL,
+                PRIMARY KEY (market_id, trade_id, time)
+            ) PARTITION BY LIST (market_id)
             "#,
-            market.exchange_name.as_str(),
-            market.as_strip(),
-            dt.format("%Y%m%d")
+            e = TradePartition::parent(exchange),
+            s = schema.trades(exchange),
         );
         let index_sql = format!(
             r#"
-            CREATE INDEX IF NOT EXISTS {e}_{m}_{t}_time_asc
-            ON trades.{e}_{m}_{t} (time)
+            CREATE INDEX IF NOT EXISTS {e}_market_time_asc
+            ON {s}.{e} (market_id, time)
             "#,
-            e = market.exchange_name.as_str(),
-            m = market.as_strip(),
-            t = dt.format("%Y%m%d")
+            e = TradePartition::parent(exchange),
+            s = schema.trades(exchange),
         );
         sqlx::query(&table_sql).execute(pool).await?;
         sqlx::query(&index_sql).execute(pool).await?;
         Ok(())
     }
 
-    async fn insert(&self, pool: &PgPool, market: &MarketDetail) -> Result<(), sqlx::Error> {
+    async fn insert(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<bool, sqlx::Error> {
         let insert_sql = format!(
             r#"
-            INSERT INTO trades.{}_{}_{} (
+            INSERT INTO {s}.{} (
                 market_id, trade_id, price, size, side, liquidation, time)
             VALUES ($1, $2, $3, $4, $5, $6, $7)
-            ON CONFLICT (trade_id) DO NOTHING
+            ON CONFLICT (market_id, trade_id, time) DO NOTHING
             "#,
-            market.exchange_name.as_str(),
-            market.as_strip(),
-            self.time()
-                .duration_trunc(Duration::days(1))
-                .unwrap()
-                .format("%Y%m%d")
+            TradePartition::parent(&market.exchange_name),
+            s = schema.trades(&market.exchange_name),
         );
-        sqlx::query(&insert_sql)
+        let result = sqlx::query(&insert_sql)
             .bind(market.market_id)
             .bind(self.id)
             .bind(self.price)
@@ -178,79 +178,74 @@ impl crate::trades::Trade for Trade {
             .bind(self.time)
             .execute(pool)
             .await?;
-        Ok(())
-    }
-
-    async fn drop_table(
-        pool: &PgPool,
-        market: &MarketDetail,
-        dt: DateTime<Utc>,
-    ) -> Result<(), sqlx::Error> {
-        let sql = format!(
-            r#"
-            DROP TABLE IF EXISTS trades.{}_{}_{}
-            "#,
-            market.exchange_name.as_str(),
-            market.as_strip(),
-            dt.format("%Y%m%d")
-        );
-        sqlx::query(&sql).execute(pool).await?;
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 }
 
 impl Trade {
-    // Select the first trade greater than the given date time. Assume and check that the first
-    // trade will occur on the same day as the datetime. If there is no trade, try checking the next
-    // day. This can occur in edge cases where the given datetime is near the end of the day and
-    // there are no further trades for the day and the next trade occurs the next day.
+    // Select the first trade greater than the given date time. The trades for the market are
+    // stored in one partitioned table so the next trade is found regardless of which day
+    // partition it falls in.
     pub async fn select_one_gt_dt(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: DateTime<Utc>,
     ) -> Result<Self, sqlx::Error> {
-        let d1 = dt.duration_trunc(Duration::days(1)).unwrap();
-        let d2 = d1 + Duration::days(1);
         let sql = format!(
             r#"
             SELECT trade_id as id, price, size, side, liquidation, time
-            FROM trades.{e}_{m}_{d1}
-            WHERE time > $1
-            UNION
-            SELECT trade_id as id, price, size, side, liquidation, time
-            FROM trades.{e}_{m}_{d2}
+            FROM {s}.{}
+            WHERE market_id = $1 AND time > $2
             ORDER BY id ASC
+            LIMIT 1
             "#,
-            e = market.exchange_name.as_str(),
-            m = market.as_strip(),
-            d1 = d1.format("%Y%m%d"),
-            d2 = d2.format("%Y%m%d"),
+            TradePartition::parent(&market.exchange_name),
+            s = schema.trades(&market.exchange_name),
         );
-        // Try current day table for trade
         let row = sqlx::query_as::<_, Trade>(&sql)
+            .bind(market.market_id)
             .bind(dt)
             .fetch_one(pool)
             .await?;
-        // Ok(Box::new(row))
         Ok(row)
     }
 
+    // Select all trades for the market on the day of the given date time
     pub async fn select_all(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: &DateTime<Utc>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let start = dt.duration_trunc(Duration::days(1)).unwrap();
+        Self::select_gte_and_lt_dts(pool, schema, market, &start, &(start + Duration::days(1)))
+            .await
+    }
+
+    pub async fn select_gte_and_lt_dts(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
     ) -> Result<Vec<Self>, sqlx::Error> {
         let sql = format!(
             r#"
             SELECT trade_id as id, price, size, side, liquidation, time
-            FROM trades.{}_{}_{}
+            FROM {s}.{}
+            WHERE market_id = $1 AND time >= $2 AND time < $3
             ORDER BY id ASC
             "#,
-            market.exchange_name.as_str(),
-            market.as_strip(),
-            dt.format("%Y%m%d")
+            TradePartition::parent(&market.exchange_name),
+            s = schema.trades(&market.exchange_name),
         );
-        let rows = sqlx::query_as::<_, Trade>(&sql).fetch_all(pool).await?;
+        let rows = sqlx::query_as::<_, Trade>(&sql)
+            .bind(market.market_id)
+            .bind(start)
+            .bind(end)
+            .fetch_all(pool)
+            .await?;
         Ok(rows)
     }
 }
@@ -0,0 +1,292 @@
+use crate::{
+    candles::ProductionCandle,
+    eldorado::{ElDorado, ElDoradoError},
+    markets::MarketDetail,
+    metrics::ResearchMetric,
+    mita::Heartbeat,
+    sessions::Session,
+    utilities::{DateRange, TimeFrame},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Candle of the interval in progress for a time frame with the metrics calculated as if the
+// interval closed now so the trading engine can act intra interval. There is one record per
+// market and time frame. It is replaced as the interval forms and then with the final candle and
+// metrics and is_closed set once the interval closes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormingCandle {
+    pub market_id: Uuid,
+    pub tf: TimeFrame,
+    pub is_closed: bool,
+    pub candle: ProductionCandle,
+    pub metric: ResearchMetric,
+    pub update_dt: DateTime<Utc>,
+}
+
+impl FormingCandle {
+    // Forming candle for the time frame interval starting at start from the closed base candles
+    // in the interval and the forming base candles
+    pub fn resample(
+        start: &DateTime<Utc>,
+        closed: &[ProductionCandle],
+        forming: &[ProductionCandle],
+    ) -> ProductionCandle {
+        let candles: Vec<_> = closed
+            .iter()
+            .filter(|c| c.datetime >= *start)
+            .chain(forming.iter())
+            .cloned()
+            .collect();
+        ProductionCandle::new_from_candles(start, &candles)
+    }
+
+    // Forming candles and provisional metrics for every time frame from the heartbeat and the
+    // forming base candles. The provisional metric is updated from a copy of the metric state of
+    // the time frame so only the candles since the state and the forming candle are pushed.
+    pub fn make_all(
+        market: &MarketDetail,
+        hb: &Heartbeat,
+        forming: &[ProductionCandle],
+        session: &Session,
+    ) -> Vec<Self> {
+        let current = match forming.last() {
+            Some(c) => c.datetime,
+            None => return Vec::new(),
+        };
+        let base = &hb.candles[&market.tf];
+        TimeFrame::tfs()
+            .iter()
+            .filter_map(|tf| {
+                let start = session.trunc(tf, &current);
+                let candle = if *tf == market.tf {
+                    forming.last().cloned()?
+                } else {
+                    Self::resample(&start, base, forming)
+                };
+                let closed = hb.candles.get(tf)?;
+                let metric = match hb.metric_states.get(tf) {
+                    Some(state) if state.datetime < start => {
+                        let mut state = state.clone();
+                        let i = closed.partition_point(|c| c.datetime <= state.datetime);
+                        for c in closed[i..].iter().filter(|c| c.datetime < start) {
+                            state.push(c);
+                        }
+                        state.push(&candle);
+                        state.metric(market)
+                    }
+                    _ => {
+                        let mut candles: Vec<_> = closed
+                            .iter()
+                            .filter(|c| c.datetime < start)
+                            .cloned()
+                            .collect();
+                        candles.push(candle.clone());
+                        ResearchMetric::new(market, *tf, &candles)
+                    }
+                };
+                Some(Self {
+                    market_id: market.market_id,
+                    tf: *tf,
+                    is_closed: false,
+                    metric,
+                    candle,
+                    update_dt: Utc::now(),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn upsert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO forming_candles (market_id, tf, datetime, is_closed, data, update_dt)
+            VALUES ($1, $2, $3, $4, $5::JSONB, $6)
+            ON CONFLICT (market_id, tf) DO UPDATE
+            SET (datetime, is_closed, data, update_dt) = (EXCLUDED.datetime, EXCLUDED.is_closed,
+                EXCLUDED.data, EXCLUDED.update_dt)
+            "#;
+        let data = serde_json::to_string(self).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        sqlx::query(sql)
+            .bind(self.market_id)
+            .bind(self.tf.as_str())
+            .bind(self.candle.datetime)
+            .bind(self.is_closed)
+            .bind(data)
+            .bind(self.update_dt)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn select_by_id(pool: &PgPool, market_id: &Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT data::TEXT FROM forming_candles
+            WHERE market_id = $1
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql).bind(market_id).fetch_all(pool).await?;
+        rows.iter()
+            .map(|r| serde_json::from_str(&r.0).map_err(|e| sqlx::Error::Decode(Box::new(e))))
+            .collect()
+    }
+}
+
+impl ElDorado {
+    // Publish the forming candles and provisional metrics for the market if the cadence has passed
+    // since the last publish and the forming base candle has changed. A cadence of 0 publishes
+    // on every new trade.
+    pub async fn publish_forming(
+        &self,
+        market: &MarketDetail,
+        heartbeats: &mut HashMap<String, Heartbeat>,
+        dt: &DateTime<Utc>,
+    ) -> Result<(), ElDoradoError> {
+        let cadence = match self.forming_cadence {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let hb = heartbeats.get_mut(&market.market_name).unwrap();
+        if hb.metrics.is_none() || *dt - hb.forming_ts < cadence {
+            return Ok(());
+        }
+        let forming = self.forming_base_candles(market, hb, dt);
+        if forming.is_empty() || hb.forming.as_ref() == forming.last() {
+            return Ok(());
+        }
+        let session = self.sessions.session(market);
+        for fc in FormingCandle::make_all(market, hb, &forming, &session).iter() {
+            self.storage.upsert_forming(fc).await?;
+        }
+        hb.forming_ts = *dt;
+        hb.forming = forming.last().cloned();
+        Ok(())
+    }
+
+    // Publish the closed candles and final metrics for the time frames that closed an interval
+    pub async fn publish_closed(
+        &self,
+        market: &MarketDetail,
+        hb: &mut Heartbeat,
+        metrics: &[ResearchMetric],
+    ) -> Result<(), ElDoradoError> {
+        if self.forming_cadence.is_none() {
+            return Ok(());
+        }
+        for metric in metrics.iter() {
+            if let Some(candle) = hb.candles.get(&metric.tf).and_then(|v| v.last()) {
+                let fc = FormingCandle {
+                    market_id: market.market_id,
+                    tf: metric.tf,
+                    is_closed: true,
+                    candle: candle.clone(),
+                    metric: metric.clone(),
+                    update_dt: Utc::now(),
+                };
+                self.storage.upsert_forming(&fc).await?;
+            }
+        }
+        // Next interval starts forming
+        hb.forming = None;
+        Ok(())
+    }
+
+    // Base time frame candles from the heartbeat through the current interval made from the live
+    // accumulators. Intervals without streamed trades are made from the last trade.
+    fn forming_base_candles(
+        &self,
+        market: &MarketDetail,
+        hb: &Heartbeat,
+        dt: &DateTime<Utc>,
+    ) -> Vec<ProductionCandle> {
+        let start = hb.ts + market.tf.as_dur();
+        let end = market.tf.next(&market.tf.trunc(dt));
+        let dr = match DateRange::new(&start, &end, &market.tf) {
+            Some(dr) => dr,
+            None => return Vec::new(),
+        };
+        let live = self.live.lock().unwrap();
+        match live.get(&market.market_id) {
+            Some(lc) => ProductionCandle::from_live_for_dr(&lc.intervals, &hb.last, &dr.dts),
+            None => ProductionCandle::from_live_for_dr(&Default::default(), &hb.last, &dr.dts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        forming::FormingCandle,
+        metrics::ResearchMetric,
+        mita::Heartbeat,
+        rolling::MetricState,
+        sessions::Session,
+        test_helpers::{market, start, volume_candle},
+        utilities::TimeFrame,
+    };
+    use chrono::Duration;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn forming_candle_includes_closed_and_forming_base() {
+        let start = start();
+        // Base candle from the previous hour is not part of the forming interval
+        let closed = vec![
+            volume_candle(start + Duration::minutes(-15), -15, dec!(90), dec!(5)),
+            volume_candle(start, 0, dec!(100), dec!(1)),
+            volume_candle(start + Duration::minutes(15), 15, dec!(110), dec!(2)),
+        ];
+        let forming = vec![volume_candle(
+            start + Duration::minutes(30),
+            30,
+            dec!(105),
+            dec!(1),
+        )];
+        let fc = FormingCandle::resample(&start, &closed, &forming);
+        assert_eq!(fc.datetime, start);
+        assert_eq!(fc.open, dec!(100));
+        assert_eq!(fc.high, dec!(110));
+        assert_eq!(fc.low, dec!(100));
+        assert_eq!(fc.close, dec!(105));
+        assert_eq!(fc.volume, dec!(4));
+    }
+
+    #[test]
+    pub fn provisional_metric_matches_full_recompute() {
+        let market = market("BTC-USD", TimeFrame::T15);
+        let candles: Vec<_> = (0..200)
+            .map(|i| {
+                volume_candle(
+                    start() + Duration::minutes(15 * i),
+                    15 * i,
+                    Decimal::from(100 + (i * 7) % 13),
+                    Decimal::from(i % 5),
+                )
+            })
+            .collect();
+        let mut hb = Heartbeat::new();
+        hb.candles.insert(TimeFrame::T15, candles[..199].to_vec());
+        // The state lags the heartbeat by a few candles
+        hb.metric_states.insert(
+            TimeFrame::T15,
+            MetricState::new(TimeFrame::T15, &candles[..195]),
+        );
+        let forming = vec![candles[199].clone()];
+        let fcs = FormingCandle::make_all(&market, &hb, &forming, &Session::utc());
+        let fc = fcs.iter().find(|fc| fc.tf == TimeFrame::T15).unwrap();
+        let full = ResearchMetric::new(&market, TimeFrame::T15, &candles);
+        let close = |a: Decimal, b: Decimal| (a - b).abs() <= dec!(0.0001);
+        assert_eq!(fc.metric.datetime, full.datetime);
+        assert!(close(fc.metric.atr_l, full.atr_l));
+        assert!(close(fc.metric.return_z_s, full.return_z_s));
+        assert!(close(fc.metric.volume_z_l, full.volume_z_l));
+        assert_eq!(fc.metric.high32, full.high32);
+        // The state is left for the closed candles
+        assert_eq!(
+            hb.metric_states[&TimeFrame::T15].datetime,
+            candles[194].datetime
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use crate::{
     candles::{CandleType, ProductionCandle, ResearchCandle},
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::{gdax::Trade as GdaxTrade, ExchangeName},
     markets::{
         MarketCandleDetail, MarketDataStatus, MarketDetail, MarketStatus, MarketTradeDetail,
     },
     mita::Heartbeat,
+    sessions::Session,
     trades::{PrIdTi, Trade},
     utilities::TimeFrame,
 };
@@ -14,6 +14,7 @@ use chrono::{DateTime, Duration, DurationRound, Utc};
 use csv::Writer;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use sqlx::PgPool;
 use std::{
     collections::HashMap,
     path::Path,
@@ -24,6 +25,22 @@ use uuid::Uuid;
 
 type Db = Arc<Mutex<HashMap<Uuid, (DateTime<Utc>, i64)>>>;
 
+// Candles sorted by datetime that run up to the first candle without a gap
+pub fn contiguous_history(
+    mut candles: Vec<ProductionCandle>,
+    first: &ProductionCandle,
+    tf: &TimeFrame,
+    session: &Session,
+) -> Vec<ProductionCandle> {
+    let mut next = first.datetime;
+    let mut i = candles.len();
+    while i > 0 && session.next(tf, &candles[i - 1].datetime) == next {
+        i -= 1;
+        next = candles[i].datetime;
+    }
+    candles.split_off(i)
+}
+
 impl ElDorado {
     pub async fn sync(
         &self,
@@ -61,6 +78,10 @@ impl ElDorado {
             // Complete the heartbeat for the market by resampling off the base market tf candles
             self.complete_heartbeat(market, heartbeats).await;
         }
+        // Synthetics are made from the leg candles once the legs are synced
+        for market in self.synthetic_markets.iter() {
+            self.sync_index(market, heartbeats).await?;
+        }
         for hb in heartbeats.iter() {
             println!(
                 "Heartbeat for {}:\tDT: {}\tLast: {}",
@@ -206,18 +227,14 @@ impl ElDorado {
         heartbeats: &mut HashMap<String, Heartbeat>,
     ) -> Result<(), sqlx::Error> {
         println!("Trying MCD start.");
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for historical.", name),
-        };
+        let pool = self.pools.exchange(&market.exchange_name);
         // Check for mcd
-        let mcd = MarketCandleDetail::select(&self.pools[&Database::ElDorado], market).await;
+        let mcd = self.storage.select_mcd(market).await;
         match mcd {
             Ok(m) => {
                 if m.last_trade_ts > heartbeats.get(&market.market_name).unwrap().ts {
                     // Validate the data from the mcd record
-                    self.use_mcd_start(&db, market, &m, heartbeats).await?;
+                    self.use_mcd_start(pool, market, &m, heartbeats).await?;
                 } else {
                     // The archive market candles end before the request start and are of no use
                     println!("MCD ends before sync start");
@@ -242,7 +259,7 @@ impl ElDorado {
     // Get all candles from start to mcd last, resample to market time frame and convert to prod
     async fn use_mcd_start(
         &self,
-        db: &Database,
+        pool: &PgPool,
         market: &MarketDetail,
         mcd: &MarketCandleDetail,
         heartbeats: &mut HashMap<String, Heartbeat>,
@@ -250,7 +267,8 @@ impl ElDorado {
         println!("Using MCD start.");
         // Select candles
         let archive_candles = ResearchCandle::select_dr(
-            &self.pools[db],
+            pool,
+            &self.schema,
             market,
             &mcd.time_frame,
             &heartbeats.get(&market.market_name).unwrap().ts,
@@ -354,7 +372,7 @@ impl ElDorado {
         heartbeats: &mut HashMap<String, Heartbeat>,
     ) -> Result<(), sqlx::Error> {
         println!("Trying MTD start.");
-        let mtd = MarketTradeDetail::select(&self.pools[&Database::ElDorado], market).await;
+        let mtd = self.storage.select_mtd(market).await;
         match mtd {
             Ok(m) => {
                 if let Some(ntd) = m.next_trade_day {
@@ -481,16 +499,15 @@ impl ElDorado {
             .await?
         {
             println!("Candle table exists. Using Eld start.");
+            // Adds any new candle columns to the existing table
+            let pool = self.pools.exchange(&market.exchange_name);
+            ProductionCandle::create_table(pool, &self.schema, market, &market.tf).await?;
             self.use_eld_start(market, heartbeats).await?;
         } else {
             // No production candles, create the candle table and return None
             println!("ElD production candles table does not exist. Creating table.");
-            let db = match market.exchange_name {
-                ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-                ExchangeName::Gdax => Database::Gdax,
-                name => panic!("{:?} not supported for historical.", name),
-            };
-            ProductionCandle::create_table(&self.pools[&db], market, &market.tf).await?;
+            let pool = self.pools.exchange(&market.exchange_name);
+            ProductionCandle::create_table(pool, &self.schema, market, &market.tf).await?;
         }
         Ok(())
     }
@@ -501,18 +518,15 @@ impl ElDorado {
         market: &MarketDetail,
         heartbeats: &mut HashMap<String, Heartbeat>,
     ) -> Result<(), sqlx::Error> {
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for historical.", name),
-        };
+        let pool = self.pools.exchange(&market.exchange_name);
         // Clean up production candles - remove any 0 volume candles
         let last_non_zero_candle =
-            ProductionCandle::select_last_non_zero(&self.pools[&db], market).await?;
-        ProductionCandle::delete_gt_dt(&self.pools[&db], market, &last_non_zero_candle.datetime)
+            ProductionCandle::select_last_non_zero(pool, &self.schema, market).await?;
+        ProductionCandle::delete_gt_dt(pool, &self.schema, market, &last_non_zero_candle.datetime)
             .await?;
         let mut candles = ProductionCandle::select_gte_dt(
-            &self.pools[&db],
+            pool,
+            &self.schema,
             market,
             &heartbeats.get(&market.market_name).unwrap().ts,
         )
@@ -730,9 +744,22 @@ impl ElDorado {
                     .await?;
                 // Write trades to table
                 println!("Writing {} trades to table.", trades.len());
+                let mut inserted = Vec::new();
                 for trade in trades.iter() {
-                    trade.insert(&self.pools[&Database::Gdax], market).await?;
+                    if trade
+                        .insert(
+                            self.pools.exchange(&market.exchange_name),
+                            &self.schema,
+                            market,
+                        )
+                        .await?
+                    {
+                        inserted.push(trade.clone());
+                    }
                 }
+                // Trades filled while the stream runs amend or complete the live candles. Trades
+                // the stream already inserted are in the live candles.
+                self.push_repaired_trades(market, &inserted);
                 // Make candles for day
                 let candles = self.make_production_candles_for_dt_from_vec(
                     market,
@@ -746,7 +773,12 @@ impl ElDorado {
                     println!("Inserting {} candles into prod db table.", cs.len());
                     for candle in cs.iter() {
                         candle
-                            .insert(&self.pools[&Database::Gdax], market, &market.tf)
+                            .insert(
+                                self.pools.exchange(&market.exchange_name),
+                                &self.schema,
+                                market,
+                                &market.tf,
+                            )
                             .await?;
                     }
                     if !cs.is_empty() {
@@ -770,7 +802,7 @@ impl ElDorado {
 
     // Take the candles created in the fill and sync functions and create the current heartbeat
     // for the market
-    async fn complete_heartbeat(
+    pub async fn complete_heartbeat(
         &self,
         market: &MarketDetail,
         heartbeats: &mut HashMap<String, Heartbeat>,
@@ -787,35 +819,41 @@ impl ElDorado {
             .clone();
         let last_ts = last.datetime;
         println!("Last TS: {}", last_ts);
+        let session = self.sessions.session(market);
         for tf in TimeFrame::tfs().iter().skip(1) {
             // Filter candles from floor of new timeframe
             // TF Prev is last divisible tf to use as base resample. This improves performance from
             // resampling from base tf each time and prevents resample of non-divisible tf
             // (ie resampleing T05 from T03 candles)
+            let tf_prev = session.resample_from(tf);
             let last_candle_prev = heartbeats
                 .get(&market.market_name)
                 .unwrap()
                 .candles
-                .get(&tf.resample_from())
+                .get(&tf_prev)
                 .unwrap()
                 .last()
                 .unwrap()
                 .datetime;
-            let filtered_candles: Vec<_> = heartbeats
+            let mut filtered_candles: Vec<_> = heartbeats
                 .get(&market.market_name)
                 .unwrap()
                 .candles
-                .get(&tf.resample_from())
+                .get(&tf_prev)
                 .unwrap()
                 .iter()
-                .filter(|c| {
-                    c.datetime
-                        < (last_candle_prev + tf.resample_from().as_dur())
-                            .duration_trunc(tf.as_dur())
-                            .unwrap()
-                })
+                .filter(|c| c.datetime < session.trunc(tf, &tf_prev.next(&last_candle_prev)))
                 .cloned()
                 .collect();
+            // The sync days do not hold the lookbacks of the long time frames so start them from
+            // the archived candles before the heartbeat
+            if tf.loads_archive_history() {
+                if let Some(first) = filtered_candles.first() {
+                    let mut history = self.read_archive_history(market, tf, &tf_prev, first);
+                    history.append(&mut filtered_candles);
+                    filtered_candles = history;
+                }
+            }
             let capacity = tf.per_day() * (self.sync_days + 10);
             heartbeats
                 .entry(market.market_name.clone())
@@ -823,7 +861,11 @@ impl ElDorado {
                     hb.candles
                         .insert(*tf, Vec::with_capacity(capacity as usize));
                     hb.candles.entry(*tf).and_modify(|v| {
-                        v.append(&mut self.resample_production_candles(&filtered_candles, tf))
+                        v.append(&mut self.resample_production_candles(
+                            &filtered_candles,
+                            tf,
+                            &session,
+                        ))
                     });
                 });
         }
@@ -835,6 +877,31 @@ impl ElDorado {
             });
     }
 
+    // Archived candles of the time frame the long time frame is resampled from, for its history
+    // before the first heartbeat candle. Only the candles that run up to the first candle without a
+    // gap are kept so they resample into whole intervals.
+    fn read_archive_history(
+        &self,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        tf_prev: &TimeFrame,
+        first: &ProductionCandle,
+    ) -> Vec<ProductionCandle> {
+        let start = first.datetime - tf.history_dur();
+        let mut candles = Vec::new();
+        let mut month = Self::trunc_month_dt(&start);
+        while month <= first.datetime {
+            candles.extend(
+                self.read_research_candles_from_file_for_month(market, &month, tf_prev)
+                    .into_iter()
+                    .filter(|c| c.datetime >= start && c.datetime < first.datetime)
+                    .map(|c| c.as_production_candle()),
+            );
+            month = Self::next_month_dt(&month);
+        }
+        contiguous_history(candles, first, tf_prev, &self.sessions.session(market))
+    }
+
     // Fill trades to the beginning of the market open on exchange from the first candle/trade
     // day in el-dorado. Process will create a market trade detail record for each market working
     // from the first candle backwards until the first trade for the market. From there the
@@ -902,36 +969,16 @@ impl ElDorado {
             // Check that candles and trades schema is created
             println!("Creating candle and trade schemas if it does not exist.");
             match market.exchange_name {
-                ExchangeName::Ftx | ExchangeName::FtxUs => {
-                    self.create_candles_schema(&self.pools[&Database::Ftx])
-                        .await?;
-                    self.create_trades_schema(&self.pools[&Database::Ftx])
-                        .await?;
-                }
-                ExchangeName::Gdax => {
-                    self.create_candles_schema(&self.pools[&Database::Gdax])
-                        .await?;
-                    self.create_trades_schema(&self.pools[&Database::Gdax])
-                        .await?;
+                ExchangeName::Ftx | ExchangeName::FtxUs | ExchangeName::Gdax => {
+                    self.create_candles_schema(&market.exchange_name).await?;
+                    self.create_trades_schema(&market.exchange_name).await?;
                 }
                 name => panic!("{:?} not supported for historical.", name),
             };
             // Check that production candles table is created
             println!("Checking production candle tables are created.");
-            if !self
-                .candle_table_exists(market, &market.tf, &CandleType::Production)
-                .await?
-            {
-                println!("Creating production candle table.");
-                let db = match market.exchange_name {
-                    ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-                    ExchangeName::Gdax => Database::Gdax,
-                    name => panic!("{:?} not supported for historical.", name),
-                };
-                ProductionCandle::create_table(&self.pools[&db], market, &market.tf).await?;
-            } else {
-                println!("Table exists.");
-            }
+            let pool = self.pools.exchange(&market.exchange_name);
+            ProductionCandle::create_table(pool, &self.schema, market, &market.tf).await?;
             Ok(true)
         }
     }
@@ -1109,8 +1156,13 @@ impl ElDorado {
             );
             // Check if there is a trade table for the date and load those trades
             if self.trade_table_exists(market, dt).await? {
-                let trade_date_trades =
-                    GdaxTrade::select_all(&self.pools[&Database::Gdax], market, dt).await?;
+                let trade_date_trades = GdaxTrade::select_all(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    dt,
+                )
+                .await?;
                 let trade_date_volume = trade_date_trades.iter().fold(dec!(0), |v, t| v + t.size());
                 println!(
                     "Daily Volume & Count:\t{}\t\t{}",
@@ -1187,12 +1239,8 @@ impl ElDorado {
     ) -> Result<(), ElDoradoError> {
         let fp = self.prep_trade_archive_path_initial(market, dt);
         self.write_trades_to_archive_path_gdax(trades, &fp);
-        mtd.update_next_day_next_status(
-            &self.pools[&Database::ElDorado],
-            dt,
-            &MarketDataStatus::Validate,
-        )
-        .await?;
+        mtd.update_next_day_next_status(self.storage.as_ref(), dt, &MarketDataStatus::Validate)
+            .await?;
         Ok(())
     }
 
@@ -1225,12 +1273,8 @@ impl ElDorado {
                 "Trade file for {} on {} exists in the expected location. Archive it.",
                 market.market_name, dt
             );
-            mtd.update_next_day_next_status(
-                &self.pools[&Database::ElDorado],
-                &dt,
-                &MarketDataStatus::Archive,
-            )
-            .await?;
+            mtd.update_next_day_next_status(self.storage.as_ref(), &dt, &MarketDataStatus::Archive)
+                .await?;
             Ok(())
         } else if self.trade_table_exists(market, &dt).await? {
             // Check the trade table for the day if the trades are validated. They would be checked
@@ -1241,8 +1285,13 @@ impl ElDorado {
             match market.exchange_name {
                 ExchangeName::Ftx | ExchangeName::FtxUs => todo!(),
                 ExchangeName::Gdax => {
-                    let trades =
-                        GdaxTrade::select_all(&self.pools[&Database::Gdax], market, &dt).await?;
+                    let trades = GdaxTrade::select_all(
+                        self.pools.exchange(&market.exchange_name),
+                        &self.schema,
+                        market,
+                        &dt,
+                    )
+                    .await?;
                     if self
                         .validate_gdax_trades_for_interval(market, &trades)
                         .await?
@@ -1255,7 +1304,7 @@ impl ElDorado {
                         // Trades from trade table are not valid - get qc trades
                         println!("Trades from Trade table are not valid. Get trades for qc.");
                         mtd.update_next_day_next_status(
-                            &self.pools[&Database::ElDorado],
+                            self.storage.as_ref(),
                             &dt,
                             &MarketDataStatus::Get,
                         )
@@ -1273,12 +1322,8 @@ impl ElDorado {
                 "Trade file for {} on {} does not exists. Get trades for day to qc.",
                 market.market_name, dt
             );
-            mtd.update_next_day_next_status(
-                &self.pools[&Database::ElDorado],
-                &dt,
-                &MarketDataStatus::Get,
-            )
-            .await?;
+            mtd.update_next_day_next_status(self.storage.as_ref(), &dt, &MarketDataStatus::Get)
+                .await?;
             Ok(())
         }
     }
@@ -1342,7 +1387,7 @@ impl ElDorado {
     ) -> Result<(), ElDoradoError> {
         // Update mtd status and next day
         mtd.update_next_day_next_status(
-            &self.pools[&Database::ElDorado],
+            self.storage.as_ref(),
             &(*d + Duration::days(1)),
             &MarketDataStatus::Completed,
         )
@@ -1357,7 +1402,7 @@ impl ElDorado {
                     let last_trade = trades.last().unwrap();
                     // Update first and last trade in the mtd
                     mtd.update_first_and_last_trades(
-                        &self.pools[&Database::ElDorado],
+                        self.storage.as_ref(),
                         first_trade,
                         last_trade,
                     )
@@ -1376,7 +1421,7 @@ impl ElDorado {
                     let last_trade = trades.last().unwrap();
                     // Update first and last trade in the mtd
                     mtd.update_first_and_last_trades(
-                        &self.pools[&Database::ElDorado],
+                        self.storage.as_ref(),
                         first_trade,
                         last_trade,
                     )
@@ -1391,7 +1436,7 @@ impl ElDorado {
     }
 
     // Cleanup the research candles table for the market by
-    // 1) Deleting an candles older than 100 days from now
+    // 1) Deleting research and archived production candles older than the retention policy
     // 2) Creating and Inserting research candles for the market and dt as it has been
     //    validated and archived
     // 3) Updated (or creating if it does not exist) the mcd for market with candle details
@@ -1401,27 +1446,22 @@ impl ElDorado {
         dt: &DateTime<Utc>,
         pb: &PathBuf,
     ) -> Result<(), ElDoradoError> {
-        // Delete candles older than 100 days in the db - research
-        let cutoff = Utc::now()
-            .duration_trunc(Duration::days(1))
-            .expect("Failed to trunc date.")
-            - Duration::days(self.sync_days);
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for historical.", name),
-        };
-        ResearchCandle::delete_lt_dt(&self.pools[&db], market, &TimeFrame::S15, &cutoff).await?;
-        // Delete candles older than the day in the db - production
+        // Delete candles older than the retention policy in the db - research
+        let policy = self.retention.policy(market);
+        let cutoff = policy.research_cutoff(self.sync_days);
+        let pool = self.pools.exchange(&market.exchange_name);
+        ResearchCandle::delete_lt_dt(pool, &self.schema, market, &TimeFrame::S15, &cutoff).await?;
+        // Delete archived candles older than the retention policy in the db - production
         ProductionCandle::delete_lt_dt(
-            &self.pools[&db],
+            pool,
+            &self.schema,
             market,
             &market.tf,
-            &(*dt + Duration::days(1)),
+            &policy.production_cutoff(&market.tf, &(*dt + Duration::days(1))),
         )
         .await?;
         // Get MCD and create if it does not exist
-        let mcd = MarketCandleDetail::select(&self.pools[&Database::ElDorado], market).await;
+        let mcd = self.storage.select_mcd(market).await;
         let candles = match mcd {
             Ok(m) => {
                 // Assert that the mcd last is the previous day and create candles for current day
@@ -1433,7 +1473,7 @@ impl ElDorado {
                 match candles {
                     Some(c) => {
                         m.update_last(
-                            &self.pools[&Database::ElDorado],
+                            self.storage.as_ref(),
                             c.last().expect("Expected last candle."),
                         )
                         .await?;
@@ -1450,22 +1490,18 @@ impl ElDorado {
                     .await?
                     .unwrap();
                 let mcd = MarketCandleDetail::new(market, &TimeFrame::S15, &candles);
-                mcd.insert(&self.pools[&Database::ElDorado]).await?;
+                self.storage.insert_mcd(&mcd).await?;
                 candles
             }
             Err(e) => return Err(ElDoradoError::Sqlx(e)),
         };
-        // If the dt is within 100 days - add to research table to use when loading market to run
+        // If the dt is within the research retention - add to research table to use when loading market to run
         if *dt >= cutoff {
             // Insert candles into research candles table
-            let db = match market.exchange_name {
-                ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-                ExchangeName::Gdax => Database::Gdax,
-                name => panic!("{:?} not supported for historical.", name),
-            };
+            let pool = self.pools.exchange(&market.exchange_name);
             for candle in candles.iter() {
                 candle
-                    .insert(&self.pools[&db], market, &TimeFrame::S15)
+                    .insert(pool, &self.schema, market, &TimeFrame::S15)
                     .await?;
             }
         }
@@ -1478,15 +1514,15 @@ impl ElDorado {
         dt: &DateTime<Utc>,
     ) -> Result<(), ElDoradoError> {
         // Clean up all the trade tables for the market for the day
-        // Drop the official trade date table.
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => {
-                GdaxTrade::drop_table(&self.pools[&Database::Gdax], market, *dt).await?;
-                Database::Gdax
-            }
-            name => panic!("{:?} not supported for historical.", name),
-        };
+        // Detach and drop the day partition now that the trades are archived.
+        let pool = self.pools.exchange(&market.exchange_name);
+        // Keep the partition if the retention policy keeps archived trades, the IG retention task
+        // drops it once it ages out.
+        if market.exchange_name == ExchangeName::Gdax
+            && !self.retention.policy(market).keeps_trades(dt)
+        {
+            GdaxTrade::drop_table(pool, &self.schema, market, *dt).await?;
+        }
         // Drop the legacy trade and qc tables - remove once prod is cleaned up
         let table_pre = format!(
             "public.trades_{}_{}",
@@ -1509,7 +1545,7 @@ impl ElDorado {
                 "#,
                 table_pre, table
             );
-            sqlx::query(&sql).execute(&self.pools[&db]).await?;
+            sqlx::query(&sql).execute(pool).await?;
         }
         let bf_table = format!(
             "public.trades_{}_{}_bf_{}",
@@ -1523,7 +1559,7 @@ impl ElDorado {
             "#,
             bf_table
         );
-        sqlx::query(&sql).execute(&self.pools[&db]).await?;
+        sqlx::query(&sql).execute(pool).await?;
         Ok(())
     }
 
@@ -1581,18 +1617,28 @@ impl ElDorado {
                     .await?;
                 // Write trades to table
                 println!("Writing {} trades to table.", trades.len());
+                let mut inserted = Vec::new();
                 for trade in trades.iter() {
-                    trade.insert(&self.pools[&Database::Gdax], market).await?;
+                    if trade
+                        .insert(
+                            self.pools.exchange(&market.exchange_name),
+                            &self.schema,
+                            market,
+                        )
+                        .await?
+                    {
+                        inserted.push(trade.clone());
+                    }
                 }
+                // Trades filled while the stream runs amend or complete the live candles. Trades
+                // the stream already inserted are in the live candles.
+                self.push_repaired_trades(market, &inserted);
             }
             name => panic!("{:?} not supported for historical.", name),
         };
         // Update mtd status to validate
-        mtd.update_prev_status(
-            &self.pools[&Database::ElDorado],
-            &MarketDataStatus::Validate,
-        )
-        .await?;
+        mtd.update_prev_status(self.storage.as_ref(), &MarketDataStatus::Validate)
+            .await?;
         Ok(())
     }
 
@@ -1614,7 +1660,8 @@ impl ElDorado {
             ExchangeName::Gdax => {
                 // Get trades from table
                 let trades = GdaxTrade::select_all(
-                    &self.pools[&Database::Gdax],
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
                     market,
                     &mtd.previous_trade_day,
                 )
@@ -1652,7 +1699,8 @@ impl ElDorado {
             }
             ExchangeName::Gdax => {
                 let trades = GdaxTrade::select_all(
-                    &self.pools[&Database::Gdax],
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
                     market,
                     &mtd.previous_trade_day,
                 )
@@ -1681,20 +1729,25 @@ impl ElDorado {
             if first_trade.trade_id == 1 {
                 // First trade, make backfill as completed
                 mtd.update_prev_day_prev_status(
-                    &self.pools[&Database::ElDorado],
+                    self.storage.as_ref(),
                     &(mtd.previous_trade_day - Duration::days(1)),
                     &MarketDataStatus::Completed,
                 )
                 .await
                 .expect("Failed to update mtd status.");
                 // Create the research candle table
-                ResearchCandle::create_table(&self.pools[&Database::Gdax], market, &TimeFrame::S15)
-                    .await
-                    .expect("Failed to create table.")
+                ResearchCandle::create_table(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    &TimeFrame::S15,
+                )
+                .await
+                .expect("Failed to create table.")
             } else {
                 // Move to next day
                 mtd.update_prev_day_prev_status(
-                    &self.pools[&Database::ElDorado],
+                    self.storage.as_ref(),
                     &(mtd.previous_trade_day - Duration::days(1)),
                     &MarketDataStatus::Get,
                 )
@@ -1703,7 +1756,7 @@ impl ElDorado {
             };
             // Update the first ftrade in the mtd
             mtd.update_first_trade(
-                &self.pools[&Database::ElDorado],
+                self.storage.as_ref(),
                 &first_trade.time,
                 &first_trade.trade_id.to_string(),
             )
@@ -1712,7 +1765,7 @@ impl ElDorado {
         } else {
             // No trades for the day but update the previous day and status
             mtd.update_prev_day_prev_status(
-                &self.pools[&Database::ElDorado],
+                self.storage.as_ref(),
                 &(mtd.previous_trade_day - Duration::days(1)),
                 &MarketDataStatus::Get,
             )
@@ -1730,7 +1783,7 @@ impl ElDorado {
     ) -> Result<(), ElDoradoError> {
         // Based on validation - update mtd to archive or to validate the next day
         if validated {
-            mtd.update_prev_status(&self.pools[&Database::ElDorado], &MarketDataStatus::Archive)
+            mtd.update_prev_status(self.storage.as_ref(), &MarketDataStatus::Archive)
                 .await?;
         } else if !trades.is_empty() {
             // Safely unwrap the first trade since it is not empty
@@ -1740,18 +1793,23 @@ impl ElDorado {
             if first_trade.trade_id == 1 {
                 // First trade, make backfill as completed
                 mtd.update_prev_day_prev_status(
-                    &self.pools[&Database::ElDorado],
+                    self.storage.as_ref(),
                     &(mtd.previous_trade_day - Duration::days(1)),
                     &MarketDataStatus::Completed,
                 )
                 .await?;
                 // Create the research candle table
-                ResearchCandle::create_table(&self.pools[&Database::Gdax], market, &TimeFrame::S15)
-                    .await?;
+                ResearchCandle::create_table(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    &TimeFrame::S15,
+                )
+                .await?;
             } else {
                 // Day not validated, move on to getting the next day
                 mtd.update_prev_day_prev_status(
-                    &self.pools[&Database::ElDorado],
+                    self.storage.as_ref(),
                     &(mtd.previous_trade_day - Duration::days(1)),
                     &MarketDataStatus::Get,
                 )
@@ -1759,7 +1817,7 @@ impl ElDorado {
             };
             // Update the first trade in the mtd
             mtd.update_first_trade(
-                &self.pools[&Database::ElDorado],
+                self.storage.as_ref(),
                 &first_trade.time,
                 &first_trade.trade_id.to_string(),
             )
@@ -1767,7 +1825,7 @@ impl ElDorado {
         } else {
             // Failed validation and there are no trades for the day - move to next day
             mtd.update_prev_day_prev_status(
-                &self.pools[&Database::ElDorado],
+                self.storage.as_ref(),
                 &(mtd.previous_trade_day - Duration::days(1)),
                 &MarketDataStatus::Get,
             )
@@ -1879,7 +1937,11 @@ impl ElDorado {
         std::path::Path::new(&path).join(f)
     }
 
-    fn prep_trade_archive_path_final(&self, market: &MarketDetail, dt: &DateTime<Utc>) -> PathBuf {
+    pub fn prep_trade_archive_path_final(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> PathBuf {
         println!(
             "Creating trade archive path final for {} on {}",
             market.market_name, dt
@@ -1910,10 +1972,32 @@ impl ElDorado {
 
 #[cfg(test)]
 mod tests {
-    use crate::candles::ResearchCandle;
+    use crate::{
+        candles::ResearchCandle, historical::contiguous_history, sessions::Session,
+        test_helpers::candles, utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
     use csv::Reader;
+    use rust_decimal::Decimal;
     use std::{fs::File, path::PathBuf};
 
+    #[test]
+    pub fn archive_history_stops_at_a_gap() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let mut history = candles(start, &TimeFrame::D01, &[Decimal::ONE; 10]);
+        // The archive is missing the third day
+        history.remove(2);
+        let first = candles(start + Duration::days(10), &TimeFrame::D01, &[Decimal::ONE]);
+        let kept = contiguous_history(history.clone(), &first[0], &TimeFrame::D01, &Session::utc());
+        assert_eq!(kept.len(), 7);
+        assert_eq!(kept[0].datetime, start + Duration::days(3));
+        // History that does not reach the first candle is not used
+        let later = candles(start + Duration::days(11), &TimeFrame::D01, &[Decimal::ONE]);
+        assert!(
+            contiguous_history(history, &later[0], &TimeFrame::D01, &Session::utc()).is_empty()
+        );
+    }
+
     //     use crate::configuration::get_configuration;
     //     use crate::events::{Event, EventStatus, EventType};
     //     use crate::exchanges::ftx::Trade as FtxTrade;
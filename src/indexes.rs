@@ -0,0 +1,359 @@
+use crate::{
+    candles::ProductionCandle,
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::ExchangeName,
+    markets::{MarketDetail, MarketStatus},
+    mita::Heartbeat,
+    trades::PrIdTi,
+    utilities::{DateRange, TimeFrame},
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Market on an exchange that is part of a composite index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexConstituent {
+    pub exchange: ExchangeName,
+    pub market: String,
+}
+
+// Composite index of the same asset across exchanges, ie BTC-USD on gdax and XBT/USD on kraken.
+// The index is published as a virtual market on the `index` exchange with its own candles and
+// metrics. Each interval the constituent candles are merged into one candle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexSpec {
+    pub name: String,
+    pub constituents: Vec<IndexConstituent>,
+    // Constituents with a vwap further than this from the median vwap are excluded, ie 0.02 = 2%
+    #[serde(default = "IndexSpec::default_max_deviation")]
+    pub max_deviation: Decimal,
+    // Constituents without a trade for this many seconds before the interval end are excluded
+    #[serde(default = "IndexSpec::default_stale_after")]
+    pub stale_after: i64,
+    // Seconds to wait for the constituent mitas to write the interval candles
+    #[serde(default = "IndexSpec::default_wait")]
+    pub wait: i64,
+}
+
+impl IndexSpec {
+    fn default_max_deviation() -> Decimal {
+        dec!(0.02)
+    }
+
+    fn default_stale_after() -> i64 {
+        900
+    }
+
+    fn default_wait() -> i64 {
+        10
+    }
+
+    // Constituent candles that make up the index for the interval. Stale constituents are
+    // dropped first and then any constituent whose vwap is an outlier from the median.
+    pub fn included<'a>(
+        &self,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        candles: &'a [ProductionCandle],
+    ) -> Vec<&'a ProductionCandle> {
+        let stale = *dt + tf.as_dur() - Duration::seconds(self.stale_after);
+        let fresh: Vec<_> = candles
+            .iter()
+            .filter(|c| c.last_trade_ts >= stale)
+            .collect();
+        let mut vwaps: Vec<_> = fresh.iter().map(|c| c.vwap).collect();
+        vwaps.sort();
+        let median = match vwaps.len() {
+            0 => return fresh,
+            n if n % 2 == 0 => (vwaps[n / 2 - 1] + vwaps[n / 2]) / dec!(2),
+            n => vwaps[n / 2],
+        };
+        if median.is_zero() {
+            return fresh;
+        }
+        fresh
+            .into_iter()
+            .filter(|c| ((c.vwap - median) / median).abs() <= self.max_deviation)
+            .collect()
+    }
+
+    // Merge the constituent candles for the interval into the index candle as if the trades were
+    // on one exchange. Volumes, values and counts are summed, the open and close are the first
+    // and last trade across the constituents and the high and low are the extremes. Outliers are
+    // already excluded so the basis between exchanges does not widen the range. If no
+    // constituent is included the last index price is carried forward.
+    pub fn make_candle(
+        &self,
+        dt: &DateTime<Utc>,
+        tf: &TimeFrame,
+        candles: &[ProductionCandle],
+        last: Option<&PrIdTi>,
+    ) -> Option<ProductionCandle> {
+        let included = self.included(dt, tf, candles);
+        if included.is_empty() {
+            return last.map(|pit| ProductionCandle::from_last(*dt, pit));
+        }
+        let volume: Decimal = included.iter().map(|c| c.volume).sum();
+        let weight = |c: &ProductionCandle| {
+            if volume.is_zero() {
+                Decimal::ONE
+            } else {
+                c.volume
+            }
+        };
+        let total: Decimal = included.iter().map(|c| weight(c)).sum();
+        let merged: Vec<_> = included.iter().map(|c| (*c).clone()).collect();
+        let mut candle = ProductionCandle::new_from_candles(dt, &merged);
+        let first = included.iter().min_by_key(|c| c.first_trade_ts).unwrap();
+        let last = included.iter().max_by_key(|c| c.last_trade_ts).unwrap();
+        candle.open = first.open;
+        candle.high = included.iter().map(|c| c.high).max().unwrap();
+        candle.low = included.iter().map(|c| c.low).min().unwrap();
+        candle.close = last.close;
+        candle.twap = included.iter().map(|c| c.twap * weight(c)).sum::<Decimal>() / total;
+        candle.first_trade_ts = first.first_trade_ts;
+        candle.first_trade_id = first.first_trade_id.clone();
+        candle.last_trade_ts = last.last_trade_ts;
+        candle.last_trade_id = last.last_trade_id.clone();
+        candle.set_vwaps();
+        Some(candle)
+    }
+}
+
+impl ElDorado {
+    // Run the composite indexes in the configuration. Each index is synced from its constituent
+    // candles and then run through the mita loop as a virtual market.
+    pub async fn index(&mut self) -> Result<(), ElDoradoError> {
+        let mut markets = Vec::new();
+        for spec in self.indexes.iter() {
+            markets.push(self.initialize_index(spec).await?);
+        }
+        self.markets = markets;
+        self.create_candles_schema(&ExchangeName::Index).await?;
+        let mut heartbeats: HashMap<String, Heartbeat> = HashMap::new();
+        for market in self.markets.iter() {
+            self.sync_index(market, &mut heartbeats).await?;
+        }
+        println!("Starting index loop.");
+        self.run_mita(&mut heartbeats).await
+    }
+
+    pub fn index_spec(&self, market: &MarketDetail) -> &IndexSpec {
+        self.indexes
+            .iter()
+            .find(|s| s.name == market.market_name)
+            .expect("Expected index for market.")
+    }
+
+    pub fn index_constituents(&self, spec: &IndexSpec) -> Vec<MarketDetail> {
+        spec.constituents
+            .iter()
+            .map(|c| {
+                self.market_names
+                    .get(&c.exchange)
+                    .and_then(|m| m.get(&c.market))
+                    .unwrap_or_else(|| {
+                        panic!("{} {} not found for index.", c.exchange.as_str(), c.market)
+                    })
+                    .clone()
+            })
+            .collect()
+    }
+
+    // Get the virtual market for the index or create it from the first constituent. All
+    // constituents must share the same base time frame.
+    async fn initialize_index(&self, spec: &IndexSpec) -> Result<MarketDetail, ElDoradoError> {
+        let constituents = self.index_constituents(spec);
+        let first = constituents.first().expect("Expected index constituents.");
+        if constituents.iter().any(|c| c.tf != first.tf) {
+            panic!("{} constituents must have the same time frame.", spec.name);
+        }
+        if let Some(market) = self
+            .market_names
+            .get(&ExchangeName::Index)
+            .and_then(|m| m.get(&spec.name))
+        {
+            return Ok(market.clone());
+        }
+        let market = MarketDetail {
+            market_id: Uuid::new_v4(),
+            exchange_name: ExchangeName::Index,
+            market_name: spec.name.clone(),
+            market_type: first.market_type,
+            base: first.base.clone(),
+            base_step: first.base_step,
+            base_min: first.base_min,
+            quote: first.quote.clone(),
+            quote_step: first.quote_step,
+            status: MarketStatus::Active,
+            tradable: false,
+            mita: None,
+            tf: first.tf,
+            last_candle: None,
+            asset_id: first.asset_id,
+        };
+        self.storage.insert_market(&market).await?;
+        Ok(market)
+    }
+
+    // Load the candles of an index exchange market for the sync period and make any missing
+    // candles up to the current interval. Then complete the heartbeat as with a mita market.
+    pub async fn sync_index(
+        &self,
+        market: &MarketDetail,
+        heartbeats: &mut HashMap<String, Heartbeat>,
+    ) -> Result<(), ElDoradoError> {
+        let pool = self.pools.exchange(&market.exchange_name);
+        ProductionCandle::create_table(pool, &self.schema, market, &market.tf).await?;
+        let start = (Utc::now() - Duration::days(self.sync_days))
+            .duration_trunc(Duration::days(1))
+            .unwrap();
+        let mut candles =
+            ProductionCandle::select_gte_dt(pool, &self.schema, market, &start).await?;
+        let from = candles
+            .last()
+            .map_or(start, |c| c.datetime + market.tf.as_dur());
+        let last = candles.last().map(|c| c.close_as_pridti());
+        if let Some(dr) = DateRange::new(&from, &market.tf.trunc(&Utc::now()), &market.tf) {
+            let mut new_candles = self.make_virtual_candles(market, &dr, last).await?;
+            self.insert_production_candles(market, &new_candles).await?;
+            candles.append(&mut new_candles);
+        }
+        let last = candles
+            .last()
+            .expect("No candles for index market.")
+            .clone();
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.ts = last.datetime;
+        heartbeat.last = last.close_as_pridti();
+        heartbeat.candles.insert(market.tf, candles);
+        heartbeats.insert(market.market_name.clone(), heartbeat);
+        self.complete_heartbeat(market, heartbeats).await;
+        Ok(())
+    }
+
+    // Candles for a virtual market on the index exchange: a synthetic pair or a composite index
+    pub async fn make_virtual_candles(
+        &self,
+        market: &MarketDetail,
+        dr: &DateRange,
+        last: Option<PrIdTi>,
+    ) -> Result<Vec<ProductionCandle>, ElDoradoError> {
+        match self.synthetic_spec(market) {
+            Some(spec) => self.make_synthetic_candles(spec, market, dr, last).await,
+            None => self.make_index_candles(market, dr, last).await,
+        }
+    }
+
+    // Make the index candles for the date range from the constituent candles
+    pub async fn make_index_candles(
+        &self,
+        market: &MarketDetail,
+        dr: &DateRange,
+        mut last: Option<PrIdTi>,
+    ) -> Result<Vec<ProductionCandle>, ElDoradoError> {
+        let spec = self.index_spec(market);
+        let mut constituent_candles = Vec::new();
+        for constituent in self.index_constituents(spec).iter() {
+            constituent_candles.push(
+                self.select_candles_for_dr(constituent, dr, spec.wait)
+                    .await?,
+            );
+        }
+        let mut candles = Vec::new();
+        for dt in dr.dts.iter() {
+            let interval: Vec<_> = constituent_candles
+                .iter()
+                .filter_map(|m| m.get(dt).cloned())
+                .collect();
+            if let Some(candle) = spec.make_candle(dt, &market.tf, &interval, last.as_ref()) {
+                last = Some(candle.close_as_pridti());
+                candles.push(candle);
+            }
+        }
+        Ok(candles)
+    }
+
+    // Select the production candles of a market for the date range mapped by datetime. Mitas write
+    // their candles at the interval end so wait up to `wait` seconds for the last interval.
+    pub async fn select_candles_for_dr(
+        &self,
+        market: &MarketDetail,
+        dr: &DateRange,
+        wait: i64,
+    ) -> Result<HashMap<DateTime<Utc>, ProductionCandle>, ElDoradoError> {
+        let pool = self.pools.exchange(&market.exchange_name);
+        let end = dr.last + market.tf.as_dur();
+        let mut waited = 0;
+        loop {
+            let candles: HashMap<_, _> =
+                ProductionCandle::select_gte_dt(pool, &self.schema, market, &dr.first)
+                    .await?
+                    .into_iter()
+                    .filter(|c| c.datetime < end)
+                    .map(|c| (c.datetime, c))
+                    .collect();
+            if candles.contains_key(&dr.last) || waited >= wait {
+                return Ok(candles);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            waited += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        indexes::IndexSpec,
+        test_helpers::{start, traded_candle},
+        utilities::TimeFrame,
+    };
+    use chrono::Duration;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn index_candle_excludes_outliers_and_stale_constituents() {
+        let spec = IndexSpec {
+            name: "BTC-USD".to_string(),
+            constituents: Vec::new(),
+            max_deviation: dec!(0.02),
+            stale_after: 600,
+            wait: 0,
+        };
+        let dt = start();
+        // Last trades the minutes before the interval end
+        let ago = |minutes: i64| dt + Duration::minutes(15 - minutes);
+        let candles = vec![
+            traded_candle(dt, ago(2), 1, dec!(100), dec!(3)),
+            traded_candle(dt, ago(1), 1, dec!(101), dec!(1)),
+            // Outlier from the median of 101
+            traded_candle(dt, ago(1), 1, dec!(110), dec!(5)),
+            // Stale - last trade 20 minutes before the interval end
+            traded_candle(dt, ago(20), 1, dec!(50), dec!(5)),
+        ];
+        let index = spec
+            .make_candle(&dt, &TimeFrame::T15, &candles, None)
+            .unwrap();
+        assert_eq!(index.volume, dec!(4));
+        assert_eq!(index.value, dec!(401));
+        // Open and close from the first and last trade, high and low are the extremes
+        assert_eq!(index.open, dec!(100));
+        assert_eq!(index.high, dec!(101));
+        assert_eq!(index.low, dec!(100));
+        assert_eq!(index.close, dec!(101));
+        assert_eq!(index.vwap, dec!(100.25));
+        assert_eq!(index.trade_count, 2);
+        // Nothing included carries the last price forward
+        let last = index.close_as_pridti();
+        let carried = spec
+            .make_candle(&dt, &TimeFrame::T15, &candles[3..], Some(&last))
+            .unwrap();
+        assert_eq!(carried.close, dec!(101));
+        assert_eq!(carried.volume, dec!(0));
+    }
+}
@@ -0,0 +1,1102 @@
+use crate::{
+    candles::{CandleType, ProductionCandle, ResearchCandle},
+    conqui::Z_METRICS,
+    eldorado::{ElDorado, ElDoradoError},
+    markets::MarketDetail,
+    metrics::{Metric, DONS},
+    mita::Heartbeat,
+    utilities::TimeFrame,
+    volatility::{RangeEstimator, RangeVolatility, RealizedVolatility},
+};
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
+use uuid::Uuid;
+
+// Indicator calculated over the candles of a market and time frame as of the last candle.
+// Indicators are registered by name in `IndicatorSpec::build` and configured per market and time
+// frame in the `indicators` config so new indicators need no table or pipeline changes. The
+// research metric z scores, atrs and ranges are the built in `IndicatorSpec::metrics`.
+pub trait Indicator: Send + Sync {
+    // Name the indicator is registered and configured by ie ema
+    fn name(&self) -> &'static str;
+    // Parameter values in the order they key the stored values ie [close, 90] for ema_close_90
+    fn params(&self) -> Vec<String>;
+    // Number of candles needed before the indicator has a value
+    fn warmup(&self) -> usize;
+    // Names of the values calculated, one per value returned by compute
+    fn outputs(&self) -> &'static [&'static str] {
+        &["value"]
+    }
+    // Values as of the last candle. Called with at least warmup candles.
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal>;
+    // Values as of the last candle given the lower time frame base candles of the market, ie
+    // realized volatility from the S15 research candle returns. Most indicators only need the
+    // candles.
+    fn compute_from_base(
+        &self,
+        candles: &[ProductionCandle],
+        _base: &[ProductionCandle],
+    ) -> Vec<Decimal> {
+        self.compute(candles)
+    }
+    // True if compute_from_base uses the base candles so they are only loaded when needed
+    fn uses_base(&self) -> bool {
+        false
+    }
+
+    // Key of the indicator values ie ema_close_90 or donchian_32.high
+    fn key(&self, output: &str) -> String {
+        let mut key = std::iter::once(self.name().to_string())
+            .chain(self.params())
+            .collect::<Vec<_>>()
+            .join("_");
+        if self.outputs().len() > 1 {
+            key = format!("{}.{}", key, output);
+        }
+        key
+    }
+
+    // Keyed values, None for each output until there are warmup candles
+    fn values(
+        &self,
+        candles: &[ProductionCandle],
+        base: &[ProductionCandle],
+    ) -> Vec<(String, Option<Decimal>)> {
+        let values: Vec<_> = if candles.len() >= self.warmup() && !candles.is_empty() {
+            self.compute_from_base(candles, base)
+                .into_iter()
+                .map(Some)
+                .collect()
+        } else {
+            vec![None; self.outputs().len()]
+        };
+        self.outputs()
+            .iter()
+            .zip(values)
+            .map(|(o, v)| (self.key(o), v))
+            .collect()
+    }
+}
+
+// Candle field an indicator is calculated on. Return and tr use the previous close and the
+// ratios are zero for candles without the volume, as in the research metrics.
+pub fn series(field: &str, candles: &[ProductionCandle]) -> Option<Vec<Decimal>> {
+    let ratio = |n: Decimal, d: Decimal| n.checked_div(d).unwrap_or(Decimal::ZERO);
+    let f: fn(&ProductionCandle, Decimal) -> Decimal = match field {
+        "open" => |c, _| c.open,
+        "high" => |c, _| c.high,
+        "low" => |c, _| c.low,
+        "close" => |c, _| c.close,
+        "return" => |c, p| match p.is_zero() {
+            true => Decimal::ZERO,
+            false => c.close / p - Decimal::ONE,
+        },
+        "tr" => |c, p| match p.is_zero() {
+            true => c.high - c.low,
+            false => (c.high - c.low)
+                .max((c.high - p).abs())
+                .max((p - c.low).abs()),
+        },
+        "upper_wick" => |c, _| {
+            (c.high - c.open.max(c.close))
+                .checked_div(c.high - c.low)
+                .unwrap_or(Decimal::ZERO)
+        },
+        "body" => |c, _| {
+            (c.open - c.close)
+                .abs()
+                .checked_div(c.high - c.low)
+                .unwrap_or(Decimal::ZERO)
+        },
+        "lower_wick" => |c, _| {
+            (c.open.min(c.close) - c.low)
+                .checked_div(c.high - c.low)
+                .unwrap_or(Decimal::ZERO)
+        },
+        "volume" => |c, _| c.volume,
+        "volume_net" => |c, _| c.volume_buy - c.volume_sell,
+        "volume_liq" => |c, _| c.volume_liq,
+        "value" => |c, _| c.value,
+        "value_net" => |c, _| c.value_buy - c.value_sell,
+        "value_liq" => |c, _| c.value_liq,
+        "trade_count" => |c, _| Decimal::from(c.trade_count),
+        "trade_count_net" => |c, _| Decimal::from(c.trade_count_buy - c.trade_count_sell),
+        "volume_liq_net" => |c, _| c.volume_liq_buy - c.volume_liq_sell,
+        "value_liq_net" => |c, _| c.value_liq_buy - c.value_liq_sell,
+        "liq_count" => |c, _| Decimal::from(c.liq_count),
+        "liq_count_net" => |c, _| Decimal::from(c.liq_count_buy - c.liq_count_sell),
+        // Whole number ratios of the counts as in the research metrics
+        "trade_count_pct" => {
+            |c, _| Decimal::from(c.trade_count_buy.checked_div(c.trade_count).unwrap_or(0))
+        }
+        "liq_count_pct" => {
+            |c, _| Decimal::from(c.liq_count_buy.checked_div(c.liq_count).unwrap_or(0))
+        }
+        "vwap" => |c, _| c.vwap,
+        "twap" => |c, _| c.twap,
+        "volume_pct" | "value_pct" | "volume_liq_pct" | "value_liq_pct" => {
+            return Some(
+                candles
+                    .iter()
+                    .map(|c| match field {
+                        "volume_pct" => ratio(c.volume_buy, c.volume),
+                        "value_pct" => ratio(c.value_buy, c.value),
+                        "volume_liq_pct" => ratio(c.volume_liq_buy, c.volume_liq),
+                        _ => ratio(c.value_liq_buy, c.value_liq),
+                    })
+                    .collect(),
+            )
+        }
+        _ => return None,
+    };
+    let mut prev = Decimal::ZERO;
+    Some(
+        candles
+            .iter()
+            .map(|c| {
+                let v = f(c, prev);
+                prev = c.close;
+                v
+            })
+            .collect(),
+    )
+}
+
+// Exponentially weighted average of the field
+#[derive(Debug, Clone)]
+pub struct Ema {
+    pub field: String,
+    pub lbp: Lookback,
+}
+
+impl Indicator for Ema {
+    fn name(&self) -> &'static str {
+        "ema"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.field.clone(), self.lbp.label.clone()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let v = series(&self.field, candles).unwrap();
+        vec![Metric::ewma(&v, self.lbp.n as i64)]
+    }
+}
+
+// Average true range as the ewma of the true range. Partial atrs are from the first candle until
+// there are lookback candles, as in the research metrics.
+#[derive(Debug, Clone)]
+pub struct Atr {
+    pub lbp: Lookback,
+    pub partial: bool,
+}
+
+impl Indicator for Atr {
+    fn name(&self) -> &'static str {
+        "atr"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone()]
+    }
+
+    fn warmup(&self) -> usize {
+        match self.partial {
+            true => 1,
+            false => self.lbp.n,
+        }
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let v = series("tr", candles).unwrap();
+        vec![Metric::ewma(&v, self.lbp.n as i64).round_dp(8)]
+    }
+}
+
+// Standard deviations of the last value of the field from the mean of the lookback. Partial z
+// scores are over all the candles until there are lookback candles, as in the research metrics.
+#[derive(Debug, Clone)]
+pub struct ZScore {
+    pub field: String,
+    pub lbp: Lookback,
+    pub partial: bool,
+}
+
+impl Indicator for ZScore {
+    fn name(&self) -> &'static str {
+        "zscore"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.field.clone(), self.lbp.label.clone()]
+    }
+
+    fn warmup(&self) -> usize {
+        match self.partial {
+            true => 1,
+            false => self.lbp.n,
+        }
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let v = series(&self.field, candles).unwrap();
+        let start = v.len().saturating_sub(self.lbp.n);
+        vec![Metric::z(&v, start, v.len()).round_dp(4)]
+    }
+}
+
+// Highest high and lowest low of the lookback candles before the last candle, or the highest and
+// lowest value of the field ie the closes for the research metric ranges
+#[derive(Debug, Clone)]
+pub struct Donchian {
+    pub field: Option<String>,
+    pub lbp: Lookback,
+}
+
+impl Indicator for Donchian {
+    fn name(&self) -> &'static str {
+        "donchian"
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.field
+            .iter()
+            .cloned()
+            .chain(std::iter::once(self.lbp.label.clone()))
+            .collect()
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n + 1
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["high", "low"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let window = &candles[candles.len() - 1 - self.lbp.n..candles.len() - 1];
+        match &self.field {
+            Some(field) => {
+                let v = series(field, window).unwrap();
+                vec![*v.iter().max().unwrap(), *v.iter().min().unwrap()]
+            }
+            None => vec![
+                window.iter().map(|c| c.high).max().unwrap(),
+                window.iter().map(|c| c.low).min().unwrap(),
+            ],
+        }
+    }
+}
+
+// Relative strength index with Wilder smoothing of the gains and losses
+#[derive(Debug, Clone)]
+pub struct Rsi {
+    pub lbp: Lookback,
+}
+
+impl Indicator for Rsi {
+    fn name(&self) -> &'static str {
+        "rsi"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n + 1
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let changes: Vec<_> = candles
+            .windows(2)
+            .map(|w| w[1].close - w[0].close)
+            .collect();
+        let gains: Vec<_> = changes.iter().map(|c| (*c).max(Decimal::ZERO)).collect();
+        let losses: Vec<_> = changes.iter().map(|c| (-c).max(Decimal::ZERO)).collect();
+        let gain = *Metric::wilder_series(&gains, self.lbp.n).last().unwrap();
+        let loss = *Metric::wilder_series(&losses, self.lbp.n).last().unwrap();
+        let rsi = match (gain.is_zero(), loss.is_zero()) {
+            (true, true) => dec!(50),
+            (_, true) => dec!(100),
+            _ => dec!(100) - dec!(100) / (Decimal::ONE + gain / loss),
+        };
+        vec![rsi.round_dp(4)]
+    }
+}
+
+// Moving average convergence divergence of the fast and slow close ewmas with the signal ewma of
+// the macd and the histogram between them
+#[derive(Debug, Clone)]
+pub struct Macd {
+    pub fast: Lookback,
+    pub slow: Lookback,
+    pub signal: Lookback,
+}
+
+impl Indicator for Macd {
+    fn name(&self) -> &'static str {
+        "macd"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![
+            self.fast.label.clone(),
+            self.slow.label.clone(),
+            self.signal.label.clone(),
+        ]
+    }
+
+    fn warmup(&self) -> usize {
+        self.slow.n.max(self.fast.n)
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["macd", "signal", "hist"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let closes = series("close", candles).unwrap();
+        let fast = Metric::ewma_series(&closes, self.fast.n as i64);
+        let slow = Metric::ewma_series(&closes, self.slow.n as i64);
+        let macd: Vec<_> = fast.iter().zip(slow.iter()).map(|(f, s)| f - s).collect();
+        let signal = Metric::ewma(&macd, self.signal.n as i64);
+        let macd = macd[macd.len() - 1];
+        vec![macd, signal, macd - signal]
+    }
+}
+
+// Bollinger bands at the multiple of the close standard deviation around the simple average and
+// %B, the close position between the bands
+#[derive(Debug, Clone)]
+pub struct Bollinger {
+    pub lbp: Lookback,
+    pub mult: Decimal,
+}
+
+impl Indicator for Bollinger {
+    fn name(&self) -> &'static str {
+        "bollinger"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone(), self.mult.normalize().to_string()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["mid", "upper", "lower", "pct_b"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let closes = series("close", &candles[candles.len() - self.lbp.n..]).unwrap();
+        let mid = closes.iter().sum::<Decimal>() / Decimal::from(closes.len());
+        let width = Metric::sd(&closes) * self.mult;
+        let (upper, lower) = (mid + width, mid - width);
+        // Bands without width put the close in the middle
+        let pct_b = (closes[closes.len() - 1] - lower)
+            .checked_div(upper - lower)
+            .unwrap_or(dec!(0.5));
+        vec![mid, upper, lower, pct_b.round_dp(4)]
+    }
+}
+
+// Keltner channels at the multiple of the atr around the close ewma
+#[derive(Debug, Clone)]
+pub struct Keltner {
+    pub lbp: Lookback,
+    pub mult: Decimal,
+}
+
+impl Indicator for Keltner {
+    fn name(&self) -> &'static str {
+        "keltner"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone(), self.mult.normalize().to_string()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["mid", "upper", "lower"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let mid = Metric::ewma(&series("close", candles).unwrap(), self.lbp.n as i64);
+        let atr = Atr {
+            lbp: self.lbp.clone(),
+            partial: false,
+        }
+        .compute(candles)[0];
+        vec![mid, mid + atr * self.mult, mid - atr * self.mult]
+    }
+}
+
+// Average directional index and the directional indicators with Wilder smoothing
+#[derive(Debug, Clone)]
+pub struct Adx {
+    pub lbp: Lookback,
+}
+
+impl Indicator for Adx {
+    fn name(&self) -> &'static str {
+        "adx"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone()]
+    }
+
+    // The directional indicators need lbp moves and the adx lbp of them
+    fn warmup(&self) -> usize {
+        self.lbp.n * 2
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["adx", "plus_di", "minus_di"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let tr = series("tr", candles).unwrap();
+        let (plus, minus): (Vec<_>, Vec<_>) = candles
+            .windows(2)
+            .map(|w| {
+                let up = w[1].high - w[0].high;
+                let down = w[0].low - w[1].low;
+                (
+                    match up > down && up > Decimal::ZERO {
+                        true => up,
+                        false => Decimal::ZERO,
+                    },
+                    match down > up && down > Decimal::ZERO {
+                        true => down,
+                        false => Decimal::ZERO,
+                    },
+                )
+            })
+            .unzip();
+        let tr = Metric::wilder_series(&tr[1..], self.lbp.n);
+        let plus = Metric::wilder_series(&plus, self.lbp.n);
+        let minus = Metric::wilder_series(&minus, self.lbp.n);
+        let di =
+            |dm: &Decimal, tr: &Decimal| (dm * dec!(100)).checked_div(*tr).unwrap_or(Decimal::ZERO);
+        let dis: Vec<_> = plus
+            .iter()
+            .zip(minus.iter())
+            .zip(tr.iter())
+            .map(|((p, m), t)| (di(p, t), di(m, t)))
+            .collect();
+        let dx: Vec<_> = dis
+            .iter()
+            .map(|(p, m)| {
+                ((p - m).abs() * dec!(100))
+                    .checked_div(p + m)
+                    .unwrap_or(Decimal::ZERO)
+            })
+            .collect();
+        let adx = *Metric::wilder_series(&dx, self.lbp.n).last().unwrap();
+        let (plus_di, minus_di) = dis[dis.len() - 1];
+        vec![adx.round_dp(4), plus_di.round_dp(4), minus_di.round_dp(4)]
+    }
+}
+
+// Supertrend trailing band at the multiple of the atr from the candle midpoint. The direction is
+// 1 when the close is above the band and -1 when below.
+#[derive(Debug, Clone)]
+pub struct Supertrend {
+    pub lbp: Lookback,
+    pub mult: Decimal,
+}
+
+impl Indicator for Supertrend {
+    fn name(&self) -> &'static str {
+        "supertrend"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone(), self.mult.normalize().to_string()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["value", "direction"]
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let atrs = Metric::ewma_series(&series("tr", candles).unwrap(), self.lbp.n as i64);
+        let mut upper = Decimal::MAX;
+        let mut lower = Decimal::MIN;
+        let mut up = true;
+        let mut prev_close = candles[0].close;
+        for (c, atr) in candles.iter().zip(atrs.iter()) {
+            let mid = (c.high + c.low) / dec!(2);
+            let (basic_upper, basic_lower) = (mid + atr * self.mult, mid - atr * self.mult);
+            // Bands only move toward the price unless the previous close crossed them
+            if basic_upper < upper || prev_close > upper {
+                upper = basic_upper;
+            }
+            if basic_lower > lower || prev_close < lower {
+                lower = basic_lower;
+            }
+            up = match up {
+                true => c.close >= lower,
+                false => c.close > upper,
+            };
+            prev_close = c.close;
+        }
+        match up {
+            true => vec![lower, Decimal::ONE],
+            false => vec![upper, -Decimal::ONE],
+        }
+    }
+}
+
+// Lookback of an indicator in candles, configured as a number or as l or s for the time frame
+// lbp_l or lbp_s. The configured value keys the indicator so l and s line up across time frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookback {
+    pub n: usize,
+    pub label: String,
+}
+
+impl Lookback {
+    pub fn new(label: &str, tf: &TimeFrame) -> Option<Self> {
+        let label = label.to_lowercase();
+        let n = match label.as_str() {
+            "l" => Some(tf.lbp_l() as usize),
+            "s" => Some(tf.lbp_s() as usize),
+            n => n.parse::<usize>().ok(),
+        };
+        match n {
+            Some(n) if n > 0 => Some(Self { n, label }),
+            _ => None,
+        }
+    }
+}
+
+// Indicator configured to calculate for markets and time frames, ie
+//   - name: "zscore"
+//     params: { field: "volume", lbp: 90 }
+//     tfs: ["t15", "h01"]
+// Empty markets or tfs calculate for all of them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct IndicatorSpec {
+    pub name: String,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    #[serde(default)]
+    pub markets: Vec<String>,
+    #[serde(default)]
+    pub tfs: Vec<TimeFrame>,
+}
+
+impl IndicatorSpec {
+    // Registry of the indicators by name. Add an arm for each new indicator. Lookbacks resolve
+    // against the time frame.
+    pub fn build(&self, tf: &TimeFrame) -> Result<Box<dyn Indicator>, String> {
+        let indicator: Box<dyn Indicator> = match self.name.to_lowercase().as_str() {
+            "ema" => Box::new(Ema {
+                field: self.field("close")?,
+                lbp: self.lbp("lbp", None, tf)?,
+            }),
+            "atr" => Box::new(Atr {
+                lbp: self.lbp("lbp", None, tf)?,
+                partial: self.flag("partial")?,
+            }),
+            "zscore" => Box::new(ZScore {
+                field: self.field("return")?,
+                lbp: self.lbp("lbp", None, tf)?,
+                partial: self.flag("partial")?,
+            }),
+            "donchian" => Box::new(Donchian {
+                field: match self.param("field") {
+                    Some(_) => Some(self.field("close")?),
+                    None => None,
+                },
+                lbp: self.lbp("lbp", None, tf)?,
+            }),
+            "rsi" => Box::new(Rsi {
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+            }),
+            "macd" => Box::new(Macd {
+                fast: self.lbp("fast", Some("s"), tf)?,
+                slow: self.lbp("slow", Some("l"), tf)?,
+                signal: self.lbp("signal", Some("9"), tf)?,
+            }),
+            "bollinger" => Box::new(Bollinger {
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+                mult: self.mult(dec!(2))?,
+            }),
+            "keltner" => Box::new(Keltner {
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+                mult: self.mult(dec!(2))?,
+            }),
+            "adx" => Box::new(Adx {
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+            }),
+            "supertrend" => Box::new(Supertrend {
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+                mult: self.mult(dec!(3))?,
+            }),
+            "parkinson" => self.range_volatility(RangeEstimator::Parkinson, tf)?,
+            "garman_klass" => self.range_volatility(RangeEstimator::GarmanKlass, tf)?,
+            "rogers_satchell" => self.range_volatility(RangeEstimator::RogersSatchell, tf)?,
+            "yang_zhang" => self.range_volatility(RangeEstimator::YangZhang, tf)?,
+            "rv" => Box::new(RealizedVolatility {
+                tf: *tf,
+                lbp: self.lbp("lbp", Some("s"), tf)?,
+            }),
+            other => return Err(format!("{} is not a registered indicator.", other)),
+        };
+        Ok(indicator)
+    }
+
+    // Built in indicators of the research metrics, calculated and stored with the configured
+    // indicators for every market and time frame: the atrs and z scores of the candle fields over
+    // the long and short lookbacks and the ranges of the closes. The research metric columns are
+    // read from their values, ie return_z_s from zscore_return_s and high64 from
+    // donchian_close_64.high.
+    pub fn metrics() -> Vec<Self> {
+        let spec = |name: &str, params: &[(&str, &str)]| Self {
+            name: name.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            markets: Vec::new(),
+            tfs: Vec::new(),
+        };
+        let mut specs = Vec::new();
+        for lbp in ["l", "s"].iter() {
+            specs.push(spec("atr", &[("lbp", lbp), ("partial", "true")]));
+            for field in Z_METRICS.iter() {
+                specs.push(spec(
+                    "zscore",
+                    &[("field", field), ("lbp", lbp), ("partial", "true")],
+                ));
+            }
+        }
+        for range in DONS.iter() {
+            specs.push(spec(
+                "donchian",
+                &[("field", "close"), ("lbp", &range.to_string())],
+            ));
+        }
+        specs
+    }
+
+    pub fn applies(&self, market: &MarketDetail, tf: &TimeFrame) -> bool {
+        (self.markets.is_empty()
+            || self
+                .markets
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&market.market_name)))
+            && (self.tfs.is_empty() || self.tfs.contains(tf))
+    }
+
+    fn param(&self, key: &str) -> Option<&String> {
+        self.params.get(key)
+    }
+
+    fn lbp(&self, key: &str, default: Option<&str>, tf: &TimeFrame) -> Result<Lookback, String> {
+        let label = match self.param(key).map(|p| p.as_str()).or(default) {
+            Some(l) => l,
+            None => return Err(format!("{} needs a {} parameter.", self.name, key)),
+        };
+        match Lookback::new(label, tf) {
+            Some(lbp) => Ok(lbp),
+            None => Err(format!(
+                "{} {} must be a positive number, l or s.",
+                self.name, key
+            )),
+        }
+    }
+
+    fn range_volatility(
+        &self,
+        estimator: RangeEstimator,
+        tf: &TimeFrame,
+    ) -> Result<Box<dyn Indicator>, String> {
+        Ok(Box::new(RangeVolatility {
+            estimator,
+            tf: *tf,
+            lbp: self.lbp("lbp", Some("s"), tf)?,
+        }))
+    }
+
+    fn flag(&self, key: &str) -> Result<bool, String> {
+        match self.param(key).map(|p| p.to_lowercase()) {
+            None => Ok(false),
+            Some(p) => p
+                .parse::<bool>()
+                .map_err(|_| format!("{} {} must be true or false.", self.name, key)),
+        }
+    }
+
+    fn mult(&self, default: Decimal) -> Result<Decimal, String> {
+        match self.param("mult").map(|p| Decimal::from_str(p)) {
+            None => Ok(default),
+            Some(Ok(mult)) if mult > Decimal::ZERO => Ok(mult),
+            _ => Err(format!("{} mult must be a positive number.", self.name)),
+        }
+    }
+
+    fn field(&self, default: &str) -> Result<String, String> {
+        let field = self
+            .param("field")
+            .cloned()
+            .unwrap_or_else(|| default.to_string());
+        match series(&field, &[]) {
+            Some(_) => Ok(field),
+            None => Err(format!(
+                "{} is not a candle field for {}.",
+                field, self.name
+            )),
+        }
+    }
+}
+
+// Values of the indicators for a market and time frame as of the candle datetime, keyed by the
+// indicator key. Stored as a JSONB document per candle so the indicators can change freely.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndicatorValues {
+    pub market_id: Uuid,
+    pub tf: TimeFrame,
+    pub datetime: DateTime<Utc>,
+    pub values: BTreeMap<String, Option<Decimal>>,
+}
+
+impl IndicatorValues {
+    pub fn new(
+        market: &MarketDetail,
+        tf: TimeFrame,
+        candles: &[ProductionCandle],
+        base: &[ProductionCandle],
+        indicators: &[Box<dyn Indicator>],
+    ) -> Option<Self> {
+        let datetime = candles.last()?.datetime;
+        Some(Self {
+            market_id: market.market_id,
+            tf,
+            datetime,
+            values: indicators
+                .iter()
+                .flat_map(|i| i.values(candles, base))
+                .collect(),
+        })
+    }
+
+    // Values as of each candle from the start index, ie for the archived candles of a month with
+    // the candles before it for the warmup
+    pub fn history(
+        market: &MarketDetail,
+        tf: TimeFrame,
+        candles: &[ProductionCandle],
+        base: &[ProductionCandle],
+        indicators: &[Box<dyn Indicator>],
+        start: usize,
+    ) -> Vec<Self> {
+        (start..candles.len())
+            .filter_map(|i| Self::new(market, tf, &candles[..=i], base, indicators))
+            .collect()
+    }
+
+    pub async fn upsert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO indicator_values (market_id, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4::JSONB, $5)
+            ON CONFLICT (market_id, tf, datetime) DO UPDATE
+            SET (data, insert_dt) = (EXCLUDED.data, EXCLUDED.insert_dt)
+            "#;
+        let data = serde_json::to_string(&self.values)
+            .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+        sqlx::query(sql)
+            .bind(self.market_id)
+            .bind(self.tf.as_str())
+            .bind(self.datetime)
+            .bind(data)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn select_by_id(pool: &PgPool, market_id: &Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT market_id, tf, datetime, data::TEXT FROM indicator_values
+            WHERE market_id = $1
+            ORDER BY datetime
+            "#;
+        let rows: Vec<(Uuid, String, DateTime<Utc>, String)> =
+            sqlx::query_as(sql).bind(market_id).fetch_all(pool).await?;
+        rows.into_iter().map(Self::from_row).collect()
+    }
+
+    pub fn from_row(row: (Uuid, String, DateTime<Utc>, String)) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            market_id: row.0,
+            tf: TimeFrame::try_from(row.1).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            datetime: row.2,
+            values: serde_json::from_str(&row.3).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+        })
+    }
+}
+
+impl ElDorado {
+    // Registered indicators configured for the market and time frame and the built in research
+    // metric indicators, last so their values are the ones stored for a key configured twice
+    pub fn indicators_for(&self, market: &MarketDetail, tf: &TimeFrame) -> Vec<Box<dyn Indicator>> {
+        self.indicators
+            .iter()
+            .filter(|spec| spec.applies(market, tf))
+            .cloned()
+            .chain(IndicatorSpec::metrics())
+            .filter_map(|spec| spec.build(tf).ok())
+            .collect()
+    }
+
+    // Calculate and save the configured indicators for the time frames of the market heartbeat
+    pub async fn insert_indicators(
+        &self,
+        market: &MarketDetail,
+        hb: &Heartbeat,
+        tfs: &[TimeFrame],
+    ) -> Result<(), ElDoradoError> {
+        let mut calcs = Vec::new();
+        for tf in tfs.iter() {
+            let indicators = self.indicators_for(market, tf);
+            if indicators.is_empty() {
+                continue;
+            }
+            if let Some(candles) = hb.candles.get(tf) {
+                calcs.push((tf, candles, indicators));
+            }
+        }
+        // Start of the lookbacks of the indicators that use the base candles
+        let start = calcs
+            .iter()
+            .flat_map(|(_, candles, indicators)| {
+                indicators
+                    .iter()
+                    .filter(|i| i.uses_base())
+                    .filter_map(move |i| candles.iter().rev().nth(i.warmup()))
+                    .map(|c| c.datetime)
+            })
+            .min();
+        let base = match start {
+            Some(start) => {
+                self.select_indicator_base_candles(market, hb, &start)
+                    .await?
+            }
+            None => Vec::new(),
+        };
+        for (tf, candles, indicators) in calcs.iter() {
+            if let Some(values) = IndicatorValues::new(market, **tf, candles, &base, indicators) {
+                self.storage.upsert_indicators(&values).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Lower time frame candles of the market for the realized volatility from the start. These are
+    // the S15 research candles the mita writes with each interval. Virtual markets without
+    // research candles use their base time frame candles in the heartbeat.
+    pub async fn select_indicator_base_candles(
+        &self,
+        market: &MarketDetail,
+        hb: &Heartbeat,
+        start: &DateTime<Utc>,
+    ) -> Result<Vec<ProductionCandle>, ElDoradoError> {
+        if self
+            .candle_table_exists(market, &TimeFrame::S15, &CandleType::Research)
+            .await?
+        {
+            let candles = ResearchCandle::select_dr(
+                self.pools.exchange(&market.exchange_name),
+                &self.schema,
+                market,
+                &TimeFrame::S15,
+                start,
+                &(hb.ts + market.tf.as_dur()),
+            )
+            .await?;
+            Ok(self.convert_research_candles(&candles))
+        } else {
+            Ok(hb
+                .candles
+                .get(&market.tf)
+                .map(|c| c.iter().filter(|c| c.datetime >= *start).cloned().collect())
+                .unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        indicators::{series, IndicatorSpec, IndicatorValues},
+        metrics::ResearchMetric,
+        test_helpers::{market, spread_candles, start},
+        utilities::TimeFrame,
+    };
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    fn spec(name: &str, params: &[(&str, &str)]) -> IndicatorSpec {
+        IndicatorSpec {
+            name: name.to_string(),
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+            markets: Vec::new(),
+            tfs: Vec::new(),
+        }
+    }
+
+    #[test]
+    pub fn registered_indicators_compute_keyed_values() {
+        let candles = spread_candles(
+            start(),
+            &TimeFrame::T15,
+            &[dec!(10), dec!(12), dec!(11), dec!(14), dec!(13)],
+            dec!(1),
+        );
+        assert_eq!(
+            series("return", &candles).unwrap()[1],
+            dec!(12) / dec!(10) - dec!(1)
+        );
+        let donchian = spec("donchian", &[("lbp", "3")])
+            .build(&TimeFrame::T15)
+            .unwrap();
+        assert_eq!(
+            donchian.values(&candles, &[]),
+            vec![
+                ("donchian_3.high".to_string(), Some(dec!(15))),
+                ("donchian_3.low".to_string(), Some(dec!(10))),
+            ]
+        );
+        let ema = spec("ema", &[("lbp", "9")]).build(&TimeFrame::T15).unwrap();
+        assert_eq!(
+            ema.values(&candles, &[]),
+            vec![("ema_close_9".to_string(), None)]
+        );
+        let z = spec("zscore", &[("field", "volume"), ("lbp", "5")])
+            .build(&TimeFrame::T15)
+            .unwrap();
+        assert_eq!(z.key("value"), "zscore_volume_5");
+        assert_eq!(z.values(&candles, &[])[0].1, Some(Decimal::ZERO));
+        // Unknown indicators and fields are rejected when the config is loaded
+        assert!(spec("unknown", &[]).build(&TimeFrame::T15).is_err());
+        assert!(spec("zscore", &[("field", "spread"), ("lbp", "5")])
+            .build(&TimeFrame::T15)
+            .is_err());
+        assert!(spec("atr", &[]).build(&TimeFrame::T15).is_err());
+    }
+
+    #[test]
+    pub fn research_metrics_read_the_builtin_indicator_values() {
+        let t15 = TimeFrame::T15;
+        let market = market("BTC-USD", t15);
+        let closes: Vec<_> = (0..20)
+            .map(|i| dec!(100) + Decimal::from(i % 7) - Decimal::from(i % 3))
+            .collect();
+        let candles = spread_candles(start(), &t15, &closes, dec!(1));
+        let indicators: Vec<_> = IndicatorSpec::metrics()
+            .iter()
+            .map(|spec| spec.build(&t15).unwrap())
+            .collect();
+        let values = IndicatorValues::new(&market, t15, &candles, &[], &indicators)
+            .unwrap()
+            .values;
+        let metric = ResearchMetric::new(&market, t15, &candles);
+        // Z scores are partial until there are lookback candles
+        assert_eq!(values["zscore_return_s"], Some(metric.return_z_s));
+        assert_eq!(
+            values["zscore_volume_liq_pct_l"],
+            Some(metric.volume_liq_pct_z_l)
+        );
+        assert_eq!(values["atr_l"], Some(metric.atr_l));
+        // Ranges are of the closes before the last candle
+        assert_eq!(values["donchian_close_4.high"], metric.high4);
+        assert_eq!(metric.high4, Some(dec!(104)));
+        assert_eq!(metric.low16, values["donchian_close_16.low"]);
+        assert_eq!(values["donchian_close_32.high"], None);
+        assert_eq!(metric.high32, None);
+    }
+
+    #[test]
+    pub fn classic_indicators_compute_on_trends() {
+        let t15 = TimeFrame::T15;
+        let mixed = spread_candles(
+            start(),
+            &t15,
+            &[dec!(10), dec!(11), dec!(10), dec!(12)],
+            dec!(1),
+        );
+        // Average gain 1 and loss 1/3 is a relative strength of 3
+        let rsi = spec("rsi", &[("lbp", "3")]).build(&t15).unwrap();
+        assert_eq!(
+            rsi.values(&mixed, &[]),
+            vec![("rsi_3".to_string(), Some(dec!(75)))]
+        );
+        // Lookbacks default to the time frame short lookback
+        let rsi = spec("rsi", &[]).build(&t15).unwrap();
+        assert_eq!(rsi.key("value"), "rsi_s");
+        assert_eq!(rsi.warmup(), 91);
+        assert!(spec("rsi", &[("lbp", "x")]).build(&t15).is_err());
+        // Rising market
+        let closes: Vec<_> = (0..40).map(|i| dec!(100) + Decimal::from(i)).collect();
+        let rising = spread_candles(start(), &t15, &closes, dec!(1));
+        let adx = spec("adx", &[("lbp", "5")]).build(&t15).unwrap();
+        let v = adx.compute(&rising);
+        assert_eq!(v[0], dec!(100));
+        assert!(v[1] > Decimal::ZERO);
+        assert_eq!(v[2], Decimal::ZERO);
+        let supertrend = spec("supertrend", &[("lbp", "5")]).build(&t15).unwrap();
+        let v = supertrend.compute(&rising);
+        assert_eq!(v[1], Decimal::ONE);
+        assert!(v[0] < dec!(139));
+        let macd = spec("macd", &[("fast", "3"), ("slow", "8"), ("signal", "4")])
+            .build(&t15)
+            .unwrap();
+        let v = macd.compute(&rising);
+        assert!(v[0] > Decimal::ZERO);
+        assert_eq!(v[2], v[0] - v[1]);
+        let keltner = spec("keltner", &[("lbp", "5")]).build(&t15).unwrap();
+        let v = keltner.compute(&rising);
+        assert_eq!(v[1] - v[0], v[0] - v[2]);
+        // Flat market has no band width so %B is in the middle
+        let flat = spread_candles(start(), &t15, &[dec!(10); 5], dec!(1));
+        let bollinger = spec("bollinger", &[("lbp", "5")]).build(&t15).unwrap();
+        assert_eq!(bollinger.key("pct_b"), "bollinger_5_2.pct_b");
+        assert_eq!(
+            bollinger.compute(&flat),
+            vec![dec!(10), dec!(10), dec!(10), dec!(0.5)]
+        );
+    }
+}
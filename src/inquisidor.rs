@@ -12,8 +12,8 @@ impl ElDorado {
             self.fill(&None, true).await?;
             // Archive all markets
             self.archive(&None).await?;
-            // Clean metrics
-            self.maintain_metrics_table().await?;
+            // Purge data past its retention
+            self.enforce_retention().await?;
             // Sleep for 1 hour
             tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
         }
@@ -19,7 +19,8 @@ pub struct Instance {
 }
 
 impl Instance {
-    pub async fn initialize(pool: &PgPool, settings: &Settings) -> Self {
+    // SQLite mode instances have no system database and start with a fresh restart state
+    pub async fn initialize(pool: Option<&PgPool>, settings: &Settings) -> Self {
         let instance_type = settings
             .application
             .instance_type
@@ -40,10 +41,13 @@ impl Instance {
             InstanceType::Conqui => None,
         };
         // Check the database for an entry for the instance to get the last restart and message info
-        let instance = match instance_type {
-            InstanceType::Ig => Self::select_ig(pool).await,
-            InstanceType::Mita => Self::select_mita(pool, &exchange_name.unwrap(), &droplet).await,
-            InstanceType::Conqui => Self::select_conqui(pool).await,
+        let instance = match (pool, instance_type) {
+            (None, _) => Err(sqlx::Error::RowNotFound),
+            (Some(pool), InstanceType::Ig) => Self::select_ig(pool).await,
+            (Some(pool), InstanceType::Mita) => {
+                Self::select_mita(pool, &exchange_name.unwrap(), &droplet).await
+            }
+            (Some(pool), InstanceType::Conqui) => Self::select_conqui(pool).await,
         };
         let (last_restart_ts, restart_count, last_message_ts) = match instance {
             Ok(i) => (i.last_restart_ts, i.restart_count, i.last_message_ts),
@@ -1,16 +1,35 @@
 pub mod alerts;
 pub mod archive;
+pub mod asof;
+pub mod backfill;
+pub mod bars;
 pub mod candles;
 pub mod configuration;
+pub mod conqui;
+pub mod correlations;
 pub mod eldorado;
 pub mod exchanges;
+pub mod forming;
 pub mod historical;
+pub mod indexes;
+pub mod indicators;
 pub mod inquisidor;
 pub mod instances;
 pub mod markets;
 pub mod metrics;
 pub mod mita;
 pub mod monitor;
+pub mod repair;
+pub mod retention;
+pub mod rolling;
+pub mod sessions;
+pub mod signals;
+pub mod storage;
 pub mod stream;
+pub mod synthetics;
+#[cfg(test)]
+mod test_helpers;
 pub mod trades;
 pub mod utilities;
+pub mod volatility;
+pub mod watermarks;
@@ -4,8 +4,12 @@ use std::{
 };
 
 use chrono::{DateTime, Utc};
-use clap::App;
-use el_dorado::eldorado::ElDorado;
+use clap::{App, Arg};
+use el_dorado::{
+    configuration::SessionConfig, eldorado::ElDorado, metrics::METRIC_VERSION,
+    repair::parse_repair_dt, sessions::Session, utilities::TimeFrame,
+};
+use std::convert::TryFrom;
 use uuid::Uuid;
 
 #[tokio::main]
@@ -18,6 +22,191 @@ async fn main() {
         .subcommand(App::new("fill").about("fill from first candle to start"))
         .subcommand(App::new("archive").about("archive trade for valid candles"))
         .subcommand(App::new("stream").about("stream trades to db"))
+        .subcommand(App::new("index").about("run composite index candles and metrics"))
+        .subcommand(App::new("synthetic").about("archive synthetic pairs from leg archives"))
+        .subcommand(
+            App::new("repair-candles")
+                .about("rebuild and reconcile candles from the trade archive")
+                .arg(
+                    Arg::new("exchange")
+                        .long("exchange")
+                        .takes_value(true)
+                        .required(true)
+                        .help("exchange of the market ie gdax"),
+                )
+                .arg(
+                    Arg::new("market")
+                        .long("market")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .help("first day to rebuild ie 2023-03-01"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .help("day to rebuild up to, not included"),
+                )
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .help("replace the stored candles that differ"),
+                ),
+        )
+        .subcommand(
+            App::new("backfill-metrics")
+                .about("calculate metrics for the archived months and write them to the archive")
+                .arg(
+                    Arg::new("exchange")
+                        .long("exchange")
+                        .takes_value(true)
+                        .required(true)
+                        .help("exchange of the market ie gdax"),
+                )
+                .arg(
+                    Arg::new("market")
+                        .long("market")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("first month to backfill ie 2023-03-01, defaults to first archived"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .takes_value(true)
+                        .help("last month to backfill, defaults to last archived"),
+                ),
+        )
+        .subcommand(
+            App::new("recompute-metrics")
+                .about("recompute metrics under the current version and compare to another version")
+                .arg(
+                    Arg::new("exchange")
+                        .long("exchange")
+                        .takes_value(true)
+                        .required(true)
+                        .help("exchange of the market ie gdax"),
+                )
+                .arg(
+                    Arg::new("market")
+                        .long("market")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("against").long("against").takes_value(true).help(
+                        "metric version to compare against, defaults to the previous version",
+                    ),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("first month to recompute ie 2023-03-01, defaults to first archived"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .takes_value(true)
+                        .help("last month to recompute, defaults to last archived"),
+                ),
+        )
+        .subcommand(
+            App::new("resample-candles")
+                .about("resample the archived candles to a time frame for a given session")
+                .arg(
+                    Arg::new("exchange")
+                        .long("exchange")
+                        .takes_value(true)
+                        .required(true)
+                        .help("exchange of the market ie gdax"),
+                )
+                .arg(
+                    Arg::new("market")
+                        .long("market")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("tf")
+                        .long("tf")
+                        .takes_value(true)
+                        .required(true)
+                        .help("time frame to resample to ie d01"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .help("first day to resample ie 2023-03-01"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .help("day to resample up to, not included"),
+                )
+                .arg(
+                    Arg::new("timezone")
+                        .long("timezone")
+                        .takes_value(true)
+                        .help(
+                            "session time zone ie America/New_York, defaults to the market session",
+                        ),
+                )
+                .arg(
+                    Arg::new("start")
+                        .long("start")
+                        .takes_value(true)
+                        .default_value("00:00")
+                        .help("local time the session day starts"),
+                ),
+        )
+        .subcommand(
+            App::new("make-bars")
+                .about("make the configured bars from the trade archive")
+                .arg(
+                    Arg::new("exchange")
+                        .long("exchange")
+                        .takes_value(true)
+                        .required(true)
+                        .help("exchange of the market ie gdax"),
+                )
+                .arg(
+                    Arg::new("market")
+                        .long("market")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .takes_value(true)
+                        .required(true)
+                        .help("first day to make bars for ie 2023-03-01"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .help("day to make bars up to, not included"),
+                ),
+        )
         // .subcommand(App::new("monitor").about("monitor active processes"))
         .get_matches();
 
@@ -25,7 +214,7 @@ async fn main() {
     match matches.subcommand_name() {
         Some("refresh") => {
             // Create new admin instance and refresh exchange
-            match ElDorado::new().await {
+            match ElDorado::for_command("refresh").await {
                 Some(eld) => eld.refresh_exchange().await,
                 None => println!("Could not create El Dorado instance."),
             }
@@ -34,7 +223,7 @@ async fn main() {
             // Create new eldorado instance and run the default fn for the instance type
             // For IG => manage the system
             // For MITA => run trade/candle/metrics engine for give markets
-            match ElDorado::new().await {
+            match ElDorado::for_command("run").await {
                 Some(mut eld) => eld.run().await,
                 None => println!("Could not create El Dorado instance."),
             }
@@ -42,7 +231,7 @@ async fn main() {
         Some("fill") => {
             // Download and archive trades from beginning of normal running sync (min 90 days) to
             // the first trades of exchange.
-            match ElDorado::new().await {
+            match ElDorado::for_command("fill").await {
                 Some(eld) => match eld.prompt_market_input(&None).await {
                     Some(m) => eld.fill(&Some(m), false).await.expect("Failed to fill."),
                     None => println!("No valid market to fill."),
@@ -52,7 +241,7 @@ async fn main() {
         }
         Some("archive") => {
             // Archive any monthly trades into candle archives, update market archive table
-            match ElDorado::new().await {
+            match ElDorado::for_command("archive").await {
                 Some(eld) => match eld.prompt_market_input(&None).await {
                     Some(m) => eld.archive(&Some(m)).await.expect("Failed to archive."),
                     None => println!("No valid market to archive."),
@@ -62,7 +251,7 @@ async fn main() {
         }
         Some("stream") => {
             // Create new mita instance and run stream until no restart
-            match ElDorado::new().await {
+            match ElDorado::for_command("stream").await {
                 Some(eld) => {
                     // Initialize shared state db for hb
                     type Db = Arc<Mutex<HashMap<Uuid, (DateTime<Utc>, i64)>>>;
@@ -72,11 +261,246 @@ async fn main() {
                 None => println!("Could not create El Dorado instance."),
             }
         }
+        Some("index") => {
+            // Build the composite index candles and metrics from the constituent markets
+            match ElDorado::for_command("index").await {
+                Some(mut eld) => eld.index().await.expect("Failed to run index."),
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("synthetic") => {
+            // Make the synthetic pair candle archives from the archived leg candles
+            match ElDorado::for_command("synthetic").await {
+                Some(eld) => eld
+                    .archive_synthetics()
+                    .await
+                    .expect("Failed to archive synthetics."),
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("repair-candles") => {
+            // Rebuild the candles for the range from the trade archive and report or overwrite
+            // the stored candles that differ
+            let args = matches.subcommand_matches("repair-candles").unwrap();
+            let (from, to) = match (
+                parse_repair_dt(args.value_of("from").unwrap()),
+                parse_repair_dt(args.value_of("to").unwrap()),
+            ) {
+                (Some(f), Some(t)) => (f, t),
+                _ => {
+                    println!("Please enter dates as YYYY-MM-DD.");
+                    return;
+                }
+            };
+            match ElDorado::for_command("repair-candles").await {
+                Some(eld) => {
+                    let exchange = args.value_of("exchange").unwrap().to_lowercase();
+                    let name = args.value_of("market").unwrap();
+                    let markets = eld
+                        .storage
+                        .select_markets()
+                        .await
+                        .expect("Failed to select markets.");
+                    match markets
+                        .iter()
+                        .find(|m| m.exchange_name.as_str() == exchange && m.market_name == name)
+                    {
+                        Some(m) => eld
+                            .repair_candles(m, &from, &to, args.is_present("overwrite"))
+                            .await
+                            .expect("Failed to repair candles."),
+                        None => println!("No valid market to repair."),
+                    }
+                }
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("backfill-metrics") => {
+            // Replay the candle archive through the live metric path and write the monthly
+            // metric files next to the candle archive
+            let args = matches.subcommand_matches("backfill-metrics").unwrap();
+            let from = args.value_of("from").map(parse_repair_dt);
+            let to = args.value_of("to").map(parse_repair_dt);
+            if from == Some(None) || to == Some(None) {
+                println!("Please enter dates as YYYY-MM-DD.");
+                return;
+            }
+            match ElDorado::for_command("backfill-metrics").await {
+                Some(eld) => {
+                    let exchange = args.value_of("exchange").unwrap().to_lowercase();
+                    let name = args.value_of("market").unwrap();
+                    let markets = eld
+                        .storage
+                        .select_markets()
+                        .await
+                        .expect("Failed to select markets.");
+                    match markets
+                        .iter()
+                        .find(|m| m.exchange_name.as_str() == exchange && m.market_name == name)
+                    {
+                        Some(m) => {
+                            eld.backfill_metrics(m, from.flatten(), to.flatten())
+                                .await
+                                .expect("Failed to backfill metrics.");
+                        }
+                        None => println!("No valid market to backfill."),
+                    }
+                }
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("recompute-metrics") => {
+            // Recompute the metrics under the current metric version next to the archived metrics
+            // of the other version and report the differences
+            let args = matches.subcommand_matches("recompute-metrics").unwrap();
+            let from = args.value_of("from").map(parse_repair_dt);
+            let to = args.value_of("to").map(parse_repair_dt);
+            if from == Some(None) || to == Some(None) {
+                println!("Please enter dates as YYYY-MM-DD.");
+                return;
+            }
+            let against = match args.value_of("against").map(|v| v.parse::<i32>()) {
+                Some(Ok(v)) => v,
+                Some(Err(_)) => {
+                    println!("Please enter the version to compare against as a number.");
+                    return;
+                }
+                None => METRIC_VERSION - 1,
+            };
+            match ElDorado::for_command("recompute-metrics").await {
+                Some(eld) => {
+                    let exchange = args.value_of("exchange").unwrap().to_lowercase();
+                    let name = args.value_of("market").unwrap();
+                    let markets = eld
+                        .storage
+                        .select_markets()
+                        .await
+                        .expect("Failed to select markets.");
+                    match markets
+                        .iter()
+                        .find(|m| m.exchange_name.as_str() == exchange && m.market_name == name)
+                    {
+                        Some(m) => eld
+                            .recompute_metrics(m, against, from.flatten(), to.flatten())
+                            .await
+                            .expect("Failed to recompute metrics."),
+                        None => println!("No valid market to recompute."),
+                    }
+                }
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("resample-candles") => {
+            // Resample the archived candles anchored to the session of the request and write them
+            // next to the candle archive
+            let args = matches.subcommand_matches("resample-candles").unwrap();
+            let (from, to) = match (
+                parse_repair_dt(args.value_of("from").unwrap()),
+                parse_repair_dt(args.value_of("to").unwrap()),
+            ) {
+                (Some(f), Some(t)) => (f, t),
+                _ => {
+                    println!("Please enter dates as YYYY-MM-DD.");
+                    return;
+                }
+            };
+            let tf = match TimeFrame::try_from(args.value_of("tf").unwrap().to_string()) {
+                Ok(tf) => tf,
+                Err(e) => {
+                    println!("Please enter a valid time frame: {}", e);
+                    return;
+                }
+            };
+            let session = match args.value_of("timezone").map(|tz| {
+                Session::try_from(&SessionConfig {
+                    timezone: tz.to_string(),
+                    start: args.value_of("start").unwrap().to_string(),
+                })
+            }) {
+                Some(Ok(s)) => Some(s),
+                Some(Err(e)) => {
+                    println!("Please enter a valid session: {}", e);
+                    return;
+                }
+                None => None,
+            };
+            match ElDorado::for_command("resample-candles").await {
+                Some(eld) => {
+                    let exchange = args.value_of("exchange").unwrap().to_lowercase();
+                    let name = args.value_of("market").unwrap();
+                    let markets = eld
+                        .storage
+                        .select_markets()
+                        .await
+                        .expect("Failed to select markets.");
+                    match markets
+                        .iter()
+                        .find(|m| m.exchange_name.as_str() == exchange && m.market_name == name)
+                    {
+                        Some(m) => {
+                            let session = session.unwrap_or_else(|| eld.sessions.session(m));
+                            let candles = eld.resample_archived_candles_for_session(
+                                m, &tf, &from, &to, &session,
+                            );
+                            let pb = eld.write_session_candles_to_file(
+                                m, &tf, &from, &to, &session, &candles,
+                            );
+                            println!("Wrote {} candles to {}.", candles.len(), pb.display());
+                        }
+                        None => println!("No valid market to resample."),
+                    }
+                }
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
+        Some("make-bars") => {
+            // Make the configured bars for the range from the daily trade archive files
+            let args = matches.subcommand_matches("make-bars").unwrap();
+            let (from, to) = match (
+                parse_repair_dt(args.value_of("from").unwrap()),
+                parse_repair_dt(args.value_of("to").unwrap()),
+            ) {
+                (Some(f), Some(t)) => (f, t),
+                _ => {
+                    println!("Please enter dates as YYYY-MM-DD.");
+                    return;
+                }
+            };
+            match ElDorado::for_command("make-bars").await {
+                Some(eld) => {
+                    if eld.bars.is_empty() {
+                        println!("No bars configured to make.");
+                        return;
+                    }
+                    let exchange = args.value_of("exchange").unwrap().to_lowercase();
+                    let name = args.value_of("market").unwrap();
+                    let markets = eld
+                        .storage
+                        .select_markets()
+                        .await
+                        .expect("Failed to select markets.");
+                    match markets
+                        .iter()
+                        .find(|m| m.exchange_name.as_str() == exchange && m.market_name == name)
+                    {
+                        Some(m) => match eld.make_bars_from_archive(m, &eld.bars, &from, &to).await
+                        {
+                            Ok(()) => (),
+                            Err(e) => println!("Could not make bars: {}", e),
+                        },
+                        None => println!("No valid market to make bars for."),
+                    }
+                }
+                None => println!("Could not create El Dorado instance."),
+            }
+        }
         // Some("monitor") => {
         //     // Create ig instance and review all existing active processes
         //     let ig = Inquisidor::new().await;
         //     ig.monitor().await;
         // }
-        _ => println!("Please run with subcommands: `run` `refresh` `stream` or `archive`."),
+        _ => {
+            println!("Please run with subcommands: `run` `refresh` `stream` `fill` `index` `archive` `synthetic` `repair-candles` `backfill-metrics` `recompute-metrics` `resample-candles` or `make-bars`.")
+        }
     }
 }
@@ -1,19 +1,20 @@
 use crate::{
     candles::ResearchCandle,
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::{gdax::Product, kraken::AssetPair, ExchangeName},
+    storage::Storage,
     trades::{PrIdTi, Trade},
     utilities::TimeFrame,
 };
 use chrono::{DateTime, Duration, DurationRound, Utc};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use uuid::Uuid;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct MarketDetail {
     pub market_id: Uuid,
     pub exchange_name: ExchangeName,
@@ -32,7 +33,7 @@ pub struct MarketDetail {
     pub asset_id: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 pub struct MarketTradeDetail {
     pub market_id: Uuid,
     pub market_start_ts: Option<DateTime<Utc>>,
@@ -46,7 +47,7 @@ pub struct MarketTradeDetail {
     pub next_status: Option<MarketDataStatus>,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 pub struct MarketCandleDetail {
     pub market_id: Uuid,
     pub exchange_name: ExchangeName,
@@ -59,7 +60,7 @@ pub struct MarketCandleDetail {
     pub last_trade_price: Decimal,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 pub struct MarketArchiveDetail {
     pub market_id: Uuid,
     pub exchange_name: ExchangeName,
@@ -82,8 +83,9 @@ impl MarketDetail {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, sqlx::Type)]
+#[derive(Debug, PartialEq, Eq, Clone, sqlx::Type, Serialize, Deserialize)]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum MarketStatus {
     // Market is new and has never been run
     New,
@@ -101,8 +103,9 @@ pub enum MarketStatus {
     Terminated,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum MarketDataStatus {
     // Data process is complete. No other action is needed.
     Completed,
@@ -128,8 +131,9 @@ impl MarketStatus {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum MarketType {
     Spot,
     Perpetual,
@@ -260,57 +264,47 @@ impl MarketDetail {
         Ok(())
     }
 
-    pub async fn update_gdax(&self, pool: &PgPool, product: &Product) -> Result<(), sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE markets
-            SET (base_step, base_min, quote_step) = ($1, $2, $3)
-            WHERE market_id = $4
-            "#,
-            product.base_increment,
-            product.min_market_funds,
-            product.quote_increment,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(())
+    pub async fn update_gdax(
+        &self,
+        storage: &dyn Storage,
+        product: &Product,
+    ) -> Result<Self, sqlx::Error> {
+        let market = Self {
+            base_step: Some(product.base_increment),
+            base_min: Some(product.min_market_funds),
+            quote_step: Some(product.quote_increment),
+            ..self.clone()
+        };
+        storage.update_market(&market).await?;
+        Ok(market)
     }
 
-    pub async fn update_kraken(&self, pool: &PgPool, ap: &AssetPair) -> Result<(), sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE markets
-            SET (base_step, base_min, quote_step) = ($1, $2, $3)
-            WHERE market_id = $4
-            "#,
-            ap.ordermin,
-            ap.ordermin,
-            ap.tick_size,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(())
+    pub async fn update_kraken(
+        &self,
+        storage: &dyn Storage,
+        ap: &AssetPair,
+    ) -> Result<Self, sqlx::Error> {
+        let market = Self {
+            base_step: ap.ordermin,
+            base_min: ap.ordermin,
+            quote_step: ap.tick_size,
+            ..self.clone()
+        };
+        storage.update_market(&market).await?;
+        Ok(market)
     }
 
     pub async fn update_last_candle(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         dt: &DateTime<Utc>,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE markets
-            SET last_candle  = $1
-            WHERE market_id = $2
-            "#,
-            dt,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(())
+    ) -> Result<Self, sqlx::Error> {
+        let market = Self {
+            last_candle: Some(*dt),
+            ..self.clone()
+        };
+        storage.update_market(&market).await?;
+        Ok(market)
     }
 
     pub async fn select_all(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
@@ -583,21 +577,10 @@ impl MarketTradeDetail {
 
     pub async fn update_prev_status(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         status: &MarketDataStatus,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET previous_status = $1
-            WHERE market_id = $2
-            "#,
-            status.as_str(),
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts: self.first_trade_ts,
@@ -608,26 +591,17 @@ impl MarketTradeDetail {
             previous_status: *status,
             next_trade_day: self.next_trade_day,
             next_status: self.next_status,
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn update_next_status(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         status: &MarketDataStatus,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET next_status = $1
-            WHERE market_id = $2
-            "#,
-            status.as_str(),
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts: self.first_trade_ts,
@@ -638,28 +612,18 @@ impl MarketTradeDetail {
             previous_status: self.previous_status,
             next_trade_day: self.next_trade_day,
             next_status: Some(*status),
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn update_prev_day_prev_status(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         datetime: &DateTime<Utc>,
         status: &MarketDataStatus,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET (previous_trade_day, previous_status) = ($1, $2)
-            WHERE market_id = $3
-            "#,
-            datetime,
-            status.as_str(),
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts: self.first_trade_ts,
@@ -670,28 +634,18 @@ impl MarketTradeDetail {
             previous_status: *status,
             next_trade_day: self.next_trade_day,
             next_status: self.next_status,
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn update_next_day_next_status(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         datetime: &DateTime<Utc>,
         status: &MarketDataStatus,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET (next_trade_day, next_status) = ($1, $2)
-            WHERE market_id = $3
-            "#,
-            datetime,
-            status.as_str(),
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts: self.first_trade_ts,
@@ -702,30 +656,18 @@ impl MarketTradeDetail {
             previous_status: self.previous_status,
             next_trade_day: Some(*datetime),
             next_status: Some(*status),
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn update_first_trade(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         datetime: &DateTime<Utc>,
         trade_id: &str,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET (first_trade_ts, first_trade_id, last_trade_ts, last_trade_id) = ($1, $2, $3, $4)
-            WHERE market_id = $5
-            "#,
-            datetime,
-            trade_id,
-            datetime,
-            trade_id,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts: *datetime,
@@ -736,12 +678,14 @@ impl MarketTradeDetail {
             previous_status: self.previous_status,
             next_trade_day: self.next_trade_day,
             next_status: self.next_status,
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn update_first_and_last_trades(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         first: &dyn Trade,
         last: &dyn Trade,
     ) -> Result<Self, sqlx::Error> {
@@ -749,21 +693,7 @@ impl MarketTradeDetail {
         let first_trade_id = first
             .trade_id()
             .min(self.first_trade_id.parse::<i64>().unwrap());
-        sqlx::query!(
-            r#"
-            UPDATE market_trade_details
-            SET (first_trade_ts, first_trade_id, last_trade_ts, last_trade_id) = ($1, $2, $3, $4)
-            WHERE market_id = $5
-            "#,
-            first_trade_ts,
-            first_trade_id.to_string(),
-            last.time(),
-            last.trade_id().to_string(),
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mtd = Self {
             market_id: self.market_id,
             market_start_ts: self.market_start_ts,
             first_trade_ts,
@@ -774,7 +704,9 @@ impl MarketTradeDetail {
             previous_status: self.previous_status,
             next_trade_day: self.next_trade_day,
             next_status: self.next_status,
-        })
+        };
+        storage.update_mtd(&mtd).await?;
+        Ok(mtd)
     }
 
     pub async fn select_all(pool: &PgPool) -> Result<Vec<MarketTradeDetail>, sqlx::Error> {
@@ -860,24 +792,10 @@ impl MarketCandleDetail {
 
     pub async fn update_last(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         candle: &ResearchCandle,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_candle_details
-            SET (last_candle, last_trade_ts, last_trade_id, last_trade_price) = ($1, $2, $3, $4)
-            WHERE market_id = $5
-            "#,
-            candle.datetime,
-            candle.last_trade_ts,
-            candle.last_trade_id,
-            candle.close,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mcd = Self {
             market_id: self.market_id,
             exchange_name: self.exchange_name,
             market_name: self.market_name.clone(),
@@ -887,7 +805,9 @@ impl MarketCandleDetail {
             last_trade_ts: candle.last_trade_ts,
             last_trade_id: candle.last_trade_id.clone(),
             last_trade_price: candle.close,
-        })
+        };
+        storage.update_mcd(&mcd).await?;
+        Ok(mcd)
     }
 
     pub async fn select_all(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
@@ -1039,26 +959,11 @@ impl MarketArchiveDetail {
 
     pub async fn update(
         &self,
-        pool: &PgPool,
+        storage: &dyn Storage,
         next_month: &DateTime<Utc>,
         last_candle: &ResearchCandle,
     ) -> Result<Self, sqlx::Error> {
-        sqlx::query!(
-            r#"
-            UPDATE market_archive_details
-            SET (last_candle_dt, last_trade_dt, last_trade_price, last_trade_id, next_month) = ($1, $2, $3, $4, $5)
-            WHERE market_id = $6
-            "#,
-            last_candle.datetime,
-            last_candle.last_trade_ts,
-            last_candle.close,
-            last_candle.last_trade_id,
-            next_month,
-            self.market_id,
-        )
-        .execute(pool)
-        .await?;
-        Ok(Self {
+        let mad = Self {
             market_id: self.market_id,
             exchange_name: self.exchange_name,
             market_name: self.market_name.clone(),
@@ -1072,7 +977,9 @@ impl MarketArchiveDetail {
             last_trade_price: last_candle.close,
             last_trade_id: last_candle.last_trade_id.clone(),
             next_month: *next_month,
-        })
+        };
+        storage.update_mad(&mad).await?;
+        Ok(mad)
     }
 }
 
@@ -1099,18 +1006,31 @@ impl ElDorado {
         (markets, market_ids)
     }
 
+    pub async fn select_markets_by_exchange(
+        &self,
+        exchange: &ExchangeName,
+    ) -> Result<Vec<MarketDetail>, sqlx::Error> {
+        let markets = self.storage.select_markets().await?;
+        Ok(markets
+            .into_iter()
+            .filter(|m| m.exchange_name == *exchange)
+            .collect())
+    }
+
     pub async fn select_markets_eligible_for_fill(
         &self,
     ) -> Result<Option<Vec<MarketDetail>>, ElDoradoError> {
         // Eligibility based on active status and existing last candle
         // Select active markets from eldorado db
-        let markets =
-            MarketDetail::select_by_status(&self.pools[&Database::ElDorado], &MarketStatus::Active)
-                .await?;
+        let markets = self.storage.select_markets().await?;
         // Filter for markets with a last candle
         let eligible_markets: Vec<MarketDetail> = markets
             .into_iter()
-            .filter(|m| m.last_candle.is_some() && m.exchange_name == ExchangeName::Gdax)
+            .filter(|m| {
+                m.status == MarketStatus::Active
+                    && m.last_candle.is_some()
+                    && m.exchange_name == ExchangeName::Gdax
+            })
             .collect();
         if !eligible_markets.is_empty() {
             Ok(Some(eligible_markets))
@@ -1124,12 +1044,18 @@ impl ElDorado {
     ) -> Result<Option<Vec<MarketDetail>>, ElDoradoError> {
         // Eligibility based on if there is a market candle detail record created for market
         // Select markets with market candle detail
-        let markets =
-            MarketDetail::select_all_join_candle_detail(&self.pools[&Database::ElDorado]).await?;
+        let mcd_ids: Vec<Uuid> = self
+            .storage
+            .select_mcds()
+            .await?
+            .iter()
+            .map(|mcd| mcd.market_id)
+            .collect();
+        let markets = self.storage.select_markets().await?;
         // Filter for markets that are currently active
         let eligible_markets: Vec<MarketDetail> = markets
             .into_iter()
-            .filter(|m| m.status == MarketStatus::Active)
+            .filter(|m| m.status == MarketStatus::Active && mcd_ids.contains(&m.market_id))
             .collect();
         if !eligible_markets.is_empty() {
             Ok(Some(eligible_markets))
@@ -1143,7 +1069,7 @@ impl ElDorado {
         market: &MarketDetail,
     ) -> Result<MarketTradeDetail, ElDoradoError> {
         // Try selecting record from database
-        match MarketTradeDetail::select(&self.pools[&Database::ElDorado], market).await {
+        match self.storage.select_mtd(market).await {
             Ok(mtd) => Ok(mtd),
             Err(sqlx::Error::RowNotFound) => {
                 // First get the first for the market - either production candle or 01d candle if
@@ -1151,7 +1077,7 @@ impl ElDorado {
                 match self.select_first_eld_trade_as_pridti(market).await? {
                     Some(p) => {
                         let mtd = MarketTradeDetail::new(market, &p).await;
-                        mtd.insert(&self.pools[&Database::ElDorado]).await?;
+                        self.storage.insert_mtd(&mtd).await?;
                         Ok(mtd)
                     }
                     None => panic!("No first candle to make market trade detail."),
@@ -1174,7 +1100,9 @@ impl ElDorado {
             Some(ms) => ms.iter().cloned().find(|m| m.market_name == market),
             None => {
                 // Select all markets and then filter for match
-                let all_markets = MarketDetail::select_all(&self.pools[&Database::ElDorado])
+                let all_markets = self
+                    .storage
+                    .select_markets()
                     .await
                     .expect("Failed to select all markets.");
                 all_markets.into_iter().find(|m| m.market_name == market)
@@ -1191,10 +1119,10 @@ impl ElDorado {
         // Filter for USD markets - iterates twice for usd filter and match. TODO: combine in one
         markets.retain(|m| m.quote_currency == *"USD");
         // Get markets from el dorado db
-        let db_markets =
-            MarketDetail::select_by_exchange(&self.pools[&Database::ElDorado], &ExchangeName::Gdax)
-                .await
-                .expect("Failed to select markets from db.");
+        let db_markets = self
+            .select_markets_by_exchange(&ExchangeName::Gdax)
+            .await
+            .expect("Failed to select markets from db.");
         let mut market_map = HashMap::new();
         for db_market in db_markets.iter() {
             market_map.insert(db_market.market_name.clone(), db_market.clone());
@@ -1202,15 +1130,16 @@ impl ElDorado {
         // If the rest api market is not in the db, add to db
         for market in markets.iter() {
             match market_map.get(&market.id) {
-                Some(m) => m
-                    .update_gdax(&self.pools[&Database::ElDorado], market)
-                    .await
-                    .expect("Failed to update market."),
+                Some(m) => {
+                    m.update_gdax(self.storage.as_ref(), market)
+                        .await
+                        .expect("Failed to update market.");
+                }
                 None => {
                     println!("Adding {:?} market for Gdax", market.id);
                     let new_market = MarketDetail::new_from_gdax_product(market);
-                    new_market
-                        .insert(&self.pools[&Database::ElDorado])
+                    self.storage
+                        .insert_market(&new_market)
                         .await
                         .expect("Failed to insert market.");
                 }
@@ -1227,12 +1156,10 @@ impl ElDorado {
         // Filter for usd markest
         markets.retain(|_, v| v.quote == *"ZUSD");
         // Get markets from db
-        let db_markets = MarketDetail::select_by_exchange(
-            &self.pools[&Database::ElDorado],
-            &ExchangeName::Kraken,
-        )
-        .await
-        .expect("Failed to select markets from db.");
+        let db_markets = self
+            .select_markets_by_exchange(&ExchangeName::Kraken)
+            .await
+            .expect("Failed to select markets from db.");
         let mut market_map = HashMap::new();
         for db_market in db_markets.iter() {
             market_map.insert(db_market.market_name.clone(), db_market.clone());
@@ -1240,15 +1167,16 @@ impl ElDorado {
         // If the market from api is not in the db - add it, otherwise update it
         for (market, ap) in markets.iter() {
             match market_map.get(market) {
-                Some(m) => m
-                    .update_kraken(&self.pools[&Database::ElDorado], ap)
-                    .await
-                    .expect("Failed to update market."),
+                Some(m) => {
+                    m.update_kraken(self.storage.as_ref(), ap)
+                        .await
+                        .expect("Failed to update market.");
+                }
                 None => {
                     println!("Adding {:?} market for Kraken", market);
                     let new_market = MarketDetail::new_from_kraken_ap(market, ap);
-                    new_market
-                        .insert(&self.pools[&Database::ElDorado])
+                    self.storage
+                        .insert_market(&new_market)
                         .await
                         .expect("Failed to insert market.");
                 }
@@ -1269,7 +1197,7 @@ mod tests {
         println!("Configuration: {:?}", configuration);
 
         // Create db connection
-        let pool = PgPool::connect_with(configuration.ftx_db.with_db())
+        let pool = PgPool::connect_with(configuration.databases["ftx"].with_db())
             .await
             .expect("Failed to connect to Postgres.");
 
@@ -2,8 +2,8 @@ use std::{cmp::Ordering, collections::HashMap, convert::TryFrom, fs::File, path:
 
 use crate::{
     candles::ProductionCandle,
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
+    indicators::{series, IndicatorSpec, IndicatorValues},
     markets::MarketDetail,
     mita::Heartbeat,
     utilities::TimeFrame,
@@ -16,7 +16,32 @@ use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
 
+// Version of the metric definitions. Bump it when the metric math changes so the metrics
+// calculated under each version can be told apart and compared side by side.
+pub const METRIC_VERSION: i32 = 1;
+
+// Donchian ranges of the research metrics
+pub const DONS: [i32; 7] = [4, 8, 16, 32, 64, 128, 256];
+
 impl TimeFrame {
+    // Hash of the parameters the metrics of the time frame are calculated with, so a change to the
+    // lookbacks or ranges shows in the lineage even without a version bump. FNV-1a as the std
+    // hashers are not stable across releases.
+    pub fn metric_param_hash(&self) -> String {
+        let params = format!(
+            "lbps={:?};lbp_l={};lbp_s={};max_len={};dons={:?}",
+            self.lbps(),
+            self.lbp_l(),
+            self.lbp_s(),
+            self.max_len(),
+            DONS
+        );
+        let hash = params.bytes().fold(0xcbf29ce484222325_u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}", hash)
+    }
+
     pub fn lbps(&self) -> [i64; 3] {
         match self {
             TimeFrame::T15 => [672, 2880, 8640],
@@ -24,12 +49,17 @@ impl TimeFrame {
             TimeFrame::H04 => [42, 180, 540],
             TimeFrame::H12 => [14, 60, 180],
             TimeFrame::D01 => [7, 30, 90],
+            TimeFrame::W01 => [4, 13, 52],
+            TimeFrame::M01 => [3, 6, 12],
+            TimeFrame::Custom(_) => self.standard_floor().lbps(),
             _ => [0, 0, 0], // To be defined
         }
     }
 
     pub fn lbp_l(&self) -> i64 {
         match self {
+            TimeFrame::M01 => 24,
+            TimeFrame::W01 => 52,
             TimeFrame::D01 => 90,
             TimeFrame::H12 => 90,
             TimeFrame::H08 => 90,
@@ -45,11 +75,14 @@ impl TimeFrame {
             TimeFrame::T01 => 630,
             TimeFrame::S30 => 360,
             TimeFrame::S15 => 270,
+            TimeFrame::Custom(_) => self.standard_floor().lbp_l(),
         }
     }
 
     pub fn lbp_s(&self) -> i64 {
         match self {
+            TimeFrame::M01 => 6,
+            TimeFrame::W01 => 8,
             TimeFrame::D01 => 10,
             TimeFrame::H12 => 14,
             TimeFrame::H08 => 21,
@@ -65,6 +98,7 @@ impl TimeFrame {
             TimeFrame::T01 => 60,
             TimeFrame::S30 => 60,
             TimeFrame::S15 => 90,
+            TimeFrame::Custom(_) => self.standard_floor().lbp_s(),
         }
     }
 
@@ -75,10 +109,27 @@ impl TimeFrame {
             TimeFrame::H04 => 582,
             TimeFrame::H12 => 194,
             TimeFrame::D01 => 97,
+            TimeFrame::W01 => 56,
+            TimeFrame::M01 => 27,
+            TimeFrame::Custom(_) => self.standard_floor().max_len(),
             _ => 1, // To be defined
         }
     }
 
+    // Time the max_len candles of the time frame span, with months at their longest
+    pub fn history_dur(&self) -> Duration {
+        match self {
+            TimeFrame::M01 => Duration::days(31 * self.max_len()),
+            _ => Duration::seconds(self.as_secs() * self.max_len()),
+        }
+    }
+
+    // Time frames whose lookbacks span more than the sync days. Their candles before the sync are
+    // read from the archive and they keep max_len candles when the heartbeat is trimmed.
+    pub fn loads_archive_history(&self) -> bool {
+        matches!(self, TimeFrame::W01 | TimeFrame::M01 | TimeFrame::Custom(_))
+    }
+
     pub fn resample_from(&self) -> TimeFrame {
         match self {
             TimeFrame::S15 => TimeFrame::S15,
@@ -96,10 +147,41 @@ impl TimeFrame {
             TimeFrame::H08 => TimeFrame::H04,
             TimeFrame::H12 => TimeFrame::H04,
             TimeFrame::D01 => TimeFrame::H12,
+            TimeFrame::W01 => TimeFrame::D01,
+            TimeFrame::M01 => TimeFrame::D01,
+            // Longest standard metric tf that the custom tf is divisible by
+            TimeFrame::Custom(secs) => TimeFrame::tfs()
+                .into_iter()
+                .rev()
+                .find(|tf| {
+                    !matches!(tf, TimeFrame::Custom(_) | TimeFrame::W01 | TimeFrame::M01)
+                        && tf.as_secs() < *secs as i64
+                        && *secs as i64 % tf.as_secs() == 0
+                })
+                .unwrap_or(TimeFrame::T15),
         }
     }
+
+    // Longest standard metric tf no longer than the custom tf. Custom tfs use its lookbacks.
+    fn standard_floor(&self) -> TimeFrame {
+        [
+            TimeFrame::T15,
+            TimeFrame::H01,
+            TimeFrame::H04,
+            TimeFrame::H12,
+            TimeFrame::D01,
+        ]
+        .iter()
+        .copied()
+        .rev()
+        .find(|tf| tf.as_secs() <= self.as_secs())
+        .unwrap_or(TimeFrame::T15)
+    }
 }
 
+// Research metric columns for the signal rules, screens, as-of queries and archived metric files.
+// The z scores, atrs and ranges are read from the built in metric indicators, which are also
+// stored by key in indicator_values with the configured indicators.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ResearchMetric {
     pub market_id: Uuid,
@@ -173,6 +255,12 @@ pub struct ResearchMetric {
     pub low64: Option<Decimal>,
     pub low128: Option<Decimal>,
     pub low256: Option<Decimal>,
+    // Metric definition the values were calculated under. Metrics from before the versioning
+    // have version 0 and no param hash.
+    #[serde(default)]
+    pub version: i32,
+    #[serde(default)]
+    pub param_hash: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, sqlx::Type, Eq, PartialEq)]
@@ -253,6 +341,44 @@ impl Metric {
         ewma
     }
 
+    pub fn ewma_series(v: &[Decimal], lbp: i64) -> Vec<Decimal> {
+        // Ewma as of each item, the last item is the same as ewma
+        let k = dec!(2) / (Decimal::from_i64(lbp).unwrap() + Decimal::ONE);
+        let ik = dec!(1.0) - k;
+        let mut ewmas: Vec<Decimal> = Vec::with_capacity(v.len());
+        for (i, x) in v.iter().enumerate() {
+            match i {
+                0 => ewmas.push(*x),
+                _ => ewmas.push(x * k + ewmas[i - 1] * ik),
+            }
+        }
+        ewmas
+    }
+
+    pub fn wilder_series(v: &[Decimal], lbp: usize) -> Vec<Decimal> {
+        // Wilder smoothing seeded with the average of the first lbp items, as of each item from
+        // the lbp item. Empty if there are fewer items than the lbp.
+        if lbp == 0 || v.len() < lbp {
+            return Vec::new();
+        }
+        let n = Decimal::from(lbp);
+        let mut smoothed = vec![v[..lbp].iter().sum::<Decimal>() / n];
+        for x in v[lbp..].iter() {
+            let prev = smoothed[smoothed.len() - 1];
+            smoothed.push((prev * (n - Decimal::ONE) + x) / n);
+        }
+        smoothed
+    }
+
+    pub fn sd(v: &[Decimal]) -> Decimal {
+        // Population standard deviation
+        let n = Decimal::from(v.len());
+        let mean = v.iter().sum::<Decimal>().checked_div(n).unwrap_or(Decimal::ZERO);
+        (v.iter().fold(dec!(0), |s, x| s + (mean - x).powi(2)) / n.max(Decimal::ONE))
+            .sqrt()
+            .unwrap_or(Decimal::ZERO)
+    }
+
     pub fn z(v: &[Decimal], rs: usize, re: usize) -> Decimal {
         // Calculate the deviation mulitple of the last item in candle
         // to standard deviation of the period
@@ -310,171 +436,22 @@ impl Metric {
 
 impl ResearchMetric {
     pub fn new(market: &MarketDetail, tf: TimeFrame, candles: &[ProductionCandle]) -> Self {
-        let n = candles.len();
-        let n_i64 = n as i64;
-        let datetime = candles[n - 1].datetime;
-        // Iterate through candles and return Vecs of Decimals to use for z calcs
-        let vecs = candles.iter().fold(
-            (
-                Decimal::ZERO, // vecs.0 close
-                Vec::new(),    // vecs.1 [close] - to be used for emas
-                Vec::new(),    // vecs.2 [high] - to be used for dons
-                Vec::new(),    // vecs.3 [low] - to be used for dons
-                Vec::new(),    // vecs.4 [volume] - to be used for z
-                Vec::new(),    // vecs.5 [return] - to be used for z
-                Vec::new(),    // vecs.6 [tr] - to be used for z
-                Vec::new(),    // vecs.7 [upper_wick]
-                Vec::new(),    // vecs.8 [body]
-                Vec::new(),    // vecs.9 [lower_wick]
-                Vec::new(),    // vecs.10 [volume_net]
-                Vec::new(),    // vecs.11 [volume_pct]
-                Vec::new(),    // vecs.12 [volume_liq]
-                Vec::new(),    // vecs.13 [volume_liq_net]
-                Vec::new(),    // vecs.14 [volume_liq_pct]
-                Vec::new(),    // vecs.15 [value]
-                Vec::new(),    // vecs.16 [Value_net]
-                Vec::new(),    // vecs.17 [value_pct]
-                Vec::new(),    // vecs.18 [value_liq]
-                Vec::new(),    // vecs.19 [value_liq_net]
-                Vec::new(),    // vecs.20 [value_liq_pct]
-                Vec::new(),    // vecs.21 [trade_count]
-                Vec::new(),    // vecs.22 [trade_count_net]
-                Vec::new(),    // vecs.23 [trade_count_pct]
-                Vec::new(),    // vecs.24 [liq_count]
-                Vec::new(),    // vecs.25 [liq_count_net]
-                Vec::new(),    // vecs.26 [liq_count_pct]
-                Decimal::ZERO, // vecs.27 high
-                Decimal::ZERO, // vecs.28 low
-            ),
-            |(
-                c,
-                mut vc,
-                mut vh,
-                mut vl,
-                mut vv,
-                mut vr,
-                mut vtr,
-                mut vuw,
-                mut vbod,
-                mut vlw,
-                mut vvnet,
-                mut vvpct,
-                mut vvl,
-                mut vvlnet,
-                mut vvlpct,
-                mut vva,
-                mut vvanet,
-                mut vvapct,
-                mut vval,
-                mut vvalnet,
-                mut vvalpct,
-                mut vtc,
-                mut vtcnet,
-                mut vtcpct,
-                mut vlc,
-                mut vlcnet,
-                mut vlcpct,
-                _h,
-                _l,
-            ),
-             can| {
-                // Map close, high low etc to their vecs
-                vc.push(can.close);
-                vh.push(can.high);
-                vl.push(can.low);
-                vv.push(can.volume);
-                // Calc return as current candle close / prev candle close - 1
-                let r = if !c.is_zero() {
-                    can.close / c - Decimal::ONE
-                } else {
-                    c
-                };
-                vr.push(r);
-                // Calc tr
-                let hl = can.high - can.low;
-                let tr = if !c.is_zero() {
-                    let hpdc = (can.high - c).abs();
-                    let pdcl = (c - can.low).abs();
-                    hl.max(hpdc).max(pdcl)
-                } else {
-                    hl
-                };
-                vtr.push(tr);
-                // Calc wicks
-                vuw.push(
-                    (can.high - can.open.max(can.close))
-                        .checked_div(hl)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                vbod.push(
-                    (can.open - can.close)
-                        .abs()
-                        .checked_div(hl)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                vlw.push(
-                    (can.open.min(can.close) - can.low)
-                        .checked_div(hl)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                // Volume
-                vvnet.push(can.volume_buy - can.volume_sell);
-                vvpct.push(
-                    can.volume_buy
-                        .checked_div(can.volume)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                // Volume Liq
-                vvl.push(can.volume_liq);
-                vvlnet.push(can.volume_liq_buy - can.volume_liq_sell);
-                vvlpct.push(
-                    can.volume_liq_buy
-                        .checked_div(can.volume_liq)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                // Value
-                vva.push(can.value);
-                vvanet.push(can.value_buy - can.value_sell);
-                vvapct.push(
-                    can.value_buy
-                        .checked_div(can.value)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                // Value Liq
-                vval.push(can.value_liq);
-                vvalnet.push(can.value_liq_buy - can.value_liq_sell);
-                vvalpct.push(
-                    can.value_liq_buy
-                        .checked_div(can.value_liq)
-                        .unwrap_or(Decimal::ZERO),
-                );
-                // Trade Count
-                vtc.push(Decimal::from_i64(can.trade_count).unwrap());
-                vtcnet.push(Decimal::from_i64(can.trade_count_buy - can.trade_count_sell).unwrap());
-                vtcpct.push(
-                    Decimal::from_i64(
-                        can.trade_count_buy
-                            .checked_div(can.trade_count)
-                            .unwrap_or(0),
-                    )
-                    .unwrap(),
-                );
-                // Trade Count Liq
-                vlc.push(Decimal::from_i64(can.liq_count).unwrap());
-                vlcnet.push(Decimal::from_i64(can.liq_count_buy - can.liq_count_sell).unwrap());
-                vlcpct.push(
-                    Decimal::from_i64(can.liq_count_buy.checked_div(can.liq_count).unwrap_or(0))
-                        .unwrap(),
-                );
-                (
-                    can.close, vc, vh, vl, vv, vr, vtr, vuw, vbod, vlw, vvnet, vvpct, vvl, vvlnet,
-                    vvlpct, vva, vvanet, vvapct, vval, vvalnet, vvalpct, vtc, vtcnet, vtcpct, vlc,
-                    vlcnet, vlcpct, can.high, can.low,
-                )
-            },
-        );
+        let last = &candles[candles.len() - 1];
+        // Z scores, atrs and ranges are the values of the built in metric indicators
+        let indicators: Vec<_> = IndicatorSpec::metrics()
+            .iter()
+            .filter_map(|spec| spec.build(&tf).ok())
+            .collect();
+        let values = IndicatorValues::new(market, tf, candles, &[], &indicators)
+            .unwrap()
+            .values;
+        let value = |key: String| values.get(&key).copied().flatten();
+        let z = |field: &str, lbp: &str| {
+            value(format!("zscore_{}_{}", field, lbp)).unwrap_or(Decimal::ZERO)
+        };
+        let don = |range: i32, output: &str| value(format!("donchian_close_{}.{}", range, output));
         // Set filters
-        let direction = match vecs.5.last() {
+        let direction = match series("return", candles).unwrap().last() {
             Some(r) => match r.cmp(&Decimal::ZERO) {
                 Ordering::Less => MetricDirection::Down,
                 Ordering::Equal => MetricDirection::NC,
@@ -482,154 +459,95 @@ impl ResearchMetric {
             },
             None => MetricDirection::NC,
         };
-        let ma_l = Metric::ewma(&vecs.1, tf.lbp_l());
-        let ma_s = Metric::ewma(&vecs.1, tf.lbp_s());
+        let closes: Vec<Decimal> = candles.iter().map(|c| c.close).collect();
+        let ma_l = Metric::ewma(&closes, tf.lbp_l());
+        let ma_s = Metric::ewma(&closes, tf.lbp_s());
         let ma_filter = match ma_l.cmp(&ma_s) {
             Ordering::Greater => MetricFilter::LS,
             Ordering::Less => MetricFilter::SL,
             Ordering::Equal => MetricFilter::Equal,
         };
-        // Set atrs
-        let atr_l = Metric::ewma(&vecs.6, tf.lbp_l()).round_dp(8);
-        let atr_s = Metric::ewma(&vecs.6, tf.lbp_s()).round_dp(8);
+        let atr_l = value("atr_l".to_string()).unwrap_or(Decimal::ZERO);
+        let atr_s = value("atr_s".to_string()).unwrap_or(Decimal::ZERO);
         let atr_filter = match atr_l.cmp(&atr_s) {
             Ordering::Greater => MetricFilter::LS,
             Ordering::Less => MetricFilter::SL,
             Ordering::Equal => MetricFilter::Equal,
         };
-        // Set slice ranges
-        let range_start_l = if n_i64.ge(&tf.lbp_l()) {
-            n - tf.lbp_l() as usize
-        } else {
-            usize::MIN
-        };
-        let range_start_s = if n_i64.ge(&tf.lbp_s()) {
-            n - tf.lbp_s() as usize
-        } else {
-            usize::MIN
-        };
-        let range_end = n;
-        // Set z scores
-        let return_z_l = Metric::z(&vecs.5, range_start_l, range_end).round_dp(4);
-        let return_z_s = Metric::z(&vecs.5, range_start_s, range_end).round_dp(4);
-        let tr_z_l = Metric::z(&vecs.6, range_start_l, range_end).round_dp(4);
-        let tr_z_s = Metric::z(&vecs.6, range_start_s, range_end).round_dp(4);
-        let upper_wick_z_l = Metric::z(&vecs.7, range_start_l, range_end).round_dp(4);
-        let upper_wick_z_s = Metric::z(&vecs.7, range_start_s, range_end).round_dp(4);
-        let body_z_l = Metric::z(&vecs.8, range_start_l, range_end).round_dp(4);
-        let body_z_s = Metric::z(&vecs.8, range_start_s, range_end).round_dp(4);
-        let lower_wick_z_l = Metric::z(&vecs.9, range_start_l, range_end).round_dp(4);
-        let lower_wick_z_s = Metric::z(&vecs.9, range_start_s, range_end).round_dp(4);
-        let volume_z_l = Metric::z(&vecs.4, range_start_l, range_end).round_dp(4);
-        let volume_z_s = Metric::z(&vecs.4, range_start_s, range_end).round_dp(4);
-        let volume_net_z_l = Metric::z(&vecs.10, range_start_l, range_end).round_dp(4);
-        let volume_net_z_s = Metric::z(&vecs.10, range_start_s, range_end).round_dp(4);
-        let volume_pct_z_l = Metric::z(&vecs.11, range_start_l, range_end).round_dp(4);
-        let volume_pct_z_s = Metric::z(&vecs.11, range_start_s, range_end).round_dp(4);
-        let volume_liq_z_l = Metric::z(&vecs.12, range_start_l, range_end).round_dp(4);
-        let volume_liq_z_s = Metric::z(&vecs.12, range_start_s, range_end).round_dp(4);
-        let volume_liq_net_z_l = Metric::z(&vecs.13, range_start_l, range_end).round_dp(4);
-        let volume_liq_net_z_s = Metric::z(&vecs.13, range_start_s, range_end).round_dp(4);
-        let volume_liq_pct_z_l = Metric::z(&vecs.14, range_start_l, range_end).round_dp(4);
-        let volume_liq_pct_z_s = Metric::z(&vecs.14, range_start_s, range_end).round_dp(4);
-        let value_z_l = Metric::z(&vecs.15, range_start_l, range_end).round_dp(4);
-        let value_z_s = Metric::z(&vecs.15, range_start_s, range_end).round_dp(4);
-        let value_net_z_l = Metric::z(&vecs.16, range_start_l, range_end).round_dp(4);
-        let value_net_z_s = Metric::z(&vecs.16, range_start_s, range_end).round_dp(4);
-        let value_pct_z_l = Metric::z(&vecs.17, range_start_l, range_end).round_dp(4);
-        let value_pct_z_s = Metric::z(&vecs.17, range_start_s, range_end).round_dp(4);
-        let value_liq_z_l = Metric::z(&vecs.18, range_start_l, range_end).round_dp(4);
-        let value_liq_z_s = Metric::z(&vecs.18, range_start_s, range_end).round_dp(4);
-        let value_liq_net_z_l = Metric::z(&vecs.19, range_start_l, range_end).round_dp(4);
-        let value_liq_net_z_s = Metric::z(&vecs.19, range_start_s, range_end).round_dp(4);
-        let value_liq_pct_z_l = Metric::z(&vecs.20, range_start_l, range_end).round_dp(4);
-        let value_liq_pct_z_s = Metric::z(&vecs.20, range_start_s, range_end).round_dp(4);
-        let trade_count_z_l = Metric::z(&vecs.21, range_start_l, range_end).round_dp(4);
-        let trade_count_z_s = Metric::z(&vecs.21, range_start_s, range_end).round_dp(4);
-        let trade_count_net_z_l = Metric::z(&vecs.22, range_start_l, range_end).round_dp(4);
-        let trade_count_net_z_s = Metric::z(&vecs.22, range_start_s, range_end).round_dp(4);
-        let trade_count_pct_z_l = Metric::z(&vecs.23, range_start_l, range_end).round_dp(4);
-        let trade_count_pct_z_s = Metric::z(&vecs.23, range_start_s, range_end).round_dp(4);
-        let liq_count_z_l = Metric::z(&vecs.24, range_start_l, range_end).round_dp(4);
-        let liq_count_z_s = Metric::z(&vecs.24, range_start_s, range_end).round_dp(4);
-        let liq_count_net_z_l = Metric::z(&vecs.25, range_start_l, range_end).round_dp(4);
-        let liq_count_net_z_s = Metric::z(&vecs.25, range_start_s, range_end).round_dp(4);
-        let liq_count_pct_z_l = Metric::z(&vecs.26, range_start_l, range_end).round_dp(4);
-        let liq_count_pct_z_s = Metric::z(&vecs.26, range_start_s, range_end).round_dp(4);
-        // Calc dons
-        let dons = Metric::dons(&[4, 8, 16, 32, 64, 128, 256], &vecs.1);
         Self {
             market_id: market.market_id,
             tf,
-            datetime,
-            high: vecs.27,
-            low: vecs.28,
-            close: vecs.0,
+            datetime: last.datetime,
+            high: last.high,
+            low: last.low,
+            close: last.close,
             atr_l,
             atr_s,
             ma_filter,
             atr_filter,
             direction,
-            return_z_l,
-            return_z_s,
-            tr_z_l,
-            tr_z_s,
-            upper_wick_z_l,
-            upper_wick_z_s,
-            body_z_l,
-            body_z_s,
-            lower_wick_z_l,
-            lower_wick_z_s,
-            volume_z_l,
-            volume_z_s,
-            volume_net_z_l,
-            volume_net_z_s,
-            volume_pct_z_l,
-            volume_pct_z_s,
-            volume_liq_z_l,
-            volume_liq_z_s,
-            volume_liq_net_z_l,
-            volume_liq_net_z_s,
-            volume_liq_pct_z_l,
-            volume_liq_pct_z_s,
-            value_z_l,
-            value_z_s,
-            value_net_z_l,
-            value_net_z_s,
-            value_pct_z_l,
-            value_pct_z_s,
-            value_liq_z_l,
-            value_liq_z_s,
-            value_liq_net_z_l,
-            value_liq_net_z_s,
-            value_liq_pct_z_l,
-            value_liq_pct_z_s,
-            trade_count_z_l,
-            trade_count_z_s,
-            trade_count_net_z_l,
-            trade_count_net_z_s,
-            trade_count_pct_z_l,
-            trade_count_pct_z_s,
-            liq_count_z_l,
-            liq_count_z_s,
-            liq_count_net_z_l,
-            liq_count_net_z_s,
-            liq_count_pct_z_l,
-            liq_count_pct_z_s,
-            high4: dons[0],
-            low4: dons[1],
-            high8: dons[2],
-            low8: dons[3],
-            high16: dons[4],
-            low16: dons[5],
-            high32: dons[6],
-            low32: dons[7],
-            high64: dons[8],
-            low64: dons[9],
-            high128: dons[10],
-            low128: dons[11],
-            high256: dons[12],
-            low256: dons[13],
+            return_z_l: z("return", "l"),
+            return_z_s: z("return", "s"),
+            tr_z_l: z("tr", "l"),
+            tr_z_s: z("tr", "s"),
+            upper_wick_z_l: z("upper_wick", "l"),
+            upper_wick_z_s: z("upper_wick", "s"),
+            body_z_l: z("body", "l"),
+            body_z_s: z("body", "s"),
+            lower_wick_z_l: z("lower_wick", "l"),
+            lower_wick_z_s: z("lower_wick", "s"),
+            volume_z_l: z("volume", "l"),
+            volume_z_s: z("volume", "s"),
+            volume_net_z_l: z("volume_net", "l"),
+            volume_net_z_s: z("volume_net", "s"),
+            volume_pct_z_l: z("volume_pct", "l"),
+            volume_pct_z_s: z("volume_pct", "s"),
+            volume_liq_z_l: z("volume_liq", "l"),
+            volume_liq_z_s: z("volume_liq", "s"),
+            volume_liq_net_z_l: z("volume_liq_net", "l"),
+            volume_liq_net_z_s: z("volume_liq_net", "s"),
+            volume_liq_pct_z_l: z("volume_liq_pct", "l"),
+            volume_liq_pct_z_s: z("volume_liq_pct", "s"),
+            value_z_l: z("value", "l"),
+            value_z_s: z("value", "s"),
+            value_net_z_l: z("value_net", "l"),
+            value_net_z_s: z("value_net", "s"),
+            value_pct_z_l: z("value_pct", "l"),
+            value_pct_z_s: z("value_pct", "s"),
+            value_liq_z_l: z("value_liq", "l"),
+            value_liq_z_s: z("value_liq", "s"),
+            value_liq_net_z_l: z("value_liq_net", "l"),
+            value_liq_net_z_s: z("value_liq_net", "s"),
+            value_liq_pct_z_l: z("value_liq_pct", "l"),
+            value_liq_pct_z_s: z("value_liq_pct", "s"),
+            trade_count_z_l: z("trade_count", "l"),
+            trade_count_z_s: z("trade_count", "s"),
+            trade_count_net_z_l: z("trade_count_net", "l"),
+            trade_count_net_z_s: z("trade_count_net", "s"),
+            trade_count_pct_z_l: z("trade_count_pct", "l"),
+            trade_count_pct_z_s: z("trade_count_pct", "s"),
+            liq_count_z_l: z("liq_count", "l"),
+            liq_count_z_s: z("liq_count", "s"),
+            liq_count_net_z_l: z("liq_count_net", "l"),
+            liq_count_net_z_s: z("liq_count_net", "s"),
+            liq_count_pct_z_l: z("liq_count_pct", "l"),
+            liq_count_pct_z_s: z("liq_count_pct", "s"),
+            high4: don(4, "high"),
+            high8: don(8, "high"),
+            high16: don(16, "high"),
+            high32: don(32, "high"),
+            high64: don(64, "high"),
+            high128: don(128, "high"),
+            high256: don(256, "high"),
+            low4: don(4, "low"),
+            low8: don(8, "low"),
+            low16: don(16, "low"),
+            low32: don(32, "low"),
+            low64: don(64, "low"),
+            low128: don(128, "low"),
+            low256: don(256, "low"),
+            version: METRIC_VERSION,
+            param_hash: tf.metric_param_hash(),
         }
     }
 
@@ -718,6 +636,8 @@ impl ResearchMetric {
                 low64 NUMERIC,
                 low128 NUMERIC,
                 low256 NUMERIC,
+                version INT NOT NULL DEFAULT 0,
+                param_hash TEXT NOT NULL DEFAULT '',
                 insert_dt timestamptz NOT NULL
             )
             "#;
@@ -739,7 +659,8 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256, insert_dt
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash,
+                insert_dt
             )
             VALUES (
                 $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
@@ -749,7 +670,7 @@ impl ResearchMetric {
                 $41, $42, $43, $44, $45, $46, $47, $48, $49, $50,
                 $51, $52, $53, $54, $55, $56, $57, $58, $59, $60,
                 $61, $62, $63, $64, $65, $66, $67, $68, $69, $70,
-                $71, now()
+                $71, $72, $73, now()
             )
             "#;
         sqlx::query(sql)
@@ -824,6 +745,8 @@ impl ResearchMetric {
             .bind(self.low64)
             .bind(self.low128)
             .bind(self.low256)
+            .bind(self.version)
+            .bind(&self.param_hash)
             .execute(pool)
             .await?;
         Ok(())
@@ -849,7 +772,7 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
             FROM research_metrics
             "#,
         )
@@ -879,10 +802,12 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
             FROM research_metrics
+            WHERE version = $1
             ORDER BY market_id, tf, datetime desc, insert_dt desc
             "#,
+            METRIC_VERSION
         )
         .fetch_all(pool)
         .await?;
@@ -909,7 +834,7 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
             FROM research_metrics
             WHERE market_id = $1
             "#,
@@ -943,11 +868,12 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
             FROM research_metrics
-            WHERE market_id = ANY($1)
+            WHERE market_id = ANY($1) AND version = $2
             "#,
-            market_ids
+            market_ids,
+            METRIC_VERSION
         )
         .fetch_all(pool)
         .await?;
@@ -978,12 +904,60 @@ impl ResearchMetric {
                 trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
                 liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
                 liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
-                high256, low4, low8, low16, low32, low64, low128, low256
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
             FROM research_metrics
-            WHERE market_id = ANY($1)
+            WHERE market_id = ANY($1) AND version = $2
             ORDER BY market_id, tf, datetime desc, insert_dt desc
             "#,
-            market_ids
+            market_ids,
+            METRIC_VERSION
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rows)
+    }
+
+    // Latest metric of each market for the time frame with a candle opened before the datetime,
+    // calculated under the current metric version and inserted by the as of datetime so that a
+    // later amendment of the candle is not seen
+    pub async fn select_by_ids_tf_lt_dt(
+        pool: &PgPool,
+        market_ids: &[Uuid],
+        tf: &TimeFrame,
+        dt: &DateTime<Utc>,
+        as_of: &DateTime<Utc>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            Self,
+            r#"
+            SELECT DISTINCT ON (market_id)
+                market_id,
+                tf as "tf: TimeFrame",
+                datetime, high, low, close, atr_l, atr_s, 
+                ma_filter as "ma_filter: MetricFilter",
+                atr_filter as "atr_filter: MetricFilter", 
+                direction as "direction: MetricDirection",
+                return_z_l, return_z_s, tr_z_l, tr_z_s, upper_wick_z_l, upper_wick_z_s, body_z_l,
+                body_z_s, lower_wick_z_l, lower_wick_z_s, volume_z_l, volume_z_s, volume_net_z_l,
+                volume_net_z_s, volume_pct_z_l, volume_pct_z_s, volume_liq_z_l, volume_liq_z_s,
+                volume_liq_net_z_l, volume_liq_net_z_s, volume_liq_pct_z_l, volume_liq_pct_z_s,
+                value_z_l, value_z_s, value_net_z_l, value_net_z_s, value_pct_z_l, value_pct_z_s,
+                value_liq_z_l, value_liq_z_s, value_liq_net_z_l, value_liq_net_z_s,
+                value_liq_pct_z_l, value_liq_pct_z_s, trade_count_z_l, trade_count_z_s,
+                trade_count_net_z_l, trade_count_net_z_s, trade_count_pct_z_l, trade_count_pct_z_s,
+                liq_count_z_l, liq_count_z_s, liq_count_net_z_l, liq_count_net_z_s,
+                liq_count_pct_z_l, liq_count_pct_z_s, high4, high8, high16, high32, high64, high128,
+                high256, low4, low8, low16, low32, low64, low128, low256, version, param_hash
+            FROM research_metrics
+            WHERE market_id = ANY($1) AND tf = $2 AND datetime < $3 AND version = $4
+                AND insert_dt <= $5
+            ORDER BY market_id, datetime desc, insert_dt desc
+            "#,
+            market_ids,
+            tf.as_str(),
+            dt,
+            METRIC_VERSION,
+            as_of
         )
         .fetch_all(pool)
         .await?;
@@ -1011,6 +985,23 @@ impl ResearchMetric {
         Ok(())
     }
 
+    pub async fn delete_by_market_lt_dt(
+        pool: &PgPool,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"
+        DELETE FROM research_metrics
+        WHERE market_id = $1 AND datetime < $2
+        "#;
+        let result = sqlx::query(sql)
+            .bind(market.market_id)
+            .bind(dt)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
     pub fn map_by_id_tf(metrics: &[Self]) -> HashMap<Uuid, HashMap<TimeFrame, Vec<Self>>> {
         let mut map: HashMap<Uuid, HashMap<TimeFrame, Vec<ResearchMetric>>> = HashMap::new();
         for metric in metrics.iter() {
@@ -1125,7 +1116,7 @@ impl ResearchMetric {
 impl ElDorado {
     pub async fn insert_metrics(&self, metrics: &[ResearchMetric]) -> Result<(), ElDoradoError> {
         for metric in metrics.iter() {
-            metric.insert(&self.pools[&Database::ElDorado]).await?;
+            self.storage.insert_metric(metric).await?;
         }
         Ok(())
     }
@@ -1136,6 +1127,10 @@ impl ElDorado {
         heartbeats: &mut HashMap<String, Heartbeat>,
     ) -> Vec<ResearchMetric> {
         let mut metrics = Vec::new();
+        // Candles may have been amended so the incremental states are rebuilt on the next update
+        if let Some(hb) = heartbeats.get_mut(&market.market_name) {
+            hb.metric_states.clear();
+        }
         for tf in TimeFrame::tfs().iter() {
             println!("Calculating metrics for {} - {}", market.market_name, tf);
             let tf_metric = ResearchMetric::new(
@@ -1152,24 +1147,57 @@ impl ElDorado {
         }
         metrics
     }
-
-    pub async fn maintain_metrics_table(&self) -> Result<(), ElDoradoError> {
-        let dt = Utc::now() - Duration::days(3);
-        ResearchMetric::delete_lt_dt(&self.pools[&Database::ElDorado], dt).await?;
-        Ok(())
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        configuration::Database,
         eldorado::ElDorado,
         metrics::{Metric, ResearchMetric},
+        utilities::TimeFrame,
     };
+    use chrono::Duration;
     use rust_decimal_macros::dec;
     use uuid::Uuid;
 
+    #[test]
+    pub fn lookbacks_fit_in_the_heartbeat_history() {
+        for tf in [
+            TimeFrame::T15,
+            TimeFrame::H01,
+            TimeFrame::H04,
+            TimeFrame::H12,
+            TimeFrame::D01,
+            TimeFrame::W01,
+            TimeFrame::M01,
+        ]
+        .iter()
+        {
+            assert!(tf.lbp_l() <= tf.max_len(), "{:?}", tf);
+            assert!(tf.lbp_s() <= tf.lbp_l(), "{:?}", tf);
+            assert!(tf.lbps().iter().all(|l| *l <= tf.max_len()), "{:?}", tf);
+        }
+    }
+
+    #[test]
+    pub fn long_time_frames_load_history_past_the_sync_days() {
+        let sync = Duration::days(100);
+        // Custom time frames take the lookbacks of the standard time frame below them in candles
+        let tf = TimeFrame::Custom(36 * 3600);
+        assert_eq!(tf.max_len(), TimeFrame::D01.max_len());
+        assert_eq!(tf.history_dur(), Duration::hours(36 * 97));
+        for tf in [tf, TimeFrame::W01, TimeFrame::M01].iter() {
+            assert!(tf.history_dur() > sync, "{:?}", tf);
+            assert!(tf.loads_archive_history(), "{:?}", tf);
+        }
+        assert!(TimeFrame::M01.history_dur() >= Duration::days(365 * 2));
+        // Standard time frames fit in the sync days
+        for tf in [TimeFrame::T15, TimeFrame::H01, TimeFrame::H12, TimeFrame::D01].iter() {
+            assert!(tf.history_dur() <= sync, "{:?}", tf);
+            assert!(!tf.loads_archive_history(), "{:?}", tf);
+        }
+    }
+
     #[tokio::test]
     pub async fn research_metric_etl() {
         // Using the sample research metrics file in the test directory:
@@ -1183,7 +1211,7 @@ mod tests {
         let metrics = ResearchMetric::from_file(&fp);
         // 2)
         let eld = ElDorado::new().await.unwrap();
-        let pool = &eld.pools[&Database::ElDorado];
+        let pool = eld.pools.system();
         let drop = "DROP TABLE IF EXISTS research_metrics";
         sqlx::query(drop)
             .execute(pool)
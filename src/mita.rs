@@ -1,14 +1,16 @@
 use crate::{
+    bars::BarBuilder,
     candles::ProductionCandle,
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::ExchangeName,
     markets::MarketDetail,
     metrics::ResearchMetric,
+    rolling::MetricState,
+    sessions::Session,
     trades::PrIdTi,
     utilities::{DateRange, TimeFrame},
 };
-use chrono::{DateTime, DurationRound, Utc};
+use chrono::{DateTime, Duration, DurationRound, Utc};
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
@@ -23,6 +25,16 @@ pub struct Heartbeat {
     pub last: PrIdTi,
     pub candles: HashMap<TimeFrame, Vec<ProductionCandle>>,
     pub metrics: Option<Vec<ResearchMetric>>,
+    // Running metric state by time frame for the incremental metric updates
+    pub metric_states: HashMap<TimeFrame, MetricState>,
+    // Last metric of each time frame the signal rules were evaluated on
+    pub prev_metrics: HashMap<TimeFrame, ResearchMetric>,
+    // Metric datetime each signal rule last fired by time frame for the cooldown
+    pub signals: HashMap<(String, TimeFrame), DateTime<Utc>>,
+    pub bars: Vec<BarBuilder>,
+    // Last published forming base candle and when it was published
+    pub forming: Option<ProductionCandle>,
+    pub forming_ts: DateTime<Utc>,
 }
 
 impl Heartbeat {
@@ -32,6 +44,26 @@ impl Heartbeat {
             last: PrIdTi::min(),
             candles: HashMap::with_capacity(TimeFrame::tfs().len()),
             metrics: None,
+            metric_states: HashMap::new(),
+            prev_metrics: HashMap::new(),
+            signals: HashMap::new(),
+            bars: Vec::new(),
+            forming: None,
+            forming_ts: DateTime::<Utc>::MIN_UTC,
+        }
+    }
+
+    // Drop the candles of each time frame that opened before the interval containing the start,
+    // leaving the candles a mita synced from the start would hold. The time frames with history
+    // from the archive keep their max_len candles.
+    pub fn trim(&mut self, start: &DateTime<Utc>, session: &Session) {
+        for (tf, candles) in self.candles.iter_mut() {
+            let first = session.trunc(tf, start);
+            let mut i = candles.partition_point(|c| c.datetime < first);
+            if tf.loads_archive_history() {
+                i = i.min(candles.len().saturating_sub(tf.max_len() as usize));
+            }
+            candles.drain(..i);
         }
     }
 }
@@ -50,6 +82,10 @@ impl ElDorado {
     pub async fn mita(&mut self) -> Result<(), ElDoradoError> {
         // Set restart value to false, error handling must explicitly set back to true
         self.instance.restart = false;
+        // Streamed trades from a previous run do not cover the intervals since the restart
+        self.live.lock().unwrap().clear();
+        self.synthetic_markets = self.initialize_synthetics().await?;
+        self.signal_rules = self.select_signal_rules().await?;
         self.initialize_mita().await?;
         // Initialize shared state db for hb
         let db: Db = Arc::new(Mutex::new(HashMap::new()));
@@ -62,17 +98,11 @@ impl ElDorado {
 
     async fn initialize_mita(&self) -> Result<(), ElDoradoError> {
         // Create any candle schemas that are needed
-        match &self.markets.first().unwrap().exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => {
-                self.create_candles_schema(&self.pools[&Database::Ftx])
-                    .await?
-            }
-            ExchangeName::Gdax => {
-                self.create_candles_schema(&self.pools[&Database::Gdax])
-                    .await?
-            }
-            name => panic!("{:?} not supported for mita.", name),
-        };
+        let exchange = &self.markets.first().unwrap().exchange_name;
+        self.create_candles_schema(exchange).await?;
+        if !self.synthetic_markets.is_empty() {
+            self.create_candles_schema(&ExchangeName::Index).await?;
+        }
         Ok(())
     }
 
@@ -92,20 +122,25 @@ impl ElDorado {
 
     // Run the Mita instance - at each interval for each market - aggregate trades into new candle
     // resample if needed and publish metrics with new candle datapoint
-    async fn run_mita(
+    pub async fn run_mita(
         &self,
         heartbeats: &mut HashMap<String, Heartbeat>,
     ) -> Result<(), ElDoradoError> {
         loop {
             // Set loop timestamp
             let dt = Utc::now();
-            // For each market, check if loop datetime is greater than market heartbeat
-            for market in self.markets.iter() {
+            // For each market, check if the market watermark is greater than market heartbeat
+            for market in self.markets.iter().chain(self.synthetic_markets.iter()) {
+                let watermark = self.watermark(market, &dt);
                 if let Some(end) =
-                    self.check_interval(market, &heartbeats[&market.market_name], &dt)
+                    self.check_interval(market, &heartbeats[&market.market_name], &watermark)
                 {
                     self.process_interval(market, heartbeats, &end).await?;
                 }
+                // Amend the closed candles that late trades landed in
+                self.amend_late_candles(market, heartbeats).await?;
+                // Publish the candles forming for the current intervals
+                self.publish_forming(market, heartbeats, &dt).await?;
             }
             // Reload heartbeats if needed (ie when a candle validation is updated)
             // Sleep for 200 ms to give control back to tokio scheduler
@@ -201,6 +236,8 @@ impl ElDorado {
                 self.update_heartbeat(market, heartbeats, candles, interval_end)
                     .await?;
             }
+            // Feed the interval trades to the information driven bars
+            self.process_interval_bars(market, heartbeats, &dr).await?;
         }
         Ok(())
     }
@@ -214,6 +251,8 @@ impl ElDorado {
         // println!("Updating and inserting metrics.");
         let metrics = self.calc_metrics_all_tfs(market, heartbeats);
         self.insert_metrics(&metrics).await?;
+        self.insert_indicators(market, &heartbeats[&market.market_name], &TimeFrame::tfs())
+            .await?;
         heartbeats
             .entry(market.market_name.clone())
             .and_modify(|hb| hb.metrics = Some(metrics));
@@ -224,111 +263,51 @@ impl ElDorado {
         &self,
         market: &MarketDetail,
         heartbeats: &mut HashMap<String, Heartbeat>,
-        mut candles: Vec<ProductionCandle>,
+        candles: Vec<ProductionCandle>,
         interval_end: &DateTime<Utc>,
     ) -> Result<(), ElDoradoError> {
-        // Create hashmap for timeframes and candles
         let last = candles.last().expect("Expected candle in Vec.");
         let last_ts = last.datetime;
         let last_pridti = last.close_as_pridti();
-        // println!("Appending new candles to base candles in heartbeat.");
-        heartbeats
-            .entry(market.market_name.clone())
-            .and_modify(|hb| {
-                hb.candles
-                    .entry(market.tf)
-                    .and_modify(|v| v.append(&mut candles));
-            });
-        // Start metrics vec
+        let metrics = self.advance_heartbeat(
+            market,
+            heartbeats.get_mut(&market.market_name).unwrap(),
+            candles,
+            interval_end,
+        );
         println!(
-            "{} - Create {} metrics for base tf.",
+            "{} - Created {} metrics for {}.",
             Utc::now(),
-            market.market_name
+            market.market_name,
+            metrics
+                .iter()
+                .map(|m| m.tf.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
         );
-        let mut metrics = vec![ResearchMetric::new(
-            market,
-            market.tf,
-            heartbeats
-                .get(&market.market_name)
-                .unwrap()
-                .candles
-                .get(&market.tf)
-                .unwrap(),
-        )];
-        // For each time frame - either append new candle for interval or clone existing
-        for tf in TimeFrame::tfs().iter().skip(1) {
-            let hb_last = heartbeats
-                .get(&market.market_name)
-                .unwrap()
-                .candles
-                .get(tf)
-                .unwrap()
-                .last()
-                .unwrap();
-            if hb_last.datetime + tf.as_dur() < interval_end.duration_trunc(tf.as_dur()).unwrap() {
-                // Resample new candles to tf from base_tf and add to tf candles
-                // Assumes tf is divisible by base tf
-                // println!("Filtering new candles for {} tf.", tf);
-                let new_candles: Vec<_> = heartbeats
-                    .get(&market.market_name)
-                    .unwrap()
-                    .candles
-                    .get(&market.tf)
-                    .unwrap()
-                    .iter()
-                    .filter(|c| {
-                        c.datetime >= hb_last.datetime + tf.as_dur()
-                            && c.datetime < interval_end.duration_trunc(tf.as_dur()).unwrap()
-                    })
-                    .cloned()
-                    .collect();
-                // println!(
-                //     "Resampling {} new base candles for {} tf",
-                //     new_candles.len(),
-                //     tf
-                // );
-                let mut resampled_candles = self.resample_production_candles(&new_candles, tf);
-                // println!("Filtered new candles for {}: {:?}", tf, new_candles);
-                // println!("{} new {} resampled candles.", resampled_candles.len(), tf);
-                // println!("Appending resampled candles to candles.");
-                heartbeats
-                    .entry(market.market_name.clone())
-                    .and_modify(|hb| {
-                        hb.candles
-                            .entry(*tf)
-                            .and_modify(|v| v.append(&mut resampled_candles));
-                    });
-                // Calc metrics on new candle vec
-                println!(
-                    "{} - Create {} metrics for {} tf.",
-                    Utc::now(),
-                    market.market_name,
-                    tf
-                );
-                metrics.push(ResearchMetric::new(
-                    market,
-                    *tf,
-                    heartbeats
-                        .get(&market.market_name)
-                        .unwrap()
-                        .candles
-                        .get(tf)
-                        .unwrap(),
-                ));
-            } else {
-                // // Interval end does not create new interval for timeframe
-                // println!("Interval end of {} does not create new interval for {}. Last candle datetime for {}: {}",
-                //     )
-                // println!("No resample for {} tf.", tf);
-            }
-        }
         // Insert metrics to db
         // println!("Inserting {} metrics into db", metrics.len());
         self.insert_metrics(&metrics).await?;
+        // Fire the signal rules on the new metrics before the heartbeat metrics are replaced
+        self.evaluate_signals(
+            market,
+            heartbeats.get_mut(&market.market_name).unwrap(),
+            &metrics,
+        )
+        .await?;
+        let tfs: Vec<_> = metrics.iter().map(|m| m.tf).collect();
+        self.insert_indicators(market, &heartbeats[&market.market_name], &tfs)
+            .await?;
+        self.publish_closed(
+            market,
+            heartbeats.get_mut(&market.market_name).unwrap(),
+            &metrics,
+        )
+        .await?;
         // Update the market last candle
         // println!("Update market last candle dt.");
         market
-            .update_last_candle(&self.pools[&Database::ElDorado], &last_ts)
+            .update_last_candle(self.storage.as_ref(), &last_ts)
             .await?;
         // Updateing the new heartbeat
         // println!("Updating heartbeat with new metrics.");
@@ -341,9 +320,106 @@ impl ElDorado {
             });
         Ok(())
     }
+
+    // Add the new base candles to the heartbeat, resample them into the time frames with a closed
+    // interval and update the metrics of those time frames. H12 and longer are anchored to the
+    // market session. The metric backfill replays the archive through this as well so the metrics
+    // match the live ones. The heartbeat is trimmed to the sync days at the first candle of each
+    // day as if a mita was started that day.
+    pub fn advance_heartbeat(
+        &self,
+        market: &MarketDetail,
+        hb: &mut Heartbeat,
+        mut candles: Vec<ProductionCandle>,
+        interval_end: &DateTime<Utc>,
+    ) -> Vec<ResearchMetric> {
+        let session = self.sessions.session(market);
+        if let Some(first) = candles.first() {
+            let start = TimeFrame::D01.trunc(&first.datetime) - Duration::days(self.sync_days);
+            if let Some(c) = hb.candles.get(&market.tf).and_then(|v| v.first()) {
+                if c.datetime < start {
+                    hb.trim(&start, &session);
+                }
+            }
+        }
+        hb.candles
+            .entry(market.tf)
+            .and_modify(|v| v.append(&mut candles));
+        let mut metrics = vec![self.update_metric(market, hb, market.tf)];
+        for tf in TimeFrame::tfs().iter().skip(1) {
+            let next = session.next(tf, &hb.candles[tf].last().unwrap().datetime);
+            let end = session.trunc(tf, interval_end);
+            if next < end {
+                // Resample new candles to tf from base_tf and add to tf candles
+                // Assumes tf is divisible by base tf
+                let new_candles: Vec<_> = hb.candles[&market.tf]
+                    .iter()
+                    .filter(|c| c.datetime >= next && c.datetime < end)
+                    .cloned()
+                    .collect();
+                let mut resampled_candles =
+                    self.resample_production_candles(&new_candles, tf, &session);
+                hb.candles
+                    .entry(*tf)
+                    .and_modify(|v| v.append(&mut resampled_candles));
+                metrics.push(self.update_metric(market, hb, *tf));
+            }
+        }
+        metrics
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    // TODO: Add unit tests
+    use crate::{
+        candles::ProductionCandle, mita::Heartbeat, sessions::Session, trades::PrIdTi,
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn trim_keeps_the_intervals_from_the_start() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let candles = |tf: TimeFrame, n: i64| -> Vec<_> {
+            (0..n)
+                .map(|i| {
+                    let pit = PrIdTi {
+                        dt: start + tf.as_dur() * i as i32,
+                        id: i,
+                        price: dec!(100),
+                    };
+                    ProductionCandle::from_last(pit.dt, &pit)
+                })
+                .collect()
+        };
+        let mut hb = Heartbeat::new();
+        hb.candles
+            .insert(TimeFrame::T15, candles(TimeFrame::T15, 960));
+        hb.candles
+            .insert(TimeFrame::D01, candles(TimeFrame::D01, 10));
+        // Trimming mid day keeps the day it is in
+        hb.trim(
+            &(start + Duration::days(2) + Duration::hours(6)),
+            &Session::utc(),
+        );
+        assert_eq!(
+            hb.candles[&TimeFrame::T15][0].datetime,
+            start + Duration::days(2) + Duration::hours(6)
+        );
+        assert_eq!(hb.candles[&TimeFrame::T15].len(), 960 - 2 * 96 - 24);
+        assert_eq!(
+            hb.candles[&TimeFrame::D01][0].datetime,
+            start + Duration::days(2)
+        );
+        assert_eq!(hb.candles[&TimeFrame::D01].len(), 8);
+        // Weekly candles loaded from the archive keep the history their lookbacks need
+        hb.candles
+            .insert(TimeFrame::W01, candles(TimeFrame::W01, 60));
+        hb.trim(&(start + Duration::weeks(59)), &Session::utc());
+        assert_eq!(
+            hb.candles[&TimeFrame::W01].len() as i64,
+            TimeFrame::W01.max_len()
+        );
+    }
 }
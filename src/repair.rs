@@ -0,0 +1,522 @@
+use crate::{
+    candles::{Candle, CandleAccumulator, CandleType, ProductionCandle, ResearchCandle},
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::ExchangeName,
+    markets::MarketDetail,
+    trades::{PrIdTi, Trade},
+    utilities::{DateRange, TimeFrame},
+};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+// A field that differs between a stored candle and the candle rebuilt from the trade archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandleDiff {
+    pub datetime: DateTime<Utc>,
+    pub field: String,
+    pub stored: String,
+    pub rebuilt: String,
+}
+
+// Reconciliation of the candles stored in one place against the rebuilt candles
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub source: String,
+    pub checked: usize,
+    // Stored candles with at least one field that differs
+    pub changed: Vec<DateTime<Utc>>,
+    // Rebuilt candles that are not stored
+    pub missing: Vec<DateTime<Utc>>,
+    pub diffs: Vec<CandleDiff>,
+}
+
+impl RepairReport {
+    // Compare each rebuilt candle to the stored candle for the same datetime field by field
+    pub fn new<T: Candle + Serialize>(source: &str, stored: &[T], rebuilt: &[T]) -> Self {
+        let stored: HashMap<_, _> = stored.iter().map(|c| (c.datetime(), c)).collect();
+        let mut report = Self::empty(source);
+        for candle in rebuilt.iter() {
+            match stored.get(&candle.datetime()) {
+                Some(s) => {
+                    report.checked += 1;
+                    let mut diffs = diff_fields(&candle.datetime(), *s, candle);
+                    if !diffs.is_empty() {
+                        report.changed.push(candle.datetime());
+                        report.diffs.append(&mut diffs);
+                    }
+                }
+                None => report.missing.push(candle.datetime()),
+            }
+        }
+        report
+    }
+
+    // Report for a place with no stored candles to check
+    pub fn empty(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty()
+    }
+
+    // Datetimes of the candles to overwrite
+    pub fn to_repair(&self) -> Vec<DateTime<Utc>> {
+        let mut dts: Vec<_> = self
+            .changed
+            .iter()
+            .chain(self.missing.iter())
+            .cloned()
+            .collect();
+        dts.sort();
+        dts
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{}: {} candles checked, {} differ and {} missing.",
+            self.source,
+            self.checked,
+            self.changed.len(),
+            self.missing.len()
+        );
+        for diff in self.diffs.iter() {
+            println!(
+                "  {}\t{}\t{} -> {}",
+                diff.datetime, diff.field, diff.stored, diff.rebuilt
+            );
+        }
+        for dt in self.missing.iter() {
+            println!("  {}\tmissing", dt);
+        }
+    }
+}
+
+// Fields of the candles, or metrics, that differ on their serialized values. Decimals are compared
+// by value as the stored scale can differ. The revision is not candle data.
+pub fn diff_fields<T: Serialize>(dt: &DateTime<Utc>, stored: &T, rebuilt: &T) -> Vec<CandleDiff> {
+    let stored = serde_json::to_value(stored).expect("Failed to serialize candle.");
+    let rebuilt = serde_json::to_value(rebuilt).expect("Failed to serialize candle.");
+    let same = |a: &Value, b: &Value| {
+        let decimal = |v: &Value| v.as_str().and_then(|s| Decimal::from_str(s).ok());
+        match (decimal(a), decimal(b)) {
+            (Some(x), Some(y)) => x == y,
+            _ => a == b,
+        }
+    };
+    match (stored, rebuilt) {
+        (Value::Object(s), Value::Object(r)) => r
+            .iter()
+            .filter(|(field, _)| field.as_str() != "revision")
+            .filter(|(field, v)| !matches!(s.get(*field), Some(sv) if same(sv, v)))
+            .map(|(field, v)| CandleDiff {
+                datetime: *dt,
+                field: field.clone(),
+                stored: s.get(field).map(|sv| sv.to_string()).unwrap_or_default(),
+                rebuilt: v.to_string(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Rebuilt candles within the first and last stored candle. Stored candles are trimmed by
+// retention so only the span that is stored can be reconciled.
+fn within_stored<T: Candle + Clone>(stored: &[T], rebuilt: &[T]) -> Vec<T> {
+    match (stored.first(), stored.last()) {
+        (Some(first), Some(last)) => rebuilt
+            .iter()
+            .filter(|c| c.datetime() >= first.datetime() && c.datetime() <= last.datetime())
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Research candles for the S15 intervals from start to end from the archive accumulators. Without
+// a last trade before the start the candles begin at the first trade.
+pub fn rebuild_research_candles(
+    accumulators: &HashMap<DateTime<Utc>, CandleAccumulator>,
+    last: Option<PrIdTi>,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+) -> Vec<ResearchCandle> {
+    let start = match last {
+        Some(_) => *start,
+        None => match accumulators.keys().filter(|d| *d >= start).min() {
+            Some(d) => *d,
+            None => return Vec::new(),
+        },
+    };
+    match DateRange::new(&start, end, &TimeFrame::S15) {
+        Some(dr) => ResearchCandle::from_accumulators_for_dr(accumulators, last, &dr.dts),
+        None => Vec::new(),
+    }
+}
+
+// Production candles for the time frame from the S15 archive accumulators merged in time order,
+// which is the same as aggregating the trades of the interval at once.
+pub fn rebuild_production_candles(
+    accumulators: &HashMap<DateTime<Utc>, CandleAccumulator>,
+    last: Option<PrIdTi>,
+    tf: &TimeFrame,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+) -> Vec<ProductionCandle> {
+    let mut dts: Vec<_> = accumulators
+        .keys()
+        .filter(|d| *d >= start && *d < end)
+        .collect();
+    dts.sort();
+    let mut merged: BTreeMap<DateTime<Utc>, CandleAccumulator> = BTreeMap::new();
+    for dt in dts.into_iter() {
+        match merged.get_mut(&tf.trunc(dt)) {
+            Some(acc) => acc.merge(&accumulators[dt]),
+            None => {
+                merged.insert(tf.trunc(dt), accumulators[dt].clone());
+            }
+        }
+    }
+    let (start, last) = match last {
+        Some(l) => (tf.trunc(start), l),
+        None => match merged
+            .iter()
+            .next()
+            .and_then(|(d, acc)| Some((*d, acc.first?)))
+        {
+            Some(first) => first,
+            None => return Vec::new(),
+        },
+    };
+    match DateRange::new(&start, end, tf) {
+        Some(dr) => ProductionCandle::from_live_for_dr(&merged, &last, &dr.dts),
+        None => Vec::new(),
+    }
+}
+
+// Parse a repair command date, either a day ie 2023-03-01 or a time ie 2023-03-01T12:00
+pub fn parse_repair_dt(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0)?));
+    }
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M")
+        .ok()
+        .map(|dt| Utc.from_utc_datetime(&dt))
+}
+
+impl ElDorado {
+    // Rebuild the production and research candles of the market from the validated trade archive
+    // for the days from through to (exclusive), reconcile them against the stored candles and
+    // print the report. With overwrite the candles that differ or are missing are replaced and
+    // the market candle and archive details updated to match.
+    pub async fn repair_candles(
+        &self,
+        market: &MarketDetail,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        overwrite: bool,
+    ) -> Result<(), ElDoradoError> {
+        if market.exchange_name != ExchangeName::Gdax {
+            println!(
+                "Candle repair not supported for {}.",
+                market.exchange_name.as_str()
+            );
+            return Ok(());
+        }
+        let days = self.repair_days(market, from, to);
+        let (first_day, last_day) = match (days.first(), days.last()) {
+            (Some(f), Some(l)) => (*f, *l),
+            _ => {
+                println!(
+                    "No archived trades for {} from {} to {}.",
+                    market.market_name, from, to
+                );
+                return Ok(());
+            }
+        };
+        let start = TimeFrame::S15.trunc(&(*from).max(first_day));
+        let end = (*to).min(last_day + Duration::days(1));
+        println!(
+            "Rebuilding {} candles from {} to {}.",
+            market.market_name, start, end
+        );
+        let last = self.repair_last_trade(market, &first_day);
+        let accumulators = self.accumulate_gdax_trades_for_dr(market, &TimeFrame::S15, &days);
+        let research = rebuild_research_candles(&accumulators, last, &start, &end);
+        let production = rebuild_production_candles(&accumulators, last, &market.tf, &start, &end);
+        // Reconcile and optionally overwrite each place the candles are stored
+        let reports = [
+            self.repair_production_candles(market, &production, &start, &end, overwrite)
+                .await?,
+            self.repair_research_table(market, &research, &start, &end, overwrite)
+                .await?,
+            self.repair_research_archive(market, &research, overwrite)
+                .await?,
+        ];
+        for report in reports.iter() {
+            report.print();
+        }
+        if overwrite && reports.iter().any(|r| !r.is_clean()) {
+            self.repair_details(market, &research).await?;
+        }
+        Ok(())
+    }
+
+    // Days from through to with a trade archive file. Candles cannot be rebuilt across a missing
+    // day so the days stop at the first gap.
+    fn repair_days(
+        &self,
+        market: &MarketDetail,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        let dr = match DateRange::new(&TimeFrame::D01.trunc(from), to, &TimeFrame::D01) {
+            Some(dr) => dr,
+            None => return Vec::new(),
+        };
+        let mut days = Vec::new();
+        for d in dr.dts.iter() {
+            if self.trade_archive_path(market, d).exists() {
+                days.push(*d);
+            } else if !days.is_empty() {
+                println!("No trade archive for {}. Stopping repair at the gap.", d);
+                break;
+            }
+        }
+        days
+    }
+
+    // Last trade of the day before the first day from its archive file
+    fn repair_last_trade(&self, market: &MarketDetail, day: &DateTime<Utc>) -> Option<PrIdTi> {
+        let pb = self.trade_archive_path(market, &(*day - Duration::days(1)));
+        if pb.exists() {
+            self.read_gdax_trades_from_file_into_vec(&pb)
+                .last()
+                .map(|t| t.as_pridti())
+        } else {
+            None
+        }
+    }
+
+    async fn repair_production_candles(
+        &self,
+        market: &MarketDetail,
+        rebuilt: &[ProductionCandle],
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        overwrite: bool,
+    ) -> Result<RepairReport, ElDoradoError> {
+        let source = format!("Production {}", market.tf);
+        if !self
+            .candle_table_exists(market, &market.tf, &CandleType::Production)
+            .await?
+        {
+            return Ok(RepairReport::empty(&source));
+        }
+        let pool = self.pools.exchange(&market.exchange_name);
+        let stored: Vec<_> = ProductionCandle::select_gte_dt(pool, &self.schema, market, start)
+            .await?
+            .into_iter()
+            .filter(|c| c.datetime < *end)
+            .collect();
+        let rebuilt = within_stored(&stored, rebuilt);
+        let report = RepairReport::new(&source, &stored, &rebuilt);
+        if overwrite {
+            for dt in report.to_repair().iter() {
+                let mut candle = rebuilt.iter().find(|c| c.datetime == *dt).unwrap().clone();
+                if let Some(s) = stored.iter().find(|c| c.datetime == *dt) {
+                    candle.revision = s.revision + 1;
+                }
+                candle
+                    .upsert(pool, &self.schema, market, &market.tf)
+                    .await?;
+            }
+        }
+        Ok(report)
+    }
+
+    async fn repair_research_table(
+        &self,
+        market: &MarketDetail,
+        rebuilt: &[ResearchCandle],
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        overwrite: bool,
+    ) -> Result<RepairReport, ElDoradoError> {
+        let source = "Research S15 table";
+        if !self
+            .candle_table_exists(market, &TimeFrame::S15, &CandleType::Research)
+            .await?
+        {
+            return Ok(RepairReport::empty(source));
+        }
+        let pool = self.pools.exchange(&market.exchange_name);
+        let stored =
+            ResearchCandle::select_dr(pool, &self.schema, market, &TimeFrame::S15, start, end)
+                .await?;
+        let rebuilt = within_stored(&stored, rebuilt);
+        let report = RepairReport::new(source, &stored, &rebuilt);
+        let dts = report.to_repair();
+        if let (true, Some(first), Some(last)) = (overwrite, dts.first(), dts.last()) {
+            let last = *last + TimeFrame::S15.as_dur();
+            ResearchCandle::delete_dr(pool, &self.schema, market, &TimeFrame::S15, first, &last)
+                .await?;
+            for candle in rebuilt
+                .iter()
+                .filter(|c| c.datetime >= *first && c.datetime < last)
+            {
+                candle
+                    .insert(pool, &self.schema, market, &TimeFrame::S15)
+                    .await?;
+            }
+        }
+        Ok(report)
+    }
+
+    // Reconcile the archived months. Months that differ are written again in full with the
+    // rebuilt candles replacing the stored ones, which resamples the other time frame files.
+    async fn repair_research_archive(
+        &self,
+        market: &MarketDetail,
+        rebuilt: &[ResearchCandle],
+        overwrite: bool,
+    ) -> Result<RepairReport, ElDoradoError> {
+        let source = "Research S15 archive";
+        let mad = match self.storage.select_mad(market).await {
+            Ok(mad) => mad,
+            Err(sqlx::Error::RowNotFound) => return Ok(RepairReport::empty(source)),
+            Err(e) => return Err(ElDoradoError::Sqlx(e)),
+        };
+        // Rebuilt candles by archived month
+        let mut months: BTreeMap<DateTime<Utc>, Vec<ResearchCandle>> = BTreeMap::new();
+        for candle in rebuilt.iter().filter(|c| c.datetime < mad.next_month) {
+            months
+                .entry(TimeFrame::M01.trunc(&candle.datetime))
+                .or_default()
+                .push(candle.clone());
+        }
+        let mut report = RepairReport::empty(source);
+        for (month, candles) in months.iter() {
+            let stored =
+                self.read_research_candles_from_file_for_month(market, month, &TimeFrame::S15);
+            let mut month_report = RepairReport::new(source, &stored, candles);
+            if overwrite && !month_report.is_clean() {
+                let mut merged: BTreeMap<_, _> =
+                    stored.into_iter().map(|c| (c.datetime, c)).collect();
+                for candle in candles.iter() {
+                    merged.insert(candle.datetime, candle.clone());
+                }
+                let merged: Vec<_> = merged.into_values().collect();
+                self.write_research_candles_to_file_for_month(market, month, &merged);
+            }
+            report.checked += month_report.checked;
+            report.changed.append(&mut month_report.changed);
+            report.missing.append(&mut month_report.missing);
+            report.diffs.append(&mut month_report.diffs);
+        }
+        Ok(report)
+    }
+
+    // Update the last trade of the market candle detail and the first and last trades of the
+    // market archive detail when their candles were rebuilt
+    async fn repair_details(
+        &self,
+        market: &MarketDetail,
+        rebuilt: &[ResearchCandle],
+    ) -> Result<(), ElDoradoError> {
+        let find = |dt: &DateTime<Utc>| rebuilt.iter().find(|c| c.datetime == *dt);
+        if let Ok(mcd) = self.storage.select_mcd(market).await {
+            if let Some(candle) = find(&mcd.last_candle) {
+                println!("Updating market candle detail last trade.");
+                mcd.update_last(self.storage.as_ref(), candle).await?;
+            }
+        }
+        if let Ok(mad) = self.storage.select_mad(market).await {
+            let first = find(&mad.first_candle_dt);
+            let last = find(&mad.last_candle_dt);
+            if first.is_some() || last.is_some() {
+                println!("Updating market archive detail trades.");
+                let mut updated = mad;
+                if let Some(c) = first {
+                    updated.first_trade_dt = c.first_trade_ts;
+                    updated.first_trade_price = c.open;
+                    updated.first_trade_id = c.first_trade_id.clone();
+                }
+                if let Some(c) = last {
+                    updated.last_trade_dt = c.last_trade_ts;
+                    updated.last_trade_price = c.close;
+                    updated.last_trade_id = c.last_trade_id.clone();
+                }
+                self.storage.update_mad(&updated).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        candles::CandleAccumulator,
+        exchanges::gdax::Trade as GdaxTrade,
+        repair::{rebuild_production_candles, rebuild_research_candles, RepairReport},
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    #[test]
+    pub fn rebuilt_candles_reconcile_against_stored() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let end = start + Duration::minutes(30);
+        let mut accumulators = HashMap::new();
+        for (id, minutes, price) in [(1, 1, dec!(100)), (2, 7, dec!(104)), (3, 16, dec!(102))]
+            .iter()
+            .copied()
+        {
+            let trade = GdaxTrade {
+                trade_id: id,
+                side: "buy".to_string(),
+                size: dec!(1),
+                price,
+                time: start + Duration::minutes(minutes),
+            };
+            accumulators
+                .entry(TimeFrame::S15.trunc(&trade.time))
+                .or_insert_with(|| CandleAccumulator::new(None))
+                .push(&trade);
+        }
+        // Without a last trade the candles start at the first trade
+        let research = rebuild_research_candles(&accumulators, None, &start, &end);
+        assert_eq!(research.len(), 116);
+        let production =
+            rebuild_production_candles(&accumulators, None, &TimeFrame::T15, &start, &end);
+        assert_eq!(production.len(), 2);
+        assert_eq!(production[0].open, dec!(100));
+        assert_eq!(production[0].close, dec!(104));
+        assert_eq!(production[0].trade_count, 2);
+        assert_eq!(production[1].close, dec!(102));
+        // Stored candle with a different close and a missing second candle
+        let mut stored = production[0].clone();
+        stored.close = dec!(103);
+        stored.high = dec!(104.00);
+        let report = RepairReport::new("test", &[stored], &production);
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.changed, vec![start]);
+        assert_eq!(report.missing, vec![start + Duration::minutes(15)]);
+        assert_eq!(report.diffs.len(), 1);
+        assert_eq!(report.diffs[0].field, "close");
+        let clean = RepairReport::new("test", &production, &production);
+        assert!(clean.is_clean());
+    }
+}
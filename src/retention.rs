@@ -0,0 +1,294 @@
+use crate::{
+    candles::{CandleType, ProductionCandle, ResearchCandle},
+    configuration::{RetentionPolicy, RetentionSettings},
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::{ftx::Trade as FtxTrade, gdax::Trade as GdaxTrade, ExchangeName},
+    markets::MarketDetail,
+    trades::{Trade, TradePartition},
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+
+impl RetentionPolicy {
+    // Retention before any configuration is applied. Metrics were always kept for 3 days.
+    pub fn base() -> Self {
+        Self {
+            metrics: Some(3),
+            ..Default::default()
+        }
+    }
+
+    // Layer the other policy on top of this one. Any value set in the other policy wins.
+    pub fn merge(&self, other: &RetentionPolicy) -> Self {
+        let mut candles = self.candles.clone();
+        candles.extend(other.candles.iter().map(|(tf, d)| (*tf, *d)));
+        Self {
+            trades: other.trades.or(self.trades),
+            candles,
+            research: other.research.or(self.research),
+            metrics: other.metrics.or(self.metrics),
+            journals: other.journals.or(self.journals),
+        }
+    }
+
+    // Start of the oldest day to keep when keeping today and the given number of days before it
+    pub fn cutoff(days: i64) -> DateTime<Utc> {
+        Utc::now().duration_trunc(Duration::days(1)).unwrap() - Duration::days(days)
+    }
+
+    // Start of the oldest day of research S15 candles to keep
+    pub fn research_cutoff(&self, sync_days: i64) -> DateTime<Utc> {
+        Self::cutoff(self.research.unwrap_or(sync_days))
+    }
+
+    // Start of the oldest production candle of the time frame to keep once the candles before
+    // the archived datetime have been archived. Without a policy for the time frame only the
+    // candles that are not archived yet are kept.
+    pub fn production_cutoff(&self, tf: &TimeFrame, archived: &DateTime<Utc>) -> DateTime<Utc> {
+        match self.candles.get(tf) {
+            Some(days) => Self::cutoff(*days).min(*archived),
+            None => *archived,
+        }
+    }
+
+    // Check if the trades for the day should be kept after they are archived
+    pub fn keeps_trades(&self, dt: &DateTime<Utc>) -> bool {
+        match self.trades {
+            Some(days) => *dt >= Self::cutoff(days),
+            None => false,
+        }
+    }
+}
+
+impl RetentionSettings {
+    // Resolve the policy for the market: base, default, exchange default and then market.
+    pub fn policy(&self, market: &MarketDetail) -> RetentionPolicy {
+        let mut policy = RetentionPolicy::base().merge(&self.default);
+        if let Some(exchange) = self.exchanges.get(market.exchange_name.as_str()) {
+            policy = policy.merge(&exchange.default);
+            if let Some((_, m)) = exchange
+                .markets
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&market.market_name))
+            {
+                policy = policy.merge(m);
+            }
+        }
+        policy
+    }
+}
+
+impl ElDorado {
+    // Purge data older than the retention policy for each market and log what was purged.
+    pub async fn enforce_retention(&self) -> Result<(), ElDoradoError> {
+        for market in self.market_ids.values() {
+            let policy = self.retention.policy(market);
+            if let Some(days) = policy.metrics {
+                let purged = self
+                    .storage
+                    .delete_metrics_lt_dt(market, &RetentionPolicy::cutoff(days))
+                    .await?;
+                self.log_purge(market, "metrics", purged);
+            }
+            // Trades and candles are only kept in the exchange databases
+            let pool = match self.pools.get_exchange(&market.exchange_name) {
+                Some(p) => p,
+                None => continue,
+            };
+            // Candles are only purged once archived, so markets without archive are skipped
+            if let Some(archived) = self.select_archived_dt(market).await? {
+                for tf in policy.candles.keys() {
+                    if ProductionCandle::table_exists(pool, &self.schema, market, tf).await? {
+                        let purged = ProductionCandle::delete_lt_dt(
+                            pool,
+                            &self.schema,
+                            market,
+                            tf,
+                            &policy.production_cutoff(tf, &archived),
+                        )
+                        .await?;
+                        self.log_purge(market, &format!("{} candles", tf), purged);
+                    }
+                }
+            }
+            if self
+                .candle_table_exists(market, &TimeFrame::S15, &CandleType::Research)
+                .await?
+            {
+                ResearchCandle::delete_lt_dt(
+                    pool,
+                    &self.schema,
+                    market,
+                    &TimeFrame::S15,
+                    &policy.research_cutoff(self.sync_days),
+                )
+                .await?;
+            }
+            // Journal trades are only kept in the default partition of the market
+            if let Some(days) = policy.journals {
+                if TradePartition::is_attached(
+                    pool,
+                    &self.schema,
+                    &market.exchange_name,
+                    &TradePartition::default(market),
+                )
+                .await?
+                {
+                    let purged = TradePartition::delete_default_lt_dt(
+                        pool,
+                        &self.schema,
+                        market,
+                        &RetentionPolicy::cutoff(days),
+                    )
+                    .await?;
+                    self.log_purge(market, "journal trades", purged);
+                }
+            }
+            if policy.trades.is_some() {
+                self.enforce_trade_retention(market, &policy).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start of the first candle not yet archived to file. The mcd tracks the daily research
+    // archive and the mad the monthly archive for markets without an mcd.
+    async fn select_archived_dt(
+        &self,
+        market: &MarketDetail,
+    ) -> Result<Option<DateTime<Utc>>, ElDoradoError> {
+        match self.storage.select_mcd(market).await {
+            Ok(mcd) => return Ok(Some(mcd.last_candle + mcd.time_frame.as_dur())),
+            Err(sqlx::Error::RowNotFound) => {}
+            Err(e) => return Err(ElDoradoError::Sqlx(e)),
+        };
+        match self.storage.select_mad(market).await {
+            Ok(mad) => Ok(Some(mad.last_candle_dt + mad.tf.as_dur())),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(e) => Err(ElDoradoError::Sqlx(e)),
+        }
+    }
+
+    // Drop the trade day partitions that have been archived and are older than the policy
+    async fn enforce_trade_retention(
+        &self,
+        market: &MarketDetail,
+        policy: &RetentionPolicy,
+    ) -> Result<(), ElDoradoError> {
+        // Only days before the next day to archive have been archived
+        let archived = match self.storage.select_mtd(market).await {
+            Ok(mtd) => match mtd.next_trade_day {
+                Some(d) => d,
+                None => return Ok(()),
+            },
+            Err(sqlx::Error::RowNotFound) => return Ok(()),
+            Err(e) => return Err(ElDoradoError::Sqlx(e)),
+        };
+        let pool = self.pools.exchange(&market.exchange_name);
+        let days = TradePartition::select_days(pool, &self.schema, market).await?;
+        let mut dropped = 0;
+        for day in days
+            .iter()
+            .filter(|d| **d < archived && !policy.keeps_trades(d))
+        {
+            match market.exchange_name {
+                ExchangeName::Ftx | ExchangeName::FtxUs => {
+                    FtxTrade::drop_table(pool, &self.schema, market, *day).await?
+                }
+                _ => GdaxTrade::drop_table(pool, &self.schema, market, *day).await?,
+            };
+            dropped += 1;
+        }
+        self.log_purge(market, "trade day partitions", dropped);
+        Ok(())
+    }
+
+    fn log_purge(&self, market: &MarketDetail, class: &str, purged: u64) {
+        if purged > 0 {
+            println!(
+                "Retention: purged {} {} for {}.",
+                purged, class, market.market_name
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        configuration::{RetentionPolicy, RetentionSettings},
+        exchanges::ExchangeName,
+        markets::MarketDetail,
+        test_helpers,
+        utilities::TimeFrame,
+    };
+
+    #[test]
+    pub fn retention_policy_resolves_market_over_exchange_over_default() {
+        let yaml = r#"
+default:
+  metrics: 5
+  candles:
+    t15: 100
+exchanges:
+  gdax:
+    default:
+      trades: 2
+      candles:
+        h01: 365
+    markets:
+      BTC-USD:
+        metrics: 30
+        candles:
+          t15: 200
+"#;
+        let mut c = config::Config::default();
+        c.merge(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .unwrap();
+        let settings: RetentionSettings = c.try_into().unwrap();
+        let btc = settings.policy(&test_helpers::market("BTC-USD", TimeFrame::T15));
+        assert_eq!(btc.trades, Some(2));
+        assert_eq!(btc.metrics, Some(30));
+        assert_eq!(btc.candles[&TimeFrame::T15], 200);
+        assert_eq!(btc.candles[&TimeFrame::H01], 365);
+        assert_eq!(btc.journals, None);
+        let eth = settings.policy(&test_helpers::market("ETH-USD", TimeFrame::T15));
+        assert_eq!(eth.metrics, Some(5));
+        assert_eq!(eth.candles[&TimeFrame::T15], 100);
+        let ftx = settings.policy(&MarketDetail {
+            exchange_name: ExchangeName::Ftx,
+            ..test_helpers::market("BTC-PERP", TimeFrame::T15)
+        });
+        assert_eq!(ftx.trades, None);
+        assert_eq!(ftx.candles.len(), 1);
+        // Nothing configured falls back to the base policy
+        let empty = RetentionSettings::default();
+        assert_eq!(
+            empty.policy(&test_helpers::market("BTC-USD", TimeFrame::T15)),
+            RetentionPolicy::base()
+        );
+    }
+
+    #[test]
+    pub fn retention_cutoffs_never_purge_unarchived_candles() {
+        let archived = RetentionPolicy::cutoff(-1);
+        let mut policy = RetentionPolicy::base();
+        assert_eq!(
+            policy.production_cutoff(&TimeFrame::T15, &archived),
+            archived
+        );
+        assert_eq!(policy.research_cutoff(100), RetentionPolicy::cutoff(100));
+        policy.candles.insert(TimeFrame::T15, 30);
+        policy.research = Some(10);
+        assert_eq!(
+            policy.production_cutoff(&TimeFrame::T15, &archived),
+            RetentionPolicy::cutoff(30)
+        );
+        // A policy longer than the archive does not keep candles that were just archived
+        assert_eq!(
+            policy.production_cutoff(&TimeFrame::T15, &RetentionPolicy::cutoff(60)),
+            RetentionPolicy::cutoff(60)
+        );
+        assert_eq!(policy.research_cutoff(100), RetentionPolicy::cutoff(10));
+    }
+}
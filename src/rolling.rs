@@ -0,0 +1,445 @@
+use crate::{
+    candles::ProductionCandle,
+    eldorado::ElDorado,
+    markets::MarketDetail,
+    metrics::{MetricDirection, MetricFilter, ResearchMetric, DONS, METRIC_VERSION},
+    mita::Heartbeat,
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+use std::{cmp::Ordering, collections::VecDeque};
+
+// Number of candles a metric state is updated with before it is rebuilt from the heartbeat
+// candles and the metric calculated in full, to reset any rounding drift in the running values
+pub const FULL_RECOMPUTE: usize = 96;
+
+// Running ewma seeded with the first value, the same as `Metric::ewma` over all the values
+#[derive(Debug, Clone)]
+pub struct Ewma {
+    k: Decimal,
+    value: Option<Decimal>,
+}
+
+impl Ewma {
+    pub fn new(lbp: i64) -> Self {
+        Self {
+            k: dec!(2) / (Decimal::from(lbp) + Decimal::ONE),
+            value: None,
+        }
+    }
+
+    pub fn push(&mut self, x: Decimal) -> Decimal {
+        let value = match self.value {
+            Some(v) => x * self.k + v * (Decimal::ONE - self.k),
+            None => x,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    pub fn value(&self) -> Decimal {
+        self.value.unwrap_or(Decimal::ZERO)
+    }
+}
+
+// Welford mean and variance over the last n values. Adding a value to a full window replaces the
+// oldest value in the same update.
+#[derive(Debug, Clone)]
+pub struct RollingWindow {
+    n: usize,
+    values: VecDeque<Decimal>,
+    mean: Decimal,
+    m2: Decimal,
+}
+
+impl RollingWindow {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            values: VecDeque::with_capacity(n + 1),
+            mean: Decimal::ZERO,
+            m2: Decimal::ZERO,
+        }
+    }
+
+    pub fn push(&mut self, x: Decimal) {
+        self.values.push_back(x);
+        if self.values.len() > self.n {
+            let y = self.values.pop_front().unwrap();
+            let mean = self.mean + (x - y) / Decimal::from(self.n);
+            self.m2 += (x - y) * (x - mean + y - self.mean);
+            self.mean = mean;
+        } else {
+            let delta = x - self.mean;
+            self.mean += delta / Decimal::from(self.values.len());
+            self.m2 += delta * (x - self.mean);
+        }
+    }
+
+    // Population standard deviation
+    pub fn sd(&self) -> Decimal {
+        match self.values.len() {
+            0 => Decimal::ZERO,
+            n => (self.m2.max(Decimal::ZERO) / Decimal::from(n))
+                .sqrt()
+                .unwrap_or(Decimal::ZERO),
+        }
+    }
+
+    // Deviation multiple of the last value from the window mean, as `Metric::z`
+    pub fn z(&self) -> Decimal {
+        match self.values.back() {
+            Some(x) => (x - self.mean)
+                .checked_div(self.sd())
+                .unwrap_or(Decimal::ZERO),
+            None => Decimal::ZERO,
+        }
+    }
+}
+
+// Highest or lowest of the last n values from a monotonic deque of the values that can still be
+// the extreme. None until n values have been pushed.
+#[derive(Debug, Clone)]
+pub struct RollingExtreme {
+    n: usize,
+    max: bool,
+    count: usize,
+    deque: VecDeque<(usize, Decimal)>,
+}
+
+impl RollingExtreme {
+    pub fn new(n: usize, max: bool) -> Self {
+        Self {
+            n,
+            max,
+            count: 0,
+            deque: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, x: Decimal) {
+        while let Some((_, back)) = self.deque.back() {
+            if (self.max && *back <= x) || (!self.max && *back >= x) {
+                self.deque.pop_back();
+            } else {
+                break;
+            }
+        }
+        self.deque.push_back((self.count, x));
+        self.count += 1;
+        while let Some((i, _)) = self.deque.front() {
+            if *i + self.n < self.count {
+                self.deque.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn value(&self) -> Option<Decimal> {
+        match self.count >= self.n {
+            true => self.deque.front().map(|(_, v)| *v),
+            false => None,
+        }
+    }
+}
+
+// Candle values the research metric z scores are calculated on, in the order of the metric fields
+fn z_values(can: &ProductionCandle, c: Decimal) -> [Decimal; 23] {
+    let hl = can.high - can.low;
+    let ratio = |n: Decimal, d: Decimal| n.checked_div(d).unwrap_or(Decimal::ZERO);
+    let count_pct = |b: i64, t: i64| Decimal::from(b.checked_div(t).unwrap_or(0));
+    [
+        match c.is_zero() {
+            true => c,
+            false => can.close / c - Decimal::ONE,
+        },
+        match c.is_zero() {
+            true => hl,
+            false => hl.max((can.high - c).abs()).max((c - can.low).abs()),
+        },
+        ratio(can.high - can.open.max(can.close), hl),
+        ratio((can.open - can.close).abs(), hl),
+        ratio(can.open.min(can.close) - can.low, hl),
+        can.volume,
+        can.volume_buy - can.volume_sell,
+        ratio(can.volume_buy, can.volume),
+        can.volume_liq,
+        can.volume_liq_buy - can.volume_liq_sell,
+        ratio(can.volume_liq_buy, can.volume_liq),
+        can.value,
+        can.value_buy - can.value_sell,
+        ratio(can.value_buy, can.value),
+        can.value_liq,
+        can.value_liq_buy - can.value_liq_sell,
+        ratio(can.value_liq_buy, can.value_liq),
+        Decimal::from(can.trade_count),
+        Decimal::from(can.trade_count_buy - can.trade_count_sell),
+        count_pct(can.trade_count_buy, can.trade_count),
+        Decimal::from(can.liq_count),
+        Decimal::from(can.liq_count_buy - can.liq_count_sell),
+        count_pct(can.liq_count_buy, can.liq_count),
+    ]
+}
+
+fn filter(l: Decimal, s: Decimal) -> MetricFilter {
+    match l.cmp(&s) {
+        Ordering::Greater => MetricFilter::LS,
+        Ordering::Less => MetricFilter::SL,
+        Ordering::Equal => MetricFilter::Equal,
+    }
+}
+
+// Running state of the research metric for a market and time frame so each new candle updates
+// the metric in constant time instead of folding over all the candles.
+#[derive(Debug, Clone)]
+pub struct MetricState {
+    pub tf: TimeFrame,
+    // Datetime of the last candle pushed
+    pub datetime: DateTime<Utc>,
+    // Candles pushed since the state was built from the candles
+    pub updates: usize,
+    last: Option<ProductionCandle>,
+    ret: Decimal,
+    ma_l: Ewma,
+    ma_s: Ewma,
+    atr_l: Ewma,
+    atr_s: Ewma,
+    z_l: Vec<RollingWindow>,
+    z_s: Vec<RollingWindow>,
+    highs: Vec<RollingExtreme>,
+    lows: Vec<RollingExtreme>,
+}
+
+impl MetricState {
+    pub fn new(tf: TimeFrame, candles: &[ProductionCandle]) -> Self {
+        let (l, s) = (tf.lbp_l() as usize, tf.lbp_s() as usize);
+        let mut state = Self {
+            tf,
+            datetime: DateTime::<Utc>::MIN_UTC,
+            updates: 0,
+            last: None,
+            ret: Decimal::ZERO,
+            ma_l: Ewma::new(tf.lbp_l()),
+            ma_s: Ewma::new(tf.lbp_s()),
+            atr_l: Ewma::new(tf.lbp_l()),
+            atr_s: Ewma::new(tf.lbp_s()),
+            z_l: (0..23).map(|_| RollingWindow::new(l)).collect(),
+            z_s: (0..23).map(|_| RollingWindow::new(s)).collect(),
+            highs: DONS
+                .iter()
+                .map(|n| RollingExtreme::new(*n as usize, true))
+                .collect(),
+            lows: DONS
+                .iter()
+                .map(|n| RollingExtreme::new(*n as usize, false))
+                .collect(),
+        };
+        for candle in candles.iter() {
+            state.push(candle);
+        }
+        state.updates = 0;
+        state
+    }
+
+    pub fn push(&mut self, candle: &ProductionCandle) {
+        // Donchians are over the closes before the current candle
+        let prev = match &self.last {
+            Some(last) => {
+                for d in self.highs.iter_mut().chain(self.lows.iter_mut()) {
+                    d.push(last.close);
+                }
+                last.close
+            }
+            None => Decimal::ZERO,
+        };
+        let values = z_values(candle, prev);
+        for (i, v) in values.iter().enumerate() {
+            self.z_l[i].push(*v);
+            self.z_s[i].push(*v);
+        }
+        self.ret = values[0];
+        self.ma_l.push(candle.close);
+        self.ma_s.push(candle.close);
+        self.atr_l.push(values[1]);
+        self.atr_s.push(values[1]);
+        self.datetime = candle.datetime;
+        self.last = Some(candle.clone());
+        self.updates += 1;
+    }
+
+    pub fn metric(&self, market: &MarketDetail) -> ResearchMetric {
+        let last = self
+            .last
+            .as_ref()
+            .expect("Expected candle in metric state.");
+        let z_l: Vec<_> = self.z_l.iter().map(|w| w.z().round_dp(4)).collect();
+        let z_s: Vec<_> = self.z_s.iter().map(|w| w.z().round_dp(4)).collect();
+        let h: Vec<_> = self.highs.iter().map(|d| d.value()).collect();
+        let l: Vec<_> = self.lows.iter().map(|d| d.value()).collect();
+        let atr_l = self.atr_l.value().round_dp(8);
+        let atr_s = self.atr_s.value().round_dp(8);
+        ResearchMetric {
+            market_id: market.market_id,
+            tf: self.tf,
+            datetime: last.datetime,
+            high: last.high,
+            low: last.low,
+            close: last.close,
+            atr_l,
+            atr_s,
+            ma_filter: filter(self.ma_l.value(), self.ma_s.value()),
+            atr_filter: filter(atr_l, atr_s),
+            direction: match self.ret.cmp(&Decimal::ZERO) {
+                Ordering::Less => MetricDirection::Down,
+                Ordering::Equal => MetricDirection::NC,
+                Ordering::Greater => MetricDirection::Up,
+            },
+            return_z_l: z_l[0],
+            return_z_s: z_s[0],
+            tr_z_l: z_l[1],
+            tr_z_s: z_s[1],
+            upper_wick_z_l: z_l[2],
+            upper_wick_z_s: z_s[2],
+            body_z_l: z_l[3],
+            body_z_s: z_s[3],
+            lower_wick_z_l: z_l[4],
+            lower_wick_z_s: z_s[4],
+            volume_z_l: z_l[5],
+            volume_z_s: z_s[5],
+            volume_net_z_l: z_l[6],
+            volume_net_z_s: z_s[6],
+            volume_pct_z_l: z_l[7],
+            volume_pct_z_s: z_s[7],
+            volume_liq_z_l: z_l[8],
+            volume_liq_z_s: z_s[8],
+            volume_liq_net_z_l: z_l[9],
+            volume_liq_net_z_s: z_s[9],
+            volume_liq_pct_z_l: z_l[10],
+            volume_liq_pct_z_s: z_s[10],
+            value_z_l: z_l[11],
+            value_z_s: z_s[11],
+            value_net_z_l: z_l[12],
+            value_net_z_s: z_s[12],
+            value_pct_z_l: z_l[13],
+            value_pct_z_s: z_s[13],
+            value_liq_z_l: z_l[14],
+            value_liq_z_s: z_s[14],
+            value_liq_net_z_l: z_l[15],
+            value_liq_net_z_s: z_s[15],
+            value_liq_pct_z_l: z_l[16],
+            value_liq_pct_z_s: z_s[16],
+            trade_count_z_l: z_l[17],
+            trade_count_z_s: z_s[17],
+            trade_count_net_z_l: z_l[18],
+            trade_count_net_z_s: z_s[18],
+            trade_count_pct_z_l: z_l[19],
+            trade_count_pct_z_s: z_s[19],
+            liq_count_z_l: z_l[20],
+            liq_count_z_s: z_s[20],
+            liq_count_net_z_l: z_l[21],
+            liq_count_net_z_s: z_s[21],
+            liq_count_pct_z_l: z_l[22],
+            liq_count_pct_z_s: z_s[22],
+            high4: h[0],
+            low4: l[0],
+            high8: h[1],
+            low8: l[1],
+            high16: h[2],
+            low16: l[2],
+            high32: h[3],
+            low32: l[3],
+            high64: h[4],
+            low64: l[4],
+            high128: h[5],
+            low128: l[5],
+            high256: h[6],
+            low256: l[6],
+            version: METRIC_VERSION,
+            param_hash: self.tf.metric_param_hash(),
+        }
+    }
+}
+
+impl ElDorado {
+    // Metric for the time frame as of the last heartbeat candle. The metric state is updated with
+    // the candles added since its last update. Every FULL_RECOMPUTE candles, or without a state,
+    // the metric is calculated in full and the state rebuilt from the candles.
+    pub fn update_metric(
+        &self,
+        market: &MarketDetail,
+        hb: &mut Heartbeat,
+        tf: TimeFrame,
+    ) -> ResearchMetric {
+        let candles = &hb.candles[&tf];
+        if let Some(state) = hb.metric_states.get_mut(&tf) {
+            let i = candles.partition_point(|c| c.datetime <= state.datetime);
+            if i > 0 && state.updates + candles.len() - i < FULL_RECOMPUTE {
+                for candle in candles[i..].iter() {
+                    state.push(candle);
+                }
+                return state.metric(market);
+            }
+        }
+        hb.metric_states.insert(tf, MetricState::new(tf, candles));
+        ResearchMetric::new(market, tf, candles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        metrics::ResearchMetric,
+        rolling::{MetricState, RollingExtreme, RollingWindow},
+        test_helpers::{market, start, zig_zag_candles},
+        utilities::TimeFrame,
+    };
+    use rust_decimal::prelude::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn rolling_estimators_match_full_window() {
+        let values: Vec<_> = (0..20).map(|i| Decimal::from((i * 7) % 11)).collect();
+        let mut w = RollingWindow::new(5);
+        let mut max = RollingExtreme::new(5, true);
+        for (i, v) in values.iter().enumerate() {
+            w.push(*v);
+            max.push(*v);
+            let window = &values[(i + 1).saturating_sub(5)..=i];
+            let mean = window.iter().sum::<Decimal>() / Decimal::from(window.len());
+            assert!((w.mean - mean).abs() < dec!(0.0000000001));
+            if i >= 4 {
+                assert_eq!(max.value(), window.iter().max().cloned());
+            }
+        }
+    }
+
+    #[test]
+    pub fn incremental_metric_matches_full_recompute() {
+        let market = market("BTC-USD", TimeFrame::T15);
+        let tf = TimeFrame::T15;
+        let candles = zig_zag_candles(start(), 400);
+        let mut state = MetricState::new(tf, &candles[..100]);
+        for i in 100..candles.len() {
+            state.push(&candles[i]);
+            let inc = state.metric(&market);
+            let full = ResearchMetric::new(&market, tf, &candles[..=i]);
+            let close = |a: Decimal, b: Decimal| (a - b).abs() <= dec!(0.0001);
+            assert_eq!(inc.datetime, full.datetime);
+            assert_eq!(inc.direction, full.direction);
+            assert_eq!(inc.ma_filter, full.ma_filter);
+            assert!(close(inc.atr_l, full.atr_l) && close(inc.atr_s, full.atr_s));
+            assert!(close(inc.return_z_s, full.return_z_s));
+            assert!(close(inc.return_z_l, full.return_z_l));
+            assert!(close(inc.tr_z_s, full.tr_z_s));
+            assert!(close(inc.volume_z_l, full.volume_z_l));
+            assert!(close(inc.trade_count_pct_z_s, full.trade_count_pct_z_s));
+            assert_eq!(inc.high4, full.high4);
+            assert_eq!(inc.low32, full.low32);
+            assert_eq!(inc.high256, full.high256);
+            assert_eq!(inc.low256, full.low256);
+        }
+    }
+}
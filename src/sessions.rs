@@ -0,0 +1,309 @@
+use crate::{
+    candles::ResearchCandle,
+    configuration::{SessionConfig, SessionSettings},
+    eldorado::ElDorado,
+    markets::MarketDetail,
+    utilities::TimeFrame,
+};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use std::{convert::TryFrom, path::PathBuf};
+
+// Session that anchors the H12, D01 and W01 candles to a local time of day in a time zone, ie
+// 00:00 America/New_York or 08:00 UTC. Days are in local time so they are 23 or 25 hours long
+// across DST changes. All other time frames are not affected by the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub tz: Tz,
+    pub start: NaiveTime,
+}
+
+impl Session {
+    // Default session: days start at 00:00 UTC
+    pub fn utc() -> Self {
+        Self {
+            tz: Tz::UTC,
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+        }
+    }
+
+    pub fn is_utc(&self) -> bool {
+        *self == Self::utc()
+    }
+
+    // Label for file names, ie America-New_York_0000
+    pub fn label(&self) -> String {
+        format!(
+            "{}_{}",
+            self.tz.name().replace('/', "-"),
+            self.start.format("%H%M")
+        )
+    }
+
+    // Check if the time frame intervals for this session differ from the UTC intervals
+    pub fn anchors(&self, tf: &TimeFrame) -> bool {
+        matches!(tf, TimeFrame::H12 | TimeFrame::D01 | TimeFrame::W01) && !self.is_utc()
+    }
+
+    // Start of the time frame interval containing the datetime
+    pub fn trunc(&self, tf: &TimeFrame, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        if !self.anchors(tf) {
+            return tf.trunc(dt);
+        }
+        let day = self.session_day(dt);
+        match tf {
+            TimeFrame::H12 => {
+                let start = day.and_time(self.start);
+                let mid = self.localize(start + Duration::hours(12));
+                if *dt >= mid {
+                    mid
+                } else {
+                    self.localize(start)
+                }
+            }
+            TimeFrame::W01 => {
+                let monday = day - Duration::days(day.weekday().num_days_from_monday() as i64);
+                self.localize(monday.and_time(self.start))
+            }
+            _ => self.localize(day.and_time(self.start)),
+        }
+    }
+
+    // Start of the next interval for a datetime at the start of an interval. Local days are at
+    // least 23 hours so adding an extra hour to the nominal length always lands in the next one.
+    pub fn next(&self, tf: &TimeFrame, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        if self.anchors(tf) {
+            self.trunc(tf, &(*dt + tf.as_dur() + Duration::hours(1)))
+        } else {
+            tf.next(dt)
+        }
+    }
+
+    // Interval starts from the start through the end exclusive
+    pub fn date_range(
+        &self,
+        tf: &TimeFrame,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        let mut dt = *start;
+        let mut dr = Vec::new();
+        while dt < *end {
+            dr.push(dt);
+            dt = self.next(tf, &dt);
+        }
+        dr
+    }
+
+    // Time frame to resample the tf from. H12 resamples from the H04 candles which do not line up
+    // with a session that is not on a 4 hour boundary so use the T15 candles instead.
+    pub fn resample_from(&self, tf: &TimeFrame) -> TimeFrame {
+        match tf {
+            TimeFrame::H12 if self.anchors(tf) => TimeFrame::T15,
+            _ => tf.resample_from(),
+        }
+    }
+
+    // Local date of the session the datetime is in
+    fn session_day(&self, dt: &DateTime<Utc>) -> NaiveDate {
+        let local = dt.with_timezone(&self.tz);
+        if local.time() < self.start {
+            local.date_naive().pred_opt().unwrap()
+        } else {
+            local.date_naive()
+        }
+    }
+
+    // Local time to UTC. Ambiguous times use the first occurence and times that fall in a DST gap
+    // are moved forward an hour.
+    fn localize(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let dt = self
+            .tz
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                self.tz
+                    .from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+            })
+            .expect("Failed to localize session start.");
+        dt.with_timezone(&Utc)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl TryFrom<&SessionConfig> for Session {
+    type Error = String;
+
+    fn try_from(config: &SessionConfig) -> Result<Self, Self::Error> {
+        let tz: Tz = config.timezone.parse()?;
+        let start = NaiveTime::parse_from_str(&config.start, "%H:%M")
+            .map_err(|e| format!("{} is not a valid session start: {}", config.start, e))?;
+        if start.minute() % 15 != 0 {
+            return Err(format!("{} is not on a 15 minute boundary.", config.start));
+        }
+        Ok(Self { tz, start })
+    }
+}
+
+impl SessionSettings {
+    // Resolve the session for the market: market, default and then UTC.
+    pub fn session(&self, market: &MarketDetail) -> Session {
+        self.markets
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&market.market_name))
+            .map(|(_, c)| c)
+            .or(self.default.as_ref())
+            .map(|c| Session::try_from(c).expect("Failed to parse session."))
+            .unwrap_or_default()
+    }
+}
+
+impl ElDorado {
+    // Resample the archived S15 candles of the market to the time frame anchored to the session
+    // given with the request instead of the configured session of the market. Returns the candles
+    // opened from the interval containing from up to to, read across the month files as needed.
+    pub fn resample_archived_candles_for_session(
+        &self,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        session: &Session,
+    ) -> Vec<ResearchCandle> {
+        let start = session.trunc(tf, from);
+        let end = session.trunc(tf, to);
+        let mut candles = Vec::new();
+        let mut month = Self::trunc_month_dt(&start);
+        while month < end {
+            candles.extend(
+                self.read_research_candles_from_file_for_month(market, &month, &TimeFrame::S15)
+                    .into_iter()
+                    .filter(|c| c.datetime >= start && c.datetime < end),
+            );
+            month = Self::next_month_dt(&month);
+        }
+        if candles.is_empty() {
+            return candles;
+        }
+        ResearchCandle::resample_by_hashmap(&candles, tf, session)
+    }
+
+    // Write the candles resampled for the session next to the market candle archive, ie
+    // {archive}/candles/gdax/BTCUSD/sessions/BTCUSD_d01_America-New_York_0000_20230301_20230401.csv
+    pub fn write_session_candles_to_file(
+        &self,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        session: &Session,
+        candles: &[ResearchCandle],
+    ) -> PathBuf {
+        let path = PathBuf::from(&self.storage_path)
+            .join("candles")
+            .join(market.exchange_name.as_str())
+            .join(market.as_strip())
+            .join("sessions");
+        std::fs::create_dir_all(&path).expect("Failed to create directories.");
+        let pb = path.join(format!(
+            "{}_{}_{}_{}_{}.csv",
+            market.as_strip(),
+            tf.as_str(),
+            session.label(),
+            from.format("%Y%m%d"),
+            to.format("%Y%m%d"),
+        ));
+        self.write_research_candles_to_file(&pb, candles);
+        pb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{configuration::SessionConfig, sessions::Session, utilities::TimeFrame};
+    use chrono::{TimeZone, Utc};
+    use std::convert::TryFrom;
+
+    fn new_york() -> Session {
+        Session::try_from(&SessionConfig {
+            timezone: "America/New_York".to_string(),
+            start: "00:00".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    pub fn new_york_days_follow_dst() {
+        let ny = new_york();
+        assert_eq!(ny.label(), "America-New_York_0000");
+        // EST is UTC-5 and EDT is UTC-4. DST started 2023-03-12.
+        let dt = Utc.with_ymd_and_hms(2023, 3, 10, 3, 0, 0).unwrap();
+        let start = ny.trunc(&TimeFrame::D01, &dt);
+        assert_eq!(start, Utc.with_ymd_and_hms(2023, 3, 9, 5, 0, 0).unwrap());
+        let end = Utc.with_ymd_and_hms(2023, 3, 14, 0, 0, 0).unwrap();
+        let dr = ny.date_range(&TimeFrame::D01, &start, &end);
+        assert_eq!(
+            dr,
+            vec![
+                Utc.with_ymd_and_hms(2023, 3, 9, 5, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 3, 10, 5, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 3, 11, 5, 0, 0).unwrap(),
+                // 23 hour day as DST starts at 02:00 local
+                Utc.with_ymd_and_hms(2023, 3, 12, 5, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 3, 13, 4, 0, 0).unwrap(),
+            ]
+        );
+        // H12 halves are at local midnight and noon
+        let noon = Utc.with_ymd_and_hms(2023, 3, 12, 18, 0, 0).unwrap();
+        assert_eq!(
+            ny.trunc(&TimeFrame::H12, &noon),
+            Utc.with_ymd_and_hms(2023, 3, 12, 16, 0, 0).unwrap()
+        );
+        // Week starts Monday 00:00 local
+        assert_eq!(
+            ny.trunc(&TimeFrame::W01, &noon),
+            Utc.with_ymd_and_hms(2023, 3, 6, 5, 0, 0).unwrap()
+        );
+        // Other time frames are not anchored
+        assert_eq!(
+            ny.trunc(&TimeFrame::H04, &noon),
+            Utc.with_ymd_and_hms(2023, 3, 12, 16, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn utc_offset_session_and_default() {
+        let settlement = Session::try_from(&SessionConfig {
+            timezone: "UTC".to_string(),
+            start: "08:00".to_string(),
+        })
+        .unwrap();
+        let dt = Utc.with_ymd_and_hms(2023, 3, 10, 7, 59, 0).unwrap();
+        assert_eq!(
+            settlement.trunc(&TimeFrame::D01, &dt),
+            Utc.with_ymd_and_hms(2023, 3, 9, 8, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Session::utc().trunc(&TimeFrame::D01, &dt),
+            TimeFrame::D01.trunc(&dt)
+        );
+        assert_eq!(settlement.resample_from(&TimeFrame::H12), TimeFrame::T15);
+        assert_eq!(
+            Session::utc().resample_from(&TimeFrame::H12),
+            TimeFrame::H04
+        );
+        assert!(Session::try_from(&SessionConfig {
+            timezone: "UTC".to_string(),
+            start: "08:10".to_string(),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use crate::{
+    alerts::Alert,
+    conqui::Z_METRICS,
+    eldorado::{ElDorado, ElDoradoError},
+    markets::MarketDetail,
+    metrics::{ResearchMetric, DONS},
+    mita::Heartbeat,
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+// Name, rule, markets, tfs and cooldown of a signal_rules row
+type SignalRuleRow = (String, String, Vec<String>, Vec<String>, i64);
+
+// Value in a rule, either a research metric field or a number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Field(String),
+    Value(Decimal),
+}
+
+impl Operand {
+    fn parse(s: &str) -> Option<Self> {
+        if let Ok(v) = Decimal::from_str(s) {
+            return Some(Operand::Value(v));
+        }
+        let s = s.to_lowercase();
+        field(&s, None).map(|_| Operand::Field(s))
+    }
+
+    // None for a Donchian field the metric does not have the range for yet
+    fn value(&self, metric: &ResearchMetric) -> Option<Decimal> {
+        match self {
+            Operand::Field(f) => field(f, Some(metric)).flatten(),
+            Operand::Value(v) => Some(*v),
+        }
+    }
+}
+
+// Research metric field by name ie close, atr_s, return_z_s, high64. Without a metric it only
+// checks the field name.
+fn field(name: &str, metric: Option<&ResearchMetric>) -> Option<Option<Decimal>> {
+    let value = |f: fn(&ResearchMetric) -> Decimal| Some(metric.map(f));
+    match name {
+        "close" => return value(|m| m.close),
+        "high" => return value(|m| m.high),
+        "low" => return value(|m| m.low),
+        "atr_l" => return value(|m| m.atr_l),
+        "atr_s" => return value(|m| m.atr_s),
+        _ => (),
+    }
+    if let Some(z) = name.strip_suffix("_z_l").filter(|z| Z_METRICS.contains(z)) {
+        return Some(metric.map(|m| m.z_l(z)));
+    }
+    if let Some(z) = name.strip_suffix("_z_s").filter(|z| Z_METRICS.contains(z)) {
+        return Some(metric.map(|m| m.z_s(z)));
+    }
+    let range = |r: &str| r.parse::<i32>().ok().filter(|r| DONS.contains(r));
+    if let Some(r) = name.strip_prefix("high").and_then(range) {
+        return Some(metric.and_then(|m| m.don_h(r)));
+    }
+    if let Some(r) = name.strip_prefix("low").and_then(range) {
+        return Some(metric.and_then(|m| m.don_l(r)));
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl Comparison {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            ">" => Some(Comparison::Gt),
+            ">=" => Some(Comparison::Gte),
+            "<" => Some(Comparison::Lt),
+            "<=" => Some(Comparison::Lte),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Comparison::Gt => ">",
+            Comparison::Gte => ">=",
+            Comparison::Lt => "<",
+            Comparison::Lte => "<=",
+        }
+    }
+
+    fn holds(&self, a: Decimal, b: Decimal) -> bool {
+        match self {
+            Comparison::Gt => a > b,
+            Comparison::Gte => a >= b,
+            Comparison::Lt => a < b,
+            Comparison::Lte => a <= b,
+        }
+    }
+}
+
+// Condition on the metrics of a market, ie
+//   return_z_s > 3
+//   close > high64
+//   direction flips to down
+// Comparisons fire when they become true so a condition that stays true only fires once. Flips
+// fire when the direction, ma_filter or atr_filter changes to the value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Compare {
+        lhs: Operand,
+        op: Comparison,
+        rhs: Operand,
+    },
+    Flip {
+        field: String,
+        to: String,
+    },
+}
+
+impl Condition {
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let tokens: Vec<_> = rule.split_whitespace().collect();
+        match tokens.as_slice() {
+            [f, "flips", "to", to] | [f, "flips", to] => {
+                let (field, to) = (f.to_lowercase(), to.to_lowercase());
+                let valid = match field.as_str() {
+                    "direction" => ["up", "down", "nc"].contains(&to.as_str()),
+                    "ma_filter" | "atr_filter" => ["sl", "ls", "equal"].contains(&to.as_str()),
+                    _ => return Err(format!("{} is not a field that flips.", f)),
+                };
+                match valid {
+                    true => Ok(Condition::Flip { field, to }),
+                    false => Err(format!("{} is not a value of {}.", to, field)),
+                }
+            }
+            [lhs, op, rhs] => {
+                let operand = |s: &str| {
+                    Operand::parse(s).ok_or(format!("{} is not a metric field or number.", s))
+                };
+                Ok(Condition::Compare {
+                    lhs: operand(lhs)?,
+                    op: Comparison::parse(op).ok_or(format!("{} is not a comparison.", op))?,
+                    rhs: operand(rhs)?,
+                })
+            }
+            _ => Err(format!("Could not parse rule: {}", rule)),
+        }
+    }
+
+    fn flip_value(field: &str, metric: &ResearchMetric) -> &'static str {
+        match field {
+            "direction" => metric.direction.as_str(),
+            "ma_filter" => metric.ma_filter.as_str(),
+            _ => metric.atr_filter.as_str(),
+        }
+    }
+
+    fn holds(&self, metric: &ResearchMetric) -> bool {
+        match self {
+            Condition::Compare { lhs, op, rhs } => match (lhs.value(metric), rhs.value(metric)) {
+                (Some(a), Some(b)) => op.holds(a, b),
+                _ => false,
+            },
+            Condition::Flip { field, to } => Self::flip_value(field, metric) == to,
+        }
+    }
+
+    // Whether the condition became true from the previous metric to the current metric
+    pub fn fires(&self, prev: &ResearchMetric, metric: &ResearchMetric) -> bool {
+        self.holds(metric) && !self.holds(prev)
+    }
+
+    // Description of the condition with the values it fired on
+    pub fn describe(&self, metric: &ResearchMetric) -> String {
+        match self {
+            Condition::Compare { lhs, op, rhs } => {
+                let show = |o: &Operand| match (o, o.value(metric)) {
+                    (Operand::Field(f), Some(v)) => format!("{} {}", f, v),
+                    (Operand::Field(f), None) => f.clone(),
+                    (Operand::Value(v), _) => v.to_string(),
+                };
+                format!("{} {} {}", show(lhs), op.as_str(), show(rhs))
+            }
+            Condition::Flip { field, to } => format!("{} flipped to {}", field, to),
+        }
+    }
+}
+
+// Signal rule configured for the mita to evaluate on each new metric, ie
+//   - name: "return spike"
+//     rule: "return_z_s > 3 on h01"
+//     cooldown: 3600
+// The time frames are set with `on` in the rule or with tfs, empty for all of them. After firing
+// the rule does not fire again for the market and time frame for the cooldown seconds. Rules can
+// also be stored in the signal_rules table, which the mita reads when it starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalSpec {
+    pub name: String,
+    pub rule: String,
+    #[serde(default)]
+    pub markets: Vec<String>,
+    #[serde(default)]
+    pub tfs: Vec<TimeFrame>,
+    #[serde(default)]
+    pub cooldown: i64,
+}
+
+impl SignalSpec {
+    // Condition and time frames of the rule
+    pub fn parse(&self) -> Result<(Condition, Vec<TimeFrame>), String> {
+        let lower = self.rule.to_lowercase();
+        let (rule, tfs) = match lower.rsplit_once(" on ") {
+            Some((rule, tfs)) => {
+                let tfs = tfs
+                    .split(',')
+                    .map(|tf| {
+                        TimeFrame::try_from(tf.trim().to_string())
+                            .map_err(|_| format!("{} is not a time frame.", tf.trim()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                (rule, tfs)
+            }
+            None => (lower.as_str(), Vec::new()),
+        };
+        let mut tfs: Vec<_> = self.tfs.iter().copied().chain(tfs).collect();
+        tfs.dedup();
+        Ok((Condition::parse(rule)?, tfs))
+    }
+
+    pub async fn upsert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO signal_rules (name, rule, markets, tfs, cooldown, insert_dt)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (name) DO UPDATE
+            SET (rule, markets, tfs, cooldown, insert_dt) =
+                (EXCLUDED.rule, EXCLUDED.markets, EXCLUDED.tfs, EXCLUDED.cooldown, EXCLUDED.insert_dt)
+            "#;
+        sqlx::query(sql)
+            .bind(&self.name)
+            .bind(&self.rule)
+            .bind(&self.markets)
+            .bind(self.tfs.iter().map(|tf| tf.as_str()).collect::<Vec<_>>())
+            .bind(self.cooldown)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn select_all(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT name, rule, markets, tfs, cooldown FROM signal_rules
+            ORDER BY name
+            "#;
+        let rows: Vec<SignalRuleRow> = sqlx::query_as(sql).fetch_all(pool).await?;
+        rows.into_iter()
+            .map(|r| {
+                Ok(Self {
+                    name: r.0,
+                    rule: r.1,
+                    markets: r.2,
+                    tfs: r
+                        .3
+                        .into_iter()
+                        .map(TimeFrame::try_from)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| sqlx::Error::Decode(e.into()))?,
+                    cooldown: r.4,
+                })
+            })
+            .collect()
+    }
+
+    pub fn applies(&self, market: &MarketDetail, tf: &TimeFrame) -> bool {
+        let tfs = self.parse().map(|(_, tfs)| tfs).unwrap_or_default();
+        (self.markets.is_empty()
+            || self
+                .markets
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(&market.market_name)))
+            && (tfs.is_empty() || tfs.contains(tf))
+    }
+}
+
+// Signal rule that fired on the metric of a market and time frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalEvent {
+    pub signal_id: Uuid,
+    pub market_id: Uuid,
+    pub name: String,
+    pub tf: TimeFrame,
+    pub datetime: DateTime<Utc>,
+    pub message: String,
+}
+
+impl SignalEvent {
+    pub fn new(
+        market: &MarketDetail,
+        spec: &SignalSpec,
+        condition: &Condition,
+        metric: &ResearchMetric,
+    ) -> Self {
+        Self {
+            signal_id: Uuid::new_v4(),
+            market_id: market.market_id,
+            name: spec.name.clone(),
+            tf: metric.tf,
+            datetime: metric.datetime,
+            message: format!(
+                "{} {} {} {}: {}",
+                spec.name,
+                market.market_name,
+                metric.tf,
+                metric.datetime,
+                condition.describe(metric)
+            ),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO signal_events (signal_id, market_id, name, tf, datetime, message, insert_dt)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#;
+        sqlx::query(sql)
+            .bind(self.signal_id)
+            .bind(self.market_id)
+            .bind(&self.name)
+            .bind(self.tf.as_str())
+            .bind(self.datetime)
+            .bind(&self.message)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn select_by_id(pool: &PgPool, market_id: &Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let sql = r#"
+            SELECT signal_id, market_id, name, tf, datetime, message FROM signal_events
+            WHERE market_id = $1
+            ORDER BY datetime
+            "#;
+        let rows: Vec<(Uuid, Uuid, String, String, DateTime<Utc>, String)> =
+            sqlx::query_as(sql).bind(market_id).fetch_all(pool).await?;
+        rows.into_iter()
+            .map(|r| {
+                Ok(Self {
+                    signal_id: r.0,
+                    market_id: r.1,
+                    name: r.2,
+                    tf: TimeFrame::try_from(r.3).map_err(|e| sqlx::Error::Decode(e.into()))?,
+                    datetime: r.4,
+                    message: r.5,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Heartbeat {
+    // Keep the metric as the previous metric of its time frame for the signal rules and return the
+    // one it replaces. The heartbeat metrics only hold the time frames that closed in the last
+    // interval, so they only seed the time frames not kept yet, ie all of them after the sync.
+    pub fn swap_prev_metric(&mut self, metric: &ResearchMetric) -> Option<ResearchMetric> {
+        if let Some(metrics) = self.metrics.as_ref() {
+            for m in metrics.iter() {
+                self.prev_metrics.entry(m.tf).or_insert_with(|| m.clone());
+            }
+        }
+        self.prev_metrics.insert(metric.tf, metric.clone())
+    }
+}
+
+impl ElDorado {
+    // Rules in the signal_rules table that parse. Rules that do not are skipped so a bad row does
+    // not stop the mita, unlike a bad rule in the config.
+    pub async fn select_signal_rules(&self) -> Result<Vec<SignalSpec>, ElDoradoError> {
+        let rules = self.storage.select_signal_rules().await?;
+        Ok(rules
+            .into_iter()
+            .filter(|spec| match spec.parse() {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Invalid signal rule for {}: {}", spec.name, e);
+                    false
+                }
+            })
+            .collect())
+    }
+
+    // Configured signal rules and the signal_rules table rules, which replace a configured rule of
+    // the same name
+    pub fn signal_specs(&self) -> Vec<&SignalSpec> {
+        self.signals
+            .iter()
+            .filter(|s| !self.signal_rules.iter().any(|r| r.name == s.name))
+            .chain(self.signal_rules.iter())
+            .collect()
+    }
+
+    // Evaluate the signal rules on the new metrics against the previous metrics in the heartbeat.
+    // Fired signals are stored and sent as alerts if the interval just closed, so a sync after a
+    // restart stores the signals without sending them.
+    pub async fn evaluate_signals(
+        &self,
+        market: &MarketDetail,
+        hb: &mut Heartbeat,
+        metrics: &[ResearchMetric],
+    ) -> Result<(), ElDoradoError> {
+        let specs = self.signal_specs();
+        if specs.is_empty() {
+            return Ok(());
+        }
+        for metric in metrics.iter() {
+            let prev = match hb.swap_prev_metric(metric) {
+                Some(p) if p.datetime < metric.datetime => p,
+                _ => continue,
+            };
+            for spec in specs.iter().filter(|s| s.applies(market, &metric.tf)) {
+                let condition = match spec.parse() {
+                    Ok((c, _)) => c,
+                    Err(_) => continue,
+                };
+                if !condition.fires(&prev, metric) {
+                    continue;
+                }
+                let key = (spec.name.clone(), metric.tf);
+                if let Some(last) = hb.signals.get(&key) {
+                    if metric.datetime < *last + Duration::seconds(spec.cooldown) {
+                        continue;
+                    }
+                }
+                hb.signals.insert(key, metric.datetime);
+                let event = SignalEvent::new(market, spec, &condition, metric);
+                println!("{} - Signal: {}", Utc::now(), event.message);
+                self.storage.insert_signal(&event).await?;
+                if Utc::now() < metric.tf.next(&metric.datetime) + metric.tf.as_dur() {
+                    Alert::new(&self.instance, &event.message)
+                        .send(&self.twilio)
+                        .await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        metrics::{MetricDirection, ResearchMetric},
+        mita::Heartbeat,
+        signals::{Condition, SignalSpec},
+        test_helpers::{candles, market},
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal::prelude::*;
+    use rust_decimal_macros::dec;
+
+    fn metric(closes: &[Decimal]) -> ResearchMetric {
+        let market = market("BTC-USD", TimeFrame::H01);
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let candles = candles(start, &TimeFrame::H01, closes);
+        ResearchMetric::new(&market, TimeFrame::H01, &candles)
+    }
+
+    #[test]
+    pub fn rules_parse_with_time_frames() {
+        let spec = SignalSpec {
+            name: "spike".to_string(),
+            rule: "return_z_s > 3 on H01".to_string(),
+            markets: Vec::new(),
+            tfs: Vec::new(),
+            cooldown: 0,
+        };
+        let (condition, tfs) = spec.parse().unwrap();
+        assert_eq!(tfs, vec![TimeFrame::H01]);
+        assert!(matches!(condition, Condition::Compare { .. }));
+        assert!(Condition::parse("direction flips to Down").is_ok());
+        assert!(Condition::parse("close > high64").is_ok());
+        assert!(Condition::parse("close > high65").is_err());
+        assert!(Condition::parse("direction flips to sideways").is_err());
+        assert!(Condition::parse("volume_z_s >> 2").is_err());
+    }
+
+    #[test]
+    pub fn conditions_fire_when_they_become_true() {
+        let mut closes: Vec<_> = (0..20).map(|i| dec!(100) + Decimal::from(i % 2)).collect();
+        let prev = metric(&closes);
+        closes.push(dec!(110));
+        let breakout = metric(&closes);
+        closes.push(dec!(111));
+        let higher = metric(&closes);
+        let above = Condition::parse("close > high4").unwrap();
+        assert!(above.fires(&prev, &breakout));
+        // Still above the range so it does not fire again
+        assert!(!above.fires(&breakout, &higher));
+        assert_eq!(
+            above.describe(&breakout),
+            format!("close 110 > high4 {}", breakout.high4.unwrap())
+        );
+        let flip = Condition::parse(&format!(
+            "direction flips to {}",
+            breakout.direction.as_str()
+        ))
+        .unwrap();
+        assert_eq!(
+            flip.fires(&prev, &breakout),
+            prev.direction != breakout.direction
+        );
+        assert!(!flip.fires(&breakout, &breakout));
+        assert_ne!(breakout.direction, MetricDirection::NC);
+    }
+
+    #[test]
+    pub fn previous_metrics_survive_base_updates() {
+        let closes: Vec<_> = (0..20).map(|i| dec!(100) + Decimal::from(i % 2)).collect();
+        let h01 = metric(&closes);
+        let base = |i: i64| {
+            let mut m = h01.clone();
+            m.tf = TimeFrame::T15;
+            m.datetime = h01.datetime + Duration::minutes(15 * i);
+            m
+        };
+        // After the sync the heartbeat holds the metrics of every time frame
+        let mut hb = Heartbeat::new();
+        hb.metrics = Some(vec![base(0), h01.clone()]);
+        // Base time frame updates between the H01 closes replace the heartbeat metrics
+        for i in 1..4 {
+            let prev = hb.swap_prev_metric(&base(i)).unwrap();
+            assert_eq!(prev.datetime, base(i - 1).datetime);
+            hb.metrics = Some(vec![base(i)]);
+        }
+        let mut next = h01.clone();
+        next.datetime = h01.datetime + Duration::hours(1);
+        assert_eq!(hb.swap_prev_metric(&next).unwrap().datetime, h01.datetime);
+        assert_eq!(hb.prev_metrics[&TimeFrame::H01].datetime, next.datetime);
+    }
+}
@@ -0,0 +1,1061 @@
+use crate::{
+    conqui::Screen,
+    correlations::CorrelationMatrix,
+    forming::FormingCandle,
+    indicators::IndicatorValues,
+    markets::{MarketArchiveDetail, MarketCandleDetail, MarketDetail, MarketTradeDetail},
+    metrics::{ResearchMetric, METRIC_VERSION},
+    signals::{SignalEvent, SignalSpec},
+    utilities::TimeFrame,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    PgPool,
+};
+use std::{fmt, str::FromStr};
+use uuid::Uuid;
+
+// Storage for the system tables that describe the markets and the state of their data along with
+// the research metrics calculated from the candles. The Postgres implementation is used for
+// production instances. The SQLite implementation is a single local file for computing metrics
+// from the file archive where there is no database server available. Trades and candles are not
+// part of storage so filling and archiving always need the exchange Postgres databases.
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    async fn select_markets(&self) -> Result<Vec<MarketDetail>, sqlx::Error>;
+    async fn select_market(&self, market_id: &Uuid) -> Result<MarketDetail, sqlx::Error>;
+    async fn insert_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error>;
+    async fn update_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error>;
+    async fn select_mtd(&self, market: &MarketDetail) -> Result<MarketTradeDetail, sqlx::Error>;
+    async fn insert_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error>;
+    async fn update_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error>;
+    async fn select_mcds(&self) -> Result<Vec<MarketCandleDetail>, sqlx::Error>;
+    async fn select_mcd(&self, market: &MarketDetail) -> Result<MarketCandleDetail, sqlx::Error>;
+    async fn insert_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error>;
+    async fn update_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error>;
+    async fn select_mad(&self, market: &MarketDetail) -> Result<MarketArchiveDetail, sqlx::Error>;
+    async fn insert_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error>;
+    async fn update_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error>;
+    async fn insert_metric(&self, metric: &ResearchMetric) -> Result<(), sqlx::Error>;
+    async fn select_metrics(&self, market_ids: &[Uuid])
+        -> Result<Vec<ResearchMetric>, sqlx::Error>;
+    // Latest metric of each market and time frame
+    async fn select_latest_metrics(
+        &self,
+        market_ids: &[Uuid],
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error>;
+    // Latest metric of each market for the time frame with a candle opened before the datetime
+    // as it was stored at the as of datetime
+    async fn select_metrics_lt_dt(
+        &self,
+        market_ids: &[Uuid],
+        tf: &TimeFrame,
+        dt: &DateTime<Utc>,
+        as_of: &DateTime<Utc>,
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error>;
+    async fn delete_metrics_by_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error>;
+    async fn delete_metrics_lt_dt(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error>;
+    async fn upsert_forming(&self, forming: &FormingCandle) -> Result<(), sqlx::Error>;
+    async fn select_forming(&self, market_id: &Uuid) -> Result<Vec<FormingCandle>, sqlx::Error>;
+    async fn upsert_indicators(&self, values: &IndicatorValues) -> Result<(), sqlx::Error>;
+    async fn select_indicators(
+        &self,
+        market_id: &Uuid,
+    ) -> Result<Vec<IndicatorValues>, sqlx::Error>;
+    async fn upsert_correlations(&self, matrix: &CorrelationMatrix) -> Result<(), sqlx::Error>;
+    async fn select_correlations(
+        &self,
+        tf: &TimeFrame,
+    ) -> Result<Vec<CorrelationMatrix>, sqlx::Error>;
+    async fn upsert_screen(&self, screen: &Screen) -> Result<(), sqlx::Error>;
+    async fn select_screens(&self, tf: &TimeFrame) -> Result<Vec<Screen>, sqlx::Error>;
+    async fn insert_signal(&self, event: &SignalEvent) -> Result<(), sqlx::Error>;
+    async fn select_signals(&self, market_id: &Uuid) -> Result<Vec<SignalEvent>, sqlx::Error>;
+    async fn upsert_signal_rule(&self, spec: &SignalSpec) -> Result<(), sqlx::Error>;
+    async fn select_signal_rules(&self) -> Result<Vec<SignalSpec>, sqlx::Error>;
+}
+
+#[derive(Debug)]
+pub struct PgStorage {
+    pub pool: PgPool,
+}
+
+impl PgStorage {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn select_markets(&self) -> Result<Vec<MarketDetail>, sqlx::Error> {
+        MarketDetail::select_all(&self.pool).await
+    }
+
+    async fn select_market(&self, market_id: &Uuid) -> Result<MarketDetail, sqlx::Error> {
+        MarketDetail::select_by_id(&self.pool, market_id).await
+    }
+
+    async fn insert_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        market.insert(&self.pool).await
+    }
+
+    async fn update_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            UPDATE markets
+            SET (market_type, base, base_step, base_min, quote, quote_step, status, tradable,
+                mita, tf, last_candle, asset_id) = ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11,
+                $12)
+            WHERE market_id = $13
+            "#;
+        sqlx::query(sql)
+            .bind(market.market_type.as_str())
+            .bind(&market.base)
+            .bind(market.base_step)
+            .bind(market.base_min)
+            .bind(&market.quote)
+            .bind(market.quote_step)
+            .bind(market.status.as_str())
+            .bind(market.tradable)
+            .bind(&market.mita)
+            .bind(market.tf.as_str())
+            .bind(market.last_candle)
+            .bind(market.asset_id)
+            .bind(market.market_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_mtd(&self, market: &MarketDetail) -> Result<MarketTradeDetail, sqlx::Error> {
+        MarketTradeDetail::select(&self.pool, market).await
+    }
+
+    async fn insert_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error> {
+        mtd.insert(&self.pool).await
+    }
+
+    async fn update_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            UPDATE market_trade_details
+            SET (market_start_ts, first_trade_ts, first_trade_id, last_trade_ts, last_trade_id,
+                previous_trade_day, previous_status, next_trade_day, next_status)
+                = ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            WHERE market_id = $10
+            "#;
+        sqlx::query(sql)
+            .bind(mtd.market_start_ts)
+            .bind(mtd.first_trade_ts)
+            .bind(&mtd.first_trade_id)
+            .bind(mtd.last_trade_ts)
+            .bind(&mtd.last_trade_id)
+            .bind(mtd.previous_trade_day)
+            .bind(mtd.previous_status.as_str())
+            .bind(mtd.next_trade_day)
+            .bind(mtd.next_status.as_ref().map(|ns| ns.as_str()))
+            .bind(mtd.market_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_mcds(&self) -> Result<Vec<MarketCandleDetail>, sqlx::Error> {
+        MarketCandleDetail::select_all(&self.pool).await
+    }
+
+    async fn select_mcd(&self, market: &MarketDetail) -> Result<MarketCandleDetail, sqlx::Error> {
+        MarketCandleDetail::select(&self.pool, market).await
+    }
+
+    async fn insert_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error> {
+        mcd.insert(&self.pool).await
+    }
+
+    async fn update_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            UPDATE market_candle_details
+            SET (first_candle, last_candle, last_trade_ts, last_trade_id, last_trade_price)
+                = ($1, $2, $3, $4, $5)
+            WHERE market_id = $6
+            "#;
+        sqlx::query(sql)
+            .bind(mcd.first_candle)
+            .bind(mcd.last_candle)
+            .bind(mcd.last_trade_ts)
+            .bind(&mcd.last_trade_id)
+            .bind(mcd.last_trade_price)
+            .bind(mcd.market_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_mad(&self, market: &MarketDetail) -> Result<MarketArchiveDetail, sqlx::Error> {
+        MarketArchiveDetail::select(&self.pool, market).await
+    }
+
+    async fn insert_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error> {
+        mad.insert(&self.pool).await
+    }
+
+    async fn update_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            UPDATE market_archive_details
+            SET (first_candle_dt, first_trade_dt, first_trade_price, first_trade_id,
+                last_candle_dt, last_trade_dt, last_trade_price, last_trade_id, next_month)
+                = ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            WHERE market_id = $10
+            "#;
+        sqlx::query(sql)
+            .bind(mad.first_candle_dt)
+            .bind(mad.first_trade_dt)
+            .bind(mad.first_trade_price)
+            .bind(&mad.first_trade_id)
+            .bind(mad.last_candle_dt)
+            .bind(mad.last_trade_dt)
+            .bind(mad.last_trade_price)
+            .bind(&mad.last_trade_id)
+            .bind(mad.next_month)
+            .bind(mad.market_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_metric(&self, metric: &ResearchMetric) -> Result<(), sqlx::Error> {
+        metric.insert(&self.pool).await
+    }
+
+    async fn select_metrics(
+        &self,
+        market_ids: &[Uuid],
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        ResearchMetric::select_by_ids(&self.pool, market_ids).await
+    }
+
+    async fn select_latest_metrics(
+        &self,
+        market_ids: &[Uuid],
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        ResearchMetric::select_by_ids_distinct(&self.pool, market_ids).await
+    }
+
+    async fn select_metrics_lt_dt(
+        &self,
+        market_ids: &[Uuid],
+        tf: &TimeFrame,
+        dt: &DateTime<Utc>,
+        as_of: &DateTime<Utc>,
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        ResearchMetric::select_by_ids_tf_lt_dt(&self.pool, market_ids, tf, dt, as_of).await
+    }
+
+    async fn delete_metrics_by_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        ResearchMetric::delete_by_market(&self.pool, market).await
+    }
+
+    async fn delete_metrics_lt_dt(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        ResearchMetric::delete_by_market_lt_dt(&self.pool, market, dt).await
+    }
+
+    async fn upsert_forming(&self, forming: &FormingCandle) -> Result<(), sqlx::Error> {
+        forming.upsert(&self.pool).await
+    }
+
+    async fn select_forming(&self, market_id: &Uuid) -> Result<Vec<FormingCandle>, sqlx::Error> {
+        FormingCandle::select_by_id(&self.pool, market_id).await
+    }
+
+    async fn upsert_indicators(&self, values: &IndicatorValues) -> Result<(), sqlx::Error> {
+        values.upsert(&self.pool).await
+    }
+
+    async fn select_indicators(
+        &self,
+        market_id: &Uuid,
+    ) -> Result<Vec<IndicatorValues>, sqlx::Error> {
+        IndicatorValues::select_by_id(&self.pool, market_id).await
+    }
+
+    async fn upsert_correlations(&self, matrix: &CorrelationMatrix) -> Result<(), sqlx::Error> {
+        matrix.upsert(&self.pool).await
+    }
+
+    async fn select_correlations(
+        &self,
+        tf: &TimeFrame,
+    ) -> Result<Vec<CorrelationMatrix>, sqlx::Error> {
+        CorrelationMatrix::select_latest(&self.pool, tf).await
+    }
+
+    async fn upsert_screen(&self, screen: &Screen) -> Result<(), sqlx::Error> {
+        screen.upsert(&self.pool).await
+    }
+
+    async fn select_screens(&self, tf: &TimeFrame) -> Result<Vec<Screen>, sqlx::Error> {
+        Screen::select_latest(&self.pool, tf).await
+    }
+
+    async fn insert_signal(&self, event: &SignalEvent) -> Result<(), sqlx::Error> {
+        event.insert(&self.pool).await
+    }
+
+    async fn select_signals(&self, market_id: &Uuid) -> Result<Vec<SignalEvent>, sqlx::Error> {
+        SignalEvent::select_by_id(&self.pool, market_id).await
+    }
+
+    async fn upsert_signal_rule(&self, spec: &SignalSpec) -> Result<(), sqlx::Error> {
+        spec.upsert(&self.pool).await
+    }
+
+    async fn select_signal_rules(&self) -> Result<Vec<SignalSpec>, sqlx::Error> {
+        SignalSpec::select_all(&self.pool).await
+    }
+}
+
+// SQLite does not have a numeric type that holds a Decimal without loss so each record is stored
+// as a json document with the columns needed for lookups pulled out next to it.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    pub pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    // Open (or create) the database file at the given path and create the tables if needed
+    pub async fn new(path: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(path)?.create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        let storage = Self { pool };
+        storage.create_tables().await?;
+        Ok(storage)
+    }
+
+    async fn create_tables(&self) -> Result<(), sqlx::Error> {
+        let tables = [
+            r#"
+            CREATE TABLE IF NOT EXISTS markets (
+                market_id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS market_trade_details (
+                market_id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS market_candle_details (
+                market_id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS market_archive_details (
+                market_id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS research_metrics (
+                market_id TEXT NOT NULL,
+                tf TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS forming_candles (
+                market_id TEXT NOT NULL,
+                tf TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                is_closed BOOLEAN NOT NULL,
+                data TEXT NOT NULL,
+                update_dt TEXT NOT NULL,
+                PRIMARY KEY (market_id, tf)
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS indicator_values (
+                market_id TEXT NOT NULL,
+                tf TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL,
+                PRIMARY KEY (market_id, tf, datetime)
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS correlation_matrices (
+                source TEXT NOT NULL,
+                tf TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL,
+                PRIMARY KEY (source, tf, datetime)
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS screens (
+                name TEXT NOT NULL,
+                tf TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL,
+                PRIMARY KEY (name, tf, datetime)
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS signal_events (
+                signal_id TEXT NOT NULL PRIMARY KEY,
+                market_id TEXT NOT NULL,
+                datetime TEXT NOT NULL,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE IF NOT EXISTS signal_rules (
+                name TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                insert_dt TEXT NOT NULL
+            )
+            "#,
+        ];
+        for sql in tables.iter() {
+            sqlx::query(sql).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    fn to_json<T: Serialize>(record: &T) -> Result<String, sqlx::Error> {
+        serde_json::to_string(record).map_err(|e| sqlx::Error::Protocol(e.to_string()))
+    }
+
+    fn from_json<T: DeserializeOwned>(data: &str) -> Result<T, sqlx::Error> {
+        serde_json::from_str(data).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+    }
+
+    // Select the json document for the market id from the given table
+    async fn select_one<T: DeserializeOwned>(
+        &self,
+        table: &str,
+        market_id: &Uuid,
+    ) -> Result<T, sqlx::Error> {
+        let sql = format!(
+            r#"
+            SELECT data FROM {}
+            WHERE market_id = $1
+            "#,
+            table
+        );
+        let row: (String,) = sqlx::query_as(&sql)
+            .bind(market_id.to_string())
+            .fetch_one(&self.pool)
+            .await?;
+        Self::from_json(&row.0)
+    }
+
+    async fn select_many<T: DeserializeOwned>(&self, table: &str) -> Result<Vec<T>, sqlx::Error> {
+        let sql = format!(
+            r#"
+            SELECT data FROM {}
+            "#,
+            table
+        );
+        let rows: Vec<(String,)> = sqlx::query_as(&sql).fetch_all(&self.pool).await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    // Insert the json document for the market id. Fails on an existing record the same as the
+    // primary key violation in Postgres.
+    async fn insert_one<T: Serialize + Sync>(
+        &self,
+        table: &str,
+        market_id: &Uuid,
+        record: &T,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            INSERT INTO {} (market_id, data)
+            VALUES ($1, $2)
+            "#,
+            table
+        );
+        sqlx::query(&sql)
+            .bind(market_id.to_string())
+            .bind(Self::to_json(record)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_one<T: Serialize + Sync>(
+        &self,
+        table: &str,
+        market_id: &Uuid,
+        record: &T,
+    ) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            UPDATE {}
+            SET data = $1
+            WHERE market_id = $2
+            "#,
+            table
+        );
+        sqlx::query(&sql)
+            .bind(Self::to_json(record)?)
+            .bind(market_id.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn select_markets(&self) -> Result<Vec<MarketDetail>, sqlx::Error> {
+        self.select_many("markets").await
+    }
+
+    async fn select_market(&self, market_id: &Uuid) -> Result<MarketDetail, sqlx::Error> {
+        self.select_one("markets", market_id).await
+    }
+
+    async fn insert_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        self.insert_one("markets", &market.market_id, market).await
+    }
+
+    async fn update_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        self.update_one("markets", &market.market_id, market).await
+    }
+
+    async fn select_mtd(&self, market: &MarketDetail) -> Result<MarketTradeDetail, sqlx::Error> {
+        self.select_one("market_trade_details", &market.market_id)
+            .await
+    }
+
+    async fn insert_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error> {
+        self.insert_one("market_trade_details", &mtd.market_id, mtd)
+            .await
+    }
+
+    async fn update_mtd(&self, mtd: &MarketTradeDetail) -> Result<(), sqlx::Error> {
+        self.update_one("market_trade_details", &mtd.market_id, mtd)
+            .await
+    }
+
+    async fn select_mcds(&self) -> Result<Vec<MarketCandleDetail>, sqlx::Error> {
+        self.select_many("market_candle_details").await
+    }
+
+    async fn select_mcd(&self, market: &MarketDetail) -> Result<MarketCandleDetail, sqlx::Error> {
+        self.select_one("market_candle_details", &market.market_id)
+            .await
+    }
+
+    async fn insert_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error> {
+        self.insert_one("market_candle_details", &mcd.market_id, mcd)
+            .await
+    }
+
+    async fn update_mcd(&self, mcd: &MarketCandleDetail) -> Result<(), sqlx::Error> {
+        self.update_one("market_candle_details", &mcd.market_id, mcd)
+            .await
+    }
+
+    async fn select_mad(&self, market: &MarketDetail) -> Result<MarketArchiveDetail, sqlx::Error> {
+        self.select_one("market_archive_details", &market.market_id)
+            .await
+    }
+
+    async fn insert_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error> {
+        self.insert_one("market_archive_details", &mad.market_id, mad)
+            .await
+    }
+
+    async fn update_mad(&self, mad: &MarketArchiveDetail) -> Result<(), sqlx::Error> {
+        self.update_one("market_archive_details", &mad.market_id, mad)
+            .await
+    }
+
+    async fn insert_metric(&self, metric: &ResearchMetric) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO research_metrics (market_id, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4, $5)
+            "#;
+        sqlx::query(sql)
+            .bind(metric.market_id.to_string())
+            .bind(metric.tf.as_str())
+            .bind(metric.datetime)
+            .bind(Self::to_json(metric)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_metrics(
+        &self,
+        market_ids: &[Uuid],
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        let mut metrics = Vec::new();
+        for market_id in market_ids.iter() {
+            let sql = r#"
+                SELECT data FROM research_metrics
+                WHERE market_id = $1 AND json_extract(data, '$.version') = $2
+                ORDER BY tf, datetime, insert_dt
+                "#;
+            let rows: Vec<(String,)> = sqlx::query_as(sql)
+                .bind(market_id.to_string())
+                .bind(METRIC_VERSION)
+                .fetch_all(&self.pool)
+                .await?;
+            for row in rows.iter() {
+                metrics.push(Self::from_json(&row.0)?);
+            }
+        }
+        Ok(metrics)
+    }
+
+    async fn select_latest_metrics(
+        &self,
+        market_ids: &[Uuid],
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        let mut metrics = Vec::new();
+        for market_id in market_ids.iter() {
+            let sql = r#"
+                SELECT data FROM research_metrics r
+                WHERE market_id = $1 AND json_extract(data, '$.version') = $2
+                AND datetime = (
+                    SELECT MAX(datetime) FROM research_metrics
+                    WHERE market_id = r.market_id AND tf = r.tf
+                    AND json_extract(data, '$.version') = $2
+                )
+                ORDER BY tf, insert_dt
+                "#;
+            let rows: Vec<(String,)> = sqlx::query_as(sql)
+                .bind(market_id.to_string())
+                .bind(METRIC_VERSION)
+                .fetch_all(&self.pool)
+                .await?;
+            for row in rows.iter() {
+                metrics.push(Self::from_json(&row.0)?);
+            }
+        }
+        Ok(metrics)
+    }
+
+    async fn select_metrics_lt_dt(
+        &self,
+        market_ids: &[Uuid],
+        tf: &TimeFrame,
+        dt: &DateTime<Utc>,
+        as_of: &DateTime<Utc>,
+    ) -> Result<Vec<ResearchMetric>, sqlx::Error> {
+        let mut metrics = Vec::new();
+        for market_id in market_ids.iter() {
+            let sql = r#"
+                SELECT data FROM research_metrics
+                WHERE market_id = $1 AND tf = $2 AND datetime < $3
+                AND json_extract(data, '$.version') = $4 AND insert_dt <= $5
+                ORDER BY datetime DESC, insert_dt DESC
+                LIMIT 1
+                "#;
+            let row: Option<(String,)> = sqlx::query_as(sql)
+                .bind(market_id.to_string())
+                .bind(tf.as_str())
+                .bind(dt)
+                .bind(METRIC_VERSION)
+                .bind(as_of)
+                .fetch_optional(&self.pool)
+                .await?;
+            if let Some(row) = row {
+                metrics.push(Self::from_json(&row.0)?);
+            }
+        }
+        Ok(metrics)
+    }
+
+    async fn delete_metrics_by_market(&self, market: &MarketDetail) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            DELETE FROM research_metrics
+            WHERE market_id = $1
+            "#;
+        sqlx::query(sql)
+            .bind(market.market_id.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_metrics_lt_dt(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"
+            DELETE FROM research_metrics
+            WHERE market_id = $1 AND datetime < $2
+            "#;
+        let result = sqlx::query(sql)
+            .bind(market.market_id.to_string())
+            .bind(dt)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn upsert_forming(&self, forming: &FormingCandle) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO forming_candles (market_id, tf, datetime, is_closed, data, update_dt)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (market_id, tf) DO UPDATE
+            SET datetime = excluded.datetime, is_closed = excluded.is_closed,
+                data = excluded.data, update_dt = excluded.update_dt
+            "#;
+        sqlx::query(sql)
+            .bind(forming.market_id.to_string())
+            .bind(forming.tf.as_str())
+            .bind(forming.candle.datetime)
+            .bind(forming.is_closed)
+            .bind(Self::to_json(forming)?)
+            .bind(forming.update_dt)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_forming(&self, market_id: &Uuid) -> Result<Vec<FormingCandle>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM forming_candles
+            WHERE market_id = $1
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(market_id.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    async fn upsert_indicators(&self, values: &IndicatorValues) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO indicator_values (market_id, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (market_id, tf, datetime) DO UPDATE
+            SET data = excluded.data, insert_dt = excluded.insert_dt
+            "#;
+        sqlx::query(sql)
+            .bind(values.market_id.to_string())
+            .bind(values.tf.as_str())
+            .bind(values.datetime)
+            .bind(Self::to_json(values)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_indicators(
+        &self,
+        market_id: &Uuid,
+    ) -> Result<Vec<IndicatorValues>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM indicator_values
+            WHERE market_id = $1
+            ORDER BY datetime
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(market_id.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    async fn upsert_correlations(&self, matrix: &CorrelationMatrix) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO correlation_matrices (source, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (source, tf, datetime) DO UPDATE
+            SET data = excluded.data, insert_dt = excluded.insert_dt
+            "#;
+        sqlx::query(sql)
+            .bind(&matrix.source)
+            .bind(matrix.tf.as_str())
+            .bind(matrix.datetime)
+            .bind(Self::to_json(matrix)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_correlations(
+        &self,
+        tf: &TimeFrame,
+    ) -> Result<Vec<CorrelationMatrix>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM correlation_matrices c
+            WHERE tf = $1
+            AND datetime = (
+                SELECT MAX(datetime) FROM correlation_matrices
+                WHERE source = c.source AND tf = c.tf
+            )
+            ORDER BY source
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(tf.as_str())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    async fn upsert_screen(&self, screen: &Screen) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO screens (name, tf, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (name, tf, datetime) DO UPDATE
+            SET data = excluded.data, insert_dt = excluded.insert_dt
+            "#;
+        sqlx::query(sql)
+            .bind(&screen.name)
+            .bind(screen.tf.as_str())
+            .bind(screen.datetime)
+            .bind(Self::to_json(screen)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_screens(&self, tf: &TimeFrame) -> Result<Vec<Screen>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM screens s
+            WHERE tf = $1
+            AND datetime = (
+                SELECT MAX(datetime) FROM screens
+                WHERE name = s.name AND tf = s.tf
+            )
+            ORDER BY name
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(tf.as_str())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    async fn insert_signal(&self, event: &SignalEvent) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO signal_events (signal_id, market_id, datetime, data, insert_dt)
+            VALUES ($1, $2, $3, $4, $5)
+            "#;
+        sqlx::query(sql)
+            .bind(event.signal_id.to_string())
+            .bind(event.market_id.to_string())
+            .bind(event.datetime)
+            .bind(Self::to_json(event)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_signals(&self, market_id: &Uuid) -> Result<Vec<SignalEvent>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM signal_events
+            WHERE market_id = $1
+            ORDER BY datetime
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(market_id.to_string())
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+
+    async fn upsert_signal_rule(&self, spec: &SignalSpec) -> Result<(), sqlx::Error> {
+        let sql = r#"
+            INSERT INTO signal_rules (name, data, insert_dt)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (name) DO UPDATE
+            SET data = excluded.data, insert_dt = excluded.insert_dt
+            "#;
+        sqlx::query(sql)
+            .bind(&spec.name)
+            .bind(Self::to_json(spec)?)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn select_signal_rules(&self) -> Result<Vec<SignalSpec>, sqlx::Error> {
+        let sql = r#"
+            SELECT data FROM signal_rules
+            ORDER BY name
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql).fetch_all(&self.pool).await?;
+        rows.iter().map(|r| Self::from_json(&r.0)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        candles::ProductionCandle,
+        markets::{MarketDataStatus, MarketTradeDetail},
+        metrics::{ResearchMetric, METRIC_VERSION},
+        signals::SignalSpec,
+        storage::{SqliteStorage, Storage},
+        test_helpers,
+        trades::PrIdTi,
+        utilities::TimeFrame,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    pub async fn sqlite_storage_round_trips_markets_and_mtd() {
+        let path = std::env::temp_dir().join(format!("eldorado_{}.db", Uuid::new_v4()));
+        let storage = SqliteStorage::new(&format!("sqlite://{}", path.display()))
+            .await
+            .expect("Failed to open sqlite storage.");
+        let mut market = test_helpers::market("LDO-USD", TimeFrame::T15);
+        storage.insert_market(&market).await.unwrap();
+        market.last_candle = Some(Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap());
+        storage.update_market(&market).await.unwrap();
+        assert_eq!(
+            storage.select_markets().await.unwrap(),
+            vec![market.clone()]
+        );
+        // No mtd for the market returns the same error as postgres
+        assert!(matches!(
+            storage.select_mtd(&market).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        let first = PrIdTi {
+            dt: Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 1).unwrap(),
+            id: 1234,
+            price: dec!(2.345),
+        };
+        let mtd = MarketTradeDetail::new(&market, &first).await;
+        storage.insert_mtd(&mtd).await.unwrap();
+        let mtd = mtd
+            .update_prev_status(&storage, &MarketDataStatus::Validate)
+            .await
+            .unwrap();
+        let selected = storage.select_mtd(&market).await.unwrap();
+        assert_eq!(selected.previous_status, mtd.previous_status);
+        assert_eq!(selected.first_trade_id, "1234");
+        std::fs::remove_file(path).expect("Failed to remove sqlite file.");
+    }
+
+    #[tokio::test]
+    pub async fn sqlite_storage_only_selects_current_metric_version() {
+        let path = std::env::temp_dir().join(format!("eldorado_{}.db", Uuid::new_v4()));
+        let storage = SqliteStorage::new(&format!("sqlite://{}", path.display()))
+            .await
+            .expect("Failed to open sqlite storage.");
+        let market = test_helpers::market("LDO-USD", TimeFrame::T15);
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let candles: Vec<_> = (0..3)
+            .map(|i| {
+                let pit = PrIdTi {
+                    dt: start + Duration::hours(i),
+                    id: i,
+                    price: dec!(2.345),
+                };
+                ProductionCandle::from_last(pit.dt, &pit)
+            })
+            .collect();
+        let current = ResearchMetric::new(&market, TimeFrame::H01, &candles[..2]);
+        // A later metric left over from the previous version is not read
+        let mut previous = ResearchMetric::new(&market, TimeFrame::H01, &candles);
+        previous.version = METRIC_VERSION - 1;
+        storage.insert_metric(&current).await.unwrap();
+        storage.insert_metric(&previous).await.unwrap();
+        let ids = [market.market_id];
+        assert_eq!(storage.select_metrics(&ids).await.unwrap().len(), 1);
+        let latest = storage.select_latest_metrics(&ids).await.unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].datetime, current.datetime);
+        let before = storage
+            .select_metrics_lt_dt(
+                &ids,
+                &TimeFrame::H01,
+                &(start + Duration::hours(3)),
+                &Utc::now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(before[0].datetime, current.datetime);
+        std::fs::remove_file(path).expect("Failed to remove sqlite file.");
+    }
+
+    #[tokio::test]
+    pub async fn sqlite_storage_selects_metric_as_stored_at_as_of() {
+        let path = std::env::temp_dir().join(format!("eldorado_{}.db", Uuid::new_v4()));
+        let storage = SqliteStorage::new(&format!("sqlite://{}", path.display()))
+            .await
+            .expect("Failed to open sqlite storage.");
+        let market = test_helpers::market("LDO-USD", TimeFrame::T15);
+        let start = Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap();
+        let mut candles = test_helpers::candles(start, &TimeFrame::H01, &[dec!(2), dec!(3)]);
+        let original = ResearchMetric::new(&market, TimeFrame::H01, &candles);
+        storage.insert_metric(&original).await.unwrap();
+        let as_of = Utc::now();
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        // A late trade amends the last candle and the metric is inserted again
+        candles[1] = test_helpers::candle(start + Duration::hours(1), 2, dec!(4));
+        let amended = ResearchMetric::new(&market, TimeFrame::H01, &candles);
+        storage.insert_metric(&amended).await.unwrap();
+        let ids = [market.market_id];
+        let cutoff = start + Duration::hours(2);
+        let known = storage
+            .select_metrics_lt_dt(&ids, &TimeFrame::H01, &cutoff, &as_of)
+            .await
+            .unwrap();
+        assert_eq!(known[0].close, dec!(3));
+        let latest = storage
+            .select_metrics_lt_dt(&ids, &TimeFrame::H01, &cutoff, &Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(latest[0].close, dec!(4));
+        std::fs::remove_file(path).expect("Failed to remove sqlite file.");
+    }
+
+    #[tokio::test]
+    pub async fn sqlite_storage_replaces_signal_rules_by_name() {
+        let path = std::env::temp_dir().join(format!("eldorado_{}.db", Uuid::new_v4()));
+        let storage = SqliteStorage::new(&format!("sqlite://{}", path.display()))
+            .await
+            .expect("Failed to open sqlite storage.");
+        let mut spec = SignalSpec {
+            name: "spike".to_string(),
+            rule: "return_z_s > 3".to_string(),
+            markets: vec!["BTC-USD".to_string()],
+            tfs: vec![TimeFrame::H01],
+            cooldown: 3600,
+        };
+        storage.upsert_signal_rule(&spec).await.unwrap();
+        spec.rule = "return_z_s > 4".to_string();
+        storage.upsert_signal_rule(&spec).await.unwrap();
+        assert_eq!(storage.select_signal_rules().await.unwrap(), vec![spec]);
+        std::fs::remove_file(path).expect("Failed to remove sqlite file.");
+    }
+}
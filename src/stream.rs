@@ -1,5 +1,4 @@
 use crate::{
-    configuration::Database,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::{error::WsError, ws::Channel, ws::Data, ws::WebSocket, ExchangeName},
     trades::Trade,
@@ -52,18 +51,28 @@ impl ElDorado {
             (Some(market), Data::FtxTrade(trade)) => {
                 trade
                     .insert(
-                        &self.pools[&Database::Ftx],
+                        self.pools.exchange(&self.instance.exchange_name.unwrap()),
+                        &self.schema,
                         &self.market_names[&self.instance.exchange_name.unwrap()][&market],
                     )
-                    .await
+                    .await?;
+                Ok(())
             }
             (Some(market), Data::GdaxTrade(trade)) => {
-                trade
+                let market = &self.market_names[&self.instance.exchange_name.unwrap()][&market];
+                // Build the live candle as the trade arrives. A trade already in the table was
+                // already added, ie a resent trade after a reconnect.
+                if trade
                     .insert(
-                        &self.pools[&Database::Gdax],
-                        &self.market_names[&self.instance.exchange_name.unwrap()][&market],
+                        self.pools.exchange(&self.instance.exchange_name.unwrap()),
+                        &self.schema,
+                        market,
                     )
-                    .await
+                    .await?
+                {
+                    self.push_live_trade(market, &trade);
+                }
+                Ok(())
             }
             (Some(market), Data::GdaxHb(hb)) => {
                 let mut db = db.lock().unwrap();
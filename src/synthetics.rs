@@ -0,0 +1,306 @@
+use crate::{
+    candles::ProductionCandle,
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::ExchangeName,
+    markets::{MarketArchiveDetail, MarketDetail, MarketStatus},
+    trades::PrIdTi,
+    utilities::{DateRange, TimeFrame},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Synthetic cross pair derived from two markets quoted in the same currency, ie ETH-BTC from
+// ETH-USD / BTC-USD. The legs must be on the same exchange so the pair can be made live by the
+// mita that runs them. The pair is published as a virtual market on the `index` exchange.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyntheticSpec {
+    pub name: String,
+    pub exchange: ExchangeName,
+    // Numerator leg ie ETH-USD
+    pub base: String,
+    // Denominator leg ie BTC-USD
+    pub quote: String,
+}
+
+impl SyntheticSpec {
+    // Make the synthetic candle from the leg candles for the same interval. The open and close
+    // are the ratio of the leg opens and closes. The intra interval path of the ratio is unknown
+    // so the high and low are bounded by the open and close. Volumes and counts are the base leg
+    // and the values are converted to the quote asset at the quote leg vwap.
+    pub fn make_candle(
+        dt: &DateTime<Utc>,
+        base: &ProductionCandle,
+        quote: &ProductionCandle,
+    ) -> Option<ProductionCandle> {
+        if quote.open.is_zero() || quote.close.is_zero() || quote.vwap.is_zero() {
+            return None;
+        }
+        let mut candle = base.clone();
+        candle.datetime = *dt;
+        candle.open = base.open / quote.open;
+        candle.close = base.close / quote.close;
+        candle.high = candle.open.max(candle.close);
+        candle.low = candle.open.min(candle.close);
+        candle.value = base.value / quote.vwap;
+        candle.value_buy = base.value_buy / quote.vwap;
+        candle.value_sell = base.value_sell / quote.vwap;
+        candle.value_liq = base.value_liq / quote.vwap;
+        candle.value_liq_buy = base.value_liq_buy / quote.vwap;
+        candle.value_liq_sell = base.value_liq_sell / quote.vwap;
+        candle.twap = if quote.twap.is_zero() {
+            candle.close
+        } else {
+            base.twap / quote.twap
+        };
+        if quote.last_trade_ts > base.last_trade_ts {
+            candle.last_trade_ts = quote.last_trade_ts;
+            candle.last_trade_id = quote.last_trade_id.clone();
+        }
+        if quote.first_trade_ts < base.first_trade_ts {
+            candle.first_trade_ts = quote.first_trade_ts;
+            candle.first_trade_id = quote.first_trade_id.clone();
+        }
+        candle.vap = None;
+        candle.set_vwaps();
+        Some(candle)
+    }
+
+    // Synthetic candles for each datetime in the base leg candles. Intervals missing a leg carry
+    // the last synthetic close forward.
+    pub fn make_candles(
+        dts: &[DateTime<Utc>],
+        base: &HashMap<DateTime<Utc>, ProductionCandle>,
+        quote: &HashMap<DateTime<Utc>, ProductionCandle>,
+        mut last: Option<PrIdTi>,
+    ) -> Vec<ProductionCandle> {
+        let mut candles = Vec::new();
+        for dt in dts.iter() {
+            let candle = match (base.get(dt), quote.get(dt)) {
+                (Some(b), Some(q)) => Self::make_candle(dt, b, q),
+                _ => None,
+            }
+            .or_else(|| last.map(|pit| ProductionCandle::from_last(*dt, &pit)));
+            if let Some(candle) = candle {
+                last = Some(candle.close_as_pridti());
+                candles.push(candle);
+            }
+        }
+        candles
+    }
+}
+
+impl ElDorado {
+    pub fn synthetic_spec(&self, market: &MarketDetail) -> Option<&SyntheticSpec> {
+        self.synthetics
+            .iter()
+            .find(|s| s.name == market.market_name)
+    }
+
+    pub fn synthetic_legs(&self, spec: &SyntheticSpec) -> (MarketDetail, MarketDetail) {
+        let leg = |name: &String| {
+            self.market_names
+                .get(&spec.exchange)
+                .and_then(|m| m.get(name))
+                .unwrap_or_else(|| {
+                    panic!(
+                        "{} {} not found for synthetic.",
+                        spec.exchange.as_str(),
+                        name
+                    )
+                })
+                .clone()
+        };
+        (leg(&spec.base), leg(&spec.quote))
+    }
+
+    // Virtual markets for the synthetics with both legs in the instance markets. These are run by
+    // the mita after the leg markets.
+    pub async fn initialize_synthetics(&self) -> Result<Vec<MarketDetail>, ElDoradoError> {
+        let mut markets = Vec::new();
+        for spec in self.synthetics.iter() {
+            let in_mita = |name: &String| {
+                self.markets
+                    .iter()
+                    .any(|m| m.exchange_name == spec.exchange && &m.market_name == name)
+            };
+            if in_mita(&spec.base) && in_mita(&spec.quote) {
+                markets.push(self.initialize_synthetic(spec).await?);
+            }
+        }
+        Ok(markets)
+    }
+
+    // Get the virtual market for the synthetic or create it from the legs
+    async fn initialize_synthetic(
+        &self,
+        spec: &SyntheticSpec,
+    ) -> Result<MarketDetail, ElDoradoError> {
+        let (base, quote) = self.synthetic_legs(spec);
+        if base.tf != quote.tf {
+            panic!("{} legs must have the same time frame.", spec.name);
+        }
+        if let Some(market) = self
+            .market_names
+            .get(&ExchangeName::Index)
+            .and_then(|m| m.get(&spec.name))
+        {
+            return Ok(market.clone());
+        }
+        let market = MarketDetail {
+            market_id: Uuid::new_v4(),
+            exchange_name: ExchangeName::Index,
+            market_name: spec.name.clone(),
+            market_type: base.market_type,
+            base: base.base.clone(),
+            base_step: base.base_step,
+            base_min: base.base_min,
+            quote: quote.base.clone(),
+            quote_step: None,
+            status: MarketStatus::Active,
+            tradable: false,
+            mita: None,
+            tf: base.tf,
+            last_candle: None,
+            asset_id: None,
+        };
+        self.storage.insert_market(&market).await?;
+        Ok(market)
+    }
+
+    // Make the synthetic candles for the date range from the leg candles in the database
+    pub async fn make_synthetic_candles(
+        &self,
+        spec: &SyntheticSpec,
+        market: &MarketDetail,
+        dr: &DateRange,
+        last: Option<PrIdTi>,
+    ) -> Result<Vec<ProductionCandle>, ElDoradoError> {
+        let (base, quote) = self.synthetic_legs(spec);
+        // Legs are run by the same mita and processed before the synthetic so there is no wait
+        let base_candles = self.select_candles_for_dr(&base, dr, 0).await?;
+        let quote_candles = self.select_candles_for_dr(&quote, dr, 0).await?;
+        println!(
+            "Making {} synthetic candles for {}.",
+            dr.dts.len(),
+            market.market_name
+        );
+        Ok(SyntheticSpec::make_candles(
+            &dr.dts,
+            &base_candles,
+            &quote_candles,
+            last,
+        ))
+    }
+
+    // Make the synthetic candle archives from the leg S15 archives for each month both legs are
+    // archived and write them with the resampled time frames as for any archived market. The
+    // archive detail of the synthetic market is kept with each month so the next run continues
+    // from it and the backfill and retention see the archived months.
+    pub async fn archive_synthetics(&self) -> Result<(), ElDoradoError> {
+        for spec in self.synthetics.iter() {
+            let market = self.initialize_synthetic(spec).await?;
+            let (base, quote) = self.synthetic_legs(spec);
+            let base_mad = self.storage.select_mad(&base).await?;
+            let quote_mad = self.storage.select_mad(&quote).await?;
+            // Continue from the archive detail of the synthetic market if it has been archived
+            let mut mad = match self.storage.select_mad(&market).await {
+                Ok(mad) => Some(mad),
+                Err(sqlx::Error::RowNotFound) => None,
+                Err(e) => return Err(ElDoradoError::Sqlx(e)),
+            };
+            let mut month = match &mad {
+                Some(mad) => mad.next_month,
+                None => {
+                    TimeFrame::M01.trunc(&base_mad.first_candle_dt.max(quote_mad.first_candle_dt))
+                }
+            };
+            let end = base_mad.next_month.min(quote_mad.next_month);
+            let mut last = mad.as_ref().map(|m| m.last_as_pridti());
+            while month < end {
+                let base_candles = self.read_s15_archive(&base, &month);
+                let quote_candles = self.read_s15_archive(&quote, &month);
+                let mut dts: Vec<_> = base_candles.keys().cloned().collect();
+                dts.sort();
+                let candles: Vec<_> =
+                    SyntheticSpec::make_candles(&dts, &base_candles, &quote_candles, last)
+                        .iter()
+                        .map(|c| c.as_research_candle())
+                        .collect();
+                let next_month = ElDorado::next_month_dt(&month);
+                if let Some(candle) = candles.last() {
+                    last = Some(candle.close_as_pridti());
+                    println!("Archiving {} for {}.", market.market_name, month);
+                    self.write_research_candles_to_file_for_month(&market, &month, &candles);
+                    mad = Some(match mad {
+                        Some(mad) => {
+                            mad.update(self.storage.as_ref(), &next_month, candle)
+                                .await?
+                        }
+                        None => {
+                            let mut mad = MarketArchiveDetail::new(
+                                &market,
+                                &TimeFrame::S15,
+                                &candles[0],
+                                candle,
+                            );
+                            mad.next_month = next_month;
+                            self.storage.insert_mad(&mad).await?;
+                            mad
+                        }
+                    });
+                }
+                month = next_month;
+            }
+        }
+        Ok(())
+    }
+
+    // Read the S15 candle archive for the market and month mapped by datetime
+    fn read_s15_archive(
+        &self,
+        market: &MarketDetail,
+        month: &DateTime<Utc>,
+    ) -> HashMap<DateTime<Utc>, ProductionCandle> {
+        self.read_research_candles_from_file_for_month(market, month, &TimeFrame::S15)
+            .iter()
+            .map(|c| (c.datetime, c.as_production_candle()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        synthetics::SyntheticSpec,
+        test_helpers::{start, volume_candle},
+    };
+    use chrono::Duration;
+    use rust_decimal_macros::dec;
+    use std::collections::HashMap;
+
+    #[test]
+    pub fn synthetic_candle_is_ratio_of_legs() {
+        let dt = start();
+        let mut eth = volume_candle(dt, 1, dec!(1600), dec!(10));
+        eth.open = dec!(1500);
+        let btc = volume_candle(dt, 1, dec!(20000), dec!(2));
+        let ethbtc = SyntheticSpec::make_candle(&dt, &eth, &btc).unwrap();
+        assert_eq!(ethbtc.open, dec!(0.075));
+        assert_eq!(ethbtc.close, dec!(0.08));
+        assert_eq!(ethbtc.high, dec!(0.08));
+        assert_eq!(ethbtc.low, dec!(0.075));
+        assert_eq!(ethbtc.volume, dec!(10));
+        assert_eq!(ethbtc.value, dec!(0.8));
+        assert_eq!(ethbtc.vwap, dec!(0.08));
+        // Missing quote leg carries the last close forward
+        let next = dt + Duration::minutes(15);
+        let base = HashMap::from([(dt, eth.clone()), (next, eth)]);
+        let quote = HashMap::from([(dt, btc)]);
+        let candles = SyntheticSpec::make_candles(&[dt, next], &base, &quote, None);
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[1].close, dec!(0.08));
+        assert_eq!(candles[1].volume, dec!(0));
+    }
+}
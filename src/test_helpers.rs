@@ -0,0 +1,164 @@
+use crate::{
+    candles::ProductionCandle,
+    exchanges::{gdax::Trade as GdaxTrade, ExchangeName},
+    markets::{MarketDetail, MarketStatus, MarketType},
+    trades::PrIdTi,
+    utilities::TimeFrame,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use uuid::Uuid;
+
+// Start of the test candles and trades
+pub fn start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap()
+}
+
+// Active gdax spot market with the base time frame, ie BTC-USD on T15
+pub fn market(name: &str, tf: TimeFrame) -> MarketDetail {
+    MarketDetail {
+        market_id: Uuid::new_v4(),
+        exchange_name: ExchangeName::Gdax,
+        market_name: name.to_string(),
+        market_type: MarketType::Spot,
+        base: None,
+        base_step: None,
+        base_min: None,
+        quote: None,
+        quote_step: None,
+        status: MarketStatus::Active,
+        tradable: true,
+        mita: None,
+        tf,
+        last_candle: None,
+        asset_id: None,
+    }
+}
+
+// Candle with all OHLC at the close and no volume, as made from the last trade
+pub fn candle(dt: DateTime<Utc>, id: i64, close: Decimal) -> ProductionCandle {
+    let pit = PrIdTi {
+        dt,
+        id,
+        price: close,
+    };
+    ProductionCandle::from_last(dt, &pit)
+}
+
+// Candles of the closes, one per time frame interval from the start
+pub fn candles(start: DateTime<Utc>, tf: &TimeFrame, closes: &[Decimal]) -> Vec<ProductionCandle> {
+    closes
+        .iter()
+        .enumerate()
+        .map(|(i, close)| {
+            candle(
+                start + Duration::seconds(tf.as_secs() * i as i64),
+                i as i64,
+                *close,
+            )
+        })
+        .collect()
+}
+
+// Candle of the interval from a single trade of the volume at the close at the traded time
+pub fn traded_candle(
+    dt: DateTime<Utc>,
+    traded: DateTime<Utc>,
+    id: i64,
+    close: Decimal,
+    volume: Decimal,
+) -> ProductionCandle {
+    let pit = PrIdTi {
+        dt: traded,
+        id,
+        price: close,
+    };
+    let mut c = ProductionCandle::from_last(dt, &pit);
+    c.volume = volume;
+    c.value = close * volume;
+    c.trade_count = 1;
+    c.set_vwaps();
+    c
+}
+
+// Candle from a single trade of the volume at the close at the start of the interval
+pub fn volume_candle(
+    dt: DateTime<Utc>,
+    id: i64,
+    close: Decimal,
+    volume: Decimal,
+) -> ProductionCandle {
+    traded_candle(dt, dt, id, close, volume)
+}
+
+// Candles of the closes with the high and low the spread either side of the close
+pub fn spread_candles(
+    start: DateTime<Utc>,
+    tf: &TimeFrame,
+    closes: &[Decimal],
+    spread: Decimal,
+) -> Vec<ProductionCandle> {
+    candles(start, tf, closes)
+        .into_iter()
+        .map(|mut c| {
+            c.high = c.close + spread;
+            c.low = c.close - spread;
+            c
+        })
+        .collect()
+}
+
+// Candles of the closes from the open with the high and low 1% past the open and close
+pub fn range_candles(
+    start: DateTime<Utc>,
+    tf: &TimeFrame,
+    open: Decimal,
+    closes: &[Decimal],
+) -> Vec<ProductionCandle> {
+    candles(start, tf, closes)
+        .into_iter()
+        .map(|mut c| {
+            c.open = open;
+            c.high = open.max(c.close) * dec!(1.01);
+            c.low = open.min(c.close) * dec!(0.99);
+            c
+        })
+        .collect()
+}
+
+// T15 candles that zig zag around a drift with varied ranges, volumes and trade counts so rolling
+// windows see rises and falls
+pub fn zig_zag_candles(start: DateTime<Utc>, n: usize) -> Vec<ProductionCandle> {
+    (0..n)
+        .map(|i| {
+            let dt = start + Duration::minutes(15 * i as i64);
+            let close = dec!(100) + Decimal::from(i % 7) - Decimal::from(i % 3) * dec!(1.5)
+                + Decimal::from(i) / dec!(10);
+            let mut c = candle(dt, i as i64, close);
+            c.open = close - dec!(0.5);
+            c.high = close + Decimal::from(i % 4);
+            c.low = c.open - Decimal::from(i % 5);
+            c.volume = Decimal::from(1 + i % 11);
+            c.volume_buy = Decimal::from(i % 6);
+            c.volume_sell = c.volume - c.volume_buy;
+            c.value = c.volume * close;
+            c.trade_count = 1 + (i % 9) as i64;
+            c.trade_count_buy = (i % 5) as i64;
+            c
+        })
+        .collect()
+}
+
+// Gdax trades a second apart with every fourth a sell and cycling sizes and prices
+pub fn trades(start: DateTime<Utc>, n: i64) -> Vec<GdaxTrade> {
+    (0..n)
+        .map(|i| GdaxTrade {
+            trade_id: i,
+            side: if i % 4 == 0 { "sell" } else { "buy" }.to_string(),
+            size: dec!(0.5) + Decimal::from(i % 3),
+            price: dec!(100) + Decimal::from(i % 7),
+            time: start + Duration::seconds(i),
+        })
+        .collect()
+}
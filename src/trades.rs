@@ -1,12 +1,13 @@
 use crate::{
-    configuration::Database,
+    candles::CandleAccumulator,
+    configuration::Schema,
     eldorado::{ElDorado, ElDoradoError},
     exchanges::{ftx::Trade as FtxTrade, gdax::Trade as GdaxTrade, ExchangeName},
     markets::MarketDetail,
     utilities::{DateRange, TimeFrame},
 };
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, DurationRound, Utc};
+use chrono::{DateTime, Duration, DurationRound, NaiveDate, TimeZone, Utc};
 use csv::Reader;
 use rust_decimal::prelude::*;
 use sqlx::PgPool;
@@ -24,21 +25,339 @@ pub trait Trade {
     fn liquidation(&self) -> bool;
     fn time(&self) -> DateTime<Utc>;
     fn as_pridti(&self) -> PrIdTi;
+    // Create the exchange parent table that all market and day partitions hang off of. The table
+    // is list partitioned on market_id and each market is range partitioned on time.
+    async fn create_parent_table(
+        pool: &PgPool,
+        schema: &Schema,
+        exchange: &ExchangeName,
+    ) -> Result<(), sqlx::Error>
+    where
+        Self: Sized;
+    // Insert the trade if it is not already in the table. Returns true if the trade was inserted.
+    async fn insert(
+        &self,
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<bool, sqlx::Error>;
+    // Create the day partition for the market, creating the exchange parent and the market
+    // partition first if needed.
     async fn create_table(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: DateTime<Utc>,
     ) -> Result<(), sqlx::Error>
     where
-        Self: Sized;
-    async fn insert(&self, pool: &PgPool, market: &MarketDetail) -> Result<(), sqlx::Error>;
+        Self: Sized,
+    {
+        Self::create_parent_table(pool, schema, &market.exchange_name).await?;
+        TradePartition::create_market_partition(pool, schema, market).await?;
+        TradePartition::create_day_partition(pool, schema, market, &dt).await
+    }
+    // Detach the day partition from the market partition and drop it
     async fn drop_table(
         pool: &PgPool,
+        schema: &Schema,
         market: &MarketDetail,
         dt: DateTime<Utc>,
     ) -> Result<(), sqlx::Error>
     where
-        Self: Sized;
+        Self: Sized,
+    {
+        TradePartition::detach_day_partition(pool, schema, market, &dt).await?;
+        let sql = format!(
+            r#"
+            DROP TABLE IF EXISTS {s}.{}
+            "#,
+            TradePartition::day(market, &dt),
+            s = schema.trades(&market.exchange_name),
+        );
+        sqlx::query(&sql).execute(pool).await?;
+        Ok(())
+    }
+}
+
+// Trades for an exchange are stored in a single parent table trades.<exchange>. Each market is a
+// list partition trades.<exchange>_<market> that is range partitioned by day into
+// trades.<exchange>_<market>_<yyyymmdd> tables. Each market also has a default partition so that
+// trades are never rejected if the day partition has not been created yet. Any trades that land
+// in the default partition are moved to the day partition when it is created and attached.
+pub struct TradePartition;
+
+impl TradePartition {
+    pub fn parent(exchange: &ExchangeName) -> String {
+        exchange.as_str().to_string()
+    }
+
+    pub fn market(market: &MarketDetail) -> String {
+        format!("{}_{}", market.exchange_name.as_str(), market.as_strip())
+    }
+
+    pub fn day(market: &MarketDetail, dt: &DateTime<Utc>) -> String {
+        format!("{}_{}", Self::market(market), dt.format("%Y%m%d"))
+    }
+
+    pub fn default(market: &MarketDetail) -> String {
+        format!("{}_default", Self::market(market))
+    }
+
+    // Check if the table in the exchange trades schema is attached as a partition to a parent table
+    pub async fn is_attached(
+        pool: &PgPool,
+        schema: &Schema,
+        exchange: &ExchangeName,
+        table: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM pg_inherits i
+                JOIN pg_class c ON c.oid = i.inhrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE n.nspname = $1 AND c.relname = $2
+            )
+            "#;
+        let row: (bool,) = sqlx::query_as(sql)
+            .bind(schema.trades(exchange))
+            .bind(table.to_lowercase())
+            .fetch_one(pool)
+            .await?;
+        Ok(row.0)
+    }
+
+    // Select the days of the day partitions currently attached to the market partition
+    pub async fn select_days(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<Vec<DateTime<Utc>>, sqlx::Error> {
+        let sql = r#"
+            SELECT c.relname
+            FROM pg_inherits i
+            JOIN pg_class c ON c.oid = i.inhrelid
+            JOIN pg_class p ON p.oid = i.inhparent
+            JOIN pg_namespace n ON n.oid = p.relnamespace
+            WHERE n.nspname = $1 AND p.relname = $2
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(schema.trades(&market.exchange_name))
+            .bind(Self::market(market).to_lowercase())
+            .fetch_all(pool)
+            .await?;
+        let prefix = format!("{}_", Self::market(market).to_lowercase());
+        let mut days: Vec<DateTime<Utc>> = rows
+            .iter()
+            .filter_map(|(t,)| t.strip_prefix(&prefix))
+            .filter_map(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .map(|d| Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap()))
+            .collect();
+        days.sort();
+        Ok(days)
+    }
+
+    // Delete trades older than the dt that are still sitting in the default partition. These are
+    // ws trades that were never moved into a day partition.
+    pub async fn delete_default_lt_dt(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = format!(
+            r#"
+            DELETE FROM {s}.{}
+            WHERE time < $1
+            "#,
+            Self::default(market),
+            s = schema.trades(&market.exchange_name),
+        );
+        let result = sqlx::query(&sql).bind(dt).execute(pool).await?;
+        Ok(result.rows_affected())
+    }
+
+    // Create the market list partition of the exchange parent table and the default range
+    // partition for the market
+    pub async fn create_market_partition(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+    ) -> Result<(), sqlx::Error> {
+        let market_sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {s}.{}
+            PARTITION OF {s}.{}
+            FOR VALUES IN ('{}')
+            PARTITION BY RANGE (time)
+            "#,
+            Self::market(market),
+            Self::parent(&market.exchange_name),
+            market.market_id,
+            s = schema.trades(&market.exchange_name),
+        );
+        let default_sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {s}.{}
+            PARTITION OF {s}.{} DEFAULT
+            "#,
+            Self::default(market),
+            Self::market(market),
+            s = schema.trades(&market.exchange_name),
+        );
+        sqlx::query(&market_sql).execute(pool).await?;
+        sqlx::query(&default_sql).execute(pool).await?;
+        Ok(())
+    }
+
+    // Select the primary key columns of the table in the exchange trades schema, in key order
+    pub async fn select_primary_key(
+        pool: &PgPool,
+        schema: &Schema,
+        exchange: &ExchangeName,
+        table: &str,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let sql = r#"
+            SELECT a.attname::TEXT
+            FROM pg_index i
+            JOIN pg_class c ON c.oid = i.indrelid
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY AS k(attnum, ord)
+            JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum = k.attnum
+            WHERE i.indisprimary AND n.nspname = $1 AND c.relname = $2
+            ORDER BY k.ord
+            "#;
+        let rows: Vec<(String,)> = sqlx::query_as(sql)
+            .bind(schema.trades(exchange))
+            .bind(table.to_lowercase())
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.into_iter().map(|(c,)| c).collect())
+    }
+
+    // Pre-partitioning day tables were created with PRIMARY KEY (trade_id). A partition must
+    // include the parent key columns in its own key so replace the legacy key with the parent key
+    // before the table is attached.
+    pub async fn migrate_legacy_day(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let day = Self::day(market, dt);
+        let pk = Self::select_primary_key(pool, schema, &market.exchange_name, &day).await?;
+        if pk.is_empty() || pk == ["market_id", "trade_id", "time"] {
+            return Ok(());
+        }
+        println!("Migrating legacy primary key {:?} on {}", pk, day);
+        let table = format!("{}.{}", schema.trades(&market.exchange_name), day);
+        let name_sql = r#"
+            SELECT conname::TEXT
+            FROM pg_constraint
+            WHERE conrelid = $1::regclass AND contype = 'p'
+            "#;
+        let name: (String,) = sqlx::query_as(name_sql)
+            .bind(&table)
+            .fetch_one(pool)
+            .await?;
+        let sql = format!(
+            r#"
+            ALTER TABLE {}
+            DROP CONSTRAINT "{}",
+            ADD PRIMARY KEY (market_id, trade_id, time)
+            "#,
+            table, name.0,
+        );
+        sqlx::query(&sql).execute(pool).await?;
+        Ok(())
+    }
+
+    // Create the day partition as a standalone table, move any trades for the day that landed
+    // in the default partition into it and attach it to the market partition. Pre-partitioning
+    // day tables have their primary key migrated to the parent key before they are attached.
+    pub async fn create_day_partition(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let day = Self::day(market, dt);
+        if Self::is_attached(pool, schema, &market.exchange_name, &day).await? {
+            return Ok(());
+        }
+        let start = dt.duration_trunc(Duration::days(1)).unwrap();
+        let end = start + Duration::days(1);
+        let create_sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {s}.{}
+            (LIKE {s}.{} INCLUDING DEFAULTS INCLUDING CONSTRAINTS)
+            "#,
+            day,
+            Self::parent(&market.exchange_name),
+            s = schema.trades(&market.exchange_name),
+        );
+        let move_sql = format!(
+            r#"
+            WITH moved AS (
+                DELETE FROM {s}.{}
+                WHERE time >= $1 AND time < $2
+                RETURNING *
+            )
+            INSERT INTO {s}.{}
+            SELECT * FROM moved
+            "#,
+            Self::default(market),
+            day,
+            s = schema.trades(&market.exchange_name),
+        );
+        let attach_sql = format!(
+            r#"
+            ALTER TABLE {s}.{}
+            ATTACH PARTITION {s}.{}
+            FOR VALUES FROM ('{}') TO ('{}')
+            "#,
+            Self::market(market),
+            day,
+            start.to_rfc3339(),
+            end.to_rfc3339(),
+            s = schema.trades(&market.exchange_name),
+        );
+        Self::migrate_legacy_day(pool, schema, market, dt).await?;
+        let mut tx = pool.begin().await?;
+        sqlx::query(&create_sql).execute(&mut tx).await?;
+        sqlx::query(&move_sql)
+            .bind(start)
+            .bind(end)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(&attach_sql).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Detach the day partition from the market partition if it is attached. The table is left in
+    // place for the caller to archive or drop.
+    pub async fn detach_day_partition(
+        pool: &PgPool,
+        schema: &Schema,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let day = Self::day(market, dt);
+        if Self::is_attached(pool, schema, &market.exchange_name, &day).await? {
+            let sql = format!(
+                r#"
+                ALTER TABLE {s}.{}
+                DETACH PARTITION {s}.{}
+                "#,
+                Self::market(market),
+                day,
+                s = schema.trades(&market.exchange_name),
+            );
+            sqlx::query(&sql).execute(pool).await?;
+        }
+        Ok(())
+    }
 }
 
 // Struct to pack information about last trade - typically used to create a candle from last trade
@@ -67,28 +386,24 @@ impl PrIdTi {
 
 impl ElDorado {
     pub async fn initialize_trade_schema_and_tables(&self) -> Result<DateTime<Utc>, sqlx::Error> {
-        match self.instance.exchange_name.unwrap() {
-            ExchangeName::Ftx | ExchangeName::FtxUs => {
-                self.create_trades_schema(&self.pools[&Database::Ftx])
-                    .await?;
-            }
-            ExchangeName::Gdax => {
-                self.create_trades_schema(&self.pools[&Database::Gdax])
-                    .await?;
-            }
-            name => panic!("{:?} not supported for trades.", name),
-        }
+        let exchange = self.instance.exchange_name.unwrap();
+        self.create_trades_schema(&exchange).await?;
         // Create trade tables for each market for today if they don't exist
         let today = Utc::now().duration_trunc(Duration::days(1)).unwrap();
         self.create_trade_tables_all_markets(today).await?;
         Ok(today)
     }
 
-    pub async fn create_trades_schema(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
-        let sql = r#"
-            CREATE SCHEMA IF NOT EXISTS trades
-            "#;
-        sqlx::query(sql).execute(pool).await?;
+    pub async fn create_trades_schema(&self, exchange: &ExchangeName) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            r#"
+            CREATE SCHEMA IF NOT EXISTS {}
+            "#,
+            self.schema.trades(exchange)
+        );
+        sqlx::query(&sql)
+            .execute(self.pools.exchange(exchange))
+            .await?;
         Ok(())
     }
 
@@ -99,10 +414,22 @@ impl ElDorado {
     ) -> Result<(), sqlx::Error> {
         match market.exchange_name {
             ExchangeName::Ftx | ExchangeName::FtxUs => {
-                FtxTrade::create_table(&self.pools[&Database::Ftx], market, dt).await?;
+                FtxTrade::create_table(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    dt,
+                )
+                .await?;
             }
             ExchangeName::Gdax => {
-                GdaxTrade::create_table(&self.pools[&Database::Gdax], market, dt).await?;
+                GdaxTrade::create_table(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    dt,
+                )
+                .await?;
             }
             name => panic!("{:?} not supported for trades.", name),
         }
@@ -124,7 +451,7 @@ impl ElDorado {
         mut dt: DateTime<Utc>,
     ) -> Result<DateTime<Utc>, sqlx::Error> {
         // If the date given is less than 2 days in the future, increment the date and
-        // create tables for the date before returning it
+        // create and attach the day partitions for the date before returning it
         if dt < Utc::now().duration_trunc(Duration::days(1)).unwrap() + Duration::days(2) {
             dt += Duration::days(1);
             self.create_trade_tables_all_markets(dt).await?;
@@ -139,19 +466,10 @@ impl ElDorado {
         market: &MarketDetail,
         dt: &DateTime<Utc>,
     ) -> Result<bool, sqlx::Error> {
-        // Get the full trade table name and then check self fn for table and schema
-        let table = format!(
-            "{}_{}_{}",
-            market.exchange_name.as_str(),
-            market.as_strip(),
-            dt.format("%Y%m%d")
-        );
-        let db = match market.exchange_name {
-            ExchangeName::Ftx | ExchangeName::FtxUs => Database::Ftx,
-            ExchangeName::Gdax => Database::Gdax,
-            name => panic!("{:?} not supported for trades.", name),
-        };
-        ElDorado::table_exists(&self.pools[&db], "trades", &table).await
+        // Check that the day partition for the market is created and attached
+        let table = TradePartition::day(market, dt);
+        let pool = self.pools.exchange(&market.exchange_name);
+        TradePartition::is_attached(pool, &self.schema, &market.exchange_name, &table).await
     }
 
     pub async fn select_first_ws_timeid(
@@ -161,8 +479,13 @@ impl ElDorado {
         // Select the first trade from database table after the start of the instance
         match market.exchange_name {
             ExchangeName::Ftx | ExchangeName::FtxUs => {
-                match FtxTrade::select_one_gt_dt(&self.pools[&Database::Ftx], market, self.start_dt)
-                    .await
+                match FtxTrade::select_one_gt_dt(
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
+                    market,
+                    self.start_dt,
+                )
+                .await
                 {
                     Ok(t) => Ok(Some(t.as_pridti())),
                     Err(sqlx::Error::RowNotFound) => Ok(None),
@@ -171,7 +494,8 @@ impl ElDorado {
             }
             ExchangeName::Gdax => {
                 match GdaxTrade::select_one_gt_dt(
-                    &self.pools[&Database::Gdax],
+                    self.pools.exchange(&market.exchange_name),
+                    &self.schema,
                     market,
                     self.start_dt,
                 )
@@ -489,46 +813,22 @@ impl ElDorado {
         }
     }
 
-    // Select all gdax trades from the gdax db for the market for the interval given. This may be
-    // accross mulitple tables as the tables are broken up by day
+    // Select all gdax trades from the gdax db for the market for the interval given. The day
+    // partitions are pruned by the planner so the interval can span any number of days.
     pub async fn select_gdax_trades_for_interval(
         &self,
         market: &MarketDetail,
         dr: &DateRange,
     ) -> Result<Option<Vec<GdaxTrade>>, ElDoradoError> {
-        // Assert that the dr has dates and is not empty
-        // println!(
-        //     "Selecting trades gte {} and lt {}",
-        //     dr.first,
-        //     dr.last + market.tf.as_dur()
-        // );
-        let mut trades = Vec::new();
-        match DateRange::new(
-            &dr.first.duration_trunc(Duration::days(1)).unwrap(),
-            &(dr.last.duration_trunc(Duration::days(1)).unwrap() + Duration::days(1)),
-            &TimeFrame::D01,
-        ) {
-            Some(days) => {
-                for d in days.dts.iter() {
-                    // Select trades
-                    // println!(
-                    //     "Selecting {} trades on day {} for interval.",
-                    //     market.market_name, d
-                    // );
-                    let mut db_trades = GdaxTrade::select_gte_and_lt_dts(
-                        &self.pools[&Database::Gdax],
-                        market,
-                        d,
-                        &dr.first,
-                        &(dr.last + market.tf.as_dur()),
-                    )
-                    .await?;
-                    trades.append(&mut db_trades);
-                }
-                Ok(Some(trades))
-            }
-            None => Ok(None),
-        }
+        let trades = GdaxTrade::select_gte_and_lt_dts(
+            self.pools.exchange(&market.exchange_name),
+            &self.schema,
+            market,
+            &dr.first,
+            &(dr.last + market.tf.as_dur()),
+        )
+        .await?;
+        Ok(Some(trades))
     }
 
     pub fn read_gdax_trades_from_file_into_vec(&self, pb: &PathBuf) -> Vec<GdaxTrade> {
@@ -594,6 +894,20 @@ impl ElDorado {
         trades
     }
 
+    // Path of the validated trade archive file for the market and day
+    pub fn trade_archive_path(&self, market: &MarketDetail, dt: &DateTime<Utc>) -> PathBuf {
+        let path = format!(
+            "{}/trades/{}/{}/{}/{}",
+            &self.storage_path,
+            &market.exchange_name.as_str(),
+            &market.as_strip(),
+            dt.format("%Y"),
+            dt.format("%m"),
+        );
+        let file_name = format!("{}-{}.csv", market.as_strip(), dt.format("%F"));
+        std::path::Path::new(&path).join(file_name)
+    }
+
     pub fn read_gdax_trades_for_dr(
         &self,
         market: &MarketDetail,
@@ -603,16 +917,7 @@ impl ElDorado {
         let mut trades: HashMap<DateTime<Utc>, Vec<GdaxTrade>> = HashMap::new();
         // For each day - load the trades into the hashmap
         for d in dr.iter() {
-            let path = format!(
-                "{}/trades/{}/{}/{}/{}",
-                &self.storage_path,
-                &market.exchange_name.as_str(),
-                &market.as_strip(),
-                d.format("%Y"),
-                d.format("%m"),
-            );
-            let file_name = format!("{}-{}.csv", market.as_strip(), d.format("%F"));
-            let pb = std::path::Path::new(&path).join(file_name);
+            let pb = self.trade_archive_path(market, d);
             let file = File::open(pb).expect("failed to open file.");
             let mut rdr = Reader::from_reader(file);
             for result in rdr.deserialize() {
@@ -625,13 +930,166 @@ impl ElDorado {
         }
         trades
     }
+
+    // Read each day's trade file on its own thread into accumulators for the tf intervals and
+    // merge the days. An interval at midnight can have trades in both days' files.
+    pub fn accumulate_gdax_trades_for_dr(
+        &self,
+        market: &MarketDetail,
+        tf: &TimeFrame,
+        dr: &[DateTime<Utc>],
+    ) -> HashMap<DateTime<Utc>, CandleAccumulator> {
+        let step = self.vap_step(market);
+        let paths: Vec<PathBuf> = dr
+            .iter()
+            .map(|d| self.trade_archive_path(market, d))
+            .collect();
+        let days: Vec<HashMap<DateTime<Utc>, CandleAccumulator>> = std::thread::scope(|s| {
+            let handles: Vec<_> = paths
+                .iter()
+                .map(|pb| {
+                    s.spawn(move || {
+                        let file = File::open(pb).expect("failed to open file.");
+                        let mut accumulators = HashMap::new();
+                        for result in Reader::from_reader(file).deserialize() {
+                            let record: GdaxTrade = result.expect("Failed to deserialize record.");
+                            accumulators
+                                .entry(tf.trunc(&record.time))
+                                .or_insert_with(|| CandleAccumulator::new(step))
+                                .push(&record);
+                        }
+                        accumulators
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("Failed to read trades."))
+                .collect()
+        });
+        let mut accumulators: HashMap<DateTime<Utc>, CandleAccumulator> = HashMap::new();
+        for day in days.into_iter() {
+            for (dt, acc) in day.into_iter() {
+                match accumulators.get_mut(&dt) {
+                    Some(a) => a.merge(&acc),
+                    None => {
+                        accumulators.insert(dt, acc);
+                    }
+                }
+            }
+        }
+        accumulators
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::configuration::get_configuration;
+    use super::{Trade, TradePartition};
+    use crate::configuration::{get_configuration, Schema};
+    use crate::exchanges::{gdax::Trade as GdaxTrade, ExchangeName};
+    use crate::test_helpers::market;
+    use crate::utilities::TimeFrame;
+    use chrono::{TimeZone, Utc};
+    use rust_decimal_macros::dec;
     use sqlx::PgPool;
 
+    #[tokio::test]
+    pub async fn create_day_partition_attaches_legacy_day_table() {
+        let configuration = get_configuration().expect("Failed to read configuration.");
+        let pool = PgPool::connect_with(configuration.databases["gdax"].with_db())
+            .await
+            .expect("Failed to connect to Postgres.");
+        let exchange = ExchangeName::Gdax;
+        let market = market("LEGACYTEST-USD", TimeFrame::T15);
+        let dt = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+        let schema = Schema::new(Some("{exchange}_"));
+        let s = schema.trades(&exchange);
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", s))
+            .execute(&pool)
+            .await
+            .expect("Failed to create schema.");
+        sqlx::query(&format!(
+            "DROP TABLE IF EXISTS {s}.{}, {s}.{}",
+            TradePartition::market(&market),
+            TradePartition::day(&market, &dt),
+            s = s
+        ))
+        .execute(&pool)
+        .await
+        .expect("Failed to drop tables.");
+
+        // Partitioned parent and market partition as created by the exchange
+        GdaxTrade::create_parent_table(&pool, &schema, &exchange)
+            .await
+            .expect("Failed to create parent table.");
+        TradePartition::create_market_partition(&pool, &schema, &market)
+            .await
+            .expect("Failed to create market partition.");
+
+        // Day table as created before partitioning, keyed on trade_id only
+        sqlx::query(&format!(
+            r#"
+            CREATE TABLE {s}.{} (
+                market_id uuid NOT NULL,
+                trade_id BIGINT NOT NULL,
+                PRIMARY KEY (trade_id),
+                price NUMERIC NOT NULL,
+                size NUMERIC NOT NULL,
+                side TEXT NOT NULL,
+                time timestamptz NOT NULL
+            )
+            "#,
+            TradePartition::day(&market, &dt),
+            s = s
+        ))
+        .execute(&pool)
+        .await
+        .expect("Failed to create legacy day table.");
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {s}.{} (market_id, trade_id, price, size, side, time)
+            VALUES ($1, 1, $2, $3, 'buy', $4)
+            "#,
+            TradePartition::day(&market, &dt),
+            s = s
+        ))
+        .bind(market.market_id)
+        .bind(dec!(100))
+        .bind(dec!(1))
+        .bind(dt)
+        .execute(&pool)
+        .await
+        .expect("Failed to insert legacy trade.");
+
+        TradePartition::create_day_partition(&pool, &schema, &market, &dt)
+            .await
+            .expect("Failed to attach legacy day table.");
+        let day = TradePartition::day(&market, &dt);
+        assert!(TradePartition::is_attached(&pool, &schema, &exchange, &day)
+            .await
+            .unwrap());
+        assert_eq!(
+            TradePartition::select_primary_key(&pool, &schema, &exchange, &day)
+                .await
+                .unwrap(),
+            vec!["market_id", "trade_id", "time"]
+        );
+        let trade =
+            GdaxTrade::select_one_gt_dt(&pool, &schema, &market, dt - chrono::Duration::days(1))
+                .await
+                .expect("Failed to select trade through the parent.");
+        assert_eq!(trade.trade_id, 1);
+
+        sqlx::query(&format!(
+            "DROP TABLE {}.{}",
+            s,
+            TradePartition::market(&market)
+        ))
+        .execute(&pool)
+        .await
+        .expect("Failed to drop market partition.");
+    }
+
     #[tokio::test]
     pub async fn insert_dup_trades_returns_error() {
         // Load configuration
@@ -639,7 +1097,7 @@ mod tests {
         println!("Configuration: {:?}", configuration);
 
         // Create db connection
-        let pool = PgPool::connect_with(configuration.ftx_db.with_db())
+        let pool = PgPool::connect_with(configuration.databases["ftx"].with_db())
             .await
             .expect("Failed to connect to Postgres.");
 
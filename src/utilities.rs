@@ -3,11 +3,18 @@ use rust_decimal::prelude::*;
 use rust_decimal_macros::dec;
 use serde::Deserialize;
 use serde::Serialize;
-use sqlx::PgPool;
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    PgPool, Postgres,
+};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::env;
 use std::fmt;
 use std::io::{self, Write};
+use std::sync::Mutex;
 use twilio::{OutboundMessage, TwilioClient};
 
 use crate::eldorado::ElDorado;
@@ -30,7 +37,7 @@ impl DateRange {
         let mut dr = Vec::new();
         while next < *end {
             dr.push(next);
-            next += tf.as_dur();
+            next = tf.next(&next);
         }
         if !dr.is_empty() {
             let first = dr.first().expect("Expected first dt in dr.");
@@ -83,7 +90,7 @@ impl ElDorado {
         let mut date_range = Vec::new();
         while dr_start < *end {
             date_range.push(dr_start);
-            dr_start += tf.as_dur();
+            dr_start = tf.next(&dr_start);
         }
         date_range
     }
@@ -272,16 +279,16 @@ impl Twilio {
                 Ok(m) => println!("{:?}", m),
                 Err(e) => eprintln!("{:?}", e),
             },
-            false => todo!(),
+            // Without a Twilio account the alert is only logged
+            false => println!("Alert: {}", message),
         }
     }
 }
 
-#[derive(
-    Debug, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type, Serialize, Deserialize, Ord, PartialOrd,
-)]
-#[sqlx(rename_all = "lowercase")]
-#[serde(rename_all = "lowercase")]
+// Standard time frames are fixed length and truncate from the unix epoch except W01 which starts
+// on Monday and M01 which is the calendar month. Custom time frames are defined in the config as a
+// length ie 2h30m and are stored as the number of seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub enum TimeFrame {
     S15,
     S30,
@@ -298,8 +305,16 @@ pub enum TimeFrame {
     H08,
     H12,
     D01,
+    W01,
+    M01,
+    Custom(u32),
 }
 
+// Custom time frames added from the config and the names of all custom time frames used. Names
+// are leaked so `as_str` can return a static str like the standard time frames.
+static CUSTOM_TFS: Mutex<Vec<TimeFrame>> = Mutex::new(Vec::new());
+static CUSTOM_NAMES: Mutex<BTreeMap<u32, &'static str>> = Mutex::new(BTreeMap::new());
+
 impl fmt::Display for TimeFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.as_str())
@@ -324,9 +339,18 @@ impl TimeFrame {
             TimeFrame::H08 => "h08",
             TimeFrame::H12 => "h12",
             TimeFrame::D01 => "d01",
+            TimeFrame::W01 => "w01",
+            TimeFrame::M01 => "m01",
+            TimeFrame::Custom(secs) => {
+                let mut names = CUSTOM_NAMES.lock().unwrap();
+                names
+                    .entry(*secs)
+                    .or_insert_with(|| Box::leak(Self::custom_name(*secs).into_boxed_str()))
+            }
         }
     }
 
+    // M01 is the nominal 30 day month. Use `trunc` and `next` to step through months.
     pub fn as_secs(&self) -> i64 {
         match self {
             TimeFrame::S15 => 15,
@@ -344,6 +368,9 @@ impl TimeFrame {
             TimeFrame::H08 => 28800,
             TimeFrame::H12 => 43200,
             TimeFrame::D01 => 86400,
+            TimeFrame::W01 => 604800,
+            TimeFrame::M01 => 2592000,
+            TimeFrame::Custom(secs) => *secs as i64,
         }
     }
 
@@ -364,6 +391,31 @@ impl TimeFrame {
             TimeFrame::H08 => Duration::hours(8),
             TimeFrame::H12 => Duration::hours(12),
             TimeFrame::D01 => Duration::days(1),
+            TimeFrame::W01 => Duration::weeks(1),
+            TimeFrame::M01 => Duration::days(30),
+            TimeFrame::Custom(secs) => Duration::seconds(*secs as i64),
+        }
+    }
+
+    // Start of the interval containing the datetime
+    pub fn trunc(&self, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            TimeFrame::W01 => {
+                let day = dt.duration_trunc(Duration::days(1)).unwrap();
+                day - Duration::days(dt.weekday().num_days_from_monday() as i64)
+            }
+            TimeFrame::M01 => Utc
+                .with_ymd_and_hms(dt.year(), dt.month(), 1, 0, 0, 0)
+                .unwrap(),
+            _ => dt.duration_trunc(self.as_dur()).unwrap(),
+        }
+    }
+
+    // Start of the next interval for a datetime at the start of an interval
+    pub fn next(&self, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            TimeFrame::M01 => ElDorado::next_month_dt(dt),
+            _ => *dt + self.as_dur(),
         }
     }
 
@@ -371,8 +423,10 @@ impl TimeFrame {
         86400 / self.as_secs()
     }
 
+    // Time frames with metrics: the standard set, W01, M01 and any custom time frames from the
+    // config, ordered by length.
     pub fn tfs() -> Vec<TimeFrame> {
-        let tfs = vec![
+        let mut tfs = vec![
             TimeFrame::T15,
             TimeFrame::H01,
             TimeFrame::H04,
@@ -380,7 +434,11 @@ impl TimeFrame {
             TimeFrame::H08,
             TimeFrame::H12,
             TimeFrame::D01,
+            TimeFrame::W01,
+            TimeFrame::M01,
         ];
+        tfs.extend(CUSTOM_TFS.lock().unwrap().iter());
+        tfs.sort_by_key(|tf| tf.as_secs());
         tfs
     }
 
@@ -405,12 +463,76 @@ impl TimeFrame {
         atfs
     }
 
+    // Add a custom time frame from the config to the metric time frames. Custom time frames are
+    // resampled from the base T15 candles so must be a multiple of 15 minutes.
+    pub fn add_custom(spec: &str) -> Result<TimeFrame, String> {
+        let tf = TimeFrame::try_from(spec.to_string())?;
+        if tf.as_secs() % TimeFrame::T15.as_secs() != 0 {
+            return Err(format!("{} is not a multiple of 15 minutes.", spec));
+        }
+        let mut customs = CUSTOM_TFS.lock().unwrap();
+        if !TimeFrame::tfs_standard().contains(&tf) && !customs.contains(&tf) {
+            customs.push(tf);
+        }
+        Ok(tf)
+    }
+
+    fn tfs_standard() -> Vec<TimeFrame> {
+        let mut tfs = TimeFrame::atfs();
+        tfs.extend([TimeFrame::W01, TimeFrame::M01]);
+        tfs
+    }
+
+    // Custom length from a spec of days, hours, minutes and seconds ie 1d12h or 2h30m. Lengths
+    // that match a fixed standard time frame return the standard time frame.
+    fn parse_custom(spec: &str) -> Option<TimeFrame> {
+        let mut secs: i64 = 0;
+        let mut digits = String::new();
+        for c in spec.chars() {
+            if c.is_ascii_digit() {
+                digits.push(c);
+                continue;
+            }
+            let n: i64 = digits.parse().ok()?;
+            digits.clear();
+            secs += n * match c {
+                'd' => 86400,
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return None,
+            };
+        }
+        if !digits.is_empty() || secs == 0 || secs % 15 != 0 || secs > u32::MAX as i64 {
+            return None;
+        }
+        Some(
+            TimeFrame::atfs()
+                .into_iter()
+                .chain([TimeFrame::W01])
+                .find(|tf| tf.as_secs() == secs)
+                .unwrap_or(TimeFrame::Custom(secs as u32)),
+        )
+    }
+
+    fn custom_name(secs: u32) -> String {
+        let mut rem = secs;
+        let mut name = String::new();
+        for (unit, len) in [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)] {
+            if rem >= len {
+                name.push_str(&format!("{}{}", rem / len, unit));
+                rem %= len;
+            }
+        }
+        name
+    }
+
     pub fn is_gt_timeframe(&self, dt1: DateTime<Utc>, dt2: DateTime<Utc>) -> bool {
-        dt1.duration_trunc(self.as_dur()).unwrap() < dt2.duration_trunc(self.as_dur()).unwrap()
+        self.trunc(&dt1) < self.trunc(&dt2)
     }
 
     pub fn is_lt_timeframe(&self, dt1: DateTime<Utc>, dt2: DateTime<Utc>) -> bool {
-        dt1.duration_trunc(self.as_dur()).unwrap() > dt2.duration_trunc(self.as_dur()).unwrap()
+        self.trunc(&dt1) > self.trunc(&dt2)
     }
 }
 
@@ -434,11 +556,51 @@ impl TryFrom<String> for TimeFrame {
             "h08" => Ok(Self::H08),
             "h12" => Ok(Self::H12),
             "d01" => Ok(Self::D01),
-            other => Err(format!("{} is not a supported TimeFrame.", other)),
+            "w01" => Ok(Self::W01),
+            "m01" => Ok(Self::M01),
+            other => Self::parse_custom(other)
+                .ok_or_else(|| format!("{} is not a supported TimeFrame.", other)),
         }
     }
 }
 
+// Time frames are stored and serialized as their str ie t15 or 2h30m
+impl Serialize for TimeFrame {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeFrame {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        TimeFrame::try_from(s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl sqlx::Type<Postgres> for TimeFrame {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <&str as sqlx::Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<'q> sqlx::Encode<'q, Postgres> for TimeFrame {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <&str as sqlx::Encode<Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Postgres> for TimeFrame {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let s = <&str as sqlx::Decode<Postgres>>::decode(value)?;
+        Ok(TimeFrame::try_from(s.to_string())?)
+    }
+}
+
 pub fn min_to_dp(increment: Decimal) -> i32 {
     if increment < dec!(1) {
         let dp = increment.scale() as i32;
@@ -590,6 +752,54 @@ mod tests {
         assert!(super::TimeFrame::T15.is_gt_timeframe(dt1, dt2));
     }
 
+    #[test]
+    pub fn weekly_and_monthly_timeframes_follow_the_calendar() {
+        use super::{DateRange, TimeFrame};
+        // 2023-03-01 is a Wednesday
+        let dt = Utc.with_ymd_and_hms(2023, 3, 1, 13, 45, 0).unwrap();
+        assert_eq!(
+            TimeFrame::W01.trunc(&dt),
+            Utc.with_ymd_and_hms(2023, 2, 27, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            TimeFrame::M01.trunc(&dt),
+            Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap()
+        );
+        let start = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap();
+        let dr = DateRange::new(&start, &end, &TimeFrame::M01).unwrap();
+        assert_eq!(dr.dts.len(), 3);
+        assert_eq!(dr.last, Utc.with_ymd_and_hms(2023, 3, 1, 0, 0, 0).unwrap());
+        assert!(TimeFrame::M01.is_gt_timeframe(dr.first, dr.last));
+    }
+
+    #[test]
+    pub fn custom_timeframes_parse_from_length() {
+        use super::TimeFrame;
+        use std::convert::TryFrom;
+        let tf = TimeFrame::try_from("2h30m".to_string()).unwrap();
+        assert_eq!(tf, TimeFrame::Custom(9000));
+        assert_eq!(tf.as_str(), "2h30m");
+        assert_eq!(TimeFrame::try_from(tf.as_str().to_string()).unwrap(), tf);
+        assert_eq!(tf.resample_from(), TimeFrame::T15);
+        assert_eq!(TimeFrame::Custom(43200 * 3).resample_from(), TimeFrame::H12);
+        // Lengths of standard time frames are the standard time frame
+        assert_eq!(
+            TimeFrame::try_from("60m".to_string()).unwrap(),
+            TimeFrame::H01
+        );
+        assert!(TimeFrame::try_from("2x".to_string()).is_err());
+        assert!(TimeFrame::add_custom("20m").is_err());
+        TimeFrame::add_custom("1d12h").unwrap();
+        let tfs = TimeFrame::tfs();
+        let i = tfs
+            .iter()
+            .position(|t| *t == TimeFrame::Custom(129600))
+            .unwrap();
+        assert_eq!(tfs[i - 1], TimeFrame::D01);
+        assert_eq!(tfs[i + 1], TimeFrame::W01);
+    }
+
     // #[test]
     // pub fn next_month_datetime_tests() {
     //     assert_eq!(
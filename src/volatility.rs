@@ -0,0 +1,271 @@
+use crate::{
+    candles::ProductionCandle,
+    indicators::{Indicator, Lookback},
+    utilities::TimeFrame,
+};
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+
+// Crypto markets trade 24/7 so a year is every interval of 365 days
+pub fn periods_per_year(tf: &TimeFrame) -> Decimal {
+    Decimal::from(365 * 86400) / Decimal::from(tf.as_secs())
+}
+
+// Annualized volatility from the average variance per interval
+fn annualize(variance: Decimal, tf: &TimeFrame) -> Decimal {
+    (variance.max(Decimal::ZERO) * periods_per_year(tf))
+        .sqrt()
+        .unwrap_or(Decimal::ZERO)
+        .round_dp(6)
+}
+
+// Log of the price ratio, zero if either price is not positive ie zero volume candles of a new
+// market
+fn ln_ratio(a: Decimal, b: Decimal) -> Decimal {
+    match a > Decimal::ZERO && b > Decimal::ZERO {
+        true => (a / b).checked_ln().unwrap_or(Decimal::ZERO),
+        false => Decimal::ZERO,
+    }
+}
+
+fn mean(v: &[Decimal]) -> Decimal {
+    v.iter()
+        .sum::<Decimal>()
+        .checked_div(Decimal::from(v.len()))
+        .unwrap_or(Decimal::ZERO)
+}
+
+// Sample variance
+fn variance(v: &[Decimal]) -> Decimal {
+    let m = mean(v);
+    v.iter()
+        .fold(Decimal::ZERO, |s, x| s + (x - m).powi(2))
+        .checked_div(Decimal::from(v.len().saturating_sub(1)))
+        .unwrap_or(Decimal::ZERO)
+}
+
+// Rogers-Satchell variance of a candle, independent of the drift
+fn rs_variance(c: &ProductionCandle) -> Decimal {
+    ln_ratio(c.high, c.close) * ln_ratio(c.high, c.open)
+        + ln_ratio(c.low, c.close) * ln_ratio(c.low, c.open)
+}
+
+// Range based volatility estimators over the lookback candles, annualized for the time frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeEstimator {
+    // Variance from the high low range
+    Parkinson,
+    // Adds the open to close move to the high low range
+    GarmanKlass,
+    // Allows for drift in the price
+    RogersSatchell,
+    // Adds the overnight open jump to Rogers-Satchell, here the open against the previous close
+    YangZhang,
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeVolatility {
+    pub estimator: RangeEstimator,
+    pub tf: TimeFrame,
+    pub lbp: Lookback,
+}
+
+impl Indicator for RangeVolatility {
+    fn name(&self) -> &'static str {
+        match self.estimator {
+            RangeEstimator::Parkinson => "parkinson",
+            RangeEstimator::GarmanKlass => "garman_klass",
+            RangeEstimator::RogersSatchell => "rogers_satchell",
+            RangeEstimator::YangZhang => "yang_zhang",
+        }
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone()]
+    }
+
+    // Yang-Zhang needs the close before the first candle
+    fn warmup(&self) -> usize {
+        match self.estimator {
+            RangeEstimator::YangZhang => self.lbp.n + 1,
+            _ => self.lbp.n,
+        }
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let window = &candles[candles.len() - self.lbp.n..];
+        let variance = match self.estimator {
+            RangeEstimator::Parkinson => {
+                let hl: Vec<_> = window
+                    .iter()
+                    .map(|c| ln_ratio(c.high, c.low).powi(2))
+                    .collect();
+                mean(&hl) / (dec!(4) * dec!(2).ln())
+            }
+            RangeEstimator::GarmanKlass => {
+                let k = dec!(2) * dec!(2).ln() - Decimal::ONE;
+                let v: Vec<_> = window
+                    .iter()
+                    .map(|c| {
+                        dec!(0.5) * ln_ratio(c.high, c.low).powi(2)
+                            - k * ln_ratio(c.close, c.open).powi(2)
+                    })
+                    .collect();
+                mean(&v)
+            }
+            RangeEstimator::RogersSatchell => {
+                mean(&window.iter().map(rs_variance).collect::<Vec<_>>())
+            }
+            RangeEstimator::YangZhang => {
+                let prev = &candles[candles.len() - self.lbp.n - 1..candles.len() - 1];
+                let opens: Vec<_> = window
+                    .iter()
+                    .zip(prev.iter())
+                    .map(|(c, p)| ln_ratio(c.open, p.close))
+                    .collect();
+                let closes: Vec<_> = window.iter().map(|c| ln_ratio(c.close, c.open)).collect();
+                let rs = mean(&window.iter().map(rs_variance).collect::<Vec<_>>());
+                // Weight that minimizes the estimator variance
+                let n = Decimal::from(self.lbp.n);
+                let k = dec!(0.34)
+                    / (dec!(1.34)
+                        + (n + Decimal::ONE)
+                            .checked_div(n - Decimal::ONE)
+                            .unwrap_or(Decimal::ONE));
+                variance(&opens) + k * variance(&closes) + (Decimal::ONE - k) * rs
+            }
+        };
+        vec![annualize(variance, &self.tf)]
+    }
+}
+
+// Realized volatility of the lookback candles from the log returns of the lower time frame
+// candles inside them, ie the S15 research candles rolled up to H01 or D01. Without lower time
+// frame candles it uses the returns of the candles themselves.
+#[derive(Debug, Clone)]
+pub struct RealizedVolatility {
+    pub tf: TimeFrame,
+    pub lbp: Lookback,
+}
+
+impl RealizedVolatility {
+    // Annualized from the sum of squared returns over the lookback intervals
+    fn realized(&self, returns: &[Decimal]) -> Decimal {
+        let sum = returns.iter().fold(Decimal::ZERO, |s, r| s + r.powi(2));
+        annualize(sum / Decimal::from(self.lbp.n), &self.tf)
+    }
+
+    fn log_returns(candles: &[ProductionCandle]) -> Vec<Decimal> {
+        candles
+            .windows(2)
+            .map(|w| ln_ratio(w[1].close, w[0].close))
+            .collect()
+    }
+}
+
+impl Indicator for RealizedVolatility {
+    fn name(&self) -> &'static str {
+        "rv"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec![self.lbp.label.clone()]
+    }
+
+    fn warmup(&self) -> usize {
+        self.lbp.n + 1
+    }
+
+    fn compute(&self, candles: &[ProductionCandle]) -> Vec<Decimal> {
+        let window = &candles[candles.len() - self.lbp.n - 1..];
+        vec![self.realized(&Self::log_returns(window))]
+    }
+
+    fn compute_from_base(
+        &self,
+        candles: &[ProductionCandle],
+        base: &[ProductionCandle],
+    ) -> Vec<Decimal> {
+        let start = candles[candles.len() - self.lbp.n].datetime;
+        let end = self.tf.next(&candles[candles.len() - 1].datetime);
+        // Base candles in the lookback with the one before it for the first return
+        let first = base.partition_point(|c| c.datetime < start);
+        let last = base.partition_point(|c| c.datetime < end);
+        if last <= first {
+            return self.compute(candles);
+        }
+        let window = &base[first.saturating_sub(1)..last];
+        vec![self.realized(&Self::log_returns(window))]
+    }
+
+    fn uses_base(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        indicators::{Indicator, Lookback},
+        test_helpers::{range_candles, start},
+        utilities::TimeFrame,
+        volatility::{periods_per_year, RangeEstimator, RangeVolatility, RealizedVolatility},
+    };
+    use rust_decimal::prelude::*;
+    use rust_decimal_macros::dec;
+
+    fn lookback(n: usize) -> Lookback {
+        Lookback {
+            n,
+            label: n.to_string(),
+        }
+    }
+
+    #[test]
+    pub fn range_estimators_are_annualized_and_agree_on_drift_free_candles() {
+        let tf = TimeFrame::D01;
+        assert_eq!(periods_per_year(&tf), dec!(365));
+        // Flat open to close with a symmetric range, every estimator sees only the range
+        let candles = range_candles(start(), &tf, dec!(100), &[dec!(100); 5]);
+        let estimate = |estimator| {
+            RangeVolatility {
+                estimator,
+                tf,
+                lbp: lookback(4),
+            }
+            .compute(&candles)[0]
+        };
+        let parkinson = estimate(RangeEstimator::Parkinson);
+        // ln(1.01 / 0.99)^2 / (4 ln 2) per day
+        let expected = ((dec!(1.01) / dec!(0.99)).ln().powi(2) / (dec!(4) * dec!(2).ln())
+            * dec!(365))
+        .sqrt()
+        .unwrap();
+        assert!((parkinson - expected).abs() < dec!(0.000001));
+        assert!(estimate(RangeEstimator::GarmanKlass) > Decimal::ZERO);
+        assert!(estimate(RangeEstimator::RogersSatchell) > Decimal::ZERO);
+        // No open jumps or moves so Yang-Zhang is the weighted Rogers-Satchell
+        assert!(estimate(RangeEstimator::YangZhang) < estimate(RangeEstimator::RogersSatchell));
+    }
+
+    #[test]
+    pub fn realized_volatility_uses_lower_time_frame_returns() {
+        let h01 = TimeFrame::H01;
+        let t15 = TimeFrame::T15;
+        // Base candles alternate up and down within each hour
+        let closes = [dec!(100), dec!(101)];
+        let base = range_candles(start(), &t15, dec!(100), &closes.repeat(6));
+        let hours = range_candles(start(), &h01, dec!(100), &[dec!(100); 3]);
+        let rv = RealizedVolatility {
+            tf: h01,
+            lbp: lookback(2),
+        };
+        // Hourly closes do not move
+        assert_eq!(rv.compute(&hours), vec![Decimal::ZERO]);
+        // 8 base returns of +/- ln(1.01) over the last 2 hours
+        let r = (dec!(101) / dec!(100)).ln();
+        let expected = (r.powi(2) * dec!(8) / dec!(2) * dec!(8760)).sqrt().unwrap();
+        let v = rv.compute_from_base(&hours[1..], &base);
+        assert!((v[0] - expected).abs() < dec!(0.000001));
+    }
+}
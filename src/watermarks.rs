@@ -0,0 +1,321 @@
+use crate::{
+    candles::ProductionCandle,
+    configuration::WatermarkSettings,
+    eldorado::{ElDorado, ElDoradoError},
+    exchanges::ExchangeName,
+    markets::MarketDetail,
+    mita::Heartbeat,
+    trades::PrIdTi,
+    utilities::{DateRange, TimeFrame},
+};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+impl WatermarkSettings {
+    // Watermark for the latest event time of a market at the wall clock now. Without an event
+    // time, ie no trades streamed, the wall clock is used.
+    pub fn watermark(&self, event: Option<DateTime<Utc>>, now: &DateTime<Utc>) -> DateTime<Utc> {
+        let floor = *now - Duration::milliseconds(self.idle);
+        let event = match event {
+            Some(e) => e.max(floor),
+            None => floor,
+        };
+        event - Duration::milliseconds(self.allowed_lateness)
+    }
+}
+
+// Replace the candle at the index with the amended candle and carry its close forward through the
+// following candles without trades. Each changed candle has its revision incremented. Returns the
+// changed candles, empty if the amended candle is the same as the existing one.
+pub fn amend_candles(
+    candles: &mut [ProductionCandle],
+    i: usize,
+    mut amended: ProductionCandle,
+) -> Vec<ProductionCandle> {
+    amended.revision = candles[i].revision;
+    if amended == candles[i] {
+        return Vec::new();
+    }
+    amended.revision += 1;
+    candles[i] = amended;
+    let mut changed = vec![candles[i].clone()];
+    let mut last = candles[i].close_as_pridti();
+    for candle in candles[i + 1..].iter_mut() {
+        if candle.trade_count > 0 {
+            break;
+        }
+        let mut carried = ProductionCandle::from_last(candle.datetime, &last);
+        carried.revision = candle.revision;
+        if carried == *candle {
+            break;
+        }
+        carried.revision += 1;
+        last = carried.close_as_pridti();
+        *candle = carried;
+        changed.push(candle.clone());
+    }
+    changed
+}
+
+impl ElDorado {
+    // Event time watermark for the market. Synthetics are held back by their slower leg.
+    pub fn watermark(&self, market: &MarketDetail, now: &DateTime<Utc>) -> DateTime<Utc> {
+        if market.exchange_name == ExchangeName::Index {
+            if let Some(spec) = self.synthetic_spec(market) {
+                let (base, quote) = self.synthetic_legs(spec);
+                return self.watermark(&base, now).min(self.watermark(&quote, now));
+            }
+        }
+        let event = self
+            .live
+            .lock()
+            .unwrap()
+            .get(&market.market_id)
+            .map(|lc| lc.watermark);
+        self.watermarks.watermark(event, now)
+    }
+
+    // Amend the closed candles of the market that received trades after they were closed. The
+    // candle is made again from the trade table and saved with the next revision, the resampled
+    // time frames are updated and the metrics recalculated.
+    pub async fn amend_late_candles(
+        &self,
+        market: &MarketDetail,
+        heartbeats: &mut HashMap<String, Heartbeat>,
+    ) -> Result<(), ElDoradoError> {
+        if heartbeats[&market.market_name].metrics.is_none() {
+            return Ok(());
+        }
+        let late: Vec<_> = {
+            let mut live = self.live.lock().unwrap();
+            match live.get_mut(&market.market_id) {
+                Some(lc) => std::mem::take(&mut lc.late).into_iter().collect(),
+                None => return Ok(()),
+            }
+        };
+        if late.is_empty() {
+            return Ok(());
+        }
+        let hb = heartbeats.get_mut(&market.market_name).unwrap();
+        let mut changed = Vec::new();
+        for dt in late.iter() {
+            let candles = hb.candles.get_mut(&market.tf).unwrap();
+            // Candles older than the heartbeat or without a previous candle are not amended
+            let i = match candles.binary_search_by_key(dt, |c| c.datetime) {
+                Ok(i) if i > 0 => i,
+                _ => continue,
+            };
+            let last = candles[i - 1].close_as_pridti();
+            if let Some(amended) = self.remake_production_candle(market, dt, &last).await? {
+                changed.append(&mut amend_candles(candles, i, amended));
+            }
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let pool = self.pools.exchange(&market.exchange_name);
+        for candle in changed.iter() {
+            println!(
+                "{} - Amending {} candle {} to revision {}.",
+                Utc::now(),
+                market.market_name,
+                candle.datetime,
+                candle.revision
+            );
+            candle
+                .upsert(pool, &self.schema, market, &market.tf)
+                .await?;
+        }
+        hb.last = hb.candles[&market.tf].last().unwrap().close_as_pridti();
+        // Resample the time frame intervals that contain the amended candles
+        let first = changed.iter().map(|c| c.datetime).min().unwrap();
+        let session = self.sessions.session(market);
+        for tf in TimeFrame::tfs().iter().skip(1) {
+            let start = session.trunc(tf, &first);
+            let base: Vec<_> = hb.candles[&market.tf]
+                .iter()
+                .filter(|c| c.datetime >= start)
+                .cloned()
+                .collect();
+            let resampled = self.resample_production_candles(&base, tf, &session);
+            let candles = hb.candles.get_mut(tf).unwrap();
+            for mut r in resampled.into_iter() {
+                // Only intervals that have closed are in the heartbeat
+                if let Ok(i) = candles.binary_search_by_key(&r.datetime, |c| c.datetime) {
+                    r.revision = candles[i].revision;
+                    if r != candles[i] {
+                        r.revision += 1;
+                        candles[i] = r;
+                    }
+                }
+            }
+        }
+        let metrics = self.calc_metrics_all_tfs(market, heartbeats);
+        self.insert_metrics(&metrics).await?;
+        self.insert_indicators(market, &heartbeats[&market.market_name], &TimeFrame::tfs())
+            .await?;
+        let hb = heartbeats.get_mut(&market.market_name).unwrap();
+        self.publish_closed(market, hb, &metrics).await?;
+        hb.metrics = Some(metrics);
+        Ok(())
+    }
+
+    // Make the candle for the closed interval again from the trades in the trade table
+    async fn remake_production_candle(
+        &self,
+        market: &MarketDetail,
+        dt: &DateTime<Utc>,
+        last: &PrIdTi,
+    ) -> Result<Option<ProductionCandle>, ElDoradoError> {
+        let dr = match DateRange::new(dt, &market.tf.next(dt), &market.tf) {
+            Some(dr) => dr,
+            None => return Ok(None),
+        };
+        match market.exchange_name {
+            ExchangeName::Gdax => Ok(self
+                .select_gdax_trades_for_interval(market, &dr)
+                .await?
+                .and_then(|trades| {
+                    ProductionCandle::from_trades_for_dr(
+                        &trades,
+                        Some(*last),
+                        &market.tf,
+                        &dr.dts,
+                        self.vap_step(market),
+                    )
+                    .pop()
+                })),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        candles::{LiveCandles, ProductionCandle},
+        configuration::WatermarkSettings,
+        exchanges::gdax::Trade as GdaxTrade,
+        test_helpers,
+        trades::PrIdTi,
+        utilities::TimeFrame,
+        watermarks::amend_candles,
+    };
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    pub fn watermark_trails_event_time_and_falls_back_to_wall_clock() {
+        let now = Utc.with_ymd_and_hms(2023, 3, 10, 0, 15, 1).unwrap();
+        // Defaults close on the wall clock
+        let settings = WatermarkSettings::default();
+        assert_eq!(settings.watermark(None, &now), now);
+        let settings = WatermarkSettings {
+            allowed_lateness: 2000,
+            idle: 30000,
+        };
+        // Stream is behind the wall clock so the interval stays open
+        let event = now - Duration::seconds(5);
+        assert_eq!(
+            settings.watermark(Some(event), &now),
+            now - Duration::seconds(7)
+        );
+        // Quiet market uses the wall clock less idle
+        assert_eq!(
+            settings.watermark(Some(now - Duration::minutes(5)), &now),
+            now - Duration::seconds(32)
+        );
+    }
+
+    #[test]
+    pub fn late_trade_marks_interval_and_amends_candle() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 10, 0, 0, 0).unwrap();
+        let mut lc = LiveCandles::new(start);
+        lc.closed = start + Duration::minutes(15);
+        let late = GdaxTrade {
+            trade_id: 2,
+            side: "buy".to_string(),
+            size: dec!(1),
+            price: dec!(110),
+            time: start + Duration::minutes(14),
+        };
+        lc.push(&late, &TimeFrame::T15, None);
+        assert!(lc.late.contains(&start));
+        assert!(lc.intervals.is_empty());
+        assert_eq!(lc.watermark, late.time);
+        // Amended candle closes higher and the close carries through the empty candle after it
+        let pit = PrIdTi {
+            dt: start,
+            id: 1,
+            price: dec!(100),
+        };
+        let mut candles = vec![
+            ProductionCandle::from_last(start, &pit),
+            ProductionCandle::from_last(start + Duration::minutes(15), &pit),
+        ];
+        let amended = ProductionCandle::from_trades_for_dr(
+            &[late],
+            Some(pit),
+            &TimeFrame::T15,
+            &[start],
+            None,
+        )
+        .pop()
+        .unwrap();
+        let changed = amend_candles(&mut candles, 0, amended.clone());
+        assert_eq!(changed.len(), 2);
+        assert_eq!(candles[0].close, dec!(110));
+        assert_eq!(candles[0].revision, 1);
+        assert_eq!(candles[1].close, dec!(110));
+        assert_eq!(candles[1].revision, 1);
+        // Same candle again is not a revision
+        assert!(amend_candles(&mut candles, 0, amended).is_empty());
+    }
+
+    #[test]
+    pub fn repaired_trade_bumps_candle_revision() {
+        let start = Utc.with_ymd_and_hms(2023, 3, 10, 0, 0, 0).unwrap();
+        let mut lc = LiveCandles::new(start);
+        lc.closed = start + Duration::minutes(30);
+        // Gap repair inserts trades for both closed intervals after they were published
+        let repaired = vec![
+            GdaxTrade {
+                trade_id: 3,
+                side: "sell".to_string(),
+                size: dec!(2),
+                price: dec!(95),
+                time: start + Duration::minutes(16),
+            },
+            GdaxTrade {
+                trade_id: 2,
+                side: "buy".to_string(),
+                size: dec!(1),
+                price: dec!(105),
+                time: start + Duration::minutes(3),
+            },
+        ];
+        lc.push_trades(&repaired, &TimeFrame::T15, None);
+        assert!(lc.late.contains(&start));
+        assert!(lc.late.contains(&(start + Duration::minutes(15))));
+        assert!(lc.intervals.is_empty());
+        let pit = PrIdTi {
+            dt: start,
+            id: 1,
+            price: dec!(100),
+        };
+        let mut candles = test_helpers::candles(start, &TimeFrame::T15, &[dec!(100), dec!(100)]);
+        let amended = ProductionCandle::from_trades_for_dr(
+            &[repaired[1].clone()],
+            Some(pit),
+            &TimeFrame::T15,
+            &[start],
+            None,
+        )
+        .pop()
+        .unwrap();
+        let changed = amend_candles(&mut candles, 0, amended);
+        assert_eq!(changed.len(), 2);
+        assert_eq!(candles[0].volume, dec!(1));
+        assert_eq!(candles[0].revision, 1);
+    }
+}